@@ -19,7 +19,7 @@ fn create_signed_srep_tags(c: &mut Criterion) {
 
     group.throughput(Elements(1));
     group.bench_function("create signed SREP tag", |b| {
-        b.iter(|| black_box(key.make_srep(Version::Rfc, now, &data)))
+        b.iter(|| black_box(key.make_srep(Version::Rfc, now, &data, None).unwrap()))
     });
     group.finish();
 }
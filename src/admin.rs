@@ -0,0 +1,835 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! A local HTTP admin endpoint that reports server state as JSON and accepts
+//! authenticated actions.
+//!
+//! Each worker publishes its online keys and request counters to a shared
+//! [`AdminRegistry`](struct.AdminRegistry.html); the admin endpoint renders the registry
+//! on request. Example output of `GET /status`:
+//!
+//! ```json
+//! {
+//!   "version": "1.3.0-draft11",
+//!   "features": [],
+//!   "long_term_public_key": "d0756ee69ff5fe96cbcf9273208fec53124b1dd3a24d3910e07c7c54e2473012",
+//!   "workers": [
+//!     {
+//!       "name": "worker-0",
+//!       "online_keys": [
+//!         { "protocol": "Rfc", "public_key": "25fd...cc66", "mint": 0, "maxt": 18446744073709551615,
+//!           "responses_signed": 10 }
+//!       ],
+//!       "grease_enabled": false,
+//!       "restarts": 0,
+//!       "counters": { "rfc_requests": 10, "classic_requests": 2, ... }
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! # Actions
+//!
+//! When an action token is configured, `POST` requests carrying an
+//! `Authorization: Bearer <token>` header queue an [`AdminAction`](enum.AdminAction.html)
+//! for every worker. Workers apply queued actions between batches of requests.
+//!
+//! Method and path | Action
+//! --- | ---
+//! `POST /rotate` | Replace each worker's online keys with freshly generated ones
+//! `POST /dump` | Log each worker's status now and start a new status interval
+//! `POST /grease/enable` | Enable deliberate response errors (if `fault_percentage` > 0)
+//! `POST /grease/disable` | Disable deliberate response errors
+//! `POST /revoke-and-exit` | Stop signing with the online keys, drop all requests, and exit
+//!
+//! # Metrics push
+//!
+//! Servers that can't be scraped can instead push the same JSON document to a collector:
+//! a [`MetricsPusher`](struct.MetricsPusher.html) `POST`s it to `metrics_push_url` every
+//! status interval, with an `Authorization: Bearer <token>` header if `metrics_push_token`
+//! is set. Remote collectors must be reached over `https://`, which requires the `https`
+//! feature; plain `http://` URLs are only accepted on a loopback host, as the token would
+//! otherwise cross the network unencrypted.
+//!
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
+use once_cell::sync::OnceCell;
+use ring::digest;
+
+use crate::http::{self, HttpUrl};
+use crate::responder::Responder;
+use crate::stats::ServerStats;
+use crate::{Error, VERSION};
+
+const HEX: Encoding = HEXLOWER_PERMISSIVE;
+
+// Longest an admin connection may take to send its request or accept the response, so a
+// client that stalls can't keep the (one connection at a time) endpoint from others
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Most bytes of request line and headers read from an admin request
+const MAX_REQUEST_HEAD_LENGTH: u64 = 8192;
+
+/// Delegated online key in use by a worker for one protocol version
+#[derive(Debug, Clone)]
+pub struct OnlineKeyStatus {
+    pub protocol: String,
+    pub public_key: String,
+    pub mint: u64,
+    pub maxt: u64,
+    /// Responses signed by this key so far
+    pub responses_signed: u64,
+}
+
+impl OnlineKeyStatus {
+    pub fn from_responder(responder: &Responder) -> Self {
+        let key = responder.online_key();
+
+        OnlineKeyStatus {
+            protocol: responder.version().to_string().to_string(),
+            public_key: HEX.encode(&key.public_key_bytes()),
+            mint: key.mint(),
+            maxt: key.maxt(),
+            responses_signed: responder.epoch_responses(),
+        }
+    }
+}
+
+/// Runtime actions that can be requested through the admin endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminAction {
+    /// Generate new online keys and delegations
+    RotateOnlineKey,
+
+    /// Log the current status and reset the counters
+    DumpMetrics,
+
+    /// Enable or disable deliberate response errors
+    SetGrease(bool),
+
+    /// Treat the keys as compromised: stop signing and exit the server
+    RevokeAndExit,
+}
+
+impl AdminAction {
+    fn name(&self) -> &'static str {
+        match self {
+            AdminAction::RotateOnlineKey => "rotate",
+            AdminAction::DumpMetrics => "dump",
+            AdminAction::SetGrease(true) => "grease_enable",
+            AdminAction::SetGrease(false) => "grease_disable",
+            AdminAction::RevokeAndExit => "revoke_and_exit",
+        }
+    }
+}
+
+/// Request and response counters of a worker since its last status update
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WorkerCounters {
+    pub rfc_requests: u64,
+    pub classic_requests: u64,
+    pub invalid_requests: u64,
+    pub health_checks: u64,
+    pub rfc_responses_sent: u64,
+    pub classic_responses_sent: u64,
+    pub bytes_sent: u64,
+    pub failed_send_attempts: u64,
+    pub retried_send_attempts: u64,
+    pub unique_clients: u64,
+    pub batches_signed: u64,
+    pub signing_latency_p50_us: u64,
+    pub signing_latency_p99_us: u64,
+    pub online_key_rotations: u64,
+    pub delegation_window_violations: u64,
+    pub clock_disagreements: u64,
+}
+
+impl WorkerCounters {
+    pub fn from_stats(stats: &dyn ServerStats) -> Self {
+        let signing = stats.signing_latency();
+        let micros = |pct: f64| signing.percentile(pct).unwrap_or_default().as_micros() as u64;
+
+        WorkerCounters {
+            rfc_requests: stats.num_rfc_requests(),
+            classic_requests: stats.num_classic_requests(),
+            invalid_requests: stats.total_invalid_requests(),
+            health_checks: stats.total_health_checks(),
+            rfc_responses_sent: stats.num_rfc_responses_sent(),
+            classic_responses_sent: stats.num_classic_responses_sent(),
+            bytes_sent: stats.total_bytes_sent(),
+            failed_send_attempts: stats.total_failed_send_attempts(),
+            retried_send_attempts: stats.total_retried_send_attempts(),
+            unique_clients: stats.total_unique_clients(),
+            batches_signed: signing.count(),
+            signing_latency_p50_us: micros(50.0),
+            signing_latency_p99_us: micros(99.0),
+            online_key_rotations: stats.total_online_key_rotations(),
+            delegation_window_violations: stats.total_delegation_window_violations(),
+            clock_disagreements: stats.total_clock_disagreements(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct WorkerStatus {
+    online_keys: Vec<OnlineKeyStatus>,
+    grease_enabled: bool,
+    restarts: u64,
+    counters: WorkerCounters,
+    interval_start: SystemTime,
+    pending_actions: Vec<AdminAction>,
+}
+
+///
+/// State shared between the server workers and the admin endpoint.
+///
+#[derive(Default)]
+pub struct AdminRegistry {
+    long_term_public_key: OnceCell<String>,
+    workers: Mutex<BTreeMap<String, WorkerStatus>>,
+}
+
+impl AdminRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add (or replace) a worker and the online keys it is using. All workers share
+    /// the same long-term key, the first one registered is reported. A replaced worker keeps
+    /// its count of restarts.
+    pub fn register_worker(
+        &self,
+        name: &str,
+        long_term_public_key: &str,
+        online_keys: Vec<OnlineKeyStatus>,
+    ) {
+        let _ = self
+            .long_term_public_key
+            .set(long_term_public_key.to_string());
+
+        let mut workers = self.workers.lock().unwrap();
+        let status = WorkerStatus {
+            online_keys,
+            grease_enabled: false,
+            restarts: workers.get(name).map_or(0, |s| s.restarts),
+            counters: WorkerCounters::default(),
+            interval_start: SystemTime::now(),
+            pending_actions: Vec::new(),
+        };
+
+        workers.insert(name.to_string(), status);
+    }
+
+    /// Count a restart of a registered worker after it panicked
+    pub fn record_restart(&self, name: &str) {
+        if let Some(status) = self.workers.lock().unwrap().get_mut(name) {
+            status.restarts += 1;
+        }
+    }
+
+    /// Publish the current counters of a registered worker
+    pub fn update_counters(&self, name: &str, counters: WorkerCounters) {
+        if let Some(status) = self.workers.lock().unwrap().get_mut(name) {
+            status.counters = counters;
+        }
+    }
+
+    /// Publish the online keys a registered worker is now using
+    pub fn update_online_keys(&self, name: &str, online_keys: Vec<OnlineKeyStatus>) {
+        if let Some(status) = self.workers.lock().unwrap().get_mut(name) {
+            status.online_keys = online_keys;
+        }
+    }
+
+    /// Publish whether a registered worker is adding deliberate response errors
+    pub fn set_grease_enabled(&self, name: &str, enabled: bool) {
+        if let Some(status) = self.workers.lock().unwrap().get_mut(name) {
+            status.grease_enabled = enabled;
+        }
+    }
+
+    /// Queue `action` for every registered worker. Returns the number of workers.
+    pub fn queue_action(&self, action: AdminAction) -> usize {
+        let mut workers = self.workers.lock().unwrap();
+        for status in workers.values_mut() {
+            status.pending_actions.push(action);
+        }
+        workers.len()
+    }
+
+    /// Remove and return the actions queued for a worker
+    pub fn take_actions(&self, name: &str) -> Vec<AdminAction> {
+        match self.workers.lock().unwrap().get_mut(name) {
+            Some(status) => std::mem::take(&mut status.pending_actions),
+            None => Vec::new(),
+        }
+    }
+
+    /// Note that a worker has started a new status interval and its counters were reset
+    pub fn reset_counters(&self, name: &str) {
+        if let Some(status) = self.workers.lock().unwrap().get_mut(name) {
+            status.counters = WorkerCounters::default();
+            status.interval_start = SystemTime::now();
+        }
+    }
+
+    /// Render the registry as a JSON object
+    pub fn to_json(&self) -> String {
+        let workers = self.workers.lock().unwrap();
+
+        let worker_json: Vec<String> = workers
+            .iter()
+            .map(|(name, status)| {
+                let keys: Vec<String> = status
+                    .online_keys
+                    .iter()
+                    .map(|k| {
+                        format!(
+                            concat!(
+                                r#"{{"protocol":"{}","public_key":"{}","mint":{},"maxt":{},"#,
+                                r#""responses_signed":{}}}"#
+                            ),
+                            k.protocol,
+                            k.public_key,
+                            k.mint,
+                            k.maxt,
+                            k.responses_signed
+                        )
+                    })
+                    .collect();
+
+                let c = &status.counters;
+                let counters = format!(
+                    concat!(
+                        r#"{{"interval_start":{},"unique_clients":{},"rfc_requests":{},"#,
+                        r#""classic_requests":{},"invalid_requests":{},"health_checks":{},"#,
+                        r#""rfc_responses_sent":{},"classic_responses_sent":{},"bytes_sent":{},"#,
+                        r#""failed_send_attempts":{},"retried_send_attempts":{},"batches_signed":{},"#,
+                        r#""signing_latency_p50_us":{},"signing_latency_p99_us":{},"#,
+                        r#""online_key_rotations":{},"delegation_window_violations":{},"#,
+                        r#""clock_disagreements":{}}}"#
+                    ),
+                    epoch_secs(status.interval_start),
+                    c.unique_clients,
+                    c.rfc_requests,
+                    c.classic_requests,
+                    c.invalid_requests,
+                    c.health_checks,
+                    c.rfc_responses_sent,
+                    c.classic_responses_sent,
+                    c.bytes_sent,
+                    c.failed_send_attempts,
+                    c.retried_send_attempts,
+                    c.batches_signed,
+                    c.signing_latency_p50_us,
+                    c.signing_latency_p99_us,
+                    c.online_key_rotations,
+                    c.delegation_window_violations,
+                    c.clock_disagreements
+                );
+
+                format!(
+                    concat!(
+                        r#"{{"name":"{}","online_keys":[{}],"grease_enabled":{},"restarts":{},"#,
+                        r#""counters":{}}}"#
+                    ),
+                    name,
+                    keys.join(","),
+                    status.grease_enabled,
+                    status.restarts,
+                    counters
+                )
+            })
+            .collect();
+
+        format!(
+            r#"{{"version":"{}","features":[{}],"long_term_public_key":"{}","workers":[{}]}}"#,
+            VERSION,
+            enabled_features()
+                .iter()
+                .map(|f| format!(r#""{}""#, f))
+                .collect::<Vec<String>>()
+                .join(","),
+            self.long_term_public_key
+                .get()
+                .map(String::as_str)
+                .unwrap_or_default(),
+            worker_json.join(",")
+        )
+    }
+}
+
+fn epoch_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Optional compile-time features enabled in this build
+pub fn enabled_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "awskms") {
+        features.push("awskms");
+    }
+    if cfg!(feature = "gcpkms") {
+        features.push("gcpkms");
+    }
+    if cfg!(feature = "dangerous-testing") {
+        features.push("dangerous-testing");
+    }
+    if cfg!(feature = "https") {
+        features.push("https");
+    }
+    features
+}
+
+/// The parts of an HTTP request the admin endpoint cares about
+#[derive(Debug, Default)]
+pub struct AdminRequest {
+    pub method: String,
+    pub path: String,
+    pub authorization: Option<String>,
+}
+
+impl AdminRequest {
+    /// Read the request line and headers of an HTTP request. Any body, and anything past
+    /// the first `MAX_REQUEST_HEAD_LENGTH` bytes, is ignored.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Self> {
+        let reader = &mut reader.take(MAX_REQUEST_HEAD_LENGTH);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+
+        let mut parts = line.split_whitespace();
+        let mut request = AdminRequest {
+            method: parts.next().unwrap_or_default().to_string(),
+            path: parts.next().unwrap_or_default().to_string(),
+            authorization: None,
+        };
+
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("authorization") {
+                    request.authorization = Some(value.trim().to_string());
+                }
+            }
+        }
+
+        Ok(request)
+    }
+
+    // Compare digests so the comparison time doesn't depend on how much of the token matched
+    fn has_token(&self, token: &str) -> bool {
+        let provided = match &self.authorization {
+            Some(value) => match value.strip_prefix("Bearer ") {
+                Some(v) => v.trim(),
+                None => return false,
+            },
+            None => return false,
+        };
+
+        let lhs = digest::digest(&digest::SHA256, provided.as_bytes());
+        let rhs = digest::digest(&digest::SHA256, token.as_bytes());
+
+        lhs.as_ref()
+            .iter()
+            .zip(rhs.as_ref())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+fn error_body(msg: &str) -> String {
+    format!(r#"{{"error":"{}"}}"#, msg)
+}
+
+/// Produce the HTTP status line and body for `request`. Actions are refused unless
+/// `token` is provided and matches the request's bearer token.
+pub fn handle_request(
+    request: &AdminRequest,
+    registry: &AdminRegistry,
+    token: Option<&str>,
+) -> (&'static str, String) {
+    match request.method.as_str() {
+        "GET" => match request.path.as_str() {
+            "/" | "/status" => ("200 OK", registry.to_json()),
+            _ => ("404 Not Found", error_body("not found")),
+        },
+        "POST" => {
+            let action = match request.path.as_str() {
+                "/rotate" => AdminAction::RotateOnlineKey,
+                "/dump" => AdminAction::DumpMetrics,
+                "/grease/enable" => AdminAction::SetGrease(true),
+                "/grease/disable" => AdminAction::SetGrease(false),
+                "/revoke-and-exit" => AdminAction::RevokeAndExit,
+                _ => return ("404 Not Found", error_body("not found")),
+            };
+
+            match token {
+                None => (
+                    "403 Forbidden",
+                    error_body("actions disabled; no admin_token configured"),
+                ),
+                Some(token) if !request.has_token(token) => {
+                    ("401 Unauthorized", error_body("missing or invalid token"))
+                }
+                Some(_) => {
+                    let num_workers = registry.queue_action(action);
+                    info!("admin action '{}' queued", action.name());
+
+                    let body = format!(
+                        r#"{{"action":"{}","workers":{}}}"#,
+                        action.name(),
+                        num_workers
+                    );
+                    ("202 Accepted", body)
+                }
+            }
+        }
+        _ => ("405 Method Not Allowed", error_body("method not allowed")),
+    }
+}
+
+fn handle_connection(stream: &mut TcpStream, registry: &AdminRegistry, token: Option<&str>) {
+    let timeouts = stream
+        .set_read_timeout(Some(CONNECTION_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(CONNECTION_TIMEOUT)));
+    if let Err(e) = timeouts {
+        warn!("error setting admin connection timeouts: {}", e);
+        return;
+    }
+
+    let request = match AdminRequest::read_from(&mut BufReader::new(&*stream)) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("error reading admin request: {}", e);
+            return;
+        }
+    };
+
+    let (status, body) = handle_request(&request, registry, token);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        warn!("error writing admin response: {}", e);
+    }
+
+    if let Err(e) = stream.shutdown(Shutdown::Both) {
+        debug!("error in admin socket shutdown: {}", e);
+    }
+}
+
+/// Serve admin requests on `listener` until the listener fails. Blocks the calling thread.
+///
+/// Actions are only accepted if `token` is provided.
+pub fn serve(listener: TcpListener, registry: &AdminRegistry, token: Option<&str>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => handle_connection(&mut stream, registry, token),
+            Err(e) => warn!("admin endpoint accept failed: {}", e),
+        }
+    }
+}
+
+/// Pushes snapshots of an [`AdminRegistry`](struct.AdminRegistry.html) to a collector
+pub struct MetricsPusher {
+    url: HttpUrl,
+    token: Option<String>,
+    timeout: Duration,
+}
+
+impl MetricsPusher {
+    pub fn new(url: HttpUrl, token: Option<&str>) -> Self {
+        MetricsPusher {
+            url,
+            token: token.map(str::to_string),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// `POST` `body` to the push URL, failing unless the collector accepts it
+    pub fn push(&self, body: &str) -> Result<(), Error> {
+        http::post_json(&self.url, body, self.token.as_deref(), self.timeout)
+    }
+
+    /// Push a snapshot of `registry` every `interval`, forever. Failures are logged and the
+    /// next snapshot is pushed as usual. Blocks the calling thread.
+    pub fn run(&self, registry: &AdminRegistry, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+
+            match self.push(&registry.to_json()) {
+                Ok(()) => debug!("pushed metrics to {}", self.url),
+                Err(e) => warn!("metrics push to {} failed: {:?}", self.url, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader, Cursor, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::admin::{
+        handle_request, serve, AdminAction, AdminRegistry, AdminRequest, MetricsPusher,
+        OnlineKeyStatus, WorkerCounters, CONNECTION_TIMEOUT, MAX_REQUEST_HEAD_LENGTH,
+    };
+
+    fn request(raw: &str) -> AdminRequest {
+        AdminRequest::read_from(&mut Cursor::new(raw.as_bytes())).unwrap()
+    }
+
+    fn status_of(raw: &str, registry: &AdminRegistry, token: Option<&str>) -> &'static str {
+        handle_request(&request(raw), registry, token).0
+    }
+
+    fn registry() -> AdminRegistry {
+        let registry = AdminRegistry::new();
+        registry.register_worker(
+            "worker-0",
+            "d0756e",
+            vec![OnlineKeyStatus {
+                protocol: "Rfc".to_string(),
+                public_key: "25fd5d".to_string(),
+                mint: 0,
+                maxt: u64::MAX,
+                responses_signed: 3,
+            }],
+        );
+        registry
+    }
+
+    #[test]
+    fn status_includes_keys_and_counters() {
+        let registry = registry();
+        let counters = WorkerCounters {
+            rfc_requests: 7,
+            ..WorkerCounters::default()
+        };
+        registry.update_counters("worker-0", counters);
+        registry.update_counters("no-such-worker", counters);
+
+        let json = registry.to_json();
+        assert!(json.contains(r#""long_term_public_key":"d0756e""#));
+        assert!(json.contains(r#""name":"worker-0""#));
+        assert!(json.contains(
+            r#""public_key":"25fd5d","mint":0,"maxt":18446744073709551615,"responses_signed":3"#
+        ));
+        assert!(json.contains(r#""rfc_requests":7"#));
+        assert!(!json.contains("no-such-worker"));
+
+        registry.reset_counters("worker-0");
+        assert!(registry.to_json().contains(r#""rfc_requests":0"#));
+    }
+
+    #[test]
+    fn restarts_survive_reregistration() {
+        let registry = registry();
+        assert!(registry.to_json().contains(r#""restarts":0"#));
+
+        registry.record_restart("worker-0");
+        registry.register_worker("worker-0", "d0756e", Vec::new());
+        assert!(registry.to_json().contains(r#""restarts":1"#));
+    }
+
+    #[test]
+    fn request_routing() {
+        let registry = registry();
+
+        assert_eq!(
+            status_of("GET / HTTP/1.1\r\n\r\n", &registry, None),
+            "200 OK"
+        );
+        assert_eq!(status_of("GET /status HTTP/1.1", &registry, None), "200 OK");
+        assert_eq!(
+            status_of("GET /x HTTP/1.1", &registry, None),
+            "404 Not Found"
+        );
+        assert_eq!(
+            status_of("DELETE / HTTP/1.1", &registry, None),
+            "405 Method Not Allowed"
+        );
+        assert_eq!(status_of("", &registry, None), "405 Method Not Allowed");
+    }
+
+    #[test]
+    fn parses_authorization_header() {
+        let req =
+            request("POST /rotate HTTP/1.1\r\nHost: x\r\nauthorization: Bearer s3cret\r\n\r\n");
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/rotate");
+        assert_eq!(req.authorization.as_deref(), Some("Bearer s3cret"));
+        assert!(req.has_token("s3cret"));
+        assert!(!req.has_token("s3cre"));
+        assert!(!request("POST /rotate HTTP/1.1\r\n\r\n").has_token("s3cret"));
+    }
+
+    #[test]
+    fn actions_require_token() {
+        let registry = registry();
+        let rotate = "POST /rotate HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n";
+
+        assert_eq!(status_of(rotate, &registry, None), "403 Forbidden");
+        assert_eq!(
+            status_of(rotate, &registry, Some("other")),
+            "401 Unauthorized"
+        );
+        assert_eq!(
+            status_of("POST /rotate HTTP/1.1\r\n\r\n", &registry, Some("s3cret")),
+            "401 Unauthorized"
+        );
+        assert!(registry.take_actions("worker-0").is_empty());
+
+        assert_eq!(status_of(rotate, &registry, Some("s3cret")), "202 Accepted");
+        assert_eq!(
+            status_of(
+                "POST /grease/disable HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n",
+                &registry,
+                Some("s3cret")
+            ),
+            "202 Accepted"
+        );
+        assert_eq!(
+            status_of(
+                "POST /revoke-and-exit HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n",
+                &registry,
+                Some("s3cret")
+            ),
+            "202 Accepted"
+        );
+        assert_eq!(
+            status_of(
+                "POST /nope HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n",
+                &registry,
+                Some("s3cret")
+            ),
+            "404 Not Found"
+        );
+
+        assert_eq!(
+            registry.take_actions("worker-0"),
+            vec![
+                AdminAction::RotateOnlineKey,
+                AdminAction::SetGrease(false),
+                AdminAction::RevokeAndExit
+            ]
+        );
+        assert!(registry.take_actions("worker-0").is_empty());
+    }
+
+    #[test]
+    fn serves_json_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = Arc::new(registry());
+
+        let reg = registry.clone();
+        thread::spawn(move || serve(listener, &reg, None));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /status HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&registry.to_json()));
+    }
+
+    #[test]
+    fn stalled_connections_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = Arc::new(registry());
+
+        let reg = registry.clone();
+        thread::spawn(move || serve(listener, &reg, None));
+
+        // Never finishes its request line
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(b"GET /sta").unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /status HTTP/1.1\r\n\r\n").unwrap();
+        stream
+            .set_read_timeout(Some(CONNECTION_TIMEOUT * 3))
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn request_heads_are_capped() {
+        let mut raw = "POST /rotate HTTP/1.1\r\nX-Padding: ".to_string();
+        raw.push_str(&"a".repeat(2 * MAX_REQUEST_HEAD_LENGTH as usize));
+        raw.push_str("\r\nAuthorization: Bearer secret\r\n\r\n");
+
+        let request = request(&raw);
+        assert_eq!(request.path, "/rotate");
+        assert_eq!(request.authorization, None);
+    }
+
+    #[test]
+    fn pushes_status_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ingest", listener.local_addr().unwrap());
+        let pusher = MetricsPusher::new(url.parse().unwrap(), Some("s3cret"));
+
+        let json = registry().to_json();
+        let body_len = json.len();
+
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let request = AdminRequest::read_from(&mut reader).unwrap();
+            let mut body = vec![0u8; body_len];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (request, body)
+        });
+
+        assert!(pusher.push(&json).is_ok());
+
+        let (request, body) = collector.join().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/ingest");
+        assert!(request.has_token("s3cret"));
+        assert_eq!(body, json.as_bytes());
+    }
+}
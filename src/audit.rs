@@ -0,0 +1,387 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Audit log of anomalous requests, kept apart from the operational log.
+//!
+//! Each anomaly is one line of JSON (NDJSON) naming the event, its source, and a hash of
+//! the request's bytes, so requests can be correlated without storing what was sent:
+//!
+//! ```json
+//! {"time":"2024-05-01T12:00:00.123Z","worker":"worker-0","event":"srv_mismatch","src_ip":"192.0.2.7","length":1024,"payload_hash":"9f86d081884c7d659a2feaa0c55ad015","detail":"SrvMismatch"}
+//! ```
+//!
+//! Workers share one [`AuditLog`](struct.AuditLog.html), each through its own
+//! [`Auditor`](struct.Auditor.html). An auditor writes at most
+//! [`MAX_ENTRIES_PER_SECOND`](constant.MAX_ENTRIES_PER_SECOND.html) entries per second, so a
+//! flood of bad requests can't flood the log; the next entry written reports how many were
+//! suppressed in a `suppressed` field.
+//!
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
+use ring::digest;
+
+use crate::version::Version;
+use crate::Error;
+
+const HEX: Encoding = HEXLOWER_PERMISSIVE;
+
+/// Most entries each worker writes per second
+pub const MAX_ENTRIES_PER_SECOND: u32 = 10;
+
+/// Number of recent nonces each worker remembers to detect replayed requests
+pub const REPLAY_WINDOW: usize = 4096;
+
+// Bytes of the request's SHA-256 hash that are logged
+const PAYLOAD_HASH_LENGTH: usize = 16;
+
+/// Kinds of anomalous requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anomaly {
+    /// Larger than any valid request
+    OversizedRequest,
+
+    /// Smaller than the minimum request size
+    UndersizedRequest,
+
+    /// Addressed to a different long-term key (SRV tag mismatch)
+    SrvMismatch,
+
+    /// Lacking a valid pre-shared key authenticator
+    Unauthenticated,
+
+    /// Offering no protocol version this server supports
+    NoCompatibleVersion,
+
+    /// Not a well-formed Roughtime request
+    ParseFailure,
+
+    /// A nonce this worker answered recently
+    Replay,
+}
+
+impl Anomaly {
+    /// The anomaly that rejecting a request with `err` represents
+    pub fn from_error(err: &Error) -> Anomaly {
+        match err {
+            Error::RequestTooLarge => Anomaly::OversizedRequest,
+            Error::RequestTooShort => Anomaly::UndersizedRequest,
+            Error::SrvMismatch => Anomaly::SrvMismatch,
+            Error::RequestNotAuthenticated => Anomaly::Unauthenticated,
+            Error::NoCompatibleVersion => Anomaly::NoCompatibleVersion,
+            _ => Anomaly::ParseFailure,
+        }
+    }
+
+    /// Name of the anomaly in audit entries
+    pub fn name(&self) -> &'static str {
+        match self {
+            Anomaly::OversizedRequest => "oversized_request",
+            Anomaly::UndersizedRequest => "undersized_request",
+            Anomaly::SrvMismatch => "srv_mismatch",
+            Anomaly::Unauthenticated => "unauthenticated",
+            Anomaly::NoCompatibleVersion => "no_compatible_version",
+            Anomaly::ParseFailure => "parse_failure",
+            Anomaly::Replay => "replay",
+        }
+    }
+}
+
+///
+/// Destination of audit entries shared by all workers.
+///
+pub struct AuditLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AuditLog {
+    /// Append audit entries to the file at `path`, creating it if needed
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog::new(Box::new(file)))
+    }
+
+    /// Write audit entries to `writer`
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        AuditLog {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    // Write one complete line, so entries from different workers never interleave
+    fn write_line(&self, line: &str) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer
+            .write_all(line.as_bytes())
+            .and_then(|_| writer.flush())
+        {
+            warn!("failed writing audit entry: {}", e);
+        }
+    }
+}
+
+///
+/// A worker's view of the [`AuditLog`](struct.AuditLog.html): rate limits its entries and
+/// remembers the nonces it recently answered.
+///
+pub struct Auditor {
+    log: Arc<AuditLog>,
+    worker: String,
+    window_start: Instant,
+    written_in_window: u32,
+    suppressed: u64,
+    seen_nonces: HashSet<Vec<u8>>,
+    nonce_order: VecDeque<Vec<u8>>,
+}
+
+impl Auditor {
+    /// Audit the requests of `worker` to `log`
+    pub fn new(log: Arc<AuditLog>, worker: &str) -> Self {
+        Auditor {
+            log,
+            worker: worker.to_string(),
+            window_start: Instant::now(),
+            written_in_window: 0,
+            suppressed: 0,
+            seen_nonces: HashSet::with_capacity(REPLAY_WINDOW),
+            nonce_order: VecDeque::with_capacity(REPLAY_WINDOW),
+        }
+    }
+
+    /// Record the outcome of parsing a request of `payload` bytes from `src` if it was
+    /// rejected or replays a recent nonce
+    pub fn audit(
+        &mut self,
+        request: &Result<(Vec<u8>, Version), Error>,
+        src: &SocketAddr,
+        payload: &[u8],
+    ) {
+        match request {
+            Ok((nonce, _)) => {
+                if self.is_replay(nonce) {
+                    self.record(Anomaly::Replay, src, payload, "nonce seen recently");
+                }
+            }
+            Err(e) => self.record(Anomaly::from_error(e), src, payload, &format!("{:?}", e)),
+        }
+    }
+
+    /// Remember `nonce`, returning true if it is among the last
+    /// [`REPLAY_WINDOW`](constant.REPLAY_WINDOW.html) nonces seen
+    pub fn is_replay(&mut self, nonce: &[u8]) -> bool {
+        if self.seen_nonces.contains(nonce) {
+            return true;
+        }
+
+        if self.nonce_order.len() == REPLAY_WINDOW {
+            if let Some(oldest) = self.nonce_order.pop_front() {
+                self.seen_nonces.remove(&oldest);
+            }
+        }
+        self.seen_nonces.insert(nonce.to_vec());
+        self.nonce_order.push_back(nonce.to_vec());
+        false
+    }
+
+    /// Record `anomaly` in a request of `payload` bytes from `src`, unless this worker has
+    /// reached its limit of entries for the current second
+    pub fn record(&mut self, anomaly: Anomaly, src: &SocketAddr, payload: &[u8], detail: &str) {
+        self.record_at(
+            Instant::now(),
+            SystemTime::now(),
+            anomaly,
+            src,
+            payload,
+            detail,
+        );
+    }
+
+    fn record_at(
+        &mut self,
+        now: Instant,
+        time: SystemTime,
+        anomaly: Anomaly,
+        src: &SocketAddr,
+        payload: &[u8],
+        detail: &str,
+    ) {
+        if now.saturating_duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.written_in_window = 0;
+        }
+
+        if self.written_in_window >= MAX_ENTRIES_PER_SECOND {
+            self.suppressed += 1;
+            return;
+        }
+        self.written_in_window += 1;
+
+        let hash = digest::digest(&digest::SHA256, payload);
+        let suppressed = if self.suppressed > 0 {
+            format!(r#","suppressed":{}"#, self.suppressed)
+        } else {
+            String::new()
+        };
+
+        let line = format!(
+            concat!(
+                r#"{{"time":"{}","worker":{:?},"event":"{}","src_ip":"{}","length":{},"#,
+                r#""payload_hash":"{}","detail":{:?}{}}}"#,
+                "\n"
+            ),
+            DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.worker,
+            anomaly.name(),
+            src.ip(),
+            payload.len(),
+            HEX.encode(&hash.as_ref()[..PAYLOAD_HASH_LENGTH]),
+            detail,
+            suppressed
+        );
+
+        self.log.write_line(&line);
+        self.suppressed = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use crate::audit::{Anomaly, AuditLog, Auditor, MAX_ENTRIES_PER_SECOND, REPLAY_WINDOW};
+    use crate::Error;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<String> {
+            let contents = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            contents.lines().map(str::to_string).collect()
+        }
+    }
+
+    fn auditor() -> (Auditor, SharedBuffer) {
+        let buffer = SharedBuffer::default();
+        let log = Arc::new(AuditLog::new(Box::new(buffer.clone())));
+        (Auditor::new(log, "worker-0"), buffer)
+    }
+
+    #[test]
+    fn entries_are_json_lines() {
+        let (mut auditor, buffer) = auditor();
+        let src: SocketAddr = "192.0.2.7:4242".parse().unwrap();
+        let time = UNIX_EPOCH + Duration::from_millis(1_714_564_800_123);
+
+        let anomaly = Anomaly::from_error(&Error::SrvMismatch);
+        auditor.record_at(Instant::now(), time, anomaly, &src, b"test", "SrvMismatch");
+
+        assert_eq!(
+            buffer.lines(),
+            vec![concat!(
+                r#"{"time":"2024-05-01T12:00:00.123Z","worker":"worker-0","event":"srv_mismatch","#,
+                r#""src_ip":"192.0.2.7","length":4,"payload_hash":"9f86d081884c7d659a2feaa0c55ad015","#,
+                r#""detail":"SrvMismatch"}"#
+            )]
+        );
+    }
+
+    #[test]
+    fn errors_map_to_anomalies() {
+        assert_eq!(
+            Anomaly::from_error(&Error::RequestTooLarge),
+            Anomaly::OversizedRequest
+        );
+        assert_eq!(
+            Anomaly::from_error(&Error::RequestNotAuthenticated),
+            Anomaly::Unauthenticated
+        );
+        assert_eq!(
+            Anomaly::from_error(&Error::InvalidNumTags(0)),
+            Anomaly::ParseFailure
+        );
+    }
+
+    #[test]
+    fn entries_are_rate_limited() {
+        let (mut auditor, buffer) = auditor();
+        let src: SocketAddr = "192.0.2.7:4242".parse().unwrap();
+        let start = Instant::now();
+        let flood = MAX_ENTRIES_PER_SECOND + 5;
+
+        for _ in 0..flood {
+            auditor.record_at(
+                start,
+                SystemTime::now(),
+                Anomaly::ParseFailure,
+                &src,
+                &[],
+                "",
+            );
+        }
+        assert_eq!(buffer.lines().len(), MAX_ENTRIES_PER_SECOND as usize);
+
+        // the first entry of the next second reports what was dropped
+        let next_second = start + Duration::from_secs(1);
+        auditor.record_at(
+            next_second,
+            SystemTime::now(),
+            Anomaly::Replay,
+            &src,
+            &[],
+            "",
+        );
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), MAX_ENTRIES_PER_SECOND as usize + 1);
+        assert!(lines.last().unwrap().ends_with(r#""suppressed":5}"#));
+        assert!(!lines[0].contains("suppressed"));
+    }
+
+    #[test]
+    fn replays_detected_within_window() {
+        let (mut auditor, _) = auditor();
+
+        assert!(!auditor.is_replay(b"first"));
+        assert!(auditor.is_replay(b"first"));
+
+        for i in 0..REPLAY_WINDOW {
+            assert!(!auditor.is_replay(&i.to_le_bytes()));
+        }
+
+        // pushed out of the window by newer nonces
+        assert!(!auditor.is_replay(b"first"));
+    }
+}
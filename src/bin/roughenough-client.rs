@@ -12,131 +12,165 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-// for value_t_or_exit!()
+// for value_t!()
 #[macro_use]
 extern crate clap;
 
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::ErrorKind::WouldBlock;
-use std::io::{Cursor, Write};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Write};
 use std::iter::Iterator;
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
 use std::time;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+use ::rand::thread_rng;
 use byteorder::{LittleEndian, ReadBytesExt};
 use chrono::offset::Utc;
 use chrono::{Local, TimeZone};
-use clap::{App, Arg};
-use data_encoding::{Encoding, BASE64, HEXLOWER_PERMISSIVE};
-use ring::rand;
-use ring::rand::SecureRandom;
-use roughenough::key::LongTermKey;
-use roughenough::merkle::MerkleTree;
-use roughenough::sign::MsgVerifier;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
+use ring::digest;
+use roughenough::chain::{self, ChainLink, MeasurementSequence};
+use roughenough::clock;
+use roughenough::client::{self, Client, Witness};
+use roughenough::key::{decode_public_key, LongTermKey};
+use roughenough::monitor::{self, ProbeOutcome, ServerHealth, Thresholds};
+use roughenough::request::{self, RequestAuthenticator};
+use roughenough::response::{ParsedResponse, ResponseHandler};
+use roughenough::server_list::{Server, ServerList, DEFAULT_EXPIRY_WARNING};
+use roughenough::sink::{self, Event, Sink};
 use roughenough::version::Version;
 use roughenough::{
-    roughenough_version, Error, RtMessage, Tag, CERTIFICATE_CONTEXT, REQUEST_FRAMING_BYTES,
-    SIGNED_RESPONSE_CONTEXT,
+    roughenough_version, Error, RtMessage, Tag, FRAME_HEADER_LENGTH, REQUEST_FRAMING_BYTES,
 };
+use yaml_rust::{Yaml, YamlLoader};
 
 const HEX: Encoding = HEXLOWER_PERMISSIVE;
 
+// Inline JSON server list used when no host or server list file is given
+const SERVERS_ENV_VAR: &str = "ROUGHENOUGH_SERVERS";
+
+// Protocols found by `--probe-protocol auto`, relative to the user's cache directory. One
+// "<host>:<port> <protocol> <unix time>" line per server.
+const PROBE_CACHE_FILE: &str = "roughenough/protocols";
+
+// Servers are probed again once their cached protocol is this old
+const PROBE_CACHE_TTL: time::Duration = time::Duration::from_secs(7 * 24 * 3600);
+
+// Configuration file read when --config isn't given, relative to $XDG_CONFIG_HOME (or
+// ~/.config)
+const CONFIG_FILE: &str = "roughenough/client.yaml";
+
+// Settings a configuration file may hold, named after the flags they are defaults for
+const CONFIG_SETTINGS: &[&str] = &[
+    "server-list",
+    "timeout",
+    "protocol",
+    "time-format",
+    "zulu",
+    "json",
+    "verbose",
+    "bind",
+    "sink",
+    "csv",
+    "webhook",
+    "interval",
+];
+
+// Exit codes, a stable contract for scripts and init systems. When several outcomes occur
+// (e.g. querying a server list), the highest code is used.
+const EXIT_OK: i32 = 0;
+const EXIT_TIMEOUT: i32 = 2;
+const EXIT_VALIDATION_FAILURE: i32 = 3;
+const EXIT_CAUSALITY_VIOLATION: i32 = 4;
+const EXIT_CONFIG_ERROR: i32 = 5;
+const EXIT_MONITOR_ALERT: i32 = 6;
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    Success (every response received and valid)
+    2    No response: a timeout, an unreachable port, or a network error sending or receiving
+    3    A response failed validation
+    4    Causality violation: a server reported a time earlier than a previously queried server
+         (analyze: also a time inconsistent with the local time between measurements)
+    5    Configuration error (invalid arguments, keys, input or output files, or --set-clock
+         failed)
+    6    Monitor: a server violated an alert threshold";
+
 type Nonce = Vec<u8>;
 
-fn create_nonce(ver: Version) -> Nonce {
-    let rng = rand::SystemRandom::new();
-    match ver {
-        Version::Classic => {
-            let mut nonce = [0u8; 64];
-            rng.fill(&mut nonce).unwrap();
-            nonce.to_vec()
-        }
-        Version::Rfc | Version::RfcDraft11 => {
-            let mut nonce = [0u8; 32];
-            rng.fill(&mut nonce).unwrap();
-            nonce.to_vec()
-        }
-    }
+// Report a configuration error (bad arguments, keys, or input or output files) and exit
+fn config_error(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(EXIT_CONFIG_ERROR);
 }
 
-fn make_request(ver: Version, nonce: &Nonce, text_dump: bool, pub_key: &Option<Vec<u8>>) -> Vec<u8> {
-    let mut msg = RtMessage::with_capacity(3);
-
-    let srv_value = match pub_key {
-        None => None,
-        Some(ref pk) => Some(LongTermKey::calc_srv_value(&pk)),
-    };
-
-    match ver {
-        Version::Classic => {
-            msg.add_field(Tag::NONC, nonce).unwrap();
-            msg.add_field(Tag::PAD, &[]).unwrap();
-
-            let padding_needed = msg.calculate_padding_length();
-            let padding: Vec<u8> = (0..padding_needed).map(|_| 0).collect();
+fn parse_public_key(pkey: &str) -> Vec<u8> {
+    decode_public_key(pkey).unwrap_or_else(|_| config_error("Error parsing public key!"))
+}
 
-            msg.clear();
-            msg.add_field(Tag::NONC, nonce).unwrap();
-            msg.add_field(Tag::PAD, &padding).unwrap();
+fn create_nonce(ver: Version) -> Nonce {
+    request::new_nonce(ver)
+}
 
-            if text_dump {
-                eprintln!("Request = {}", msg);
-            }
+// What `--dump` and `--dump-hex` print of each exchanged message
+#[derive(Clone, Copy, Default)]
+struct Dump {
+    text: bool,
+    hex: bool,
+}
 
-            msg.encode().unwrap()
+impl Dump {
+    fn print(&self, label: &str, msg: &RtMessage) {
+        if self.text {
+            eprintln!("{} = {}", label, msg);
         }
-        Version::Rfc | Version::RfcDraft11 => {
-            if srv_value.is_some() {
-                let val = srv_value.as_ref().unwrap();
-                msg.add_field(Tag::SRV, val).unwrap();
+        if self.hex {
+            match msg.to_annotated_hex() {
+                Ok(hex) => eprintln!("{} (hex) =\n{}", label, hex),
+                Err(e) => eprintln!("{} (hex) unavailable: {:?}", label, e),
             }
-            msg.add_field(Tag::VER, ver.wire_bytes()).unwrap();
-            msg.add_field(Tag::NONC, nonce).unwrap();
-            msg.add_field(Tag::ZZZZ, &[]).unwrap();
-
-            let padding_needed = msg.calculate_padding_length();
-            let padding: Vec<u8> = (0..padding_needed).map(|_| 0).collect();
-
-            msg.clear();
+        }
+    }
+}
 
-            if srv_value.is_some() {
-                let val = srv_value.as_ref().unwrap();
-                msg.add_field(Tag::SRV, val).unwrap();
-            }
-            msg.add_field(Tag::VER, ver.wire_bytes()).unwrap();
-            msg.add_field(Tag::NONC, nonce).unwrap();
-            msg.add_field(Tag::ZZZZ, &padding).unwrap();
+fn make_request(
+    ver: Version,
+    nonce: &Nonce,
+    dump: Dump,
+    pub_key: &Option<Vec<u8>>,
+    authenticator: Option<&RequestAuthenticator>,
+) -> Vec<u8> {
+    let srv_value = pub_key.as_ref().map(|pk| LongTermKey::calc_srv_value(pk));
+    let msg = request::make_request(ver, nonce, srv_value.as_deref(), authenticator);
 
-            if text_dump {
-                eprintln!("Request = {}", msg);
-            }
+    dump.print("Request", &msg);
 
-            msg.encode_framed().unwrap()
-        }
-    }
+    msg.encode_for(ver).unwrap()
 }
 
-fn receive_response(ver: Version, buf: &[u8], buf_len: usize) -> RtMessage {
-    match ver {
-        Version::Classic => RtMessage::from_bytes(&buf[0..buf_len]).unwrap(),
-        Version::Rfc | Version::RfcDraft11 => {
-            verify_framing(&buf).unwrap();
-            RtMessage::from_bytes(&buf[12..buf_len]).unwrap()
-        }
+fn receive_response(ver: Version, buf: &[u8]) -> Result<RtMessage, String> {
+    let (msg, received_ver) = decode_message(buf)?;
+    if (ver == Version::Classic) != (received_ver == Version::Classic) {
+        return Err(format!("Expected a {} response, received {}", ver, received_ver));
     }
+    Ok(msg)
 }
 
 fn verify_framing(buf: &[u8]) -> Result<(), Error> {
-    if &buf[0..8] != REQUEST_FRAMING_BYTES {
+    if &buf[0..REQUEST_FRAMING_BYTES.len()] != REQUEST_FRAMING_BYTES {
         eprintln!("RFC response is missing framing header bytes");
         return Err(Error::InvalidResponse);
     }
 
-    let mut cur = Cursor::new(&buf[8..12]);
+    let mut cur = Cursor::new(&buf[REQUEST_FRAMING_BYTES.len()..FRAME_HEADER_LENGTH]);
     let reported_len = cur.read_u32::<LittleEndian>()?;
 
-    if (reported_len as usize) > buf.len() - 12 {
+    if (reported_len as usize) > buf.len() - FRAME_HEADER_LENGTH {
         eprintln!("buflen = {}, reported_len = {}", buf.len(), reported_len);
         return Err(Error::MessageTooShort);
     }
@@ -146,10 +180,10 @@ fn verify_framing(buf: &[u8]) -> Result<(), Error> {
 
 fn stress_test_forever(ver: Version, addr: &SocketAddr) -> ! {
     if !addr.ip().is_loopback() {
-        panic!(
+        config_error(&format!(
             "Cannot use non-loopback address {} for stress testing",
             addr.ip()
-        );
+        ));
     }
 
     println!("Stress testing!");
@@ -161,176 +195,1092 @@ fn stress_test_forever(ver: Version, addr: &SocketAddr) -> ! {
         "0.0.0.0:0"
     })
         .expect("Couldn't open UDP socket");
-    let request = make_request(ver, &nonce, false, &None);
+    let request = make_request(ver, &nonce, Dump::default(), &None, None);
     loop {
         socket.send_to(&request, addr).unwrap();
     }
 }
 
-struct ResponseHandler {
+// Decode a raw request or response, detecting RFC framing. Returns the message and the
+// protocol version it was sent with.
+fn decode_message(bytes: &[u8]) -> Result<(RtMessage, Version), String> {
+    if !bytes.starts_with(REQUEST_FRAMING_BYTES) {
+        let msg = RtMessage::from_bytes_detailed(bytes)
+            .map_err(|e| format!("Malformed message: {}", e))?;
+        return Ok((msg, Version::Classic));
+    }
+
+    if bytes.len() < FRAME_HEADER_LENGTH {
+        return Err("Message is too short to hold a framing header".into());
+    }
+    verify_framing(bytes).map_err(|e| format!("Invalid framing: {:?}", e))?;
+
+    let msg = RtMessage::from_bytes_detailed(&bytes[FRAME_HEADER_LENGTH..])
+        .map_err(|e| format!("Malformed message: {}", e))?;
+
+    // requests list the versions they support, responses only the one they chose
+    let version = match msg.get_field(Tag::VER) {
+        Some(ver) if ver.ends_with(Version::RfcDraft11.wire_bytes()) => Version::RfcDraft11,
+        _ => Version::Rfc,
+    };
+    Ok((msg, version))
+}
+
+fn read_input(path: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let result = if path == "-" {
+        std::io::stdin().read_to_end(&mut bytes)
+    } else {
+        File::open(path).and_then(|mut f| f.read_to_end(&mut bytes))
+    };
+
+    if let Err(e) = result {
+        config_error(&format!("Error reading {:?}: {}", path, e));
+    }
+    bytes
+}
+
+// Publish `event` to every sink; a sink that fails is reported but doesn't stop the client
+fn publish(sinks: &mut [Box<dyn Sink>], event: Event) {
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.publish(&event) {
+            eprintln!("Error publishing to sink: {:?}", e);
+        }
+    }
+}
+
+// Open `path` for appending measurements, writing the header row if the file is new or empty
+fn open_csv(path: &str) -> io::Result<File> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(b"ts_us,server,midpoint_us,radius_us,rtt_us,offset_us,verified\n")?;
+    }
+    Ok(file)
+}
+
+// Quote a CSV field if it contains a separator, quote, or line break
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+// A response as evidence for causality checks: the server that sent it, the earliest and
+// latest time it allows (in microseconds since the epoch), and a hash of its bytes
+struct Observation {
+    server: String,
+    address: SocketAddr,
+    public_key: Option<Vec<u8>>,
+    lower: u64,
+    upper: u64,
+    response_hash: Vec<u8>,
+}
+
+impl Observation {
+    fn to_json(&self) -> String {
+        let public_key = match self.public_key {
+            Some(ref key) => format!(r#""{}""#, HEX.encode(key)),
+            None => "null".to_string(),
+        };
+        format!(
+            r#"{{ "server": {:?}, "address": "{}", "public_key": {}, "lower_us": {}, "upper_us": {}, "response_sha512": "{}" }}"#,
+            self.server,
+            self.address,
+            public_key,
+            self.lower,
+            self.upper,
+            HEX.encode(&self.response_hash)
+        )
+    }
+}
+
+// Earliest and latest time, in microseconds since the epoch, that a response allows
+fn time_bounds_micros(version: Version, midpoint: u64, radius: u32) -> (u64, u64) {
+    let unit = version.profile().micros_per_unit;
+    let (midpoint, radius) = (midpoint.saturating_mul(unit), radius as u64 * unit);
+    (
+        midpoint.saturating_sub(radius),
+        midpoint.saturating_add(radius),
+    )
+}
+
+// Time left in the delegation of the online key that signed a response, as of its midpoint
+fn delegation_remaining(version: Version, midpoint: u64, maxt: u64) -> time::Duration {
+    let remaining = maxt.saturating_sub(midpoint);
+    time::Duration::from_micros(remaining.saturating_mul(version.profile().micros_per_unit))
+}
+
+fn format_time(version: Version, midpoint: u64, use_utc: bool, time_format: &str) -> String {
+    let micros = midpoint.saturating_mul(version.profile().micros_per_unit);
+    format_micros(micros, use_utc, time_format)
+}
+
+// Format a time given in microseconds since the epoch
+fn format_micros(micros: u64, use_utc: bool, time_format: &str) -> String {
+    let seconds = micros / 10_u64.pow(6);
+    let nsecs = ((micros - (seconds * 10_u64.pow(6))) * 10_u64.pow(3)) as u32;
+
+    if use_utc {
+        let ts = Utc.timestamp_opt(seconds as i64, nsecs).unwrap();
+        ts.format(time_format).to_string()
+    } else {
+        let ts = Local.timestamp_opt(seconds as i64, nsecs).unwrap();
+        ts.format(time_format).to_string()
+    }
+}
+
+// The server's time (in microseconds since the epoch) when a verified response was received,
+// when that was, and the uncertainty of the estimate
+type ClockSample = (u64, Instant, u64);
+
+// `--set-clock`: step the system clock to the server's time as of now, or only report the
+// change with `--dry-run`. The clock is left alone if there is no trustworthy sample of the
+// server's time, for the reason given.
+fn set_system_clock(
+    sample: Result<ClockSample, String>,
+    dry_run: bool,
+    can_set_clock: bool,
+    json: bool,
+    use_utc: bool,
+    time_format: &str,
+) -> i32 {
+    let (estimate, received, uncertainty) = match sample {
+        Ok(sample) => sample,
+        Err(reason) => {
+            eprintln!("System clock not set: {}", reason);
+            return EXIT_OK;
+        }
+    };
+
+    let target = estimate + received.elapsed().as_micros() as u64;
+    let delta = target as i128 - unix_now_micros() as i128;
+    let direction = if delta < 0 { "backward" } else { "forward" };
+    let formatted = format_micros(target, use_utc, time_format);
+
+    if json {
+        println!(
+            r#"{{ "set_clock": {{ "dry_run": {}, "delta_us": {}, "direction": "{}", "time": {:?}, "uncertainty_us": {}, "privileged": {} }} }}"#,
+            dry_run, delta, direction, formatted, uncertainty, can_set_clock
+        );
+    } else {
+        println!(
+            "{} system clock {} by {}us to {} (+/- {}us)",
+            if dry_run { "Would step" } else { "Stepping" },
+            direction,
+            delta.unsigned_abs(),
+            formatted,
+            uncertainty
+        );
+    }
+
+    if dry_run {
+        if !can_set_clock {
+            eprintln!("Warning: this process lacks CAP_SYS_TIME, setting the clock would fail");
+        }
+        return EXIT_OK;
+    }
+
+    match clock::set_system_time(UNIX_EPOCH + time::Duration::from_micros(target)) {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            eprintln!("Error setting the system clock: {:?}", e);
+            EXIT_CONFIG_ERROR
+        }
+    }
+}
+
+// With a server list requiring `quorum` witnesses, the time on which the most servers with
+// distinct public keys agree, as of `now`. Each sample is paired with its server's key.
+fn corroborated_sample(
+    samples: &[(Vec<u8>, ClockSample)],
+    quorum: usize,
+    now: Instant,
+    verbose: bool,
+) -> Result<ClockSample, String> {
+    let witnesses: Vec<Witness> = samples
+        .iter()
+        .map(|(key, (estimate, received, uncertainty))| {
+            let elapsed = now.saturating_duration_since(*received).as_micros() as u64;
+            Witness {
+                public_key: key.clone(),
+                lower_us: (estimate + elapsed).saturating_sub(*uncertainty),
+                upper_us: estimate + elapsed + uncertainty,
+            }
+        })
+        .collect();
+
+    match client::corroborate(&witnesses, quorum) {
+        Some(agreed) => {
+            if verbose {
+                eprintln!(
+                    "Time corroborated by {} servers with distinct keys ({} required)",
+                    agreed.witnesses, quorum
+                );
+            }
+            let radius = (agreed.upper_us - agreed.lower_us) / 2;
+            Ok((agreed.lower_us + radius, now, radius))
+        }
+        None => Err(format!(
+            "fewer than {} servers with distinct keys agree on the time",
+            quorum
+        )),
+    }
+}
+
+// `parse` subcommand: validate a previously captured response, exiting non-zero on failure
+fn parse_main(matches: &ArgMatches, config: &ClientConfig) -> ! {
+    let response_path = matches.value_of("response").unwrap();
+    let request_path = matches.value_of("request");
+    let json = config.flag(matches, "json");
+    let pub_key = matches.value_of("public-key").map(parse_public_key);
+
+    if response_path == "-" && request_path == Some("-") {
+        config_error("Only one of the request and response can be read from stdin");
+    }
+
+    let response = read_input(response_path);
+    let request = request_path.map(read_input);
+
+    let result = parse_response(&response, request.as_deref(), pub_key);
+    let (resp, version, parsed) = match result {
+        Ok(v) => v,
+        Err(e) => {
+            if json {
+                println!(r#"{{ "valid": false, "error": {:?} }}"#, e);
+            } else {
+                eprintln!("Validation failed: {}", e);
+            }
+            process::exit(EXIT_VALIDATION_FAILURE);
+        }
+    };
+
+    let out = format_time(version, parsed.midpoint, true, "%Y-%m-%dT%H:%M:%SZ");
+    if json {
+        println!(
+            r#"{{ "valid": true, "version": "{}", "midpoint": {:?}, "radius": {}, "verified": {}, "message": {} }}"#,
+            version,
+            out,
+            parsed.radius,
+            parsed.verified,
+            resp.to_debug_json()
+        );
+    } else {
+        println!("Response = {}", resp);
+        println!(
+            "Valid {} response: midpoint={}, radius={}, verified={}",
+            version,
+            out,
+            parsed.radius,
+            if parsed.verified { "Yes" } else { "No" }
+        );
+    }
+    process::exit(EXIT_OK);
+}
+
+fn parse_response(
+    response: &[u8],
+    request: Option<&[u8]>,
     pub_key: Option<Vec<u8>>,
-    msg: HashMap<Tag, Vec<u8>>,
-    srep: HashMap<Tag, Vec<u8>>,
-    cert: HashMap<Tag, Vec<u8>>,
-    dele: HashMap<Tag, Vec<u8>>,
-    nonce: Nonce,
+) -> Result<(RtMessage, Version, ParsedResponse), String> {
+    let (resp, version) = decode_message(response)?;
+    let resp_nonce = resp.get_field(Tag::NONC).map(|n| n.to_vec());
+
+    let nonce = match request {
+        Some(bytes) => {
+            let (req, _) = decode_message(bytes)?;
+            let nonce = req
+                .get_field(Tag::NONC)
+                .ok_or("Request is missing the NONC tag")?
+                .to_vec();
+            if resp_nonce.is_some() && resp_nonce.as_ref() != Some(&nonce) {
+                return Err("Response nonce does not match the request".into());
+            }
+            nonce
+        }
+        None => resp_nonce.ok_or("Response has no NONC tag; provide the request with -r")?,
+    };
+
+    let parsed = ResponseHandler::new(version, pub_key, resp.clone(), nonce)?.extract_time()?;
+    Ok((resp, version, parsed))
+}
+
+// A server to query, named on the command line or taken from a server list
+struct Target {
+    name: String,
+    addr: SocketAddr,
+    // The server's other addresses, tried in order when `addr` doesn't answer
+    alternates: Vec<SocketAddr>,
     version: Version,
+    pub_key: Option<Vec<u8>>,
 }
 
-struct ParsedResponse {
-    verified: bool,
-    midpoint: u64,
-    radius: u32,
-}
-
-impl ResponseHandler {
-    pub fn new(
-        version: Version,
-        pub_key: Option<Vec<u8>>,
-        response: RtMessage,
-        nonce: Nonce,
-    ) -> ResponseHandler {
-        let msg = response.into_hash_map();
-        let srep = RtMessage::from_bytes(&msg[&Tag::SREP])
-            .unwrap()
-            .into_hash_map();
-        let cert = RtMessage::from_bytes(&msg[&Tag::CERT])
-            .unwrap()
-            .into_hash_map();
-        let dele = RtMessage::from_bytes(&cert[&Tag::DELE])
-            .unwrap()
-            .into_hash_map();
-
-        ResponseHandler {
-            pub_key,
-            msg,
-            srep,
-            cert,
-            dele,
-            nonce,
+// Load the server list in the file `source`, or standard input if `source` is `-`. Without a
+// source, the list is the inline JSON in ROUGHENOUGH_SERVERS. With `sample`, only that many
+// servers, chosen at random while the list is read, are kept.
+fn load_server_list(source: Option<&str>, sample: Option<usize>) -> ServerList {
+    let reader: Box<dyn Read> = match source {
+        Some("-") => Box::new(io::stdin()),
+        Some(path) => Box::new(File::open(path).unwrap_or_else(|e| {
+            config_error(&format!("Error reading server list '{}': {}", path, e))
+        })),
+        None => match env::var(SERVERS_ENV_VAR) {
+            Ok(contents) => Box::new(Cursor::new(contents)),
+            Err(_) => config_error(&format!("No server list given and {} is not set", SERVERS_ENV_VAR)),
+        },
+    };
+
+    let list = match sample {
+        Some(count) => ServerList::sample_from_reader(reader, count, &mut thread_rng()),
+        None => ServerList::from_reader(reader),
+    };
+
+    list.unwrap_or_else(|e| config_error(&format!("Error loading server list: {:?}", e)))
+}
+
+fn targets_from_list<'a>(
+    client: &Client,
+    servers: impl ExactSizeIterator<Item = &'a Server>,
+) -> Vec<Target> {
+    let now = client.local_clock().now();
+    let mut targets = Vec::with_capacity(servers.len());
+
+    for server in servers {
+        let key = match server.key_valid_at(now) {
+            Some(key) => key,
+            None => {
+                eprintln!("Skipping {:?}: none of its public keys are valid now", server.name);
+                continue;
+            }
+        };
+
+        if key.expires_within(now, DEFAULT_EXPIRY_WARNING) {
+            let until = chrono::DateTime::<Utc>::from(key.valid_until.unwrap());
+            eprintln!(
+                "Warning: server list entry for {:?} expires soon (validUntil {})",
+                server.name,
+                until.to_rfc3339()
+            );
+        }
+
+        let version = match server.protocol_version() {
+            Some(version) => version,
+            None => {
+                eprintln!("Skipping {:?}: unsupported version {:?}", server.name, server.version);
+                continue;
+            }
+        };
+
+        let mut addrs = Vec::new();
+        for a in server.udp_addresses() {
+            match client.resolve(&a.address, None) {
+                Ok(addr) if !addrs.contains(&addr) => addrs.push(addr),
+                _ => continue,
+            }
+        }
+
+        // DNS may itself depend on correct time (e.g. DNSSEC validation); fall back to the
+        // list's IP literals only when none of the server's hostnames resolved
+        if addrs.is_empty() {
+            addrs = server.udp_fallbacks();
+            if !addrs.is_empty() {
+                eprintln!(
+                    "Warning: could not resolve {:?}, using its fallback addresses",
+                    server.name
+                );
+            }
+        }
+
+        if addrs.is_empty() {
+            eprintln!("Skipping {:?}: no resolvable UDP address", server.name);
+            continue;
+        }
+
+        let addr = addrs.remove(0);
+        targets.push(Target {
+            name: server.name.clone(),
+            addr,
+            alternates: addrs,
             version,
+            pub_key: Some(key.public_key.clone()),
+        });
+    }
+
+    targets
+}
+
+// After `request` to `failed` went unanswered, send it to each of a server's other
+// addresses in turn. Returns the response, the address that answered, and when (monotonic
+// and in microseconds since the epoch) the answered request was sent.
+fn fail_over(
+    client: &Client,
+    name: &str,
+    addrs: &[SocketAddr],
+    failed: SocketAddr,
+    request: &[u8],
+    verbose: bool,
+) -> Option<(Vec<u8>, SocketAddr, (Instant, u64))> {
+    for &addr in addrs.iter().filter(|a| **a != failed) {
+        if verbose {
+            eprintln!("No response from {}, retrying {:?} at {}", failed, name, addr);
+        }
+        let clock = client.local_clock();
+        let sent = (clock.instant(), clock.unix_micros());
+        match client.exchange(&addr, request) {
+            Ok(buf) => return Some((buf, addr, sent)),
+            Err(e) if verbose => eprintln!("No response from {}: {:?}", addr, e),
+            Err(_) => continue,
         }
     }
+    None
+}
+
+// Why an exchange that failed over to no avail got no response
+fn no_response_reason(e: &Error) -> &'static str {
+    match e {
+        Error::Unreachable(_) => "port unreachable",
+        _ => "timeout",
+    }
+}
 
-    pub fn extract_time(&self) -> ParsedResponse {
-        let midpoint = self.srep[&Tag::MIDP]
-            .as_slice()
-            .read_u64::<LittleEndian>()
-            .unwrap();
-        let radius = self.srep[&Tag::RADI]
-            .as_slice()
-            .read_u32::<LittleEndian>()
-            .unwrap();
+// Query `target` once and validate its response
+fn probe(client: &Client, target: &Target) -> ProbeOutcome {
+    let nonce = create_nonce(target.version);
+    let request = make_request(target.version, &nonce, Dump::default(), &target.pub_key, None);
+
+    let mut start = client.local_clock().instant();
+    let buf = match client.exchange(&target.addr, &request) {
+        Ok(buf) => buf,
+        Err(e @ (Error::Timeout(_) | Error::Unreachable(_))) => {
+            match fail_over(client, &target.name, &target.alternates, target.addr, &request, false)
+            {
+                Some((buf, _, (sent, _))) => {
+                    start = sent;
+                    buf
+                }
+                None => return ProbeOutcome::Failed(no_response_reason(&e).to_string()),
+            }
+        }
+        Err(e) => return ProbeOutcome::Failed(format!("{:?}", e)),
+    };
+    let rtt = client.local_clock().instant().saturating_duration_since(start);
 
-        self.validate_merkle();
-        self.validate_midpoint(midpoint);
+    let parsed = receive_response(target.version, &buf).and_then(|resp| {
+        ResponseHandler::new(target.version, target.pub_key.clone(), resp, nonce)
+            .and_then(|handler| handler.extract_time())
+    });
 
-        let verified = if self.pub_key.is_some() {
-            self.validate_dele();
-            self.validate_srep();
-            true
-        } else {
-            false
+    match parsed {
+        Ok(parsed) if parsed.verified => {
+            let (lower, upper) = time_bounds_micros(target.version, parsed.midpoint, parsed.radius);
+            ProbeOutcome::Valid { rtt, lower, upper }
+        }
+        Ok(_) => ProbeOutcome::Failed("response was not verified".to_string()),
+        Err(e) => ProbeOutcome::Failed(e),
+    }
+}
+
+fn version_from_protocol(protocol: u8) -> Option<Version> {
+    match protocol {
+        0 => Some(Version::Classic),
+        1 => Some(Version::Rfc),
+        11 => Some(Version::RfcDraft11),
+        _ => None,
+    }
+}
+
+fn protocol_number(version: Version) -> u8 {
+    match version {
+        Version::Classic => 0,
+        Version::Rfc => 1,
+        Version::RfcDraft11 => 11,
+    }
+}
+
+// Defaults for command line flags from a YAML configuration file, so that cron jobs and
+// long-running monitors don't need long command lines. Flags given on the command line win.
+#[derive(Default)]
+struct ClientConfig {
+    settings: BTreeMap<String, Vec<String>>,
+}
+
+impl ClientConfig {
+    // Load the file named by --config in `args`, else the default file if it exists
+    fn load(args: &[String]) -> ClientConfig {
+        let explicit = args
+            .iter()
+            .position(|a| a == "--config")
+            .and_then(|i| args.get(i + 1))
+            .map(PathBuf::from)
+            .or_else(|| {
+                args.iter()
+                    .find_map(|a| a.strip_prefix("--config="))
+                    .map(PathBuf::from)
+            });
+
+        let path = match explicit {
+            Some(path) => path,
+            None => match default_config_path() {
+                Some(path) if path.exists() => path,
+                _ => return ClientConfig::default(),
+            },
+        };
+
+        fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| ClientConfig::parse(&contents))
+            .unwrap_or_else(|e| {
+                config_error(&format!("Error in config file {}: {}", path.display(), e))
+            })
+    }
+
+    fn parse(contents: &str) -> Result<ClientConfig, String> {
+        let docs = YamlLoader::load_from_str(contents).map_err(|e| e.to_string())?;
+        let entries = match docs.first() {
+            None => return Ok(ClientConfig::default()),
+            Some(Yaml::Hash(entries)) => entries,
+            Some(_) => return Err("expected a mapping of settings".to_string()),
         };
 
-        ParsedResponse {
-            verified,
-            midpoint,
-            radius,
+        let mut settings = BTreeMap::new();
+        for (key, value) in entries {
+            let name = match key.as_str() {
+                Some(name) if CONFIG_SETTINGS.contains(&name) => name,
+                Some(name) => return Err(format!("unknown setting '{}'", name)),
+                None => return Err(format!("setting names must be strings, found {:?}", key)),
+            };
+            let values = match value {
+                Yaml::Array(items) => items.iter().map(yaml_scalar).collect(),
+                _ => yaml_scalar(value).map(|v| vec![v]),
+            };
+            match values {
+                Some(values) => settings.insert(name.to_string(), values),
+                None => return Err(format!("invalid value for '{}'", name)),
+            };
         }
+
+        Ok(ClientConfig { settings })
     }
 
-    fn validate_dele(&self) {
-        let mut full_cert = Vec::from(CERTIFICATE_CONTEXT.as_bytes());
-        full_cert.extend(&self.cert[&Tag::DELE]);
+    fn value(&self, name: &str) -> Option<&str> {
+        self.settings
+            .get(name)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
 
-        assert!(
-            self.validate_sig(
-                self.pub_key.as_ref().unwrap(),
-                &self.cert[&Tag::SIG],
-                &full_cert,
-            ),
-            "Invalid signature on DELE tag, response may not be authentic"
-        );
+    fn value_or<'a>(&'a self, name: &str, default: &'a str) -> &'a str {
+        self.value(name).unwrap_or(default)
     }
 
-    fn validate_srep(&self) {
-        let mut full_srep = Vec::from(SIGNED_RESPONSE_CONTEXT.as_bytes());
-        full_srep.extend(&self.msg[&Tag::SREP]);
+    fn values(&self, name: &str) -> Vec<&str> {
+        self.settings
+            .get(name)
+            .map(|values| values.iter().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
 
-        assert!(
-            self.validate_sig(&self.dele[&Tag::PUBK], &self.msg[&Tag::SIG], &full_srep),
-            "Invalid signature on SREP tag, response may not be authentic"
-        );
+    // A flag is set by the command line, or by a true, yes, or on value in the file
+    fn flag(&self, matches: &ArgMatches, name: &str) -> bool {
+        matches.is_present(name) || matches!(self.value(name), Some("true" | "yes" | "on"))
     }
+}
 
-    fn validate_merkle(&self) {
-        let srep = RtMessage::from_bytes(&self.msg[&Tag::SREP])
-            .unwrap()
-            .into_hash_map();
-        let index = self.msg[&Tag::INDX]
-            .as_slice()
-            .read_u32::<LittleEndian>()
-            .unwrap();
-        let paths = &self.msg[&Tag::PATH];
+fn yaml_scalar(value: &Yaml) -> Option<String> {
+    match value {
+        Yaml::String(s) | Yaml::Real(s) => Some(s.clone()),
+        Yaml::Integer(i) => Some(i.to_string()),
+        Yaml::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
 
-        let hash = match self.version {
-            Version::Classic => MerkleTree::new_sha512_classic(),
-            Version::Rfc | Version::RfcDraft11 => MerkleTree::new_sha512_ietf(),
+fn default_config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join(CONFIG_FILE))
+}
+
+fn probe_cache_path() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join(PROBE_CACHE_FILE))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn unix_now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+// The cache lines of servers other than `server`, and `server`'s protocol and probe time
+fn read_probe_cache(server: &str) -> (Vec<String>, Option<(Version, u64)>) {
+    let contents = probe_cache_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .unwrap_or_default();
+
+    let mut others = Vec::new();
+    let mut entry = None;
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [name, protocol, when] if *name == server => {
+                let protocol = protocol.parse().ok().and_then(version_from_protocol);
+                entry = protocol.zip(when.parse().ok());
+            }
+            [_, _, _] => others.push(line.to_string()),
+            _ => (),
         }
-        .root_from_paths(index as usize, &self.nonce, paths);
+    }
+    (others, entry)
+}
 
-        assert_eq!(
-            hash,
-            srep[&Tag::ROOT],
-            "Nonce is not present in the response's merkle tree"
-        );
+fn cached_protocol(server: &str) -> Option<Version> {
+    match read_probe_cache(server).1 {
+        Some((version, when)) if unix_now().saturating_sub(when) < PROBE_CACHE_TTL.as_secs() => {
+            Some(version)
+        }
+        _ => None,
     }
+}
 
-    fn validate_midpoint(&self, midpoint: u64) {
-        let mint = self.dele[&Tag::MINT]
-            .as_slice()
-            .read_u64::<LittleEndian>()
-            .unwrap();
-        let maxt = self.dele[&Tag::MAXT]
-            .as_slice()
-            .read_u64::<LittleEndian>()
-            .unwrap();
-
-        assert!(
-            midpoint >= mint,
-            "Response midpoint {} lies *before* delegation span ({}, {})",
-            midpoint,
-            mint,
-            maxt
-        );
-        assert!(
-            midpoint <= maxt,
-            "Response midpoint {} lies *after* delegation span ({}, {})",
-            midpoint,
-            mint,
-            maxt
+fn cache_protocol(server: &str, version: Version) {
+    let path = match probe_cache_path() {
+        Some(path) => path,
+        None => return,
+    };
+
+    let (mut lines, _) = read_probe_cache(server);
+    lines.push(format!("{} {} {}", server, protocol_number(version), unix_now()));
+
+    let result = fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| fs::write(&path, lines.join("\n") + "\n"));
+    if let Err(e) = result {
+        eprintln!("Warning: unable to cache protocol in {:?}: {}", path, e);
+    }
+}
+
+enum ProtocolProbe {
+    // The protocol version of the (valid) response
+    Answered(Version),
+    Invalid(String),
+    NoResponse,
+}
+
+// Send a request of every protocol version to `addr` at once, newest protocol first, and
+// report how the server answered each
+fn probe_protocols(
+    client: &Client,
+    addr: &SocketAddr,
+    pub_key: &Option<Vec<u8>>,
+    authenticator: Option<&RequestAuthenticator>,
+) -> Vec<(Version, ProtocolProbe)> {
+    let sent: Vec<_> = [Version::Rfc, Version::RfcDraft11, Version::Classic]
+        .into_iter()
+        .map(|version| {
+            let nonce = create_nonce(version);
+            let request = make_request(version, &nonce, Dump::default(), pub_key, authenticator);
+            let socket = client
+                .socket_for(addr)
+                .and_then(|socket| Ok(socket.send_to(&request, addr).map(|_| socket)?));
+            (version, nonce, socket)
+        })
+        .collect();
+
+    sent.into_iter()
+        .map(|(version, nonce, socket)| {
+            let buf = match socket.and_then(|socket| client.receive(&socket, addr)) {
+                Ok(buf) => buf,
+                Err(Error::Timeout(_)) | Err(Error::Unreachable(_)) => {
+                    return (version, ProtocolProbe::NoResponse)
+                }
+                Err(e) => return (version, ProtocolProbe::Invalid(format!("{:?}", e))),
+            };
+
+            let answered = decode_message(&buf).and_then(|(resp, answered)| {
+                ResponseHandler::new(answered, pub_key.clone(), resp, nonce)
+                    .and_then(|handler| handler.extract_time())
+                    .map(|_| answered)
+            });
+
+            match answered {
+                Ok(answered) => (version, ProtocolProbe::Answered(answered)),
+                Err(e) => (version, ProtocolProbe::Invalid(e)),
+            }
+        })
+        .collect()
+}
+
+// The protocol to query `host` with for `--probe-protocol auto`: the newest protocol the
+// server answered, from the cache or from probing it now
+fn auto_protocol(
+    client: &Client,
+    host: &str,
+    addr: &SocketAddr,
+    pub_key: &Option<Vec<u8>>,
+    authenticator: Option<&RequestAuthenticator>,
+    verbose: bool,
+) -> Version {
+    let server = format!("{}:{}", host, addr.port());
+    if let Some(version) = cached_protocol(&server) {
+        if verbose {
+            eprintln!("Using protocol {} for {:?} from an earlier probe", version, server);
+        }
+        return version;
+    }
+
+    let probes = probe_protocols(client, addr, pub_key, authenticator);
+    for (requested, probe) in probes.iter() {
+        let outcome = match probe {
+            ProtocolProbe::Answered(answered) => format!("answered with a {} response", answered),
+            ProtocolProbe::Invalid(e) => format!("got an invalid response: {}", e),
+            ProtocolProbe::NoResponse => "got no response".to_string(),
+        };
+        eprintln!("Probe {:?}: {} request {}", server, requested, outcome);
+    }
+
+    match probes.iter().find_map(|(_, probe)| match probe {
+        ProtocolProbe::Answered(answered) => Some(*answered),
+        _ => None,
+    }) {
+        Some(version) => {
+            cache_protocol(&server, version);
+            version
+        }
+        None => {
+            eprintln!("{:?} gave no valid response to any protocol", server);
+            let responded = probes
+                .iter()
+                .any(|(_, probe)| matches!(probe, ProtocolProbe::Invalid(_)));
+            process::exit(if responded {
+                EXIT_VALIDATION_FAILURE
+            } else {
+                EXIT_TIMEOUT
+            });
+        }
+    }
+}
+
+// `monitor` subcommand: probe every server in a list on an interval, alerting when a server
+// violates a threshold. Without a webhook, the first alert ends the monitor with a non-zero
+// exit code; with one, alerts are posted to it and monitoring continues.
+fn monitor_main(matches: &ArgMatches, config: &ClientConfig) -> ! {
+    let list_source = matches
+        .value_of("server-list")
+        .or_else(|| config.value("server-list"));
+    let interval = value_t!(matches, "interval", u64).unwrap_or_else(|e| config_error(&e.message));
+    let rounds = value_t!(matches, "rounds", u64).unwrap_or_else(|e| config_error(&e.message));
+    let window = value_t!(matches, "window", usize).unwrap_or_else(|e| config_error(&e.message));
+    let min_success_rate = value_t!(matches, "min-success-rate", f64)
+        .ok()
+        .filter(|rate| (0.0..=100.0).contains(rate))
+        .unwrap_or_else(|| config_error("--min-success-rate must be between 0 and 100"));
+    let max_latency =
+        value_t!(matches, "max-latency", u64).unwrap_or_else(|e| config_error(&e.message));
+    let timeout_secs =
+        value_t!(matches, "timeout", u64).unwrap_or_else(|e| config_error(&e.message));
+    let webhook = matches.value_of("webhook").or_else(|| config.value("webhook"));
+    let json = config.flag(matches, "json");
+
+    let interval = time::Duration::from_secs(interval);
+    let timeout = time::Duration::from_secs(timeout_secs);
+    let thresholds = Thresholds {
+        min_success_rate,
+        max_latency: time::Duration::from_millis(max_latency),
+    };
+
+    let client = Client::builder().read_timeout(timeout).build();
+    let mut health: BTreeMap<String, ServerHealth> = BTreeMap::new();
+    let mut alerted = false;
+    let mut round = 0;
+
+    // A list file is re-read every round to pick up key rotations and address changes; a list
+    // from stdin or the environment can only be read once
+    let reread_list = !matches!(list_source, None | Some("-"));
+    let mut list = load_server_list(list_source, None);
+
+    loop {
+        let round_start = Instant::now();
+
+        if reread_list && round > 0 {
+            list = load_server_list(list_source, None);
+        }
+
+        for target in targets_from_list(&client, list.servers().iter()) {
+            let outcome = probe(&client, &target);
+            let server = health
+                .entry(target.name.clone())
+                .or_insert_with(|| ServerHealth::new(window));
+            let alerts = server.record(client.local_clock().instant(), &outcome, &thresholds);
+
+            if json {
+                println!("{}", server.to_json(&target.name));
+            } else {
+                match outcome {
+                    ProbeOutcome::Valid { rtt, .. } => println!(
+                        "{}: ok, rtt {}ms, success rate {:.1}%",
+                        target.name,
+                        rtt.as_millis(),
+                        server.success_rate()
+                    ),
+                    ProbeOutcome::Failed(ref e) => println!(
+                        "{}: failed ({}), success rate {:.1}%",
+                        target.name,
+                        e,
+                        server.success_rate()
+                    ),
+                }
+            }
+
+            for alert in alerts {
+                alerted = true;
+                eprintln!("ALERT {:?}: {}", target.name, alert);
+                if let Some(url) = webhook {
+                    if let Err(e) = monitor::post_webhook(url, &alert.to_json(&target.name), timeout)
+                    {
+                        eprintln!("Error posting alert to webhook: {:?}", e);
+                    }
+                }
+            }
+        }
+
+        if alerted && webhook.is_none() {
+            process::exit(EXIT_MONITOR_ALERT);
+        }
+
+        round += 1;
+        if rounds != 0 && round >= rounds {
+            break;
+        }
+        thread::sleep(interval.saturating_sub(round_start.elapsed()));
+    }
+
+    process::exit(if alerted { EXIT_MONITOR_ALERT } else { EXIT_OK });
+}
+
+// A measurement from an archived chain: the bounds on the time its response allows and the
+// local time it was received, if recorded
+#[derive(Clone)]
+struct Measurement {
+    location: String,
+    run: usize,
+    server: String,
+    lower: u64,
+    upper: u64,
+    received: Option<u64>,
+}
+
+// Report one finding of `analyze` and return the exit code it warrants
+fn analysis_finding(json: bool, kind: &str, location: &str, server: &str, detail: &str) -> i32 {
+    if json {
+        println!(
+            r#"{{ "finding": "{}", "location": {:?}, "server": {:?}, "detail": {:?} }}"#,
+            kind, location, server, detail
         );
+    } else {
+        println!("{} at {} ({:?}): {}", kind, location, server, detail);
+    }
+
+    match kind {
+        "invalid_response" => EXIT_VALIDATION_FAILURE,
+        _ => EXIT_CAUSALITY_VIOLATION,
+    }
+}
+
+// `analyze` subcommand: check the measurements of every archived chain, oldest first, for
+// causality violations and for times that disagree with the local time between them
+fn analyze_main(matches: &ArgMatches, config: &ClientConfig) -> ! {
+    let archive_dir = matches.value_of("archive-dir").unwrap();
+    let list = matches
+        .value_of("server-list")
+        .or_else(|| config.value("server-list"))
+        .map(|l| load_server_list(Some(l), None));
+    let slack = value_t!(matches, "slack", u64).unwrap_or_else(|e| config_error(&e.message));
+    let json = config.flag(matches, "json");
+    let slack_us = slack as i128 * 1_000_000;
+
+    let archive = chain::load_archive(archive_dir)
+        .unwrap_or_else(|e| config_error(&format!("Error loading archive: {:?}", e)));
+
+    let mut exit_code = EXIT_OK;
+    let mut measurements = 0;
+    let mut violations = 0;
+    let mut mismatches = 0;
+    let mut invalid = 0;
+
+    // The measurement with the latest earliest-possible time so far; no later measurement
+    // may report a time entirely before it. And the measurement just before the current one.
+    let mut latest_lower: Option<Measurement> = None;
+    let mut previous: Option<Measurement> = None;
+
+    for (run, (path, sequence)) in archive.iter().enumerate() {
+        for (idx, link) in sequence.links().iter().enumerate() {
+            measurements += 1;
+
+            // Keys are looked up as of when the response was received, so rotated keys
+            // still verify old measurements
+            let at = link
+                .received
+                .map(|micros| UNIX_EPOCH + time::Duration::from_micros(micros))
+                .unwrap_or_else(SystemTime::now);
+            let pub_key = list.as_ref().and_then(|list| {
+                list.servers()
+                    .iter()
+                    .find(|s| s.name == link.server)
+                    .and_then(|s| s.key_valid_at(at))
+                    .map(|k| k.public_key.clone())
+            });
+
+            let location = format!("{}:{}", path, idx);
+            let parsed = decode_message(&link.response).and_then(|(resp, _)| {
+                ResponseHandler::new(link.version, pub_key, resp, link.nonce.clone())?
+                    .extract_time()
+            });
+            let (lower, upper) = match parsed {
+                Ok(p) => time_bounds_micros(link.version, p.midpoint, p.radius),
+                Err(e) => {
+                    invalid += 1;
+                    let code =
+                        analysis_finding(json, "invalid_response", &location, &link.server, &e);
+                    exit_code = exit_code.max(code);
+                    continue;
+                }
+            };
+
+            let m = Measurement {
+                location,
+                run,
+                server: link.server.clone(),
+                lower,
+                upper,
+                received: link.received,
+            };
+
+            if let Some(ref earlier) = latest_lower {
+                if m.upper < earlier.lower {
+                    violations += 1;
+                    let detail = format!(
+                        "reported a time before the earlier response from {:?} at {}{}",
+                        earlier.server,
+                        earlier.location,
+                        if earlier.run != m.run { " (across runs)" } else { "" }
+                    );
+                    let code = analysis_finding(
+                        json,
+                        "causality_violation",
+                        &m.location,
+                        &m.server,
+                        &detail,
+                    );
+                    exit_code = exit_code.max(code);
+                }
+            }
+
+            // Like the monitor's interval sanity check, but with a fixed slack as the round
+            // trip times aren't archived
+            if let (Some(prev), Some(received)) = (previous.as_ref(), m.received) {
+                if let Some(prev_received) = prev.received {
+                    let local_us = received as i128 - prev_received as i128;
+                    let server_min_us = m.lower as i128 - prev.upper as i128;
+                    let server_max_us = m.upper as i128 - prev.lower as i128;
+
+                    if local_us + slack_us < server_min_us || local_us - slack_us > server_max_us
+                    {
+                        mismatches += 1;
+                        let detail = format!(
+                            "{}us passed locally since {}, the server's times allow {}us to {}us",
+                            local_us, prev.location, server_min_us, server_max_us
+                        );
+                        let code = analysis_finding(
+                            json,
+                            "interval_mismatch",
+                            &m.location,
+                            &m.server,
+                            &detail,
+                        );
+                        exit_code = exit_code.max(code);
+                    }
+                }
+            }
+
+            if !matches!(latest_lower, Some(ref l) if l.lower >= m.lower) {
+                latest_lower = Some(m.clone());
+            }
+            previous = Some(m);
+        }
     }
 
-    fn validate_sig(&self, public_key: &[u8], sig: &[u8], data: &[u8]) -> bool {
-        let mut verifier = MsgVerifier::new(public_key);
-        verifier.update(data);
-        verifier.verify(sig)
+    if json {
+        println!(
+            r#"{{ "runs": {}, "measurements": {}, "invalid_responses": {}, "causality_violations": {}, "interval_mismatches": {} }}"#,
+            archive.len(),
+            measurements,
+            invalid,
+            violations,
+            mismatches
+        );
+    } else {
+        println!(
+            "Analyzed {} measurements from {} runs: {} invalid responses, {} causality violations, {} interval mismatches",
+            measurements,
+            archive.len(),
+            invalid,
+            violations,
+            mismatches
+        );
     }
+    process::exit(exit_code);
 }
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = ClientConfig::load(&args);
+
+    // The list in ROUGHENOUGH_SERVERS (or the config file) is queried if neither a host nor a
+    // server list is given
+    let servers_in_env =
+        env::var_os(SERVERS_ENV_VAR).is_some() || config.value("server-list").is_some();
+
     let matches = App::new("roughenough client")
         .version(&*roughenough_version())
+        .after_help(EXIT_CODES_HELP)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .global(true)
+            .help("YAML file of defaults for flags not given on the command line, by their long names: server-list, timeout, protocol, time-format, zulu, json, verbose, bind, sink, csv, webhook, and interval. Defaults to $XDG_CONFIG_HOME/roughenough/client.yaml (or ~/.config/roughenough/client.yaml) if it exists.")
+        )
         .arg(Arg::with_name("host")
-            .required(true)
-            .help("The Roughtime server to connect to.")
+            .required_unless("server-list")
+            .required(!servers_in_env)
+            .help("The Roughtime server to connect to: a hostname, IPv4 address, or IPv6 address (with optional %zone). May include the port as host:port or [ipv6]:port.")
             .takes_value(true))
         .arg(Arg::with_name("port")
-            .required(true)
-            .help("The Roughtime server port to connect to.")
+            .help("The Roughtime server port to connect to. Required unless the host includes the port.")
             .takes_value(true))
+        .arg(Arg::with_name("server-list")
+            .short("l")
+            .long("server-list")
+            .takes_value(true)
+            .conflicts_with_all(&["host", "port", "public-key", "protocol"])
+            .help("Query every server in the provided JSON server list instead of a single host, or read the list from stdin if '-'. Each response is validated using the server's public key that is valid at the time of the query. Without a host or server list, the inline JSON list in the ROUGHENOUGH_SERVERS environment variable is used."))
+        .arg(Arg::with_name("sample")
+            .long("sample")
+            .takes_value(true)
+            .help("Query only this many servers, chosen at random, from the server list. Hostnames of the other servers are never resolved.")
+        )
         .arg(Arg::with_name("verbose")
             .short("v")
             .long("verbose")
@@ -339,21 +1289,36 @@ fn main() {
             .short("d")
             .long("dump")
             .help("Pretty text dump of the exchanged Roughtime messages."))
+        .arg(Arg::with_name("dump-hex")
+            .long("dump-hex")
+            .help("Dump the exchanged Roughtime messages as hex, with every offset, tag, and value (including those of nested messages) labeled."))
         .arg(Arg::with_name("json")
             .short("j")
             .long("json")
             .help("Output the server's response in JSON format."))
+        .arg(Arg::with_name("sink")
+            .long("sink")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Also publish each verified time and each violation as JSON to 'file:<path>', 'syslog', or 'mqtt://host:port/topic' (needs the 'mqtt' feature). May be repeated."))
+        .arg(Arg::with_name("violations-format")
+            .long("violations-format")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .help("With 'json', also print each causality violation to stdout as a JSON object with both responses' servers, addresses, public keys, time bounds (in microseconds since the epoch) and SHA-512 hashes."))
         .arg(Arg::with_name("public-key")
             .short("k")
             .long("public-key")
             .takes_value(true)
-            .help("The server public key used to validate responses. When set, will add SRV tag to request to bind request to the expected public key. If unset, no validation will be performed."))
+            .help("The server public key used to validate responses. When set, will add SRV tag to request to bind request to the expected public key. If unset, no validation will be performed. Accepts hex, base64, OpenSSH ('ssh-ed25519 ...') and bech32 encodings."))
         .arg(Arg::with_name("time-format")
             .short("f")
             .long("time-format")
             .takes_value(true)
             .help("The strftime format string used to print the time received from the server.")
-            .default_value("%b %d %Y %H:%M:%S %Z")
+            .default_value(config.value_or("time-format", "%b %d %Y %H:%M:%S %Z"))
         )
         .arg(Arg::with_name("num-requests")
             .short("n")
@@ -362,10 +1327,16 @@ fn main() {
             .help("The number of requests to make to the server (each from a different source port). This is mainly useful for testing batch response handling.")
             .default_value("1")
         )
+        .arg(Arg::with_name("concurrency")
+            .long("concurrency")
+            .takes_value(true)
+            .help("The maximum number of requests (each from a different source port) awaiting a response at once. Defaults to all of them, so the server can answer them in a single batch. Use 1 to send requests one after another.")
+        )
         .arg(Arg::with_name("stress")
             .short("s")
             .long("stress")
             .help("Stress test the server by sending the same request as fast as possible. Please only use this on your own server.")
+            .conflicts_with("server-list")
         )
         .arg(Arg::with_name("output-requests")
             .short("o")
@@ -379,167 +1350,714 @@ fn main() {
             .takes_value(true)
             .help("Writes all server responses to the specified file, in addition to processing them. Useful for generating fuzzer inputs.")
         )
+        .arg(Arg::with_name("csv")
+            .long("csv")
+            .takes_value(true)
+            .help("Appends one CSV row per response to the specified file (writing a header if it is new): the local receive time, server, midpoint, radius, round-trip time, the server's offset from the local clock (all in microseconds), and whether the response was verified.")
+        )
         .arg(Arg::with_name("protocol")
             .short("p")
             .long("protocol")
             .takes_value(true)
             .help("Roughtime protocol version to use (0 = classic, 1 = rfc, 11 = draft11)")
-            .default_value("0")
+            .default_value(config.value_or("protocol", "0"))
+        )
+        .arg(Arg::with_name("expect-version")
+            .long("expect-version")
+            .takes_value(true)
+            .help("Fail the measurement (exit code 3) if a server answers with a protocol version other than this one (0 = classic, 1 = rfc, 11 = draft11). Also the version requested when --protocol isn't given.")
         )
+        .arg(Arg::with_name("probe-protocol")
+            .long("probe-protocol")
+            .takes_value(true)
+            .possible_values(&["auto"])
+            .conflicts_with("server-list")
+            .help("With 'auto', probe an unfamiliar server with requests of every protocol at once, report which it answers and with what version, and query it with the newest one. The result is cached for a week in the user's cache directory (e.g. ~/.cache/roughenough/protocols)."))
         .arg(Arg::with_name("timeout")
             .short("t")
             .long("timeout")
             .takes_value(true)
             .help("Seconds to wait for server response")
-            .default_value("10")
+            .default_value(config.value_or("timeout", "10"))
+        )
+        .arg(Arg::with_name("bind")
+            .short("b")
+            .long("bind")
+            .takes_value(true)
+            .help("Local IP address to send requests from, selecting the network interface used.")
+        )
+        .arg(Arg::with_name("chain-file")
+            .short("c")
+            .long("chain-file")
+            .takes_value(true)
+            .conflicts_with("stress")
+            .help("Chain each request's nonce to the previous response and append every exchange to the provided file. An existing file is verified and its chain is continued, so repeated runs build one continuous, verifiable chain.")
+        )
+        .arg(Arg::with_name("psk")
+            .long("psk")
+            .takes_value(true)
+            .help("Hex-encoded pre-shared key of a private deployment. Requests are authenticated with HMAC-SHA256(psk, nonce) placed in their padding.")
+        )
+        .arg(Arg::with_name("delegation-warning")
+            .long("delegation-warning")
+            .takes_value(true)
+            .help("Warn when a validated response's online key delegation (DELE MAXT) expires within this many hours.")
+            .default_value("24")
         )
         .arg(Arg::with_name("zulu")
             .short("z")
             .long("zulu")
             .help("Display time in UTC (default is local time zone)")
         )
-        .get_matches();
-
-    let host = matches.value_of("host").unwrap();
-    let port = value_t_or_exit!(matches.value_of("port"), u16);
-    let verbose = matches.is_present("verbose");
-    let text_dump = matches.is_present("dump");
-    let json = matches.is_present("json");
-    let num_requests = value_t_or_exit!(matches.value_of("num-requests"), u16) as usize;
-    let timeout_secs = value_t_or_exit!(matches.value_of("timeout"), u64);
+        .arg(Arg::with_name("set-clock")
+            .long("set-clock")
+            .conflicts_with("stress")
+            .help("Step the system clock to the server's time once every response was received and valid. Requires a single host and its public key (-k), or a server list that sets 'witnesses' (M), in which case M servers with distinct public keys must agree on the time. Also requires CAP_SYS_TIME (or root), which is checked before any request is sent.")
+        )
+        .arg(Arg::with_name("dry-run")
+            .long("dry-run")
+            .requires("set-clock")
+            .help("With --set-clock, report the change that would be made (delta, direction, and resulting time) without making it.")
+        )
+        .subcommand(SubCommand::with_name("parse")
+            .about("Parse and validate a raw response captured earlier (e.g. with -O), without contacting a server. Exits non-zero if the response is malformed or fails validation.")
+            .arg(Arg::with_name("response")
+                .required(true)
+                .help("File containing the raw response, or '-' to read it from stdin."))
+            .arg(Arg::with_name("request")
+                .short("r")
+                .long("request")
+                .takes_value(true)
+                .help("File containing the raw request (or '-' for stdin). Its nonce is used to check the response's Merkle proof; otherwise the nonce echoed in the response is used."))
+            .arg(Arg::with_name("public-key")
+                .short("k")
+                .long("public-key")
+                .takes_value(true)
+                .help("The server public key used to validate the response's signatures. If unset, signatures are not checked. Accepts the same encodings as the query -k option."))
+            .arg(Arg::with_name("json")
+                .short("j")
+                .long("json")
+                .help("Output the result in JSON format, including every field of the response with its derived value."))
+        )
+        .subcommand(SubCommand::with_name("monitor")
+            .about("Probe every server in a server list on an interval, tracking success rate, latency, and the sanity of the time between responses. Alerts are posted to a webhook if given; otherwise the first alert exits with code 6.")
+            .arg(Arg::with_name("server-list")
+                .short("l")
+                .long("server-list")
+                .takes_value(true)
+                .required(!servers_in_env)
+                .help("JSON server list of the servers to probe, or '-' to read it from stdin. A list file is re-read every round. Defaults to the inline JSON list in the ROUGHENOUGH_SERVERS environment variable."))
+            .arg(Arg::with_name("interval")
+                .short("i")
+                .long("interval")
+                .takes_value(true)
+                .default_value(config.value_or("interval", "60"))
+                .help("Seconds between rounds of probes."))
+            .arg(Arg::with_name("rounds")
+                .short("n")
+                .long("rounds")
+                .takes_value(true)
+                .default_value("0")
+                .help("Number of rounds to run before exiting, or 0 to run until stopped."))
+            .arg(Arg::with_name("window")
+                .long("window")
+                .takes_value(true)
+                .default_value("20")
+                .help("Number of most recent probes of a server its success rate is computed over."))
+            .arg(Arg::with_name("min-success-rate")
+                .long("min-success-rate")
+                .takes_value(true)
+                .default_value("90")
+                .help("Alert when the percentage of a server's recent probes that succeeded falls below this."))
+            .arg(Arg::with_name("max-latency")
+                .long("max-latency")
+                .takes_value(true)
+                .default_value("1000")
+                .help("Alert when a response's round-trip time exceeds this many milliseconds."))
+            .arg(Arg::with_name("timeout")
+                .short("t")
+                .long("timeout")
+                .takes_value(true)
+                .default_value(config.value_or("timeout", "5"))
+                .help("Seconds to wait for each response; a probe that times out fails."))
+            .arg(Arg::with_name("webhook")
+                .short("w")
+                .long("webhook")
+                .takes_value(true)
+                .help("http:// (or, if built with the https feature, https://) URL that each alert is POSTed to as a JSON object."))
+            .arg(Arg::with_name("json")
+                .short("j")
+                .long("json")
+                .help("Output each server's health after every probe in JSON format."))
+        )
+        .subcommand(SubCommand::with_name("analyze")
+            .about("Analyze the measurements of every chain file (see --chain-file) archived in a directory, across the full history of prior runs. Reports causality violations and times inconsistent with the local time between measurements, exiting non-zero if any are found.")
+            .arg(Arg::with_name("archive-dir")
+                .long("archive-dir")
+                .takes_value(true)
+                .required(true)
+                .help("Directory of archived chain files. Files are analyzed in name order, so they should be named to sort oldest first (e.g. by date)."))
+            .arg(Arg::with_name("server-list")
+                .short("l")
+                .long("server-list")
+                .takes_value(true)
+                .help("JSON server list whose public keys are used to validate the archived responses' signatures. Keys are chosen by server name as of when each response was received. If unset, signatures are not checked."))
+            .arg(Arg::with_name("slack")
+                .long("slack")
+                .takes_value(true)
+                .default_value("1")
+                .help("Seconds by which the local and server time between two measurements may disagree, allowing for network round trips."))
+            .arg(Arg::with_name("json")
+                .short("j")
+                .long("json")
+                .help("Output each finding, and a summary, in JSON format."))
+        )
+        .get_matches_from_safe(&args)
+        .unwrap_or_else(|e| match e.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+            _ => config_error(&e.message),
+        });
+
+    if let Some(parse_matches) = matches.subcommand_matches("parse") {
+        parse_main(parse_matches, &config);
+    }
+    if let Some(monitor_matches) = matches.subcommand_matches("monitor") {
+        monitor_main(monitor_matches, &config);
+    }
+    if let Some(analyze_matches) = matches.subcommand_matches("analyze") {
+        analyze_main(analyze_matches, &config);
+    }
+
+    let verbose = config.flag(&matches, "verbose");
+    let dump = Dump {
+        text: matches.is_present("dump"),
+        hex: matches.is_present("dump-hex"),
+    };
+    let json = config.flag(&matches, "json");
+    let num_requests = value_t!(matches, "num-requests", u16)
+        .unwrap_or_else(|e| config_error(&e.message)) as usize;
+    let concurrency = match matches.value_of("concurrency") {
+        Some(_) => value_t!(matches, "concurrency", u16)
+            .ok()
+            .filter(|&c| c > 0)
+            .unwrap_or_else(|| config_error("--concurrency must be between 1 and 65535"))
+            as usize,
+        None => num_requests.max(1),
+    };
+    let timeout_secs =
+        value_t!(matches, "timeout", u64).unwrap_or_else(|e| config_error(&e.message));
     let time_format = matches.value_of("time-format").unwrap();
     let stress = matches.is_present("stress");
-    let pub_key = matches.value_of("public-key").map(|pkey| {
-        HEX.decode(pkey.as_ref())
-            .or_else(|_| BASE64.decode(pkey.as_ref()))
-            .expect("Error parsing public key!")
-    });
+    let pub_key = matches.value_of("public-key").map(parse_public_key);
     let output_requests = matches.value_of("output-requests");
     let output_responses = matches.value_of("output-responses");
-    let protocol = value_t_or_exit!(matches.value_of("protocol"), u8);
-    let use_utc = matches.is_present("zulu");
+    let csv_path = matches.value_of("csv").or_else(|| config.value("csv"));
+    let protocol =
+        value_t!(matches, "protocol", u8).unwrap_or_else(|e| config_error(&e.message));
+    let use_utc = config.flag(&matches, "zulu");
+    let delegation_warning = value_t!(matches, "delegation-warning", u64)
+        .map(|hours| time::Duration::from_secs(hours.saturating_mul(3600)))
+        .unwrap_or_else(|e| config_error(&e.message));
+    // A host on the command line takes precedence over the config file's server list
+    let server_list = matches.value_of("server-list").or_else(|| {
+        match matches.is_present("host") {
+            true => None,
+            false => config.value("server-list"),
+        }
+    });
+    let from_list = server_list.is_some() || !matches.is_present("host");
+    let chain_file = matches.value_of("chain-file");
+    let violations_json = matches.value_of("violations-format") == Some("json");
+    let sink_specs = match matches.values_of("sink") {
+        Some(specs) => specs.collect(),
+        None => config.values("sink"),
+    };
+    let mut sinks: Vec<Box<dyn Sink>> = sink_specs
+        .into_iter()
+        .map(|spec| {
+            sink::from_spec(spec)
+                .unwrap_or_else(|e| config_error(&format!("Error creating sink: {:?}", e)))
+        })
+        .collect();
+    let authenticator = matches.value_of("psk").map(|psk| {
+        let key = HEX
+            .decode(psk.as_ref())
+            .unwrap_or_else(|_| config_error("Error parsing pre-shared key!"));
+        RequestAuthenticator::new(&key)
+    });
 
-    if verbose {
-        eprintln!("Requesting time from: {:?}:{:?}", host, port);
+    let mut client_builder = Client::builder().read_timeout(time::Duration::from_secs(timeout_secs));
+    if let Some(bind) = matches.value_of("bind").or_else(|| config.value("bind")) {
+        let ip = bind
+            .parse::<IpAddr>()
+            .unwrap_or_else(|_| config_error(&format!("Invalid bind address '{}'", bind)));
+        client_builder = client_builder.bind_addr(ip);
     }
+    let client = client_builder.build();
 
-    let version = match protocol {
-        0 => Version::Classic,
-        1 => Version::Rfc,
-        11 => Version::RfcDraft11,
-        _ => panic!(
+    let version = version_from_protocol(protocol).unwrap_or_else(|| {
+        config_error(&format!(
             "Invalid protocol '{}'; valid values are 0, 1, or 11",
             protocol
-        ),
+        ))
+    });
+
+    let expect_version = matches.value_of("expect-version").map(|_| {
+        value_t!(matches, "expect-version", u8)
+            .ok()
+            .and_then(version_from_protocol)
+            .unwrap_or_else(|| config_error("--expect-version must be 0, 1, or 11"))
+    });
+    let version = match expect_version {
+        Some(expected) if matches.occurrences_of("protocol") == 0 => expected,
+        _ => version,
     };
 
-    let addr = (host, port).to_socket_addrs().unwrap().next().unwrap();
+    let probe_auto = matches.is_present("probe-protocol");
+    if probe_auto && matches.occurrences_of("protocol") > 0 {
+        config_error("--probe-protocol and --protocol can't be used together");
+    }
+    if probe_auto && from_list {
+        config_error("--probe-protocol requires a single host, not a server list");
+    }
 
-    if stress {
-        stress_test_forever(version, &addr)
+    let sample = matches.value_of("sample").map(|_| {
+        value_t!(matches, "sample", usize)
+            .ok()
+            .filter(|&n| n > 0)
+            .unwrap_or_else(|| config_error("--sample must be a positive number of servers"))
+    });
+    if sample.is_some() && !from_list {
+        config_error("--sample requires a server list, not a single host");
+    }
+
+    // Check the privilege to set the clock now rather than after a successful measurement
+    let set_clock = matches.is_present("set-clock");
+    let dry_run = matches.is_present("dry-run");
+    let can_set_clock = clock::can_set_system_time();
+    let list = if from_list {
+        Some(load_server_list(server_list, sample))
+    } else {
+        None
+    };
+    let witnesses = list.as_ref().and_then(ServerList::witnesses);
+    if set_clock {
+        if from_list && witnesses.is_none() {
+            config_error("--set-clock with a server list requires the list to set 'witnesses'");
+        }
+        if !from_list && pub_key.is_none() {
+            config_error("--set-clock requires the server's public key (-k)");
+        }
+        if let (Some(quorum), Some(count)) = (witnesses, sample) {
+            if count < quorum {
+                config_error(&format!("--set-clock requires --sample of at least {}", quorum));
+            }
+        }
+        if !can_set_clock && !dry_run {
+            config_error("--set-clock requires CAP_SYS_TIME (or root) to set the system clock");
+        }
     }
 
-    let mut requests = Vec::with_capacity(num_requests);
-    let mut file_for_requests =
-        output_requests.map(|o| File::create(o).expect("Failed to create file!"));
-    let mut file_for_responses =
-        output_responses.map(|o| File::create(o).expect("Failed to create file!"));
+    let targets = if let Some(list) = list {
+        targets_from_list(&client, list.servers().iter())
+    } else {
+        let host = matches.value_of("host").unwrap();
+        let port = matches
+            .value_of("port")
+            .map(|_| value_t!(matches, "port", u16).unwrap_or_else(|e| config_error(&e.message)));
 
-    for _ in 0..num_requests {
-        let nonce = create_nonce(version);
-        let socket = UdpSocket::bind(if addr.is_ipv6() {
-            "[::]:0"
+        let addr = client
+            .resolve(host, port)
+            .unwrap_or_else(|e| config_error(&format!("Error parsing server address: {:?}", e)));
+
+        let version = if probe_auto {
+            auto_protocol(&client, host, &addr, &pub_key, authenticator.as_ref(), verbose)
         } else {
-            "0.0.0.0:0"
-        })
-            .expect("Couldn't open UDP socket");
-        let request = make_request(version, &nonce, text_dump, &pub_key);
+            version
+        };
 
-        if let Some(f) = file_for_requests.as_mut() {
-            f.write_all(&request).expect("Failed to write to file!")
+        if verbose {
+            eprintln!("Requesting time from: {:?} ({})", host, addr);
         }
+        vec![Target {
+            name: host.to_string(),
+            addr,
+            alternates: Vec::new(),
+            version,
+            pub_key,
+        }]
+    };
 
-        requests.push((nonce, request, socket));
+    if stress {
+        stress_test_forever(version, &targets[0].addr)
     }
 
-    for &mut (_, ref request, ref mut socket) in &mut requests {
-        socket.send_to(request, addr).unwrap();
-    }
+    let mut chain = chain_file.map(|path| {
+        if num_requests != 1 {
+            config_error("--chain-file requires a single request per server (-n 1)");
+        }
+        MeasurementSequence::load(path)
+            .unwrap_or_else(|e| config_error(&format!("Error loading chain file: {:?}", e)))
+    });
+
+    let create = |path: &str| {
+        File::create(path)
+            .unwrap_or_else(|e| config_error(&format!("Error creating file '{}': {}", path, e)))
+    };
+    let mut file_for_requests = output_requests.map(create);
+    let mut file_for_responses = output_responses.map(create);
+    let mut csv_file = csv_path.map(|path| {
+        open_csv(path).unwrap_or_else(|e| config_error(&format!("Error opening CSV file: {}", e)))
+    });
 
-    for (nonce, _, socket) in requests {
-        let duration = time::Duration::from_secs(timeout_secs);
-        socket
-            .set_read_timeout(Some(duration))
-            .expect("Failed setting send timeout");
+    let mut exit_code = EXIT_OK;
 
-        let mut buf = [0u8; 4096];
+    // The last response from the previously queried server. Each server is queried after the
+    // previous one answered, so no later server may report a time entirely before it.
+    let mut earlier: Option<Observation> = None;
 
-        let resp_len = match socket.recv_from(&mut buf) {
-            Ok((resp_len, _)) => resp_len,
-            Err(e) if e.kind() == WouldBlock => {
-                eprintln!("Timeout waiting for response");
-                return;
-            }
-            Err(e) => panic!("{}", e),
-        };
+    // The sample of the last verified response. With a server list that requires witnesses,
+    // the samples of every verified response, with the public key of their server.
+    let mut clock_sample: Option<ClockSample> = None;
+    let mut witness_samples: Vec<(Vec<u8>, ClockSample)> = Vec::new();
+
+    let clock = client.local_clock();
+    'targets: for target in targets {
+        let Target {
+            name,
+            addr,
+            alternates,
+            version,
+            pub_key,
+        } = target;
 
-        if let Some(f) = file_for_responses.as_mut() {
-            f.write_all(&buf[0..resp_len])
-                .expect("Failed to write to file!")
+        if verbose && from_list {
+            eprintln!("Requesting time from: {:?} ({})", name, addr);
         }
 
-        let resp = receive_response(version, &buf, resp_len);
+        let mut requests = Vec::with_capacity(num_requests);
+        let mut outgoing = Vec::with_capacity(num_requests);
+        for _ in 0..num_requests {
+            let (blind, nonce) = match chain.as_ref() {
+                Some(chain) => chain.next_nonce(version),
+                None => (Vec::new(), create_nonce(version)),
+            };
+            let sockets = client
+                .socket_for(&addr)
+                .and_then(|socket| Ok((socket.try_clone()?, socket)));
+            let (sender, socket) = match sockets {
+                Ok(sockets) => sockets,
+                Err(e) => {
+                    eprintln!("Error opening UDP socket: {:?}", e);
+                    exit_code = exit_code.max(EXIT_TIMEOUT);
+                    continue 'targets;
+                }
+            };
+            let request =
+                make_request(version, &nonce, dump, &pub_key, authenticator.as_ref());
+
+            if let Some(f) = file_for_requests.as_mut() {
+                f.write_all(&request)
+                    .unwrap_or_else(|e| config_error(&format!("Error writing requests: {}", e)));
+            }
 
-        if text_dump {
-            eprintln!("Response = {}", resp);
+            outgoing.push((request.clone(), sender));
+            requests.push((blind, nonce, socket, request));
         }
 
-        let ParsedResponse {
-            verified,
-            midpoint,
-            radius,
-        } = ResponseHandler::new(version, pub_key.clone(), resp.clone(), nonce.clone())
-            .extract_time();
+        // Keep up to `concurrency` requests in flight, each from its own source port. One
+        // more is sent as each response is awaited.
+        // Requests are sent in order, so the i-th send time (monotonic and in microseconds
+        // since the epoch) is that of the i-th request
+        let mut outgoing = outgoing.into_iter();
+        let mut sent_at = Vec::with_capacity(num_requests);
+        for (request, sender) in outgoing.by_ref().take(concurrency - 1) {
+            sent_at.push((clock.instant(), clock.unix_micros()));
+            if let Err(e) = sender.send_to(&request, addr) {
+                eprintln!("Error sending request: {}", e);
+                exit_code = exit_code.max(EXIT_TIMEOUT);
+                continue 'targets;
+            }
+        }
 
-        let map = resp.into_hash_map();
-        let index = map[&Tag::INDX]
-            .as_slice()
-            .read_u32::<LittleEndian>()
-            .unwrap();
+        let mut latest = None;
 
-        let (seconds, nsecs) = match version {
-            Version::Classic => {
-                let seconds = midpoint / 10_u64.pow(6);
-                let nsecs = (midpoint - (seconds * 10_u64.pow(6))) * 10_u64.pow(3);
-                (seconds, nsecs as u32)
+        for (i, (blind, nonce, socket, request)) in requests.into_iter().enumerate() {
+            if let Some((request, sender)) = outgoing.next() {
+                sent_at.push((clock.instant(), clock.unix_micros()));
+                if let Err(e) = sender.send_to(&request, addr) {
+                    eprintln!("Error sending request: {}", e);
+                    exit_code = exit_code.max(EXIT_TIMEOUT);
+                    break;
+                }
             }
-            Version::Rfc | Version::RfcDraft11 => (midpoint, 0),
-        };
 
-        let verify_str = if verified { "Yes" } else { "No" };
+            // A server that doesn't answer at its first address is retried at its others
+            let (buf, served_by, (sent_instant, sent_micros)) =
+                match client.receive(&socket, &addr) {
+                    Ok(buf) => (buf, addr, sent_at[i]),
+                    Err(e @ (Error::Timeout(_) | Error::Unreachable(_))) => {
+                        match fail_over(&client, &name, &alternates, addr, &request, verbose) {
+                            Some(answered) => answered,
+                            None => {
+                                match e {
+                                    Error::Unreachable(_) => eprintln!("Server port unreachable"),
+                                    _ => eprintln!("Timeout waiting for response"),
+                                }
+                                exit_code = exit_code.max(EXIT_TIMEOUT);
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Error receiving response: {:?}", e);
+                        exit_code = exit_code.max(EXIT_TIMEOUT);
+                        break;
+                    }
+                };
+            let rtt = clock.instant().saturating_duration_since(sent_instant);
+            let received = clock.unix_micros();
+
+            if let Some(f) = file_for_responses.as_mut() {
+                f.write_all(&buf)
+                    .unwrap_or_else(|e| config_error(&format!("Error writing responses: {}", e)));
+            }
 
-        let out = if use_utc {
-            let ts = Utc.timestamp_opt(seconds as i64, nsecs).unwrap();
-            ts.format(time_format).to_string()
-        } else {
-            let ts = Local.timestamp_opt(seconds as i64, nsecs).unwrap();
-            ts.format(time_format).to_string()
-        };
+            let parsed = receive_response(version, &buf).and_then(|resp| {
+                dump.print("Response", &resp);
+                let handler =
+                    ResponseHandler::new(version, pub_key.clone(), resp.clone(), nonce.clone())?;
+                let parsed = handler.extract_time()?;
+                if let Some(expected) = expect_version {
+                    handler.expect_version(expected)?;
+                }
+                let leap_info = handler.leap_info()?;
+                Ok((resp, parsed, handler.version_warnings(&[version]), leap_info))
+            });
+
+            let (
+                resp,
+                ParsedResponse {
+                    verified,
+                    midpoint,
+                    radius,
+                    maxt,
+                },
+                version_warnings,
+                leap_info,
+            ) = match parsed {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("Invalid response from {:?}: {}", name, e);
+                    exit_code = exit_code.max(EXIT_VALIDATION_FAILURE);
+                    publish(
+                        &mut sinks,
+                        Event::Violation {
+                            server: &name,
+                            kind: "invalid_response",
+                            detail: &e,
+                        },
+                    );
+                    continue;
+                }
+            };
+
+            for warning in version_warnings {
+                eprintln!("Warning: {:?}: {}", name, warning);
+            }
 
-        if verbose {
-            eprintln!(
-                "Received time from server: midpoint={:?}, radius={:?}, verified={} (merkle_index={})",
-                out, radius, verify_str, index
+            let (lower, upper) = time_bounds_micros(version, midpoint, radius);
+            let observed = Observation {
+                server: name.clone(),
+                address: served_by,
+                public_key: pub_key.clone(),
+                lower,
+                upper,
+                response_hash: digest::digest(&digest::SHA512, &buf).as_ref().to_vec(),
+            };
+            if let Some(ref earlier) = earlier {
+                if upper < earlier.lower {
+                    let detail = format!(
+                        "{:?} reported a time before the earlier response from {:?}",
+                        name, earlier.server
+                    );
+                    eprintln!("Causality violation: {}", detail);
+                    if violations_json {
+                        println!(
+                            r#"{{ "violation": "causality_violation", "detail": {:?}, "gap_us": {}, "earlier": {}, "later": {} }}"#,
+                            detail,
+                            earlier.lower - upper,
+                            earlier.to_json(),
+                            observed.to_json()
+                        );
+                    }
+                    exit_code = exit_code.max(EXIT_CAUSALITY_VIOLATION);
+                    publish(
+                        &mut sinks,
+                        Event::Violation {
+                            server: &name,
+                            kind: "causality_violation",
+                            detail: &detail,
+                        },
+                    );
+                }
+            }
+            publish(
+                &mut sinks,
+                Event::Time {
+                    server: &name,
+                    midpoint: lower + (upper - lower) / 2,
+                    radius: (upper - lower) / 2,
+                    verified,
+                },
             );
+            latest = Some(observed);
+            if verified {
+                // The midpoint is most likely halfway through the round trip
+                let estimate = lower + (upper - lower) / 2 + rtt.as_micros() as u64 / 2;
+                let uncertainty = (upper - lower) / 2 + rtt.as_micros() as u64 / 2;
+                clock_sample = Some((estimate, clock.instant(), uncertainty));
+                if let (Some(_), Some(key)) = (witnesses, &pub_key) {
+                    witness_samples.push((key.clone(), clock_sample.unwrap()));
+                }
+            }
+            let delays = client::one_way_delay_bounds(sent_micros, rtt, lower, upper);
+
+            if let Some(f) = csv_file.as_mut() {
+                // The server's midpoint compared to the local midpoint of the round trip
+                let local = sent_micros + rtt.as_micros() as u64 / 2;
+                let server = lower + (upper - lower) / 2;
+                let row = format!(
+                    "{},{},{},{},{},{},{}\n",
+                    received,
+                    csv_field(&name),
+                    server,
+                    (upper - lower) / 2,
+                    rtt.as_micros(),
+                    server as i64 - local as i64,
+                    verified
+                );
+                f.write_all(row.as_bytes())
+                    .unwrap_or_else(|e| config_error(&format!("Error writing CSV file: {}", e)));
+            }
+
+            if let (Some(chain), Some(path)) = (chain.as_mut(), chain_file) {
+                let link = ChainLink {
+                    server: name.clone(),
+                    version,
+                    blind,
+                    nonce: nonce.clone(),
+                    response: buf.clone(),
+                    received: Some(received),
+                };
+                chain
+                    .push_and_persist(link, path)
+                    .unwrap_or_else(|e| config_error(&format!("Error saving chain file: {:?}", e)));
+            }
+
+            let map = resp.into_hash_map();
+            let index = map[&Tag::INDX]
+                .as_slice()
+                .read_u32::<LittleEndian>()
+                .unwrap();
+
+            let verify_str = if verified { "Yes" } else { "No" };
+            let out = format_time(version, midpoint, use_utc, time_format);
+
+            // Only an authenticated delegation is worth warning about
+            let remaining = delegation_remaining(version, midpoint, maxt);
+            let delegation_expiring = verified && remaining < delegation_warning;
+            if delegation_expiring {
+                eprintln!(
+                    "Warning: the delegation of {:?}'s online key expires in {} minutes (MAXT {})",
+                    name,
+                    remaining.as_secs() / 60,
+                    format_time(version, maxt, use_utc, time_format)
+                );
+            }
+
+            if verbose {
+                eprintln!(
+                    "Received time from server: midpoint={:?}, radius={:?}, verified={} (merkle_index={})",
+                    out, radius, verify_str, index
+                );
+                if served_by != addr {
+                    eprintln!("Response served by alternate address {}", served_by);
+                }
+                if let Some(ref leap_info) = leap_info {
+                    eprintln!("Leap second information: {}", leap_info);
+                }
+                match delays {
+                    Some(d) => eprintln!(
+                        "One-way delay bounds (rtt {}us): outbound {}..{}us, inbound {}..{}us",
+                        rtt.as_micros(),
+                        d.outbound_us.0,
+                        d.outbound_us.1,
+                        d.inbound_us.0,
+                        d.inbound_us.1
+                    ),
+                    None => eprintln!(
+                        "One-way delay bounds (rtt {}us): none, the server's time lies outside \
+                         the round trip by the local clock",
+                        rtt.as_micros()
+                    ),
+                }
+            }
+
+            if json {
+                let delays_json = match delays {
+                    Some(d) => format!(
+                        r#"{{ "outbound": [{}, {}], "inbound": [{}, {}] }}"#,
+                        d.outbound_us.0, d.outbound_us.1, d.inbound_us.0, d.inbound_us.1
+                    ),
+                    None => "null".to_string(),
+                };
+                let leap_json = match leap_info {
+                    Some(l) => format!("{:?}", l.to_string()),
+                    None => "null".to_string(),
+                };
+                println!(
+                    r#"{{ "server": {:?}, "address": "{}", "midpoint": {:?}, "radius": {:?}, "verified": {}, "merkle_index": {}, "delegation_expiring": {}, "rtt_us": {}, "one_way_delay_us": {}, "leap_info": {} }}"#,
+                    name,
+                    served_by,
+                    out,
+                    radius,
+                    verified,
+                    index,
+                    delegation_expiring,
+                    rtt.as_micros(),
+                    delays_json,
+                    leap_json
+                );
+            } else if from_list {
+                println!("{}: {}", name, out);
+            } else {
+                println!("{}", out);
+            }
         }
 
-        if json {
-            println!(
-                r#"{{ "midpoint": {:?}, "radius": {:?}, "verified": {}, "merkle_index": {} }}"#,
-                out, radius, verified, index
-            );
-        } else {
-            println!("{}", out);
+        if latest.is_some() {
+            earlier = latest;
         }
     }
+
+    if set_clock {
+        // Only a quorum of witnesses need to agree, any other server may have failed
+        let sample = match witnesses {
+            Some(quorum) => corroborated_sample(&witness_samples, quorum, clock.instant(), verbose),
+            None => clock_sample
+                .filter(|_| exit_code == EXIT_OK)
+                .ok_or_else(|| "not every response was received and valid".to_string()),
+        };
+        exit_code = exit_code.max(set_system_clock(
+            sample,
+            dry_run,
+            can_set_clock,
+            json,
+            use_utc,
+            time_format,
+        ));
+    }
+
+    process::exit(exit_code);
 }
@@ -20,14 +20,21 @@
 #[macro_use]
 extern crate log;
 
-use clap::{App, Arg};
-use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
+use std::process;
+
+use clap::{App, AppSettings, Arg, SubCommand};
+use data_encoding::{Encoding, BASE64, HEXLOWER_PERMISSIVE};
 use log::LevelFilter;
 use simple_logger::SimpleLogger;
 
+use roughenough::config;
+#[allow(unused_imports)]
+use roughenough::key::{decode_public_key, LongTermKey};
 #[allow(unused_imports)]
-use roughenough::kms::{EnvelopeEncryption, KmsProvider};
+use roughenough::kms::{self, EnvelopeEncryption, KmsProvider};
 use roughenough::roughenough_version;
+use roughenough::server_list::{Server, ServerAddress, ServerKey};
+use roughenough::PUBLIC_KEY_LENGTH;
 
 #[allow(dead_code)]
 const HEX: Encoding = HEXLOWER_PERMISSIVE;
@@ -87,6 +94,139 @@ fn decrypt_blob(kms_key: &str, hex_blob: &str) {
     }
 }
 
+#[cfg(not(any(feature = "awskms", feature = "gcpkms")))]
+fn migrate_seed(_: &str, _: &str) {
+    // main() will exit if kms support is not enabled, making this unreachable
+    unreachable!()
+}
+
+#[cfg(any(feature = "awskms", feature = "gcpkms"))]
+fn migrate_seed(config_arg: &str, kms_key: &str) {
+    let config = match config::make_config(config_arg) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("Error loading config '{}': {:?}", config_arg, e);
+            return;
+        }
+    };
+
+    // Reads the seed however the existing config protects it: plaintext or a (prior) KMS key
+    let plaintext_seed = match kms::load_seed(config.as_ref()) {
+        Ok(seed) => seed,
+        Err(e) => {
+            error!("Error loading existing seed: {:?}", e);
+            return;
+        }
+    };
+    let old_pubkey = LongTermKey::new(&plaintext_seed).public_key();
+
+    let kms_client = get_kms(kms_key);
+    let encrypted_blob = match EnvelopeEncryption::encrypt_seed(&kms_client, &plaintext_seed) {
+        Ok(blob) => blob,
+        Err(e) => {
+            error!("Error: {:?}", e);
+            return;
+        }
+    };
+
+    // Round-trip the new blob to prove the server will derive the same long-term identity
+    let new_pubkey = match EnvelopeEncryption::decrypt_seed(&kms_client, &encrypted_blob) {
+        Ok(seed) => LongTermKey::new(&seed).public_key(),
+        Err(e) => {
+            error!("Error decrypting newly encrypted seed: {:?}", e);
+            return;
+        }
+    };
+
+    if new_pubkey != old_pubkey {
+        error!(
+            "Public key changed during migration ({} != {}); not using the new seed",
+            HEX.encode(&new_pubkey),
+            HEX.encode(&old_pubkey)
+        );
+        return;
+    }
+
+    info!(
+        "Seed migrated from '{}' protection; public key {} is unchanged",
+        config.kms_protection(),
+        HEX.encode(&old_pubkey)
+    );
+    println!("kms_protection: \"{}\"", kms_key);
+    println!("seed: {}", HEX.encode(&encrypted_blob));
+}
+
+// Read-only check that a server's seed loads (via KMS if protected) and derives the expected
+// public key. Only the public key is printed, never the seed.
+fn verify_seed(config_arg: &str) {
+    let config = match config::make_config(config_arg) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("Error loading config '{}': {:?}", config_arg, e);
+            process::exit(1);
+        }
+    };
+
+    match kms::verify_seed(config.as_ref()) {
+        Ok(public_key) => {
+            let checked = if config.public_key().is_some() {
+                "matches the configured public_key"
+            } else {
+                "no public_key configured to compare against"
+            };
+            info!(
+                "Seed loaded with '{}' protection; {}",
+                config.kms_protection(),
+                checked
+            );
+            println!("{}", HEX.encode(&public_key));
+        }
+        Err(e) => {
+            error!("Seed verification failed: {:?}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Print everything an operator needs to publish a server with `public_key`: its encodings,
+// the `SRV` value clients send to it, and an entry for a JSON server list
+fn publish_key(encoded: &str, name: &str, version: &str, addresses: Vec<&str>) {
+    let public_key = match decode_public_key(encoded) {
+        Ok(key) if key.len() == PUBLIC_KEY_LENGTH => key,
+        Ok(key) => {
+            error!("Public key must be {} bytes, found {}", PUBLIC_KEY_LENGTH, key.len());
+            process::exit(1);
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            process::exit(1);
+        }
+    };
+
+    let server = Server {
+        name: name.to_string(),
+        version: version.to_string(),
+        keys: vec![ServerKey {
+            public_key: public_key.clone(),
+            valid_from: None,
+            valid_until: None,
+        }],
+        addresses: addresses
+            .into_iter()
+            .map(|address| ServerAddress {
+                protocol: "udp".to_string(),
+                address: address.to_string(),
+                fallbacks: Vec::new(),
+            })
+            .collect(),
+    };
+
+    println!("public key (hex)    : {}", HEX.encode(&public_key));
+    println!("public key (base64) : {}", BASE64.encode(&public_key));
+    println!("SRV value (hex)     : {}", HEX.encode(&LongTermKey::calc_srv_value(&public_key)));
+    println!("{}", server.to_json());
+}
+
 #[cfg(feature = "awskms")]
 fn get_kms(kms_key: &str) -> impl KmsProvider {
     use roughenough::kms::AwsKms;
@@ -106,22 +246,18 @@ pub fn main() {
         .init()
         .unwrap();
 
-    if !(cfg!(feature = "gcpkms") || cfg!(feature = "awskms")) {
-        warn!("KMS support was not compiled into this build; nothing to do.");
-        warn!("See the Roughenough documentation for information on KMS support.");
-        warn!("  https://github.com/int08h/roughenough/blob/master/doc/OPTIONAL-FEATURES.md");
-        return;
-    }
+    let kms_enabled = cfg!(feature = "gcpkms") || cfg!(feature = "awskms");
 
     let matches = App::new("roughenough-kms")
         .version(&*roughenough_version())
         .long_about("Encrypt and decrypt Roughenough long-term server seeds using a KMS")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("KEY_ID")
                 .short("k")
                 .long("kms-key")
                 .takes_value(true)
-                .required(true)
+                .required(kms_enabled)
                 .help("Identity of the KMS key to be used"),
         )
         .arg(
@@ -140,8 +276,110 @@ pub fn main() {
                 .required(false)
                 .help("32 byte hex seed for the server's long-term identity"),
         )
+        .subcommand(
+            SubCommand::with_name("migrate-seed")
+                .about(
+                    "Re-encrypt a server's seed under a new KMS key, verifying its public key \
+                     is unchanged",
+                )
+                .arg(
+                    Arg::with_name("KEY_ID")
+                        .short("k")
+                        .long("kms-key")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Identity of the KMS key to encrypt the seed with"),
+                )
+                .arg(
+                    Arg::with_name("CONFIG")
+                        .required(true)
+                        .help("Current server config (file or ENV) holding the seed to migrate"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-seed")
+                .about(
+                    "Check that a server's seed can be loaded and derives its configured \
+                     public_key, printing only the public key",
+                )
+                .arg(
+                    Arg::with_name("CONFIG")
+                        .required(true)
+                        .help("Server config (file or ENV) holding the seed to verify"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("publish-key")
+                .about(
+                    "Print a public key's hex and base64 encodings, its SRV value, and a \
+                     server list entry for publishing the server",
+                )
+                .arg(
+                    Arg::with_name("PUBLIC_KEY")
+                        .required(true)
+                        .help("Long-term public key (hex, base64, OpenSSH or bech32)"),
+                )
+                .arg(
+                    Arg::with_name("NAME")
+                        .short("n")
+                        .long("name")
+                        .takes_value(true)
+                        .default_value("roughtime.example.com")
+                        .help("Name of the server in the server list entry"),
+                )
+                .arg(
+                    Arg::with_name("ADDRESS")
+                        .short("a")
+                        .long("address")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .default_value("roughtime.example.com:2002")
+                        .help("host:port of the server, may be given more than once"),
+                )
+                .arg(
+                    Arg::with_name("CLASSIC")
+                        .long("classic")
+                        .help("The server speaks Google-Roughtime rather than IETF-Roughtime"),
+                ),
+        )
         .get_matches();
 
+    // Publishing only needs the public key
+    if let Some(publish) = matches.subcommand_matches("publish-key") {
+        let version = match publish.is_present("CLASSIC") {
+            true => "Google-Roughtime",
+            false => "IETF-Roughtime",
+        };
+        publish_key(
+            publish.value_of("PUBLIC_KEY").expect("Invalid public key"),
+            publish.value_of("NAME").expect("Invalid name"),
+            version,
+            publish.values_of("ADDRESS").expect("Invalid address").collect(),
+        );
+        return;
+    }
+
+    // Plaintext seeds can be verified without KMS support
+    if let Some(verify) = matches.subcommand_matches("verify-seed") {
+        verify_seed(verify.value_of("CONFIG").expect("Invalid config"));
+        return;
+    }
+
+    if !kms_enabled {
+        warn!("KMS support was not compiled into this build; nothing to do.");
+        warn!("See the Roughenough documentation for information on KMS support.");
+        warn!("  https://github.com/int08h/roughenough/blob/master/doc/OPTIONAL-FEATURES.md");
+        return;
+    }
+
+    if let Some(migrate) = matches.subcommand_matches("migrate-seed") {
+        let kms_key = migrate.value_of("KEY_ID").expect("Invalid KMS key id");
+        let config_arg = migrate.value_of("CONFIG").expect("Invalid config");
+        migrate_seed(config_arg, kms_key);
+        return;
+    }
+
     let kms_key = matches.value_of("KEY_ID").expect("Invalid KMS key id");
 
     if matches.is_present("SEED") {
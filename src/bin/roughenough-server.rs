@@ -23,34 +23,155 @@
 #[macro_use]
 extern crate log;
 
+use std::any::Any;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::panic::{self, AssertUnwindSafe};
 use std::process;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{env, io, thread};
 
+use data_encoding::HEXLOWER;
 use log::LevelFilter;
 use mio::net::UdpSocket;
 use mio::Events;
 use net2::unix::UnixUdpBuilderExt;
 use net2::UdpBuilder;
+#[cfg(feature = "dangerous-testing")]
+use once_cell::sync::OnceCell;
 use once_cell::sync::Lazy;
 use simple_logger::SimpleLogger;
 
+use roughenough::admin;
+use roughenough::admin::{enabled_features, AdminRegistry, MetricsPusher};
+use roughenough::audit::AuditLog;
 use roughenough::config;
 use roughenough::config::ServerConfig;
+use roughenough::key::{KmsProtection, LongTermKey};
+use roughenough::kms;
+#[cfg(feature = "dangerous-testing")]
+use roughenough::misbehave::Misbehavior;
 use roughenough::roughenough_version;
-use roughenough::server::Server;
+use roughenough::server::{self, Server};
+use roughenough::stats::MetricsAggregator;
+
+// Exit status after the online keys were revoked through the admin endpoint, so supervisors
+// can tell a revocation from a crash
+const EXIT_REVOKED: i32 = 3;
 
 // All processing threads poll this. Starts TRUE and will be set to FASLE by
 // the Ctrl-C (SIGINT) handler created in `set_ctrlc_handler()`
 static KEEP_RUNNING: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(true));
 
-fn polling_loop(cfg: Arc<Mutex<Box<dyn ServerConfig>>>, socket: UdpSocket) {
+// Workers restarted after a panic, across all workers
+static WORKER_RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+// Pause before restarting a worker that panicked
+const WORKER_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+// Set from `--misbehave` before any processing threads start
+#[cfg(feature = "dangerous-testing")]
+static MISBEHAVIOR: OnceCell<Misbehavior> = OnceCell::new();
+
+// What every worker, and every restart of one, is built from
+#[derive(Clone)]
+struct WorkerContext {
+    cfg: Arc<Mutex<Box<dyn ServerConfig>>>,
+    // Loaded once at startup, so restarting a worker never needs the KMS
+    seed: Arc<Vec<u8>>,
+    listen_addr: SocketAddr,
+    registry: Option<Arc<AdminRegistry>>,
+    audit_log: Option<Arc<AuditLog>>,
+    aggregator: Option<Arc<MetricsAggregator>>,
+}
+
+// Run a worker until Ctrl-C. A worker that panics is logged and restarted with a fresh
+// `Server` (and socket), so one bad request can't silently take a share of capacity down.
+fn polling_loop(ctx: WorkerContext, socket: UdpSocket) {
+    let name = thread::current().name().unwrap_or("worker").to_string();
+    let mut socket = Some(socket);
+    let mut restarts = 0u64;
+
+    loop {
+        let sock = match socket.take() {
+            Some(sock) => sock,
+            None => match bind_socket(&ctx.listen_addr) {
+                Ok(sock) => sock,
+                Err(e) => {
+                    error!("{} can't rebind {} to restart: {:?}", name, ctx.listen_addr, e);
+                    return;
+                }
+            },
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_worker(&ctx, sock, restarts == 0)
+        }));
+
+        let cause = match result {
+            Ok(()) => return,
+            Err(cause) => cause,
+        };
+
+        restarts += 1;
+        let total = WORKER_RESTARTS.fetch_add(1, Ordering::AcqRel) + 1;
+        error!(
+            "{} panicked: {}; restarting it (restart {} of this worker, {} in total)",
+            name,
+            panic_message(cause.as_ref()),
+            restarts,
+            total
+        );
+        if let Some(registry) = &ctx.registry {
+            registry.record_restart(&name);
+        }
+
+        // Don't spin if the worker panics again straight away
+        thread::sleep(WORKER_RESTART_DELAY);
+        if !KEEP_RUNNING.load(Ordering::Acquire) {
+            return;
+        }
+    }
+}
+
+fn panic_message(cause: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = cause.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = cause.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown cause"
+    }
+}
+
+fn run_worker(ctx: &WorkerContext, socket: UdpSocket, show_config: bool) {
     let mut server = {
-        let config = cfg.lock().unwrap();
-        let server = Server::new(config.as_ref(), socket);
+        // A panic while the lock was held leaves the config itself intact
+        let config = ctx.cfg.lock().unwrap_or_else(|e| e.into_inner());
+        let mut server = Server::with_seed(config.as_ref(), socket, &ctx.seed);
+
+        if let Some(registry) = &ctx.registry {
+            server.set_admin_registry(registry.clone());
+        }
+
+        if let Some(audit_log) = &ctx.audit_log {
+            server.set_audit_log(audit_log.clone());
+        }
 
-        display_config(&server, config.as_ref());
+        if let Some(aggregator) = &ctx.aggregator {
+            server.set_metrics_aggregator(aggregator.clone());
+        }
+
+        #[cfg(feature = "dangerous-testing")]
+        if let Some(misbehavior) = MISBEHAVIOR.get() {
+            server.set_misbehavior(*misbehavior);
+        }
+
+        if show_config {
+            display_config(&server, config.as_ref(), ctx.listen_addr);
+        }
         server
     };
 
@@ -59,6 +180,11 @@ fn polling_loop(cfg: Arc<Mutex<Box<dyn ServerConfig>>>, socket: UdpSocket) {
     loop {
         server.process_events(&mut events);
 
+        if server.exit_requested() {
+            error!("Online keys revoked by admin action, exiting");
+            process::exit(EXIT_REVOKED);
+        }
+
         if !KEEP_RUNNING.load(Ordering::Acquire) {
             warn!("Ctrl-C caught, exiting...");
             return;
@@ -73,13 +199,7 @@ fn set_ctrlc_handler() {
 
 // Bind to the server port using SO_REUSEPORT and SO_REUSEADDR so the kernel will more fairly
 // balance traffic to each worker. https://lwn.net/Articles/542629/
-fn bind_socket(config: Arc<Mutex<Box<dyn ServerConfig>>>) -> io::Result<UdpSocket> {
-    let sock_addr = config
-        .lock()
-        .unwrap()
-        .udp_socket_addr()
-        .expect("udp sock addr");
-
+fn bind_socket(sock_addr: &SocketAddr) -> io::Result<UdpSocket> {
     let std_socket = UdpBuilder::new_v4()?
         .reuse_address(true)?
         .reuse_port(true)?
@@ -89,7 +209,119 @@ fn bind_socket(config: Arc<Mutex<Box<dyn ServerConfig>>>) -> io::Result<UdpSocke
     Ok(mio_socket)
 }
 
-fn display_config(server: &Server, cfg: &dyn ServerConfig) {
+// Spawn the local admin endpoint and metrics pusher, if configured, and return the registry
+// workers publish to
+fn start_admin_endpoint(config: Arc<Mutex<Box<dyn ServerConfig>>>) -> Option<Arc<AdminRegistry>> {
+    let cfg = config.lock().unwrap();
+    if cfg.admin_port().is_none() && cfg.metrics_push_url().is_none() {
+        return None;
+    }
+    let registry = Arc::new(AdminRegistry::new());
+
+    if let Some(admin_port) = cfg.admin_port() {
+        let token = cfg.admin_token().map(str::to_string);
+
+        let listener = TcpListener::bind(("127.0.0.1", admin_port))
+            .expect("failed to bind TCP listener for admin endpoint");
+
+        let reg = registry.clone();
+        thread::Builder::new()
+            .name("admin".to_string())
+            .spawn(move || admin::serve(listener, &reg, token.as_deref()))
+            .expect("failure spawning admin thread");
+    }
+
+    if let Some(url) = cfg.metrics_push_url() {
+        // Already checked by is_valid_config()
+        let url = url.parse().expect("metrics_push_url");
+        let pusher = MetricsPusher::new(url, cfg.metrics_push_token());
+        let interval = cfg.status_interval();
+
+        let reg = registry.clone();
+        thread::Builder::new()
+            .name("metrics-push".to_string())
+            .spawn(move || pusher.run(&reg, interval))
+            .expect("failure spawning metrics push thread");
+    }
+
+    Some(registry)
+}
+
+// Open the audit log, if configured, shared by all workers
+fn open_audit_log(config: Arc<Mutex<Box<dyn ServerConfig>>>) -> Option<Arc<AuditLog>> {
+    let path = config.lock().unwrap().audit_log()?;
+
+    match AuditLog::open(&path) {
+        Ok(audit_log) => Some(Arc::new(audit_log)),
+        Err(e) => {
+            error!("failed opening audit log '{}': {:?}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+// Write where the server is listening, so tests and orchestration can find an ephemeral port.
+// The file is replaced atomically, a reader never sees it partially written.
+fn write_discovery(path: &str, listen_addr: SocketAddr, public_key: &[u8]) -> io::Result<()> {
+    let record = format!(
+        r#"{{"address":"{}","port":{},"public_key":"{}"}}"#,
+        listen_addr,
+        listen_addr.port(),
+        HEXLOWER.encode(public_key)
+    );
+
+    if path == "-" {
+        println!("{}", record);
+        return Ok(());
+    }
+
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, format!("{}\n", record))?;
+    fs::rename(&tmp_path, path)
+}
+
+// One JSON record of the effective configuration, for support requests to carry
+fn startup_record(config_arg: &str, cfg: &dyn ServerConfig, public_key: &[u8]) -> String {
+    let source = if config_arg == "ENV" {
+        "env".to_string()
+    } else {
+        format!("file:{}", config_arg)
+    };
+
+    let seed_backend = match cfg.kms_protection() {
+        KmsProtection::Plaintext => "plaintext",
+        KmsProtection::AwsKmsEnvelope(_) => "aws-kms",
+        KmsProtection::GoogleKmsEnvelope(_) => "gcp-kms",
+    };
+
+    let stats_backend = if cfg.client_stats_enabled() {
+        "per-client"
+    } else {
+        "aggregated"
+    };
+
+    let features: Vec<String> = enabled_features()
+        .iter()
+        .map(|f| format!(r#""{}""#, f))
+        .collect();
+
+    format!(
+        concat!(
+            r#"{{"version":"{}","features":[{}],"config_source":{:?},"seed_backend":"{}","#,
+            r#""stats_backend":"{}","long_term_public_key":"{}","srv":"{}","settings":{}}}"#
+        ),
+        roughenough_version(),
+        features.join(","),
+        source,
+        seed_backend,
+        stats_backend,
+        HEXLOWER.encode(public_key),
+        HEXLOWER.encode(&LongTermKey::calc_srv_value(public_key)),
+        config::effective_settings_json(cfg)
+    )
+}
+
+fn display_config(server: &Server, cfg: &dyn ServerConfig, listen_addr: SocketAddr) {
     info!("Processing thread          : {}", server.thread_name());
     info!("Number of workers          : {}", cfg.num_workers());
     info!("Long-term public key       : {}", server.get_public_key());
@@ -99,11 +331,7 @@ fn display_config(server: &Server, cfg: &dyn ServerConfig) {
         cfg.status_interval().as_secs()
     );
 
-    info!(
-        "Server listening on        : {}:{}",
-        cfg.interface(),
-        cfg.port()
-    );
+    info!("Server listening on        : {}", listen_addr);
     if let Some(hc_port) = cfg.health_check_port() {
         info!(
             "TCP health check           : {}:{}",
@@ -113,6 +341,27 @@ fn display_config(server: &Server, cfg: &dyn ServerConfig) {
     } else {
         info!("TCP health check           : disabled");
     }
+    if let Some(admin_port) = cfg.admin_port() {
+        info!(
+            "Admin endpoint             : 127.0.0.1:{} (actions {})",
+            admin_port,
+            if cfg.admin_token().is_some() {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        );
+    } else {
+        info!("Admin endpoint             : disabled");
+    }
+    match cfg.metrics_push_url() {
+        Some(url) => info!(
+            "Metrics push               : {} every {} seconds",
+            url,
+            cfg.status_interval().as_secs()
+        ),
+        None => info!("Metrics push               : disabled"),
+    }
     info!(
         "Client req/resp tracking   : {}",
         if cfg.client_stats_enabled() {
@@ -121,11 +370,99 @@ fn display_config(server: &Server, cfg: &dyn ServerConfig) {
             "aggregated"
         }
     );
+    info!(
+        "PSK request authentication : {}",
+        if cfg.psk().is_some() {
+            "required"
+        } else {
+            "disabled"
+        }
+    );
+    info!(
+        "Response padding           : {}",
+        if cfg.pad_responses_enabled() {
+            "full batch size"
+        } else {
+            "disabled"
+        }
+    );
+    info!(
+        "Response order             : {}",
+        if cfg.shuffle_responses_enabled() {
+            "shuffled per batch"
+        } else {
+            "request arrival"
+        }
+    );
+    match cfg.online_key_validity() {
+        Some(validity) => info!("Online key validity        : {} seconds", validity.as_secs()),
+        None => info!("Online key validity        : unbounded"),
+    }
+    match cfg.audit_log() {
+        Some(path) => info!("Audit log                  : {}", path),
+        None => info!("Audit log                  : disabled"),
+    }
+    let clock_sources = cfg.clock_sources();
+    if clock_sources.is_empty() {
+        info!("Clock sources              : system");
+    } else {
+        let names: Vec<String> = clock_sources.iter().map(|s| s.to_string()).collect();
+        info!(
+            "Clock sources              : {}; agree within {}ms, quorum {}",
+            names.join(", "),
+            cfg.clock_tolerance().as_millis(),
+            if cfg.clock_quorum_required() { "required" } else { "not required" }
+        );
+    }
     if cfg.fault_percentage() > 0 {
         info!("Deliberate response errors : ~{}%", cfg.fault_percentage());
     } else {
         info!("Deliberate response errors : disabled");
     }
+    if let Some(mode) = cfg.compromised() {
+        error!("KEYS COMPROMISED, NOT SIGNING: requests answered {}", mode);
+    }
+    #[cfg(feature = "dangerous-testing")]
+    if let Some(misbehavior) = MISBEHAVIOR.get() {
+        warn!("MISBEHAVING CLOCK          : {:?}", misbehavior);
+    }
+}
+
+// Handle `--misbehave <mode>`, which is only available with the `dangerous-testing` feature
+#[cfg(feature = "dangerous-testing")]
+fn set_misbehavior(mode: &str) {
+    match mode.parse::<Misbehavior>() {
+        Ok(misbehavior) => {
+            warn!("**DANGER** responses will carry WRONG TIMES: {:?}", misbehavior);
+            MISBEHAVIOR.set(misbehavior).unwrap();
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "dangerous-testing"))]
+fn set_misbehavior(_mode: &str) {
+    error!("--misbehave requires a server built with the 'dangerous-testing' feature");
+    process::exit(1);
+}
+
+// Refuse all-zero and other guessable seeds, which would make the server's identity a known
+// key, unless `--allow-insecure-test-seed` was given
+fn check_seed_entropy(seed: &[u8], allow_insecure: bool) {
+    match kms::check_seed_entropy(seed) {
+        Ok(_) => (),
+        Err(e) if allow_insecure => {
+            warn!("**INSECURE** {:?}; allowed by --allow-insecure-test-seed", e);
+        }
+        Err(e) => {
+            error!("{:?}; refusing to start", e);
+            error!("Use --allow-insecure-test-seed to permit this seed in test environments");
+            process::exit(1);
+        }
+    }
 }
 
 pub fn main() {
@@ -137,14 +474,31 @@ pub fn main() {
 
     info!("Roughenough server v{} starting", roughenough_version());
 
-    let mut args = env::args();
-    if args.len() != 2 {
-        error!("Usage: server <ENV | /path/to/config.yaml>");
-        process::exit(1);
-    }
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let allow_insecure_seed = match args.iter().position(|a| a == "--allow-insecure-test-seed") {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        }
+        None => false,
+    };
 
-    let arg1 = args.nth(1).unwrap();
-    let config = match config::make_config(&arg1) {
+    let config_arg = match args.as_slice() {
+        [config_arg] => config_arg,
+        [flag, mode, config_arg] if flag == "--misbehave" => {
+            set_misbehavior(mode);
+            config_arg
+        }
+        _ => {
+            error!(
+                "Usage: server [--allow-insecure-test-seed] [--misbehave <mode>] \
+                 <ENV | /path/to/config.yaml>"
+            );
+            process::exit(1);
+        }
+    };
+
+    let config = match config::make_config(config_arg) {
         Err(e) => {
             error!("{:?}", e);
             process::exit(1)
@@ -153,24 +507,86 @@ pub fn main() {
         Ok(cfg) => Arc::new(Mutex::new(cfg)),
     };
 
+    // Workers (including restarted ones) share this copy of the seed rather than each
+    // loading it, so a KMS outage after startup can't keep a worker from restarting
+    let seed = match kms::load_seed(config.lock().unwrap().as_ref()) {
+        Ok(seed) => Arc::new(seed),
+        Err(e) => {
+            error!("Error loading seed, refusing to start: {:?}", e);
+            process::exit(1);
+        }
+    };
+    check_seed_entropy(&seed, allow_insecure_seed);
+
+    // Never serve if the configured key can't produce responses that clients accept
+    let public_key = match server::self_test(config.lock().unwrap().as_ref()) {
+        Ok(public_key) => {
+            info!("Startup self-test passed");
+            public_key
+        }
+        Err(e) => {
+            error!("Startup self-test failed, refusing to start: {:?}", e);
+            process::exit(1);
+        }
+    };
+
+    info!(
+        "Startup {}",
+        startup_record(config_arg, config.lock().unwrap().as_ref(), &public_key)
+    );
+
     set_ctrlc_handler();
 
     // TODO(stuart) TCP healthcheck REUSEADDR and RESUSEPORT on the tcp socket
 
+    let registry = start_admin_endpoint(config.clone());
+    let audit_log = open_audit_log(config.clone());
+
     let mut threads = Vec::new();
 
     let num_workers = config.lock().unwrap().num_workers();
+
+    // Balance across workers is only meaningful with more than one
+    let aggregator = if num_workers > 1 {
+        Some(Arc::new(MetricsAggregator::new(num_workers)))
+    } else {
+        None
+    };
+
+    let mut listen_addr = config
+        .lock()
+        .unwrap()
+        .udp_socket_addr()
+        .expect("udp sock addr");
+
     for i in 0..num_workers {
-        let cfg = config.clone();
-        let socket = bind_socket(cfg.clone()).unwrap();
+        let socket = bind_socket(&listen_addr).unwrap();
+        // A configured port of 0 is chosen by the OS on the first bind, the other workers
+        // share it
+        listen_addr = socket.local_addr().unwrap();
+        let ctx = WorkerContext {
+            cfg: config.clone(),
+            seed: seed.clone(),
+            listen_addr,
+            registry: registry.clone(),
+            audit_log: audit_log.clone(),
+            aggregator: aggregator.clone(),
+        };
         let thread = thread::Builder::new()
             .name(format!("worker-{}", i))
-            .spawn(move || polling_loop(cfg.clone(), socket) )
+            .spawn(move || polling_loop(ctx, socket))
             .expect("failure spawning thread");
 
         threads.push(thread);
     }
 
+    if let Some(path) = config.lock().unwrap().discovery_file() {
+        if let Err(e) = write_discovery(&path, listen_addr, &public_key) {
+            error!("failed writing discovery file '{}': {:?}", path, e);
+            process::exit(1);
+        }
+    }
+
     for t in threads {
         t.join().expect("join failed")
     }
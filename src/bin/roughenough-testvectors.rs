@@ -0,0 +1,63 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Prints the protocol conformance test vectors (see `roughenough::testvectors`) as JSON,
+//! one object per line, and optionally writes each request and response to a file
+//!
+
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use clap::{App, Arg};
+
+use roughenough::roughenough_version;
+use roughenough::testvectors::TestVector;
+
+pub fn main() {
+    let matches = App::new("roughenough-testvectors")
+        .version(&*roughenough_version())
+        .long_about(
+            "Generate the canonical request and response of each supported Roughtime protocol \
+             version from fixed keys and a fixed clock, for checking other implementations \
+             against Roughenough byte for byte",
+        )
+        .arg(
+            Arg::with_name("out-dir")
+                .short("o")
+                .long("out-dir")
+                .takes_value(true)
+                .help("Also write each vector's request and response to <version>-request.bin and <version>-response.bin in this directory"),
+        )
+        .get_matches();
+
+    let vectors = TestVector::all();
+
+    if let Some(dir) = matches.value_of("out-dir") {
+        for vector in &vectors {
+            for (kind, bytes) in [("request", &vector.request), ("response", &vector.response)] {
+                let path = Path::new(dir).join(format!("{}-{}.bin", vector.version, kind));
+                if let Err(e) = fs::write(&path, bytes) {
+                    eprintln!("Error writing {}: {}", path.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+
+    for vector in &vectors {
+        println!("{}", vector.to_json());
+    }
+}
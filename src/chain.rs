@@ -0,0 +1,424 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Chained measurements whose state survives process restarts.
+//!
+//! Each request's nonce is derived from the previous response and a random blind,
+//! `SHA-512(previous response || blind)` truncated to the nonce length of the protocol
+//! version, as the Roughtime draft recommends. A chain therefore proves the order in which
+//! responses were received; if a server's response contradicts the order, the chain is
+//! cryptographic proof of its misbehavior. Other clients can build compatible chains with
+//! [`chained_nonce`](fn.chained_nonce.html).
+//!
+//! A [`MeasurementSequence`](struct.MeasurementSequence.html) is persisted as a file with
+//! one JSON object per link, so a periodic (e.g. cron-invoked) client can append to the same
+//! chain on every run:
+//!
+//! ```json
+//! {"server":"roughtime.int08h.com","version":"Rfc","blind":"9c1b...","nonce":"e4a0...","response":"524f...","received":1700000000123456}
+//! ```
+//!
+//! Chain files rotated out of use can be kept in a directory and loaded together with
+//! [`load_archive`](fn.load_archive.html) to analyze the full history of measurements.
+//!
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+
+use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::version::Version;
+use crate::Error;
+
+const HEX: Encoding = HEXLOWER_PERMISSIVE;
+
+/// Size (in bytes) of the random blind mixed into each chained nonce
+pub const BLIND_LENGTH: usize = 32;
+
+/// One request/response exchange in a measurement chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainLink {
+    /// Name of the server that was queried
+    pub server: String,
+
+    /// Protocol version of the exchange
+    pub version: Version,
+
+    /// Random value mixed into the nonce
+    pub blind: Vec<u8>,
+
+    /// Nonce sent in the request
+    pub nonce: Vec<u8>,
+
+    /// The server's response, exactly as received
+    pub response: Vec<u8>,
+
+    /// Local time, in microseconds since the epoch, the response was received. Links
+    /// written by older clients don't have it.
+    pub received: Option<u64>,
+}
+
+impl ChainLink {
+    fn to_json(&self) -> String {
+        let received = match self.received {
+            Some(micros) => format!(r#","received":{}"#, micros),
+            None => String::new(),
+        };
+        format!(
+            r#"{{"server":{:?},"version":"{}","blind":"{}","nonce":"{}","response":"{}"{}}}"#,
+            self.server,
+            self.version,
+            HEX.encode(&self.blind),
+            HEX.encode(&self.nonce),
+            HEX.encode(&self.response),
+            received
+        )
+    }
+
+    fn from_json(line: &str) -> Result<Self, Error> {
+        let docs = YamlLoader::load_from_str(line)
+            .map_err(|e| invalid(format!("malformed chain link: {}", e)))?;
+
+        if docs.len() != 1 {
+            return Err(invalid("malformed chain link".to_string()));
+        }
+
+        let doc = &docs[0];
+        let version = match doc["version"].as_str() {
+            Some("Classic") => Version::Classic,
+            Some("Rfc") => Version::Rfc,
+            Some("RfcDraft11") => Version::RfcDraft11,
+            other => return Err(invalid(format!("unknown chain link version {:?}", other))),
+        };
+
+        Ok(ChainLink {
+            server: str_field(doc, "server")?.to_string(),
+            version,
+            blind: hex_field(doc, "blind")?,
+            nonce: hex_field(doc, "nonce")?,
+            response: hex_field(doc, "response")?,
+            received: doc["received"].as_i64().map(|micros| micros as u64),
+        })
+    }
+}
+
+///
+/// A sequence of chained measurements, optionally loaded from and persisted to a file.
+///
+#[derive(Debug, Default)]
+pub struct MeasurementSequence {
+    links: Vec<ChainLink>,
+}
+
+impl MeasurementSequence {
+    /// Create an empty sequence
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the sequence stored in `path`. A missing file yields an empty sequence. The
+    /// loaded chain is verified, a broken chain is an error.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut f) => {
+                f.read_to_string(&mut contents)
+                    .map_err(|e| invalid(format!("could not read chain '{}': {}", path, e)))?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(invalid(format!("could not open chain '{}': {}", path, e))),
+        }
+
+        let mut links = Vec::new();
+        for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+            links.push(ChainLink::from_json(line)?);
+        }
+
+        let sequence = MeasurementSequence { links };
+        sequence.verify()?;
+        Ok(sequence)
+    }
+
+    /// The links in this sequence, oldest first
+    pub fn links(&self) -> &[ChainLink] {
+        &self.links
+    }
+
+    /// Generate a random blind and the nonce it chains to. Returns `(blind, nonce)`.
+    pub fn next_nonce(&self, version: Version) -> (Vec<u8>, Vec<u8>) {
+        let mut blind = vec![0u8; BLIND_LENGTH];
+        SystemRandom::new().fill(&mut blind).unwrap();
+
+        let prev_response = self.links.last().map(|l| l.response.as_slice());
+        let nonce = chained_nonce(prev_response, &blind, version);
+
+        (blind, nonce)
+    }
+
+    /// Add a link to the end of the sequence. Its nonce must chain to the previous response.
+    pub fn push(&mut self, link: ChainLink) -> Result<(), Error> {
+        self.check_link(self.links.len(), &link)?;
+        self.links.push(link);
+        Ok(())
+    }
+
+    /// Add a link and append it to the file at `path`
+    pub fn push_and_persist(&mut self, link: ChainLink, path: &str) -> Result<(), Error> {
+        self.check_link(self.links.len(), &link)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| invalid(format!("could not open chain '{}': {}", path, e)))?;
+        writeln!(file, "{}", link.to_json())?;
+
+        self.links.push(link);
+        Ok(())
+    }
+
+    /// Check that every link's nonce was derived from the response preceding it
+    pub fn verify(&self) -> Result<(), Error> {
+        for (idx, link) in self.links.iter().enumerate() {
+            self.check_link(idx, link)?;
+        }
+        Ok(())
+    }
+
+    fn check_link(&self, idx: usize, link: &ChainLink) -> Result<(), Error> {
+        let prev_response = match idx {
+            0 => None,
+            _ => Some(self.links[idx - 1].response.as_slice()),
+        };
+
+        let expected = chained_nonce(prev_response, &link.blind, link.version);
+        if expected != link.nonce {
+            return Err(invalid(format!(
+                "chain broken at link {} ({}): nonce does not follow from previous response",
+                idx, link.server
+            )));
+        }
+        Ok(())
+    }
+}
+
+///
+/// The nonce of a request chained to `prior_response`, the complete response (as received,
+/// including any framing) to the previous request: `SHA-512(prior_response || blind)`
+/// truncated to the nonce length of `version`. The first request of a chain has no prior
+/// response and its nonce is `SHA-512(blind)`.
+///
+/// `blind` must be random and kept, along with the responses, to later prove the order of
+/// the measurements.
+///
+pub fn chained_nonce(prior_response: Option<&[u8]>, blind: &[u8], version: Version) -> Vec<u8> {
+    let mut ctx = digest::Context::new(&digest::SHA512);
+    if let Some(response) = prior_response {
+        ctx.update(response);
+    }
+    ctx.update(blind);
+
+    ctx.finish().as_ref()[..version.profile().nonce_length].to_vec()
+}
+
+///
+/// Load every chain file in the directory `dir`, in file name order, as `(path, sequence)`
+/// pairs. Each chain is verified on its own; the order of the files is not proven by the
+/// chains, so archives should be named to sort oldest first (e.g. by date).
+///
+pub fn load_archive(dir: &str) -> Result<Vec<(String, MeasurementSequence)>, Error> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| invalid(format!("could not read archive '{}': {}", dir, e)))?;
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| invalid(format!("could not read archive '{}': {}", dir, e)))?
+            .path();
+        if path.is_file() {
+            paths.push(path.to_string_lossy().into_owned());
+        }
+    }
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|path| MeasurementSequence::load(&path).map(|seq| (path, seq)))
+        .collect()
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidConfiguration(msg)
+}
+
+fn str_field<'a>(doc: &'a Yaml, name: &str) -> Result<&'a str, Error> {
+    doc[name]
+        .as_str()
+        .ok_or_else(|| invalid(format!("chain link is missing '{}'", name)))
+}
+
+fn hex_field(doc: &Yaml, name: &str) -> Result<Vec<u8>, Error> {
+    HEX.decode(str_field(doc, name)?.as_bytes())
+        .map_err(|e| invalid(format!("chain link '{}' is not valid hex: {}", name, e)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use crate::chain::{chained_nonce, load_archive, ChainLink, MeasurementSequence, HEX};
+    use crate::version::Version;
+
+    fn link(seq: &MeasurementSequence, server: &str, version: Version) -> ChainLink {
+        let (blind, nonce) = seq.next_nonce(version);
+        ChainLink {
+            server: server.to_string(),
+            version,
+            blind,
+            nonce,
+            response: format!("response from {}", server).into_bytes(),
+            received: None,
+        }
+    }
+
+    #[test]
+    fn nonces_chain_to_previous_response() {
+        let mut seq = MeasurementSequence::new();
+
+        let first = link(&seq, "a", Version::Rfc);
+        assert_eq!(first.nonce.len(), 32);
+        seq.push(first).unwrap();
+
+        let second = link(&seq, "b", Version::Classic);
+        assert_eq!(second.nonce.len(), 64);
+        assert_eq!(
+            second.nonce,
+            chained_nonce(Some(b"response from a"), &second.blind, Version::Classic)
+        );
+        seq.push(second).unwrap();
+        assert!(seq.verify().is_ok());
+
+        // a link whose nonce isn't derived from the last response is rejected
+        let mut unchained = link(&MeasurementSequence::new(), "c", Version::Rfc);
+        assert!(seq.push(unchained.clone()).is_err());
+        unchained.nonce = chained_nonce(Some(b"response from b"), &unchained.blind, Version::Rfc);
+        assert!(seq.push(unchained).is_ok());
+    }
+
+    #[test]
+    fn chained_nonce_test_vectors() {
+        let blind: Vec<u8> = (0u8..32).collect();
+        let prior = HEX.decode(b"524f55474854494d0000000000000000").unwrap();
+
+        let vectors: [(Option<&[u8]>, Version, &str); 4] = [
+            (
+                None,
+                Version::Rfc,
+                "3d94eea49c580aef816935762be049559d6d1440dede12e6a125f1841fff8e6f",
+            ),
+            (
+                None,
+                Version::Classic,
+                "3d94eea49c580aef816935762be049559d6d1440dede12e6a125f1841fff8e6f\
+                 a9d71862a3e5746b571be3d187b0041046f52ebd850c7cbd5fde8ee38473b649",
+            ),
+            (
+                Some(&prior),
+                Version::RfcDraft11,
+                "5f8a69c6477fac83e3aae07ecc4270d3eb0177b9b051e16326cc01434149bf4d",
+            ),
+            (
+                Some(&prior),
+                Version::Classic,
+                "5f8a69c6477fac83e3aae07ecc4270d3eb0177b9b051e16326cc01434149bf4d\
+                 b2fb221aa4b3d61819ca445e70a478c92186e2642b438b81c6821166e0ac5e39",
+            ),
+        ];
+
+        for (prior, version, expected) in vectors {
+            assert_eq!(
+                HEX.encode(&chained_nonce(prior, &blind, version)),
+                expected,
+                "{:?} chained to {:?}",
+                version,
+                prior
+            );
+        }
+    }
+
+    #[test]
+    fn chain_survives_reload() {
+        let path = env::temp_dir().join(format!("roughenough-chain-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        // first "run"
+        let mut seq = MeasurementSequence::load(path).unwrap();
+        assert!(seq.links().is_empty());
+        let l = link(&seq, "a", Version::RfcDraft11);
+        seq.push_and_persist(l, path).unwrap();
+
+        // second "run" continues the chain
+        let mut seq = MeasurementSequence::load(path).unwrap();
+        assert_eq!(seq.links().len(), 1);
+        let mut l = link(&seq, "b \"quoted\"", Version::Rfc);
+        l.received = Some(1_700_000_000_123_456);
+        seq.push_and_persist(l, path).unwrap();
+
+        let reloaded = MeasurementSequence::load(path).unwrap();
+        assert_eq!(reloaded.links(), seq.links());
+        assert_eq!(reloaded.links()[1].server, "b \"quoted\"");
+        assert_eq!(reloaded.links()[0].received, None);
+        assert_eq!(reloaded.links()[1].received, Some(1_700_000_000_123_456));
+
+        // tampering with a stored response breaks the chain
+        let contents = fs::read_to_string(path).unwrap();
+        let tampered = contents.replacen("726573706f6e73652066726f6d2061", "00", 1);
+        fs::write(path, tampered).unwrap();
+        assert!(MeasurementSequence::load(path).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn archive_loads_in_name_order() {
+        let dir = env::temp_dir().join(format!("roughenough-archive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+
+        for (name, server) in &[("2024-02.json", "later"), ("2024-01.json", "earlier")] {
+            let mut seq = MeasurementSequence::new();
+            let l = link(&seq, server, Version::Rfc);
+            seq.push_and_persist(l, dir.join(name).to_str().unwrap())
+                .unwrap();
+        }
+
+        let archive = load_archive(dir.to_str().unwrap()).unwrap();
+        assert_eq!(archive.len(), 2);
+        assert!(archive[0].0.ends_with("2024-01.json"));
+        assert_eq!(archive[0].1.links()[0].server, "earlier");
+        assert_eq!(archive[1].1.links()[0].server, "later");
+
+        // a broken chain anywhere in the archive is an error
+        fs::write(dir.join("2024-03.json"), "{}\n").unwrap();
+        assert!(load_archive(dir.to_str().unwrap()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,990 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Building blocks for Roughtime clients.
+//!
+//! Server addresses are accepted in any of these forms, with the port optional when a
+//! default is supplied:
+//!
+//! | Form                  | Example                               |
+//! |-----------------------|---------------------------------------|
+//! | hostname              | `roughtime.int08h.com:2002`           |
+//! | IPv4                  | `192.0.2.7:2002`                      |
+//! | bracketed IPv6        | `[2001:db8::7]:2002`                  |
+//! | scoped IPv6           | `[fe80::1%eth0]:2002`, `[fe80::1%2]`  |
+//! | bare IPv6 (no port)   | `2001:db8::7`, `fe80::1%eth0`         |
+//!
+//! A bare IPv6 address can't carry a port, since the port would be indistinguishable from
+//! the address's last group; enclose the address in brackets to give one.
+//!
+//! [`Client`](struct.Client.html) applies an embedder's policy for name resolution, socket
+//! binding, and timeouts to each exchange with a server. A
+//! [`CancellationToken`](struct.CancellationToken.html) lets the embedder abandon a client's
+//! in-flight exchanges promptly, e.g. when a GUI or daemon shuts down, rather than waiting
+//! out their timeouts.
+//!
+//! Every reading of local time (timeouts, measurement timestamps, checks of validity
+//! periods) goes through the client's [`LocalClock`](trait.LocalClock.html). Tests of an
+//! embedding can build a client with a [`MockClock`](struct.MockClock.html) to simulate a
+//! device whose clock is wildly wrong, or jumps while an exchange is in flight.
+//!
+//! Unless built with an existing socket, a client sends every request from a fresh socket,
+//! so no NAT mapping outlives a query and long-lived clients (e.g. `monitor`) need no
+//! keepalives. A connected socket also learns of ICMP port unreachable messages; the client
+//! keeps waiting through them, since they may be stale or the server briefly down, and
+//! reports [`Error::Unreachable`](../enum.Error.html#variant.Unreachable) rather than a
+//! timeout if no response arrives.
+//!
+//! Processes that can't create sockets (e.g. sandboxed by seccomp, or started by systemd
+//! with a socket passed in) can hand the client an existing socket with
+//! [`ClientBuilder::socket`](struct.ClientBuilder.html#method.socket); a socket received as
+//! a file descriptor is converted with `std::os::unix::io::FromRawFd`.
+//!
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::Error;
+
+/// Default time allowed to resolve a server's address
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time allowed for a server's response to arrive
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest response a client will receive
+const MAX_RESPONSE_LENGTH: usize = 4096;
+
+/// How often a blocked exchange checks whether its client was cancelled
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Resolves a host (as split by [`split_host_port`](fn.split_host_port.html)) and port to
+/// a socket address
+pub type Resolver = dyn Fn(&str, u16) -> Result<SocketAddr, Error> + Send + Sync;
+
+///
+/// Split `addr` into its host and (if present) port. Brackets around an IPv6 host are
+/// removed; a zone ID (`%eth0`) remains part of the host.
+///
+pub fn split_host_port(addr: &str) -> Result<(&str, Option<u16>), Error> {
+    let invalid = |why: &str| {
+        Error::InvalidConfiguration(format!("invalid server address '{}': {}", addr, why))
+    };
+
+    let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+        let (host, after) = rest.split_once(']').ok_or_else(|| invalid("missing ']'"))?;
+        if host.parse::<Ipv6Addr>().is_err() && !host.contains('%') {
+            return Err(invalid("only IPv6 addresses may be enclosed in brackets"));
+        }
+        match after {
+            "" => (host, None),
+            _ => match after.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err(invalid("expected ':<port>' after ']'")),
+            },
+        }
+    } else {
+        match addr.matches(':').count() {
+            0 => (addr, None),
+            1 => {
+                let (host, port) = addr.split_once(':').unwrap();
+                (host, Some(port))
+            }
+            // more than one colon is an unbracketed IPv6 address
+            _ => (addr, None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(invalid("missing host"));
+    }
+
+    let port = match port {
+        Some(port) => Some(port.parse::<u16>().map_err(|_| invalid("invalid port"))?),
+        None => None,
+    };
+
+    Ok((host, port))
+}
+
+///
+/// Resolve `host` and `port` to a socket address. `host` may be a hostname, an IPv4 address,
+/// or an IPv6 address with an optional zone ID. A numeric zone ID is used as the scope ID
+/// directly; an interface name is resolved by the system resolver.
+///
+pub fn resolve(host: &str, port: u16) -> Result<SocketAddr, Error> {
+    if let Some((ip, zone)) = host.split_once('%') {
+        let ip: Ipv6Addr = ip.parse().map_err(|_| {
+            Error::InvalidConfiguration(format!(
+                "zone ID in '{}' is only valid with an IPv6 address",
+                host
+            ))
+        })?;
+
+        if let Ok(scope_id) = zone.parse::<u32>() {
+            return Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)));
+        }
+    }
+
+    (host, port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| Error::InvalidConfiguration(format!("unable to resolve '{}'", host)))
+}
+
+///
+/// Parse and resolve a server address in any of the forms listed in the
+/// [module documentation](index.html). `default_port` is used when `addr` has no port.
+///
+pub fn parse_address(addr: &str, default_port: Option<u16>) -> Result<SocketAddr, Error> {
+    let (host, port) = split_host_port(addr)?;
+
+    match port.or(default_port) {
+        Some(port) => resolve(host, port),
+        None => Err(Error::InvalidConfiguration(format!(
+            "server address '{}' has no port",
+            addr
+        ))),
+    }
+}
+
+///
+/// Cancels the exchanges of every [`Client`](struct.Client.html) built with it, from any
+/// thread. Clones share the same state; once cancelled, a token stays cancelled.
+///
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Abandon in-flight exchanges and fail new ones with `Error::Cancelled`
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+///
+/// The local time a client reads: wall clock time for timestamps and validity checks, and a
+/// monotonic clock for timeouts and round trip times.
+///
+pub trait LocalClock: Send + Sync {
+    /// Wall clock time
+    fn now(&self) -> SystemTime;
+
+    /// Monotonic time, which only moves forward
+    fn instant(&self) -> Instant;
+
+    /// Wall clock time in microseconds since the Unix epoch, 0 if before it
+    fn unix_micros(&self) -> u64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64
+    }
+}
+
+/// The host's clocks, the default [`LocalClock`](trait.LocalClock.html)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HostClock;
+
+impl LocalClock for HostClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+///
+/// A [`LocalClock`](trait.LocalClock.html) for tests. Its wall clock starts at an arbitrary
+/// time and runs at the host's rate from there. It can be set to another time (a step of the
+/// wall clock alone), or advanced, which moves both clocks forward at once as if that much
+/// time had passed.
+///
+#[derive(Debug)]
+pub struct MockClock {
+    state: Mutex<MockState>,
+}
+
+#[derive(Debug)]
+struct MockState {
+    // Wall clock time as of `set_at`
+    wall: SystemTime,
+    set_at: Instant,
+    // Total of all advances, added to the host's monotonic clock
+    advanced: Duration,
+}
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        MockClock {
+            state: Mutex::new(MockState {
+                wall: now,
+                set_at: Instant::now(),
+                advanced: Duration::ZERO,
+            }),
+        }
+    }
+
+    /// Step the wall clock to `now`
+    pub fn set(&self, now: SystemTime) {
+        let mut state = self.state.lock().unwrap();
+        state.wall = now;
+        state.set_at = Instant::now();
+    }
+
+    /// Move both the wall and the monotonic clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.wall += by;
+        state.advanced += by;
+    }
+}
+
+impl LocalClock for MockClock {
+    fn now(&self) -> SystemTime {
+        let state = self.state.lock().unwrap();
+        state.wall + state.set_at.elapsed()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now() + self.state.lock().unwrap().advanced
+    }
+}
+
+///
+/// Builds a [`Client`](struct.Client.html). By default addresses are resolved with
+/// [`resolve`](fn.resolve.html), sockets are bound to the unspecified address of the server's
+/// address family, both timeouts are 10 seconds, and local time is the host's.
+///
+pub struct ClientBuilder {
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    resolver: Arc<Resolver>,
+    bind_addr: Option<IpAddr>,
+    socket: Option<UdpSocket>,
+    cancellation: CancellationToken,
+    local_clock: Arc<dyn LocalClock>,
+}
+
+impl ClientBuilder {
+    /// Time allowed to resolve a server's address. A resolver that takes longer is abandoned.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Time allowed for a server's response to arrive after the request is sent
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Resolve server addresses with `resolver` instead of the system resolver
+    pub fn resolver<F>(mut self, resolver: F) -> Self
+    where
+        F: Fn(&str, u16) -> Result<SocketAddr, Error> + Send + Sync + 'static,
+    {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Send requests from `addr`, selecting the interface (and address family) they use
+    pub fn bind_addr(mut self, addr: IpAddr) -> Self {
+        self.bind_addr = Some(addr);
+        self
+    }
+
+    /// Send every request from `socket`, an already bound (and possibly connected) socket,
+    /// instead of creating one per exchange. `bind_addr` is then ignored. If `socket` is
+    /// connected, only its peer can be queried.
+    pub fn socket(mut self, socket: UdpSocket) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// Cancel the client's exchanges with `token`, which may be shared with other clients.
+    /// By default each client has a token of its own.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Read local time from `clock` instead of the host's clocks
+    pub fn local_clock(mut self, clock: Arc<dyn LocalClock>) -> Self {
+        self.local_clock = clock;
+        self
+    }
+
+    pub fn build(self) -> Client {
+        Client {
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            resolver: self.resolver,
+            bind_addr: self.bind_addr,
+            socket: self.socket,
+            cancellation: self.cancellation,
+            local_clock: self.local_clock,
+        }
+    }
+}
+
+///
+/// Sends Roughtime requests and receives their responses, following the resolution, binding,
+/// and timeout policy it was built with. Requests and responses are raw bytes; building and
+/// validating them is up to the caller.
+///
+pub struct Client {
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    resolver: Arc<Resolver>,
+    bind_addr: Option<IpAddr>,
+    socket: Option<UdpSocket>,
+    cancellation: CancellationToken,
+    local_clock: Arc<dyn LocalClock>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("connect_timeout", &self.connect_timeout)
+            .field("read_timeout", &self.read_timeout)
+            .field("bind_addr", &self.bind_addr)
+            .field("socket", &self.socket)
+            .field("cancelled", &self.cancellation.is_cancelled())
+            .finish()
+    }
+}
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            resolver: Arc::new(resolve),
+            bind_addr: None,
+            socket: None,
+            cancellation: CancellationToken::new(),
+            local_clock: Arc::new(HostClock),
+        }
+    }
+
+    /// The clock this client reads local time from
+    pub fn local_clock(&self) -> &dyn LocalClock {
+        self.local_clock.as_ref()
+    }
+
+    /// The token that cancels this client's exchanges, for handing to another thread
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Cancel this client (and every client sharing its token). Exchanges in flight fail
+    /// with `Error::Cancelled` within moments, as do all later ones.
+    pub fn shutdown(&self) {
+        self.cancellation.cancel();
+    }
+
+    fn check_cancelled(&self, operation: &str) -> Result<(), Error> {
+        match self.cancellation.is_cancelled() {
+            true => Err(Error::Cancelled(operation.to_string())),
+            false => Ok(()),
+        }
+    }
+
+    /// Parse `addr` (see the [module documentation](index.html)) and resolve it with this
+    /// client's resolver, within the connect timeout
+    pub fn resolve(&self, addr: &str, default_port: Option<u16>) -> Result<SocketAddr, Error> {
+        let (host, port) = split_host_port(addr)?;
+        let port = port.or(default_port).ok_or_else(|| {
+            Error::InvalidConfiguration(format!("server address '{}' has no port", addr))
+        })?;
+
+        // Resolvers may block indefinitely; run this one where it can be abandoned
+        let (tx, rx) = mpsc::channel();
+        let resolver = Arc::clone(&self.resolver);
+        let host_owned = host.to_string();
+        thread::spawn(move || {
+            let _ = tx.send(resolver(&host_owned, port));
+        });
+
+        let operation = format!("resolving '{}'", host);
+        let deadline = self.local_clock.instant() + self.connect_timeout;
+        loop {
+            self.check_cancelled(&operation)?;
+
+            let remaining = deadline.saturating_duration_since(self.local_clock.instant());
+            match rx.recv_timeout(remaining.min(CANCEL_POLL_INTERVAL)) {
+                Ok(result) => return result,
+                Err(_) if remaining.is_zero() => return Err(Error::Timeout(operation)),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    /// A socket for exchanging messages with `server`, bound per this client's policy and
+    /// with its read timeout applied. A client built with an existing socket returns a
+    /// duplicate of it, which needs no permission to create sockets.
+    pub fn socket_for(&self, server: &SocketAddr) -> Result<UdpSocket, Error> {
+        if let Some(ref socket) = self.socket {
+            if let Ok(peer) = socket.peer_addr() {
+                if peer != *server {
+                    return Err(Error::InvalidConfiguration(format!(
+                        "client socket is connected to {}, not {}",
+                        peer, server
+                    )));
+                }
+            }
+
+            let socket = socket.try_clone()?;
+            socket.set_read_timeout(Some(self.read_timeout))?;
+            return Ok(socket);
+        }
+
+        let bind_ip = self.bind_addr.unwrap_or(match server {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        });
+
+        let socket = UdpSocket::bind(SocketAddr::new(bind_ip, 0))?;
+        socket.set_read_timeout(Some(self.read_timeout))?;
+        Ok(socket)
+    }
+
+    /// Send `request` to `server` from a new socket and wait for the response
+    pub fn exchange(&self, server: &SocketAddr, request: &[u8]) -> Result<Vec<u8>, Error> {
+        self.check_cancelled(&format!("sending to {}", server))?;
+        let socket = self.socket_for(server)?;
+        socket.send_to(request, server)?;
+        self.receive(&socket, server)
+    }
+
+    /// Like [`receive`](fn.receive.html), but waits for this client's read timeout and gives
+    /// up early if the client is cancelled
+    pub fn receive(&self, socket: &UdpSocket, server: &SocketAddr) -> Result<Vec<u8>, Error> {
+        let operation = format!("waiting for response from {}", server);
+        let deadline = self.local_clock.instant() + self.read_timeout;
+        let mut unreachable = false;
+        let result = loop {
+            self.check_cancelled(&operation)?;
+
+            // a zero read timeout is an error, so always wait at least a moment
+            let remaining = deadline.saturating_duration_since(self.local_clock.instant());
+            let wait = remaining
+                .min(CANCEL_POLL_INTERVAL)
+                .max(Duration::from_millis(1));
+            socket.set_read_timeout(Some(wait))?;
+
+            match receive(socket, server) {
+                Err(Error::Timeout(_)) if !remaining.is_zero() => continue,
+                Err(Error::Unreachable(_)) if !remaining.is_zero() => unreachable = true,
+                Err(Error::Timeout(_)) if unreachable => break Err(Error::Unreachable(operation)),
+                result => break result,
+            }
+        };
+
+        socket.set_read_timeout(Some(self.read_timeout))?;
+        result
+    }
+}
+
+///
+/// Bounds, in microseconds, on the one-way network delays of one exchange. See
+/// [`one_way_delay_bounds`](fn.one_way_delay_bounds.html).
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DelayBounds {
+    /// Least and most time the request spent on its way to the server
+    pub outbound_us: (u64, u64),
+
+    /// Least and most time the response spent on its way back
+    pub inbound_us: (u64, u64),
+}
+
+///
+/// Bound the one-way delays of an exchange whose request was sent at `sent_us` (local time,
+/// microseconds since the epoch) and whose response arrived `rtt` later by the monotonic
+/// clock, given the server's time lies between `lower_us` and `upper_us`.
+///
+/// The server took its time at some point during the round trip, so the request was in
+/// flight for between `lower_us - sent_us` and `upper_us - sent_us`, and the response for
+/// the rest of the round trip. The bounds are only as good as the local clock; `None` means
+/// the server's interval lies entirely outside the round trip, i.e. the two clocks disagree
+/// by more than the network delay can explain.
+///
+pub fn one_way_delay_bounds(
+    sent_us: u64,
+    rtt: Duration,
+    lower_us: u64,
+    upper_us: u64,
+) -> Option<DelayBounds> {
+    let rtt_us = rtt.as_micros() as i128;
+    let outbound_min = lower_us as i128 - sent_us as i128;
+    let outbound_max = upper_us as i128 - sent_us as i128;
+
+    if outbound_max < 0 || outbound_min > rtt_us {
+        return None;
+    }
+
+    let outbound = (outbound_min.max(0), outbound_max.min(rtt_us));
+    Some(DelayBounds {
+        outbound_us: (outbound.0 as u64, outbound.1 as u64),
+        inbound_us: ((rtt_us - outbound.1) as u64, (rtt_us - outbound.0) as u64),
+    })
+}
+
+///
+/// One server's account of the current time: an interval, in microseconds since the epoch,
+/// that it vouches for under its long-term public key.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Witness {
+    pub public_key: Vec<u8>,
+    pub lower_us: u64,
+    pub upper_us: u64,
+}
+
+///
+/// The interval of time that enough distinct witnesses agree on. See
+/// [`corroborate`](fn.corroborate.html).
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corroboration {
+    pub lower_us: u64,
+    pub upper_us: u64,
+    /// Number of distinct public keys whose intervals contain all of `lower_us..=upper_us`
+    pub witnesses: usize,
+}
+
+///
+/// Find the interval of time vouched for by the most witnesses with distinct public keys,
+/// provided there are at least `quorum` of them. Servers listed under several names with the
+/// same key count once (the last of their intervals is used), so a single compromised key
+/// can't outvote the others.
+///
+/// Every witness interval must be taken at the same moment, e.g. by advancing each by the
+/// local time elapsed since its response arrived.
+///
+pub fn corroborate(witnesses: &[Witness], quorum: usize) -> Option<Corroboration> {
+    let mut by_key: Vec<&Witness> = Vec::with_capacity(witnesses.len());
+    for witness in witnesses {
+        match by_key.iter_mut().find(|w| w.public_key == witness.public_key) {
+            Some(seen) => *seen = witness,
+            None => by_key.push(witness),
+        }
+    }
+
+    // The largest set of overlapping intervals overlaps from the latest lower bound among them
+    let mut best: Option<Corroboration> = None;
+    for start in by_key.iter().map(|w| w.lower_us) {
+        let covering: Vec<&&Witness> = by_key
+            .iter()
+            .filter(|w| w.lower_us <= start && start <= w.upper_us)
+            .collect();
+
+        let larger = match best {
+            Some(best) => covering.len() > best.witnesses,
+            None => true,
+        };
+        if covering.len() >= quorum && larger {
+            best = Some(Corroboration {
+                lower_us: start,
+                upper_us: covering.iter().map(|w| w.upper_us).min().unwrap(),
+                witnesses: covering.len(),
+            });
+        }
+    }
+
+    best
+}
+
+///
+/// Wait (up to the socket's read timeout) for the response from `server`. Datagrams from
+/// other sources are ignored. An ICMP port unreachable reported on a connected socket fails
+/// with `Error::Unreachable`.
+///
+pub fn receive(socket: &UdpSocket, server: &SocketAddr) -> Result<Vec<u8>, Error> {
+    let mut buf = [0u8; MAX_RESPONSE_LENGTH];
+
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) if from == *server => return Ok(buf[..len].to_vec()),
+            Ok(_) => continue,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Err(Error::Timeout(format!(
+                    "waiting for response from {}",
+                    server
+                )))
+            }
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                return Err(Error::Unreachable(format!(
+                    "waiting for response from {}",
+                    server
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, SocketAddr, UdpSocket};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use crate::client::{
+        corroborate, one_way_delay_bounds, parse_address, split_host_port, CancellationToken,
+        Client, Corroboration, DelayBounds, LocalClock, MockClock, Witness,
+    };
+    use crate::error::ErrorKind;
+    use crate::Error;
+
+    #[test]
+    fn split_address_forms() {
+        let cases = [
+            (
+                "roughtime.int08h.com:2002",
+                "roughtime.int08h.com",
+                Some(2002),
+            ),
+            ("roughtime.int08h.com", "roughtime.int08h.com", None),
+            ("192.0.2.7:2002", "192.0.2.7", Some(2002)),
+            ("[2001:db8::7]:2002", "2001:db8::7", Some(2002)),
+            ("[2001:db8::7]", "2001:db8::7", None),
+            ("2001:db8::7", "2001:db8::7", None),
+            ("[fe80::1%eth0]:2002", "fe80::1%eth0", Some(2002)),
+            ("fe80::1%2", "fe80::1%2", None),
+            ("::1", "::1", None),
+        ];
+
+        for (addr, host, port) in cases {
+            assert_eq!(split_host_port(addr).unwrap(), (host, port), "{}", addr);
+        }
+
+        for bad in [
+            "",
+            ":2002",
+            "[::1",
+            "[::1]2002",
+            "[]:2002",
+            "[example.com]:2002",
+            "host:port",
+            "host:70000",
+        ] {
+            assert!(split_host_port(bad).is_err(), "accepted '{}'", bad);
+        }
+    }
+
+    #[test]
+    fn parse_resolves_addresses() {
+        let expect = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        assert_eq!(
+            parse_address("127.0.0.1:2002", None).unwrap(),
+            expect("127.0.0.1:2002")
+        );
+        assert_eq!(
+            parse_address("127.0.0.1", Some(2002)).unwrap(),
+            expect("127.0.0.1:2002")
+        );
+        assert_eq!(
+            parse_address("[::1]:2002", Some(1)).unwrap(),
+            expect("[::1]:2002")
+        );
+        assert_eq!(
+            parse_address("::1", Some(2002)).unwrap(),
+            expect("[::1]:2002")
+        );
+
+        match parse_address("[fe80::1%3]:2002", None).unwrap() {
+            SocketAddr::V6(v6) => {
+                assert_eq!(v6.ip().to_string(), "fe80::1");
+                assert_eq!(v6.port(), 2002);
+                assert_eq!(v6.scope_id(), 3);
+            }
+            v4 => panic!("expected IPv6, got {}", v4),
+        }
+
+        assert!(parse_address("127.0.0.1", None).is_err());
+        assert!(parse_address("127.0.0.1%3", Some(2002)).is_err());
+    }
+
+    #[test]
+    fn builder_policy_is_applied() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let client = Client::builder()
+            .resolver(move |host, port| match host {
+                "time.example" => Ok(SocketAddr::new(server_addr.ip(), port)),
+                _ => Err(Error::InvalidConfiguration(format!("unknown '{}'", host))),
+            })
+            .bind_addr(IpAddr::from([127, 0, 0, 1]))
+            .read_timeout(Duration::from_millis(200))
+            .build();
+
+        let addr = client
+            .resolve(&format!("time.example:{}", server_addr.port()), None)
+            .unwrap();
+        assert_eq!(addr, server_addr);
+        assert!(client.resolve("elsewhere.example:2002", None).is_err());
+
+        // echo one request back, ignore the second
+        let echo = thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            let (len, from) = server.recv_from(&mut buf).unwrap();
+            assert!(from.ip().is_loopback());
+            server.send_to(&buf[..len], from).unwrap();
+            server.recv_from(&mut buf).unwrap();
+        });
+
+        assert_eq!(client.exchange(&addr, b"ping").unwrap(), b"ping");
+        let err = client.exchange(&addr, b"ignored").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Io);
+        assert!(matches!(err, Error::Timeout(_)));
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn existing_sockets_are_used() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let other_addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_addr = socket.local_addr().unwrap();
+        socket.connect(server_addr).unwrap();
+
+        let client = Client::builder()
+            .socket(socket)
+            .bind_addr(IpAddr::from([127, 0, 0, 2]))
+            .read_timeout(Duration::from_millis(200))
+            .build();
+
+        let echo = thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            for _ in 0..2 {
+                let (len, from) = server.recv_from(&mut buf).unwrap();
+                assert_eq!(from, socket_addr);
+                server.send_to(&buf[..len], from).unwrap();
+            }
+        });
+
+        assert_eq!(client.exchange(&server_addr, b"ping").unwrap(), b"ping");
+        assert_eq!(client.exchange(&server_addr, b"pong").unwrap(), b"pong");
+        echo.join().unwrap();
+
+        let err = client.exchange(&other_addr, b"ping").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Configuration);
+    }
+
+    #[test]
+    fn slow_resolvers_are_abandoned() {
+        let client = Client::builder()
+            .resolver(|_, _| {
+                thread::sleep(Duration::from_secs(5));
+                Err(Error::InvalidConfiguration("too late".into()))
+            })
+            .connect_timeout(Duration::from_millis(50))
+            .build();
+
+        assert!(matches!(
+            client.resolve("slow.example:2002", None),
+            Err(Error::Timeout(_))
+        ));
+    }
+
+    #[test]
+    fn cancellation_abandons_exchanges() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let token = CancellationToken::new();
+        let client = Client::builder()
+            .read_timeout(Duration::from_secs(30))
+            .cancellation_token(token.clone())
+            .build();
+
+        // the server never answers; cancel the exchange from another thread
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        });
+
+        let start = Instant::now();
+        let err = client.exchange(&server_addr, b"ping").unwrap_err();
+        assert!(matches!(err, Error::Cancelled(_)), "{:?}", err);
+        assert_eq!(err.kind(), ErrorKind::Io);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        canceller.join().unwrap();
+
+        // a cancelled client stays cancelled
+        assert!(matches!(
+            client.exchange(&server_addr, b"ping"),
+            Err(Error::Cancelled(_))
+        ));
+        assert!(matches!(
+            client.resolve("127.0.0.1:2002", None),
+            Err(Error::Cancelled(_))
+        ));
+    }
+
+    #[test]
+    fn port_unreachable_is_reported() {
+        // a port nothing listens on
+        let closed_addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(closed_addr).unwrap();
+        let client = Client::builder()
+            .socket(socket)
+            .read_timeout(Duration::from_millis(300))
+            .build();
+
+        let err = client.exchange(&closed_addr, b"ping").unwrap_err();
+        assert!(matches!(err, Error::Unreachable(_)), "{:?}", err);
+        assert_eq!(err.kind(), ErrorKind::Io);
+    }
+
+    #[test]
+    fn mock_clock_steps_and_advances() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_000));
+        assert!(clock.unix_micros() >= 1_000_000_000);
+        assert!(clock.unix_micros() < 1_010_000_000);
+
+        // a step of the wall clock leaves the monotonic clock alone
+        let before = clock.instant();
+        clock.set(UNIX_EPOCH);
+        assert!(clock.unix_micros() < 10_000_000);
+        assert!(clock.instant() - before < Duration::from_secs(10));
+
+        clock.advance(Duration::from_secs(3_600));
+        assert!(clock.unix_micros() >= 3_600_000_000);
+        assert!(clock.instant() - before >= Duration::from_secs(3_600));
+    }
+
+    #[test]
+    fn timeouts_follow_the_local_clock() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let client = Client::builder()
+            .read_timeout(Duration::from_secs(30))
+            .local_clock(clock.clone())
+            .build();
+
+        // the server never answers; the clock jumps past the read timeout instead
+        let jumper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            clock.advance(Duration::from_secs(60));
+        });
+
+        let start = Instant::now();
+        let err = client.exchange(&server_addr, b"ping").unwrap_err();
+        assert!(matches!(err, Error::Timeout(_)), "{:?}", err);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        jumper.join().unwrap();
+    }
+
+    #[test]
+    fn delay_bounds_split_the_round_trip() {
+        let rtt = Duration::from_micros(1_000);
+
+        // a precise server time 300us after sending
+        assert_eq!(
+            one_way_delay_bounds(10_000, rtt, 10_300, 10_300),
+            Some(DelayBounds {
+                outbound_us: (300, 300),
+                inbound_us: (700, 700),
+            })
+        );
+
+        // an interval wider than the round trip is clamped to it
+        assert_eq!(
+            one_way_delay_bounds(10_000, rtt, 9_000, 10_400),
+            Some(DelayBounds {
+                outbound_us: (0, 400),
+                inbound_us: (600, 1_000),
+            })
+        );
+
+        // a server time entirely before the request was sent, or after the response arrived
+        assert_eq!(one_way_delay_bounds(10_000, rtt, 8_000, 9_999), None);
+        assert_eq!(one_way_delay_bounds(10_000, rtt, 11_001, 12_000), None);
+    }
+
+    #[test]
+    fn witnesses_with_distinct_keys_corroborate() {
+        let witness = |key: u8, lower_us, upper_us| Witness {
+            public_key: vec![key; 32],
+            lower_us,
+            upper_us,
+        };
+
+        let witnesses = [
+            witness(1, 1_000, 2_000),
+            witness(2, 1_500, 2_500),
+            witness(3, 1_800, 1_900),
+            // far off, e.g. a compromised server
+            witness(4, 9_000, 9_100),
+        ];
+        let agreed = Corroboration {
+            lower_us: 1_800,
+            upper_us: 1_900,
+            witnesses: 3,
+        };
+        assert_eq!(corroborate(&witnesses, 3), Some(agreed));
+        assert_eq!(corroborate(&witnesses, 2), Some(agreed));
+        assert_eq!(corroborate(&witnesses, 4), None);
+
+        // the same key listed under other names counts once
+        let sybils = [
+            witness(4, 9_000, 9_100),
+            witness(4, 9_000, 9_100),
+            witness(4, 9_050, 9_150),
+            witness(1, 1_000, 2_000),
+        ];
+        assert_eq!(corroborate(&sybils, 2), None);
+        assert_eq!(corroborate(&sybils, 1).unwrap().witnesses, 1);
+        assert_eq!(corroborate(&[], 1), None);
+    }
+}
@@ -0,0 +1,585 @@
+// Copyright 2017-2024 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Redundant sources of the time the server signs.
+//!
+//! By default the server signs the time of the system clock. With the `clock_sources`
+//! setting it instead reads every configured [`ClockSource`](trait.ClockSource.html) for
+//! each batch and signs the time a majority of them agree on, so a single broken clock can't
+//! make it sign a wrong time. Sources are given as a comma separated list of:
+//!
+//! | Source            | Clock                                                       |
+//! |-------------------|-------------------------------------------------------------|
+//! | `system`          | The system's realtime clock                                 |
+//! | `phc:<path>`      | A PTP hardware clock such as `/dev/ptp0` (Linux only)       |
+//!
+//! Any source may be followed by `@<milliseconds>`, a fixed offset added to each of its
+//! readings, e.g. `phc:/dev/ptp0@-37000` for a PTP clock that keeps TAI.
+//!
+//! A [`ClockQuorum`](struct.ClockQuorum.html) logs an error whenever the set of sources that
+//! disagree with the others changes.
+//!
+//! The client's `--set-clock` goes the other way and steps the system clock to a measured
+//! time with [`set_system_time`](fn.set_system_time.html).
+//!
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::ServerConfig;
+use crate::Error;
+
+///
+/// A source of the current time.
+///
+pub trait ClockSource: Send {
+    /// Name of the source in log messages
+    fn name(&self) -> String;
+
+    /// The current time according to this source
+    fn now(&mut self) -> Result<SystemTime, Error>;
+}
+
+///
+/// The kind of a configured clock source.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClockKind {
+    /// The system's realtime clock
+    System,
+
+    /// A PTP hardware clock, by device path
+    Phc(String),
+}
+
+///
+/// A configured clock source: its kind and a fixed offset applied to its readings.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClockSpec {
+    pub kind: ClockKind,
+
+    /// Milliseconds added to each reading of the source, may be negative
+    pub offset_ms: i64,
+}
+
+impl ClockSpec {
+    /// Parse a comma separated list of clock sources as described in the
+    /// [module documentation](index.html)
+    pub fn parse_list(s: &str) -> Result<Vec<ClockSpec>, Error> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|spec| !spec.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    /// Open the clock this specifies
+    pub fn open(&self) -> Result<Box<dyn ClockSource>, Error> {
+        let clock: Box<dyn ClockSource> = match self.kind {
+            ClockKind::System => Box::new(SystemClock),
+            ClockKind::Phc(ref path) => Box::new(PhcClock::open(path)?),
+        };
+
+        match self.offset_ms {
+            0 => Ok(clock),
+            offset_ms => Ok(Box::new(OffsetClock::new(clock, offset_ms))),
+        }
+    }
+}
+
+impl FromStr for ClockSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: &str| {
+            Error::InvalidConfiguration(format!("invalid clock source '{}': {}", s, why))
+        };
+
+        let (source, offset_ms) = match s.rsplit_once('@') {
+            Some((source, offset)) => (
+                source,
+                offset
+                    .parse::<i64>()
+                    .map_err(|_| invalid("offset must be a whole number of milliseconds"))?,
+            ),
+            None => (s, 0),
+        };
+
+        let kind = match source.split_once(':') {
+            None if source == "system" => ClockKind::System,
+            Some(("phc", path)) if !path.is_empty() => ClockKind::Phc(path.to_string()),
+            _ => return Err(invalid("expected 'system' or 'phc:<path>'")),
+        };
+
+        Ok(ClockSpec { kind, offset_ms })
+    }
+}
+
+impl fmt::Display for ClockSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ClockKind::System => write!(f, "system")?,
+            ClockKind::Phc(ref path) => write!(f, "phc:{}", path)?,
+        }
+
+        match self.offset_ms {
+            0 => Ok(()),
+            offset_ms => write!(f, "@{}", offset_ms),
+        }
+    }
+}
+
+///
+/// The system's realtime clock.
+///
+pub struct SystemClock;
+
+impl ClockSource for SystemClock {
+    fn name(&self) -> String {
+        "system".to_string()
+    }
+
+    fn now(&mut self) -> Result<SystemTime, Error> {
+        Ok(SystemTime::now())
+    }
+}
+
+///
+/// A PTP hardware clock (PHC), read with `clock_gettime` on its dynamic clock ID.
+///
+pub struct PhcClock {
+    path: String,
+    #[cfg(target_os = "linux")]
+    device: std::fs::File,
+}
+
+impl PhcClock {
+    #[cfg(target_os = "linux")]
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let device = std::fs::File::open(path).map_err(|e| {
+            Error::InvalidConfiguration(format!("unable to open clock '{}': {}", path, e))
+        })?;
+
+        let mut clock = PhcClock {
+            path: path.to_string(),
+            device,
+        };
+        clock.now()?;
+        Ok(clock)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(path: &str) -> Result<Self, Error> {
+        Err(Error::InvalidConfiguration(format!(
+            "unable to open clock '{}': PTP hardware clocks are only supported on Linux",
+            path
+        )))
+    }
+}
+
+impl ClockSource for PhcClock {
+    fn name(&self) -> String {
+        format!("phc:{}", self.path)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn now(&mut self) -> Result<SystemTime, Error> {
+        use std::os::unix::io::AsRawFd;
+
+        // FD_TO_CLOCKID() of the kernel's dynamic POSIX clocks
+        let clock_id = ((!self.device.as_raw_fd()) << 3) | 3;
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+
+        // SAFETY: `ts` is a valid timespec for the duration of the call
+        if unsafe { libc::clock_gettime(clock_id, &mut ts) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn now(&mut self) -> Result<SystemTime, Error> {
+        unreachable!("PHC clocks can't be opened on this platform")
+    }
+}
+
+///
+/// Another clock source with a fixed offset added to its readings.
+///
+pub struct OffsetClock {
+    inner: Box<dyn ClockSource>,
+    offset_ms: i64,
+}
+
+impl OffsetClock {
+    pub fn new(inner: Box<dyn ClockSource>, offset_ms: i64) -> Self {
+        OffsetClock { inner, offset_ms }
+    }
+}
+
+impl ClockSource for OffsetClock {
+    fn name(&self) -> String {
+        format!("{}@{}", self.inner.name(), self.offset_ms)
+    }
+
+    fn now(&mut self) -> Result<SystemTime, Error> {
+        let offset = Duration::from_millis(self.offset_ms.unsigned_abs());
+        let now = self.inner.now()?;
+
+        match self.offset_ms < 0 {
+            true => Ok(now - offset),
+            false => Ok(now + offset),
+        }
+    }
+}
+
+///
+/// The outcome of reading every source of a [`ClockQuorum`](struct.ClockQuorum.html).
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockReading {
+    /// The median time of the majority of sources that agree, `None` if there is no majority
+    pub agreed: Option<SystemTime>,
+
+    /// The time of the first configured source, or of the system clock if it failed
+    pub primary: SystemTime,
+
+    /// Number of sources that failed or disagreed with the majority
+    pub dissenting: usize,
+}
+
+impl ClockReading {
+    /// A reading of a single clock, which always agrees with itself
+    pub fn single(time: SystemTime) -> Self {
+        ClockReading {
+            agreed: Some(time),
+            primary: time,
+            dissenting: 0,
+        }
+    }
+
+    /// The agreed time if there is one, otherwise the primary source's
+    pub fn best(&self) -> SystemTime {
+        self.agreed.unwrap_or(self.primary)
+    }
+}
+
+///
+/// Several clock sources, of which a majority must agree within a tolerance.
+///
+pub struct ClockQuorum {
+    sources: Vec<Box<dyn ClockSource>>,
+    tolerance: Duration,
+
+    // Indexes of the sources that dissented in the previous reading
+    dissenters: Vec<usize>,
+}
+
+impl ClockQuorum {
+    pub fn new(sources: Vec<Box<dyn ClockSource>>, tolerance: Duration) -> Self {
+        ClockQuorum {
+            sources,
+            tolerance,
+            dissenters: Vec::new(),
+        }
+    }
+
+    /// Open the clock sources configured in `config`. `None` if none are configured, in which
+    /// case the system clock is used alone.
+    pub fn from_config(config: &dyn ServerConfig) -> Result<Option<Self>, Error> {
+        let specs = config.clock_sources();
+        if specs.is_empty() {
+            return Ok(None);
+        }
+
+        let sources = specs
+            .iter()
+            .map(ClockSpec::open)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Some(ClockQuorum::new(sources, config.clock_tolerance())))
+    }
+
+    /// Read every source. The largest group of sources within the tolerance of one of them
+    /// is a majority if it holds more than half of all sources (failed ones included).
+    pub fn read(&mut self) -> ClockReading {
+        let readings: Vec<Option<i128>> = self
+            .sources
+            .iter_mut()
+            .map(|source| match source.now() {
+                Ok(time) => Some(micros(time)),
+                Err(e) => {
+                    warn!("clock source {} failed: {:?}", source.name(), e);
+                    None
+                }
+            })
+            .collect();
+
+        let tolerance = self.tolerance.as_micros() as i128;
+        let mut largest: Vec<usize> = Vec::new();
+        for center in readings.iter().flatten() {
+            let near: Vec<usize> = readings
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| matches!(r, Some(r) if (r - center).abs() <= tolerance))
+                .map(|(idx, _)| idx)
+                .collect();
+
+            if near.len() > largest.len() {
+                largest = near;
+            }
+        }
+
+        let agreed = if largest.len() * 2 > readings.len() {
+            let mut times: Vec<i128> = largest.iter().filter_map(|&idx| readings[idx]).collect();
+            times.sort_unstable();
+            Some(from_micros(times[times.len() / 2]))
+        } else {
+            largest.clear();
+            None
+        };
+
+        let dissenters: Vec<usize> = (0..readings.len())
+            .filter(|idx| !largest.contains(idx))
+            .collect();
+
+        if dissenters != self.dissenters {
+            self.log_change(&readings, &dissenters, agreed.is_some());
+            self.dissenters = dissenters;
+        }
+
+        ClockReading {
+            agreed,
+            primary: readings[0].map(from_micros).unwrap_or_else(SystemTime::now),
+            dissenting: self.dissenters.len(),
+        }
+    }
+
+    fn log_change(&self, readings: &[Option<i128>], dissenters: &[usize], has_quorum: bool) {
+        if dissenters.is_empty() {
+            info!("all {} clock sources agree again", self.sources.len());
+            return;
+        }
+
+        let reference = readings.iter().find_map(|r| *r).unwrap_or_default();
+        let described: Vec<String> = self
+            .sources
+            .iter()
+            .zip(readings)
+            .map(|(source, reading)| match reading {
+                Some(r) => format!("{} {:+}ms", source.name(), (r - reference) / 1000),
+                None => format!("{} failed", source.name()),
+            })
+            .collect();
+
+        let outcome = match has_quorum {
+            true => "majority agrees",
+            false => "NO QUORUM",
+        };
+        error!(
+            "clock sources disagree by more than {:?} ({}): {}",
+            self.tolerance,
+            outcome,
+            described.join(", ")
+        );
+    }
+}
+
+// Microseconds since the epoch, negative before it
+fn micros(time: SystemTime) -> i128 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_micros() as i128,
+        Err(e) => -(e.duration().as_micros() as i128),
+    }
+}
+
+fn from_micros(micros: i128) -> SystemTime {
+    let magnitude = Duration::from_micros(micros.unsigned_abs() as u64);
+    match micros < 0 {
+        true => UNIX_EPOCH - magnitude,
+        false => UNIX_EPOCH + magnitude,
+    }
+}
+
+// Bit of CAP_SYS_TIME in the kernel's capability sets
+#[cfg(target_os = "linux")]
+const CAP_SYS_TIME: u32 = 25;
+
+///
+/// Whether this process may set the system clock: it has `CAP_SYS_TIME` in its effective
+/// capabilities on Linux, or runs as root on other Unix systems.
+///
+#[cfg(target_os = "linux")]
+pub fn can_set_system_time() -> bool {
+    let status = match std::fs::read_to_string("/proc/self/status") {
+        Ok(status) => status,
+        Err(_) => return false,
+    };
+
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .map(|caps| caps & (1 << CAP_SYS_TIME) != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn can_set_system_time() -> bool {
+    // SAFETY: geteuid() has no preconditions and can't fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn can_set_system_time() -> bool {
+    false
+}
+
+///
+/// Step the system's realtime clock to `time`. Requires the privilege checked by
+/// [`can_set_system_time`](fn.can_set_system_time.html).
+///
+#[cfg(unix)]
+pub fn set_system_time(time: SystemTime) -> Result<(), Error> {
+    let since = time
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::InvalidConfiguration("time is before the epoch".to_string()))?;
+
+    let ts = libc::timespec {
+        tv_sec: since.as_secs() as libc::time_t,
+        tv_nsec: since.subsec_nanos() as libc::c_long,
+    };
+
+    // SAFETY: `ts` is a valid timespec for the duration of the call
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &ts) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_system_time(_time: SystemTime) -> Result<(), Error> {
+    Err(Error::InvalidConfiguration(
+        "setting the system clock is only supported on Unix".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::clock::*;
+
+    // A clock that always reads the same time, or fails
+    struct FixedClock(Option<u64>);
+
+    impl ClockSource for FixedClock {
+        fn name(&self) -> String {
+            format!("fixed:{:?}", self.0)
+        }
+
+        fn now(&mut self) -> Result<SystemTime, Error> {
+            match self.0 {
+                Some(ms) => Ok(UNIX_EPOCH + Duration::from_millis(ms)),
+                None => Err(Error::Timeout("reading broken clock".to_string())),
+            }
+        }
+    }
+
+    fn quorum(readings: &[Option<u64>]) -> ClockQuorum {
+        let sources = readings
+            .iter()
+            .map(|&ms| Box::new(FixedClock(ms)) as Box<dyn ClockSource>)
+            .collect();
+        ClockQuorum::new(sources, Duration::from_millis(100))
+    }
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn parse_clock_specs() {
+        let specs = ClockSpec::parse_list("system, phc:/dev/ptp0@-37000,").unwrap();
+        assert_eq!(
+            specs,
+            vec![
+                ClockSpec {
+                    kind: ClockKind::System,
+                    offset_ms: 0
+                },
+                ClockSpec {
+                    kind: ClockKind::Phc("/dev/ptp0".to_string()),
+                    offset_ms: -37000
+                },
+            ]
+        );
+        assert_eq!(specs[1].to_string(), "phc:/dev/ptp0@-37000");
+        assert!(ClockSpec::parse_list("").unwrap().is_empty());
+
+        for bad in ["gps", "phc:", "system@soon", "system:x"] {
+            assert!(bad.parse::<ClockSpec>().is_err(), "accepted '{}'", bad);
+        }
+    }
+
+    #[test]
+    fn offsets_are_applied() {
+        let mut ahead = OffsetClock::new(Box::new(FixedClock(Some(5_000))), 1_500);
+        let mut behind = OffsetClock::new(Box::new(FixedClock(Some(5_000))), -1_500);
+
+        assert_eq!(ahead.now().unwrap(), at(6_500));
+        assert_eq!(behind.now().unwrap(), at(3_500));
+        assert_eq!(ahead.name(), "fixed:Some(5000)@1500");
+    }
+
+    #[test]
+    fn majority_sets_the_time() {
+        let reading = quorum(&[Some(1_000), Some(1_050), Some(1_020)]).read();
+        assert_eq!(reading.agreed, Some(at(1_020)));
+        assert_eq!(reading.dissenting, 0);
+
+        // one broken clock is outvoted, whether it's wrong or fails
+        let reading = quorum(&[Some(9_000), Some(1_000), Some(1_050)]).read();
+        assert_eq!(reading.agreed, Some(at(1_050)));
+        assert_eq!(reading.primary, at(9_000));
+        assert_eq!(reading.dissenting, 1);
+
+        let reading = quorum(&[None, Some(1_000), Some(1_050)]).read();
+        assert_eq!(reading.agreed, Some(at(1_050)));
+        assert_eq!(reading.dissenting, 1);
+    }
+
+    #[test]
+    fn no_majority_means_no_agreed_time() {
+        let reading = quorum(&[Some(1_000), Some(5_000)]).read();
+        assert_eq!(reading.agreed, None);
+        assert_eq!(reading.primary, at(1_000));
+        assert_eq!(reading.best(), at(1_000));
+        assert_eq!(reading.dissenting, 2);
+
+        let reading = quorum(&[Some(1_000), Some(5_000), None]).read();
+        assert_eq!(reading.agreed, None);
+
+        let reading = quorum(&[Some(1_000)]).read();
+        assert_eq!(reading, ClockReading::single(at(1_000)));
+    }
+}
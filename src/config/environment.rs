@@ -12,14 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::time::Duration;
 use std::{env, thread};
 
 use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
 
-use crate::config::ServerConfig;
-use crate::config::{DEFAULT_BATCH_SIZE, DEFAULT_STATUS_INTERVAL};
-use crate::key::KmsProtection;
+use crate::clock::ClockSpec;
+use crate::config::{ServerConfig, SettingSource};
+use crate::config::{DEFAULT_BATCH_SIZE, DEFAULT_CLOCK_TOLERANCE, DEFAULT_STATUS_INTERVAL};
+use crate::key::{decode_public_key, KmsProtection};
+use crate::leap::LeapInfo;
+use crate::responder::CompromisedMode;
 use crate::Error;
 
 const HEX: Encoding = HEXLOWER_PERMISSIVE;
@@ -28,18 +32,39 @@ const HEX: Encoding = HEXLOWER_PERMISSIVE;
 /// Obtain a Roughenough server configuration ([ServerConfig](trait.ServerConfig.html))
 /// from environment variables.
 ///
-///   Config parameter  | Environment Variable
-///   ----------------  | --------------------
-///   port              | `ROUGHENOUGH_PORT`
-///   interface         | `ROUGHENOUGH_INTERFACE`
-///   seed              | `ROUGHENOUGH_SEED`
-///   batch_size        | `ROUGHENOUGH_BATCH_SIZE`
-///   status_interval   | `ROUGHENOUGH_STATUS_INTERVAL`
-///   kms_protection    | `ROUGHENOUGH_KMS_PROTECTION`
-///   health_check_port | `ROUGHENOUGH_HEALTH_CHECK_PORT`
-///   client_stats      | `ROUGHENOUGH_CLIENT_STATS`
-///   fault_percentage  | `ROUGHENOUGH_FAULT_PERCENTAGE`
-///   num_workers       | `ROUGHENOUGH_NUM_WORKERS`
+///   Config parameter      | Environment Variable
+///   --------------------- | --------------------
+///   port                  | `ROUGHENOUGH_PORT`
+///   interface             | `ROUGHENOUGH_INTERFACE`
+///   seed                  | `ROUGHENOUGH_SEED`
+///   batch_size            | `ROUGHENOUGH_BATCH_SIZE`
+///   status_interval       | `ROUGHENOUGH_STATUS_INTERVAL`
+///   kms_protection        | `ROUGHENOUGH_KMS_PROTECTION`
+///   health_check_port     | `ROUGHENOUGH_HEALTH_CHECK_PORT`
+///   client_stats          | `ROUGHENOUGH_CLIENT_STATS`
+///   fault_percentage      | `ROUGHENOUGH_FAULT_PERCENTAGE`
+///   num_workers           | `ROUGHENOUGH_NUM_WORKERS`
+///   admin_port            | `ROUGHENOUGH_ADMIN_PORT`
+///   admin_token           | `ROUGHENOUGH_ADMIN_TOKEN`
+///   psk                   | `ROUGHENOUGH_PSK`
+///   pad_responses         | `ROUGHENOUGH_PAD_RESPONSES`
+///   shuffle_responses     | `ROUGHENOUGH_SHUFFLE_RESPONSES`
+///   strict_parsing        | `ROUGHENOUGH_STRICT_PARSING`
+///   online_key_validity   | `ROUGHENOUGH_ONLINE_KEY_VALIDITY`
+///   audit_log             | `ROUGHENOUGH_AUDIT_LOG`
+///   public_key            | `ROUGHENOUGH_PUBLIC_KEY`
+///   discovery_file        | `ROUGHENOUGH_DISCOVERY_FILE`
+///   compromised           | `ROUGHENOUGH_COMPROMISED`
+///   clock_sources         | `ROUGHENOUGH_CLOCK_SOURCES`
+///   clock_tolerance       | `ROUGHENOUGH_CLOCK_TOLERANCE`
+///   clock_quorum_required | `ROUGHENOUGH_CLOCK_QUORUM_REQUIRED`
+///   metrics_push_url      | `ROUGHENOUGH_METRICS_PUSH_URL`
+///   metrics_push_token    | `ROUGHENOUGH_METRICS_PUSH_TOKEN`
+///   leap_info             | `ROUGHENOUGH_LEAP_INFO`
+///
+/// Variables can also override individual settings of another configuration (see
+/// [`overriding`](#method.overriding)), so environment variables take precedence over a
+/// config file, which takes precedence over the defaults.
 ///
 pub struct EnvironmentConfig {
     port: u16,
@@ -52,6 +77,24 @@ pub struct EnvironmentConfig {
     client_stats: bool,
     fault_percentage: u8,
     num_workers: usize,
+    admin_port: Option<u16>,
+    admin_token: Option<String>,
+    psk: Option<Vec<u8>>,
+    pad_responses: bool,
+    shuffle_responses: bool,
+    strict_parsing: bool,
+    online_key_validity: Option<Duration>,
+    audit_log: Option<String>,
+    public_key: Option<Vec<u8>>,
+    discovery_file: Option<String>,
+    compromised: Option<CompromisedMode>,
+    clock_sources: Vec<ClockSpec>,
+    clock_tolerance: Duration,
+    clock_quorum_required: bool,
+    metrics_push_url: Option<String>,
+    metrics_push_token: Option<String>,
+    leap_info: Option<LeapInfo>,
+    sources: HashMap<&'static str, SettingSource>,
 }
 
 const ROUGHENOUGH_PORT: &str = "ROUGHENOUGH_PORT";
@@ -63,10 +106,71 @@ const ROUGHENOUGH_KMS_PROTECTION: &str = "ROUGHENOUGH_KMS_PROTECTION";
 const ROUGHENOUGH_HEALTH_CHECK_PORT: &str = "ROUGHENOUGH_HEALTH_CHECK_PORT";
 const ROUGHENOUGH_CLIENT_STATS: &str = "ROUGHENOUGH_CLIENT_STATS";
 const ROUGHENOUGH_FAULT_PERCENTAGE: &str = "ROUGHENOUGH_FAULT_PERCENTAGE";
-const ROUGHENOUGH_NUM_WORKERS: &str = "ROUGHENOUGH_NUM_WORKERS:";
+const ROUGHENOUGH_NUM_WORKERS: &str = "ROUGHENOUGH_NUM_WORKERS";
+const ROUGHENOUGH_ADMIN_PORT: &str = "ROUGHENOUGH_ADMIN_PORT";
+const ROUGHENOUGH_ADMIN_TOKEN: &str = "ROUGHENOUGH_ADMIN_TOKEN";
+const ROUGHENOUGH_PSK: &str = "ROUGHENOUGH_PSK";
+const ROUGHENOUGH_PAD_RESPONSES: &str = "ROUGHENOUGH_PAD_RESPONSES";
+const ROUGHENOUGH_SHUFFLE_RESPONSES: &str = "ROUGHENOUGH_SHUFFLE_RESPONSES";
+const ROUGHENOUGH_STRICT_PARSING: &str = "ROUGHENOUGH_STRICT_PARSING";
+const ROUGHENOUGH_ONLINE_KEY_VALIDITY: &str = "ROUGHENOUGH_ONLINE_KEY_VALIDITY";
+const ROUGHENOUGH_AUDIT_LOG: &str = "ROUGHENOUGH_AUDIT_LOG";
+const ROUGHENOUGH_PUBLIC_KEY: &str = "ROUGHENOUGH_PUBLIC_KEY";
+const ROUGHENOUGH_DISCOVERY_FILE: &str = "ROUGHENOUGH_DISCOVERY_FILE";
+const ROUGHENOUGH_COMPROMISED: &str = "ROUGHENOUGH_COMPROMISED";
+const ROUGHENOUGH_CLOCK_SOURCES: &str = "ROUGHENOUGH_CLOCK_SOURCES";
+const ROUGHENOUGH_CLOCK_TOLERANCE: &str = "ROUGHENOUGH_CLOCK_TOLERANCE";
+const ROUGHENOUGH_CLOCK_QUORUM_REQUIRED: &str = "ROUGHENOUGH_CLOCK_QUORUM_REQUIRED";
+const ROUGHENOUGH_METRICS_PUSH_URL: &str = "ROUGHENOUGH_METRICS_PUSH_URL";
+const ROUGHENOUGH_METRICS_PUSH_TOKEN: &str = "ROUGHENOUGH_METRICS_PUSH_TOKEN";
+const ROUGHENOUGH_LEAP_INFO: &str = "ROUGHENOUGH_LEAP_INFO";
+
+// YAML key of each setting and the environment variable that sets it
+const SETTING_VARS: &[(&str, &str)] = &[
+    ("port", ROUGHENOUGH_PORT),
+    ("interface", ROUGHENOUGH_INTERFACE),
+    ("seed", ROUGHENOUGH_SEED),
+    ("batch_size", ROUGHENOUGH_BATCH_SIZE),
+    ("status_interval", ROUGHENOUGH_STATUS_INTERVAL),
+    ("kms_protection", ROUGHENOUGH_KMS_PROTECTION),
+    ("health_check_port", ROUGHENOUGH_HEALTH_CHECK_PORT),
+    ("client_stats", ROUGHENOUGH_CLIENT_STATS),
+    ("fault_percentage", ROUGHENOUGH_FAULT_PERCENTAGE),
+    ("num_workers", ROUGHENOUGH_NUM_WORKERS),
+    ("admin_port", ROUGHENOUGH_ADMIN_PORT),
+    ("admin_token", ROUGHENOUGH_ADMIN_TOKEN),
+    ("psk", ROUGHENOUGH_PSK),
+    ("pad_responses", ROUGHENOUGH_PAD_RESPONSES),
+    ("shuffle_responses", ROUGHENOUGH_SHUFFLE_RESPONSES),
+    ("strict_parsing", ROUGHENOUGH_STRICT_PARSING),
+    ("online_key_validity", ROUGHENOUGH_ONLINE_KEY_VALIDITY),
+    ("audit_log", ROUGHENOUGH_AUDIT_LOG),
+    ("public_key", ROUGHENOUGH_PUBLIC_KEY),
+    ("discovery_file", ROUGHENOUGH_DISCOVERY_FILE),
+    ("compromised", ROUGHENOUGH_COMPROMISED),
+    ("clock_sources", ROUGHENOUGH_CLOCK_SOURCES),
+    ("clock_tolerance", ROUGHENOUGH_CLOCK_TOLERANCE),
+    ("clock_quorum_required", ROUGHENOUGH_CLOCK_QUORUM_REQUIRED),
+    ("metrics_push_url", ROUGHENOUGH_METRICS_PUSH_URL),
+    ("metrics_push_token", ROUGHENOUGH_METRICS_PUSH_TOKEN),
+    ("leap_info", ROUGHENOUGH_LEAP_INFO),
+];
 
 impl EnvironmentConfig {
+    /// Configuration from the environment alone, unset variables take their default values
     pub fn new() -> Result<Self, Error> {
+        Self::from_vars(None, |name| env::var(name).ok())
+    }
+
+    /// The settings of `base`, overridden by any environment variables that are set
+    pub fn overriding(base: &dyn ServerConfig) -> Result<Self, Error> {
+        Self::from_vars(Some(base), |name| env::var(name).ok())
+    }
+
+    fn from_vars(
+        base: Option<&dyn ServerConfig>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Error> {
         let mut cfg = EnvironmentConfig {
             port: 0,
             interface: "".to_string(),
@@ -78,31 +182,88 @@ impl EnvironmentConfig {
             client_stats: false,
             fault_percentage: 0,
             num_workers: thread::available_parallelism().unwrap().get(),
+            admin_port: None,
+            admin_token: None,
+            psk: None,
+            pad_responses: false,
+            shuffle_responses: false,
+            strict_parsing: false,
+            online_key_validity: None,
+            audit_log: None,
+            public_key: None,
+            discovery_file: None,
+            compromised: None,
+            clock_sources: Vec::new(),
+            clock_tolerance: DEFAULT_CLOCK_TOLERANCE,
+            clock_quorum_required: false,
+            metrics_push_url: None,
+            metrics_push_token: None,
+            leap_info: None,
+            sources: HashMap::new(),
         };
 
-        if let Ok(port) = env::var(ROUGHENOUGH_PORT) {
+        if let Some(base) = base {
+            cfg.port = base.port();
+            cfg.interface = base.interface().to_string();
+            cfg.seed = base.seed();
+            cfg.batch_size = base.batch_size();
+            cfg.status_interval = base.status_interval();
+            cfg.kms_protection = base.kms_protection().clone();
+            cfg.health_check_port = base.health_check_port();
+            cfg.client_stats = base.client_stats_enabled();
+            cfg.fault_percentage = base.fault_percentage();
+            cfg.num_workers = base.num_workers();
+            cfg.admin_port = base.admin_port();
+            cfg.admin_token = base.admin_token().map(str::to_string);
+            cfg.psk = base.psk().map(<[u8]>::to_vec);
+            cfg.pad_responses = base.pad_responses_enabled();
+            cfg.shuffle_responses = base.shuffle_responses_enabled();
+            cfg.strict_parsing = base.strict_parsing_enabled();
+            cfg.online_key_validity = base.online_key_validity();
+            cfg.audit_log = base.audit_log();
+            cfg.public_key = base.public_key().map(<[u8]>::to_vec);
+            cfg.discovery_file = base.discovery_file();
+            cfg.compromised = base.compromised();
+            cfg.clock_sources = base.clock_sources();
+            cfg.clock_tolerance = base.clock_tolerance();
+            cfg.clock_quorum_required = base.clock_quorum_required();
+            cfg.metrics_push_url = base.metrics_push_url().map(str::to_string);
+            cfg.metrics_push_token = base.metrics_push_token().map(str::to_string);
+            cfg.leap_info = base.leap_info();
+        }
+
+        for (name, env_var) in SETTING_VARS {
+            let source = match (var(env_var), base) {
+                (Some(_), _) => SettingSource::Environment,
+                (None, Some(base)) => base.setting_source(name),
+                (None, None) => SettingSource::Default,
+            };
+            cfg.sources.insert(name, source);
+        }
+
+        if let Some(port) = var(ROUGHENOUGH_PORT) {
             cfg.port = port
                 .parse()
                 .unwrap_or_else(|_| panic!("invalid port: {}", port));
         };
 
-        if let Ok(interface) = env::var(ROUGHENOUGH_INTERFACE) {
+        if let Some(interface) = var(ROUGHENOUGH_INTERFACE) {
             cfg.interface = interface.to_string();
         };
 
-        if let Ok(seed) = env::var(ROUGHENOUGH_SEED) {
+        if let Some(seed) = var(ROUGHENOUGH_SEED) {
             cfg.seed = HEX
                 .decode(seed.as_bytes())
                 .expect("invalid seed value; 'seed' should be a hex value");
         };
 
-        if let Ok(batch_size) = env::var(ROUGHENOUGH_BATCH_SIZE) {
+        if let Some(batch_size) = var(ROUGHENOUGH_BATCH_SIZE) {
             cfg.batch_size = batch_size
                 .parse()
                 .unwrap_or_else(|_| panic!("invalid batch_size: {}", batch_size));
         };
 
-        if let Ok(status_interval) = env::var(ROUGHENOUGH_STATUS_INTERVAL) {
+        if let Some(status_interval) = var(ROUGHENOUGH_STATUS_INTERVAL) {
             let val: u16 = status_interval
                 .parse()
                 .unwrap_or_else(|_| panic!("invalid status_interval: {}", status_interval));
@@ -110,13 +271,13 @@ impl EnvironmentConfig {
             cfg.status_interval = Duration::from_secs(u64::from(val));
         };
 
-        if let Ok(kms_protection) = env::var(ROUGHENOUGH_KMS_PROTECTION) {
+        if let Some(kms_protection) = var(ROUGHENOUGH_KMS_PROTECTION) {
             cfg.kms_protection = kms_protection
                 .parse()
                 .unwrap_or_else(|_| panic!("invalid kms_protection value: {}", kms_protection));
         }
 
-        if let Ok(health_check_port) = env::var(ROUGHENOUGH_HEALTH_CHECK_PORT) {
+        if let Some(health_check_port) = var(ROUGHENOUGH_HEALTH_CHECK_PORT) {
             let val: u16 = health_check_port
                 .parse()
                 .unwrap_or_else(|_| panic!("invalid health_check_port: {}", health_check_port));
@@ -124,24 +285,121 @@ impl EnvironmentConfig {
             cfg.health_check_port = Some(val);
         };
 
-        if let Ok(mut client_stats) = env::var(ROUGHENOUGH_CLIENT_STATS) {
+        if let Some(mut client_stats) = var(ROUGHENOUGH_CLIENT_STATS) {
             client_stats.make_ascii_lowercase();
 
             cfg.client_stats = client_stats == "yes" || client_stats == "on";
         }
 
-        if let Ok(fault_percentage) = env::var(ROUGHENOUGH_FAULT_PERCENTAGE) {
+        if let Some(fault_percentage) = var(ROUGHENOUGH_FAULT_PERCENTAGE) {
             cfg.fault_percentage = fault_percentage
                 .parse()
                 .unwrap_or_else(|_| panic!("invalid fault_percentage: {}", fault_percentage));
         };
 
-        if let Ok(num_workers) = env::var(ROUGHENOUGH_NUM_WORKERS) {
+        if let Some(num_workers) = var(ROUGHENOUGH_NUM_WORKERS) {
             cfg.num_workers = num_workers
                 .parse()
                 .unwrap_or_else(|_| panic!("invalid num_workers: {}", num_workers));
         };
 
+        if let Some(admin_port) = var(ROUGHENOUGH_ADMIN_PORT) {
+            let val: u16 = admin_port
+                .parse()
+                .unwrap_or_else(|_| panic!("invalid admin_port: {}", admin_port));
+
+            cfg.admin_port = Some(val);
+        };
+
+        if let Some(admin_token) = var(ROUGHENOUGH_ADMIN_TOKEN) {
+            cfg.admin_token = Some(admin_token);
+        };
+
+        if let Some(psk) = var(ROUGHENOUGH_PSK) {
+            let val = HEX
+                .decode(psk.as_bytes())
+                .expect("invalid psk value; 'psk' should be a hex value");
+
+            cfg.psk = Some(val);
+        };
+
+        if let Some(mut pad_responses) = var(ROUGHENOUGH_PAD_RESPONSES) {
+            pad_responses.make_ascii_lowercase();
+
+            cfg.pad_responses = pad_responses == "yes" || pad_responses == "on";
+        }
+
+        if let Some(mut shuffle_responses) = var(ROUGHENOUGH_SHUFFLE_RESPONSES) {
+            shuffle_responses.make_ascii_lowercase();
+
+            cfg.shuffle_responses = shuffle_responses == "yes" || shuffle_responses == "on";
+        }
+
+        if let Some(mut strict_parsing) = var(ROUGHENOUGH_STRICT_PARSING) {
+            strict_parsing.make_ascii_lowercase();
+
+            cfg.strict_parsing = strict_parsing == "yes" || strict_parsing == "on";
+        }
+
+        if let Some(validity) = var(ROUGHENOUGH_ONLINE_KEY_VALIDITY) {
+            let val: u64 = validity
+                .parse()
+                .unwrap_or_else(|_| panic!("invalid online_key_validity: {}", validity));
+
+            cfg.online_key_validity = Some(Duration::from_secs(val));
+        };
+
+        if let Some(audit_log) = var(ROUGHENOUGH_AUDIT_LOG) {
+            cfg.audit_log = Some(audit_log);
+        };
+
+        if let Some(public_key) = var(ROUGHENOUGH_PUBLIC_KEY) {
+            cfg.public_key = Some(decode_public_key(&public_key)?);
+        };
+
+        if let Some(discovery_file) = var(ROUGHENOUGH_DISCOVERY_FILE) {
+            cfg.discovery_file = Some(discovery_file);
+        };
+
+        if let Some(compromised) = var(ROUGHENOUGH_COMPROMISED) {
+            cfg.compromised = Some(
+                compromised
+                    .parse()
+                    .unwrap_or_else(|_| panic!("invalid compromised value: {}", compromised)),
+            );
+        };
+
+        if let Some(clock_sources) = var(ROUGHENOUGH_CLOCK_SOURCES) {
+            cfg.clock_sources = ClockSpec::parse_list(&clock_sources)?;
+        };
+
+        if let Some(tolerance) = var(ROUGHENOUGH_CLOCK_TOLERANCE) {
+            let val: u64 = tolerance
+                .parse()
+                .unwrap_or_else(|_| panic!("invalid clock_tolerance: {}", tolerance));
+
+            cfg.clock_tolerance = Duration::from_millis(val);
+        };
+
+        if let Some(mut quorum_required) = var(ROUGHENOUGH_CLOCK_QUORUM_REQUIRED) {
+            quorum_required.make_ascii_lowercase();
+
+            cfg.clock_quorum_required = quorum_required == "yes" || quorum_required == "on";
+        }
+
+        if let Some(push_url) = var(ROUGHENOUGH_METRICS_PUSH_URL) {
+            cfg.metrics_push_url = Some(push_url);
+        }
+
+        if let Some(push_token) = var(ROUGHENOUGH_METRICS_PUSH_TOKEN) {
+            cfg.metrics_push_token = Some(push_token);
+        }
+
+        if let Some(leap_info) = var(ROUGHENOUGH_LEAP_INFO) {
+            let val: LeapInfo = leap_info.parse()?;
+            cfg.leap_info = Some(val).filter(|info| !info.is_empty());
+        }
+
         Ok(cfg)
     }
 }
@@ -186,4 +444,156 @@ impl ServerConfig for EnvironmentConfig {
     fn num_workers(&self) -> usize {
         self.num_workers
     }
+
+    fn admin_port(&self) -> Option<u16> {
+        self.admin_port
+    }
+
+    fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+
+    fn psk(&self) -> Option<&[u8]> {
+        self.psk.as_deref()
+    }
+
+    fn pad_responses_enabled(&self) -> bool {
+        self.pad_responses
+    }
+
+    fn shuffle_responses_enabled(&self) -> bool {
+        self.shuffle_responses
+    }
+
+    fn strict_parsing_enabled(&self) -> bool {
+        self.strict_parsing
+    }
+
+    fn online_key_validity(&self) -> Option<Duration> {
+        self.online_key_validity
+    }
+
+    fn audit_log(&self) -> Option<String> {
+        self.audit_log.clone()
+    }
+
+    fn public_key(&self) -> Option<&[u8]> {
+        self.public_key.as_deref()
+    }
+
+    fn discovery_file(&self) -> Option<String> {
+        self.discovery_file.clone()
+    }
+
+    fn compromised(&self) -> Option<CompromisedMode> {
+        self.compromised
+    }
+
+    fn clock_sources(&self) -> Vec<ClockSpec> {
+        self.clock_sources.clone()
+    }
+
+    fn clock_tolerance(&self) -> Duration {
+        self.clock_tolerance
+    }
+
+    fn clock_quorum_required(&self) -> bool {
+        self.clock_quorum_required
+    }
+
+    fn metrics_push_url(&self) -> Option<&str> {
+        self.metrics_push_url.as_deref()
+    }
+
+    fn metrics_push_token(&self) -> Option<&str> {
+        self.metrics_push_token.as_deref()
+    }
+
+    fn leap_info(&self) -> Option<LeapInfo> {
+        self.leap_info.clone()
+    }
+
+    fn setting_source(&self, key: &str) -> SettingSource {
+        self.sources
+            .get(key)
+            .copied()
+            .unwrap_or(SettingSource::Default)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use crate::config::environment::SETTING_VARS;
+    use crate::config::{
+        effective_settings_json, EnvironmentConfig, FileConfig, MemoryConfig, ServerConfig,
+        SettingSource, DEFAULT_BATCH_SIZE,
+    };
+
+    fn from_vars(base: Option<&dyn ServerConfig>, vars: &[(&str, &str)]) -> EnvironmentConfig {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        EnvironmentConfig::from_vars(base, |name| vars.get(name).cloned()).unwrap()
+    }
+
+    #[test]
+    fn every_setting_has_a_variable() {
+        for (name, var) in SETTING_VARS {
+            assert_eq!(*var, format!("ROUGHENOUGH_{}", name.to_ascii_uppercase()));
+        }
+
+        let json = effective_settings_json(&MemoryConfig::new(0));
+        assert_eq!(json.matches(r#""source":"#).count(), SETTING_VARS.len());
+        for (name, _) in SETTING_VARS {
+            assert!(
+                json.contains(&format!(r#""{}":{{"value""#, name)),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn variables_take_precedence_over_file_and_defaults() {
+        let path = std::env::temp_dir().join("roughenough-env-precedence.yaml");
+        fs::write(
+            &path,
+            "interface: 127.0.0.1\nport: 8686\nnum_workers: 4\nseed: a32049da0ffde0ded92ce10a0230d35fe615ec8461c14986baa63fe3b3bac3db\n",
+        )
+        .unwrap();
+        let file = FileConfig::new(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let cfg = from_vars(
+            Some(&file),
+            &[
+                ("ROUGHENOUGH_PORT", "8687"),
+                ("ROUGHENOUGH_NUM_WORKERS", "2"),
+            ],
+        );
+
+        assert_eq!(cfg.port(), 8687);
+        assert_eq!(cfg.setting_source("port"), SettingSource::Environment);
+        assert_eq!(cfg.num_workers(), 2);
+        assert_eq!(
+            cfg.setting_source("num_workers"),
+            SettingSource::Environment
+        );
+        assert_eq!(cfg.interface(), "127.0.0.1");
+        assert_eq!(cfg.setting_source("interface"), SettingSource::File);
+        assert_eq!(cfg.seed(), file.seed());
+        assert_eq!(cfg.batch_size(), DEFAULT_BATCH_SIZE);
+        assert_eq!(cfg.setting_source("batch_size"), SettingSource::Default);
+
+        // without a file, unset variables leave the defaults
+        let cfg = from_vars(None, &[("ROUGHENOUGH_PORT", "8687")]);
+        assert_eq!(cfg.port(), 8687);
+        assert_eq!(cfg.setting_source("port"), SettingSource::Environment);
+        assert_eq!(cfg.interface(), "");
+        assert_eq!(cfg.setting_source("interface"), SettingSource::Default);
+    }
 }
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Read;
 use std::thread;
@@ -20,9 +21,12 @@ use std::time::Duration;
 use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
 use yaml_rust::YamlLoader;
 
-use crate::config::ServerConfig;
-use crate::config::{DEFAULT_BATCH_SIZE, DEFAULT_STATUS_INTERVAL};
-use crate::key::KmsProtection;
+use crate::clock::ClockSpec;
+use crate::config::{ServerConfig, SettingSource};
+use crate::config::{DEFAULT_BATCH_SIZE, DEFAULT_CLOCK_TOLERANCE, DEFAULT_STATUS_INTERVAL};
+use crate::key::{decode_public_key, KmsProtection};
+use crate::leap::LeapInfo;
+use crate::responder::CompromisedMode;
 use crate::Error;
 
 const HEX: Encoding = HEXLOWER_PERMISSIVE;
@@ -50,6 +54,24 @@ pub struct FileConfig {
     client_stats: bool,
     fault_percentage: u8,
     num_workers: usize,
+    admin_port: Option<u16>,
+    admin_token: Option<String>,
+    psk: Option<Vec<u8>>,
+    pad_responses: bool,
+    shuffle_responses: bool,
+    strict_parsing: bool,
+    online_key_validity: Option<Duration>,
+    audit_log: Option<String>,
+    public_key: Option<Vec<u8>>,
+    discovery_file: Option<String>,
+    compromised: Option<CompromisedMode>,
+    clock_sources: Vec<ClockSpec>,
+    clock_tolerance: Duration,
+    clock_quorum_required: bool,
+    metrics_push_url: Option<String>,
+    metrics_push_token: Option<String>,
+    leap_info: Option<LeapInfo>,
+    keys_set: HashSet<String>,
 }
 
 impl FileConfig {
@@ -83,9 +105,31 @@ impl FileConfig {
             client_stats: false,
             fault_percentage: 0,
             num_workers: thread::available_parallelism().unwrap().get(),
+            admin_port: None,
+            admin_token: None,
+            psk: None,
+            pad_responses: false,
+            shuffle_responses: false,
+            strict_parsing: false,
+            online_key_validity: None,
+            audit_log: None,
+            public_key: None,
+            discovery_file: None,
+            compromised: None,
+            clock_sources: Vec::new(),
+            clock_tolerance: DEFAULT_CLOCK_TOLERANCE,
+            clock_quorum_required: false,
+            metrics_push_url: None,
+            metrics_push_token: None,
+            leap_info: None,
+            keys_set: HashSet::new(),
         };
 
         for (key, value) in cfg[0].as_hash().unwrap() {
+            if let Some(key) = key.as_str() {
+                config.keys_set.insert(key.to_string());
+            }
+
             match key.as_str().unwrap() {
                 "port" => config.port = value.as_i64().unwrap() as u16,
                 "interface" => config.interface = value.as_str().unwrap().to_string(),
@@ -123,6 +167,80 @@ impl FileConfig {
                     let val = value.as_i64().unwrap() as usize;
                     config.num_workers = val;
                 }
+                "admin_port" => {
+                    let val = value.as_i64().unwrap() as u16;
+                    config.admin_port = Some(val);
+                }
+                "psk" => {
+                    let val = value.as_str().unwrap().to_string();
+                    let psk = HEX
+                        .decode(val.as_bytes())
+                        .expect("psk value invalid; 'psk' must be a valid hex value");
+                    config.psk = Some(psk);
+                }
+                "pad_responses" => {
+                    let val = value.as_str().unwrap().to_ascii_lowercase();
+                    config.pad_responses = val == "yes" || val == "on";
+                }
+                "shuffle_responses" => {
+                    let val = value.as_str().unwrap().to_ascii_lowercase();
+                    config.shuffle_responses = val == "yes" || val == "on";
+                }
+                "strict_parsing" => {
+                    let val = value.as_str().unwrap().to_ascii_lowercase();
+                    config.strict_parsing = val == "yes" || val == "on";
+                }
+                "admin_token" => {
+                    let val = value.as_str().unwrap().to_string();
+                    config.admin_token = Some(val);
+                }
+                "online_key_validity" => {
+                    let val = value.as_i64().expect("online_key_validity value invalid");
+                    config.online_key_validity = Some(Duration::from_secs(val as u64));
+                }
+                "audit_log" => {
+                    let val = value.as_str().unwrap().to_string();
+                    config.audit_log = Some(val);
+                }
+                "public_key" => {
+                    let val = decode_public_key(value.as_str().unwrap())?;
+                    config.public_key = Some(val);
+                }
+                "discovery_file" => {
+                    let val = value.as_str().unwrap().to_string();
+                    config.discovery_file = Some(val);
+                }
+                "compromised" => {
+                    let val = value
+                        .as_str()
+                        .unwrap()
+                        .parse()
+                        .unwrap_or_else(|_| panic!("invalid compromised value: {:?}", value));
+                    config.compromised = Some(val);
+                }
+                "clock_sources" => {
+                    config.clock_sources = ClockSpec::parse_list(value.as_str().unwrap())?;
+                }
+                "clock_tolerance" => {
+                    let val = value.as_i64().expect("clock_tolerance value invalid");
+                    config.clock_tolerance = Duration::from_millis(val as u64);
+                }
+                "clock_quorum_required" => {
+                    let val = value.as_str().unwrap().to_ascii_lowercase();
+                    config.clock_quorum_required = val == "yes" || val == "on";
+                }
+                "metrics_push_url" => {
+                    let val = value.as_str().unwrap().to_string();
+                    config.metrics_push_url = Some(val);
+                }
+                "metrics_push_token" => {
+                    let val = value.as_str().unwrap().to_string();
+                    config.metrics_push_token = Some(val);
+                }
+                "leap_info" => {
+                    let val: LeapInfo = value.as_str().unwrap().parse()?;
+                    config.leap_info = Some(val).filter(|info| !info.is_empty());
+                }
                 unknown => {
                     return Err(Error::InvalidConfiguration(format!(
                         "unknown config key: {}",
@@ -176,4 +294,80 @@ impl ServerConfig for FileConfig {
     fn num_workers(&self) -> usize {
         self.num_workers
     }
+
+    fn admin_port(&self) -> Option<u16> {
+        self.admin_port
+    }
+
+    fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+
+    fn psk(&self) -> Option<&[u8]> {
+        self.psk.as_deref()
+    }
+
+    fn pad_responses_enabled(&self) -> bool {
+        self.pad_responses
+    }
+
+    fn shuffle_responses_enabled(&self) -> bool {
+        self.shuffle_responses
+    }
+
+    fn strict_parsing_enabled(&self) -> bool {
+        self.strict_parsing
+    }
+
+    fn online_key_validity(&self) -> Option<Duration> {
+        self.online_key_validity
+    }
+
+    fn audit_log(&self) -> Option<String> {
+        self.audit_log.clone()
+    }
+
+    fn public_key(&self) -> Option<&[u8]> {
+        self.public_key.as_deref()
+    }
+
+    fn discovery_file(&self) -> Option<String> {
+        self.discovery_file.clone()
+    }
+
+    fn compromised(&self) -> Option<CompromisedMode> {
+        self.compromised
+    }
+
+    fn clock_sources(&self) -> Vec<ClockSpec> {
+        self.clock_sources.clone()
+    }
+
+    fn clock_tolerance(&self) -> Duration {
+        self.clock_tolerance
+    }
+
+    fn clock_quorum_required(&self) -> bool {
+        self.clock_quorum_required
+    }
+
+    fn metrics_push_url(&self) -> Option<&str> {
+        self.metrics_push_url.as_deref()
+    }
+
+    fn metrics_push_token(&self) -> Option<&str> {
+        self.metrics_push_token.as_deref()
+    }
+
+    fn leap_info(&self) -> Option<LeapInfo> {
+        self.leap_info.clone()
+    }
+
+    fn setting_source(&self, key: &str) -> SettingSource {
+        if self.keys_set.contains(key) {
+            SettingSource::File
+        } else {
+            SettingSource::Default
+        }
+    }
 }
@@ -17,9 +17,12 @@ use std::time::Duration;
 
 use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
 
+use crate::clock::ClockSpec;
 use crate::config::ServerConfig;
-use crate::config::{DEFAULT_BATCH_SIZE, DEFAULT_STATUS_INTERVAL};
+use crate::config::{DEFAULT_BATCH_SIZE, DEFAULT_CLOCK_TOLERANCE, DEFAULT_STATUS_INTERVAL};
 use crate::key::KmsProtection;
+use crate::leap::LeapInfo;
+use crate::responder::CompromisedMode;
 
 const HEX: Encoding = HEXLOWER_PERMISSIVE;
 
@@ -37,6 +40,23 @@ pub struct MemoryConfig {
     pub client_stats: bool,
     pub fault_percentage: u8,
     pub num_workers: usize,
+    pub admin_port: Option<u16>,
+    pub admin_token: Option<String>,
+    pub psk: Option<Vec<u8>>,
+    pub pad_responses: bool,
+    pub shuffle_responses: bool,
+    pub strict_parsing: bool,
+    pub online_key_validity: Option<Duration>,
+    pub audit_log: Option<String>,
+    pub public_key: Option<Vec<u8>>,
+    pub discovery_file: Option<String>,
+    pub compromised: Option<CompromisedMode>,
+    pub clock_sources: Vec<ClockSpec>,
+    pub clock_tolerance: Duration,
+    pub clock_quorum_required: bool,
+    pub metrics_push_url: Option<String>,
+    pub metrics_push_token: Option<String>,
+    pub leap_info: Option<LeapInfo>,
 }
 
 impl MemoryConfig {
@@ -53,6 +73,23 @@ impl MemoryConfig {
             client_stats: false,
             fault_percentage: 0,
             num_workers: thread::available_parallelism().unwrap().get(),
+            admin_port: None,
+            admin_token: None,
+            psk: None,
+            pad_responses: false,
+            shuffle_responses: false,
+            strict_parsing: false,
+            online_key_validity: None,
+            audit_log: None,
+            public_key: None,
+            discovery_file: None,
+            compromised: None,
+            clock_sources: Vec::new(),
+            clock_tolerance: DEFAULT_CLOCK_TOLERANCE,
+            clock_quorum_required: false,
+            metrics_push_url: None,
+            metrics_push_token: None,
+            leap_info: None,
         }
     }
 }
@@ -97,4 +134,72 @@ impl ServerConfig for MemoryConfig {
     fn num_workers(&self) -> usize {
         self.num_workers
     }
+
+    fn admin_port(&self) -> Option<u16> {
+        self.admin_port
+    }
+
+    fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+
+    fn psk(&self) -> Option<&[u8]> {
+        self.psk.as_deref()
+    }
+
+    fn pad_responses_enabled(&self) -> bool {
+        self.pad_responses
+    }
+
+    fn shuffle_responses_enabled(&self) -> bool {
+        self.shuffle_responses
+    }
+
+    fn strict_parsing_enabled(&self) -> bool {
+        self.strict_parsing
+    }
+
+    fn online_key_validity(&self) -> Option<Duration> {
+        self.online_key_validity
+    }
+
+    fn audit_log(&self) -> Option<String> {
+        self.audit_log.clone()
+    }
+
+    fn public_key(&self) -> Option<&[u8]> {
+        self.public_key.as_deref()
+    }
+
+    fn discovery_file(&self) -> Option<String> {
+        self.discovery_file.clone()
+    }
+
+    fn compromised(&self) -> Option<CompromisedMode> {
+        self.compromised
+    }
+
+    fn clock_sources(&self) -> Vec<ClockSpec> {
+        self.clock_sources.clone()
+    }
+
+    fn clock_tolerance(&self) -> Duration {
+        self.clock_tolerance
+    }
+
+    fn clock_quorum_required(&self) -> bool {
+        self.clock_quorum_required
+    }
+
+    fn metrics_push_url(&self) -> Option<&str> {
+        self.metrics_push_url.as_deref()
+    }
+
+    fn metrics_push_token(&self) -> Option<&str> {
+        self.metrics_push_token.as_deref()
+    }
+
+    fn leap_info(&self) -> Option<LeapInfo> {
+        self.leap_info.clone()
+    }
 }
@@ -25,9 +25,15 @@
 use std::net::SocketAddr;
 use std::time::Duration;
 
+use data_encoding::HEXLOWER;
+
+use crate::clock::{ClockQuorum, ClockSpec};
+use crate::http::HttpUrl;
 use crate::key::KmsProtection;
+use crate::leap::LeapInfo;
+use crate::responder::CompromisedMode;
 use crate::Error;
-use crate::SEED_LENGTH;
+use crate::{MAX_BATCH_SIZE, PUBLIC_KEY_LENGTH, SEED_LENGTH};
 
 pub use self::environment::EnvironmentConfig;
 pub use self::file::FileConfig;
@@ -43,6 +49,39 @@ pub const DEFAULT_BATCH_SIZE: u8 = 64;
 /// Amount of time between each logged status update.
 pub const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(600);
 
+/// Shortest accepted pre-shared key (in bytes) for request authentication.
+pub const MIN_PSK_LENGTH: usize = 16;
+
+/// Shortest accepted admin endpoint action token.
+pub const MIN_ADMIN_TOKEN_LENGTH: usize = 16;
+
+/// Shortest accepted online key delegation window.
+pub const MIN_ONLINE_KEY_VALIDITY: Duration = Duration::from_secs(60);
+
+/// Largest difference between clock sources that still counts as agreement.
+pub const DEFAULT_CLOCK_TOLERANCE: Duration = Duration::from_millis(100);
+
+/// Where the effective value of a setting came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingSource {
+    /// Set in the YAML config file
+    File,
+    /// Set by an environment variable
+    Environment,
+    /// Not set, the default value is in effect
+    Default,
+}
+
+impl SettingSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SettingSource::File => "file",
+            SettingSource::Environment => "env",
+            SettingSource::Default => "default",
+        }
+    }
+}
+
 ///
 /// Specifies parameters needed to configure a Roughenough server.
 ///
@@ -52,7 +91,7 @@ pub const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(600);
 /// YAML Key | Environment Variable | Necessity | Description
 /// --- | --- | --- | ---
 /// `interface` | `ROUGHENOUGH_INTERFACE` | Required | IP address or interface name for listening to client requests
-/// `port` | `ROUGHENOUGH_PORT` | Required | UDP port to listen for requests
+/// `port` | `ROUGHENOUGH_PORT` | Required | UDP port to listen for requests. `0` binds an ephemeral port chosen by the OS, see `discovery_file`.
 /// `seed` | `ROUGHENOUGH_SEED` | Required | A 32-byte hexadecimal value used to generate the server's long-term key pair. **This is a secret value and must be un-guessable**, treat it with care. (If compiled with KMS support, length will vary)
 /// `batch_size` | `ROUGHENOUGH_BATCH_SIZE` | Optional | The maximum number of requests to process in one batch. All nonces in a batch are used to build a Merkle tree, the root of which is signed. Default is `64` requests per batch.
 /// `status_interval` | `ROUGHENOUGH_STATUS_INTERVAL` | Optional | Number of _seconds_ between each logged status update. Default is `600` seconds (10 minutes).
@@ -61,6 +100,23 @@ pub const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(600);
 /// `client_stats` | `ROUGHENOUGH_CLIENT_STATS` | Optional | A value of `on` or `yes` will enable tracking of per-client request statistics that will be output each time server status is logged. Default is `off` (disabled).
 /// `fault_percentage` | `ROUGHENOUGH_FAULT_PERCENTAGE` | Optional | Likelihood (as a percentage) that the server will intentionally return an invalid client response. An integer range from `0` (disabled, all responses valid) to `50` (50% of responses will be invalid). Default is `0` (disabled).
 /// `num_workers` | `ROUGHENOUGH_NUM_WORKERS` | Optional | Number of worker threads created to process requests. Defaults to `thread::available_parallelism()`
+/// `admin_port` | `ROUGHENOUGH_ADMIN_PORT` | Optional | If present, enable an HTTP admin endpoint on `127.0.0.1` at the provided port that reports server status as JSON (and accepts actions if `admin_token` is set).
+/// `psk` | `ROUGHENOUGH_PSK` | Optional | If present, a hexadecimal pre-shared key (at least 16 bytes) for private deployments. Only requests carrying HMAC-SHA256(psk, nonce) at the start of their padding are answered, all others are silently dropped. **This is a secret value**, treat it with care.
+/// `pad_responses` | `ROUGHENOUGH_PAD_RESPONSES` | Optional | A value of `on` or `yes` will pad every response to the size of a response from a full batch, so response sizes don't reveal how many requests were in a batch. Default is `off` (disabled).
+/// `shuffle_responses` | `ROUGHENOUGH_SHUFFLE_RESPONSES` | Optional | A value of `on` or `yes` will send the responses of each batch in random order rather than in the order the requests arrived, so the order of responses doesn't link them to requests. Default is `off` (disabled).
+/// `strict_parsing` | `ROUGHENOUGH_STRICT_PARSING` | Optional | A value of `on` or `yes` will reject requests containing tags this server doesn't know. Default is `off`: unknown tags (e.g. those of newer drafts or extensions) are ignored.
+/// `admin_token` | `ROUGHENOUGH_ADMIN_TOKEN` | Optional | If present, a secret (at least 16 characters) that enables the admin endpoint's `POST` actions. Requests must carry an `Authorization: Bearer <token>` header. **This is a secret value**, treat it with care.
+/// `online_key_validity` | `ROUGHENOUGH_ONLINE_KEY_VALIDITY` | Optional | If present, number of _seconds_ (at least `60`) each online key is delegated for. The delegation's `MINT`/`MAXT` bound that window, and the online key is replaced as soon as the clock leaves it. Default is unbounded delegations.
+/// `audit_log` | `ROUGHENOUGH_AUDIT_LOG` | Optional | If present, path of a file that anomalous requests (oversized, SRV mismatches, replays, parse failures, ...) are appended to as newline-delimited JSON, separate from the operational log. Default is no audit log.
+/// `public_key` | `ROUGHENOUGH_PUBLIC_KEY` | Optional | If present, the long-term public key (hex, base64, OpenSSH or bech32) the `seed` is expected to derive. The server refuses to start if the seed derives a different key. Default is no check.
+/// `discovery_file` | `ROUGHENOUGH_DISCOVERY_FILE` | Optional | If present, once listening the server writes a JSON object with the address, port, and long-term public key it serves to this file (or stdout if `-`). Useful with `port` `0` in tests. Default is no discovery output.
+/// `compromised` | `ROUGHENOUGH_COMPROMISED` | Optional | If present, `silent` or `zero-signature`. The server signs nothing and logs an error for every batch of requests: `silent` drops them, `zero-signature` answers with an all-zero signature that clients reject. Default is normal operation.
+/// `clock_sources` | `ROUGHENOUGH_CLOCK_SOURCES` | Optional | If present, a comma separated list of clock sources (`system`, `phc:<path>`, each optionally followed by `@<offset ms>`). The time a majority of them agree on is signed, and disagreements are logged as errors. See [`clock`](../clock/index.html). Default is the system clock alone.
+/// `clock_tolerance` | `ROUGHENOUGH_CLOCK_TOLERANCE` | Optional | Number of _milliseconds_ clock sources may differ by and still agree. Default is `100` milliseconds.
+/// `clock_quorum_required` | `ROUGHENOUGH_CLOCK_QUORUM_REQUIRED` | Optional | A value of `on` or `yes` will drop requests rather than sign a time when no majority of `clock_sources` agree. Default is `off` (sign the first source's time and log an error).
+/// `metrics_push_url` | `ROUGHENOUGH_METRICS_PUSH_URL` | Optional | If present, an `https://` URL (requires the `https` feature), or an `http://` URL on a loopback host (`localhost`, `127.0.0.1`, `[::1]`), that a JSON snapshot of the server's metrics (the admin endpoint's `/status` document) is `POST`ed to every `status_interval`. See [`admin::MetricsPusher`](../admin/struct.MetricsPusher.html). Default is no push.
+/// `metrics_push_token` | `ROUGHENOUGH_METRICS_PUSH_TOKEN` | Optional | If present, a secret sent as an `Authorization: Bearer <token>` header with each metrics push. **This is a secret value**, treat it with care.
+/// `leap_info` | `ROUGHENOUGH_LEAP_INFO` | Optional | If present, leap second information added to every signed response as the `DUT1`, `DTAI`, and `LEAP` tags of draft-ietf-ntp-roughtime-02, e.g. `dtai=37, leap=61771`. See [`leap`](../leap/index.html). Default is none: later drafts dropped these tags.
 ///
 /// Implementations of this trait obtain a valid configuration from different back-end
 /// sources. See:
@@ -114,6 +170,96 @@ pub trait ServerConfig: Send {
     /// Rust's `thread::available_parallelism()`.
     fn num_workers(&self) -> usize;
 
+    /// [Optional] If present, the TCP port of the local admin endpoint. The endpoint only
+    /// listens on `127.0.0.1` and reports build info, keys, and per-worker counters as JSON.
+    fn admin_port(&self) -> Option<u16>;
+
+    /// [Optional] If present, the pre-shared key that requests must be authenticated with.
+    /// Requests lacking a valid authenticator (HMAC-SHA256 over the nonce, placed at the start
+    /// of the request's padding) are dropped. See
+    /// [RequestAuthenticator](../request/struct.RequestAuthenticator.html).
+    fn psk(&self) -> Option<&[u8]>;
+
+    /// [Optional] If present, the secret bearer token that authorizes admin endpoint actions
+    /// (online key rotation, metrics dump, toggling deliberate errors). Actions are disabled
+    /// when absent.
+    fn admin_token(&self) -> Option<&str>;
+
+    /// [Optional] A value of `on` or `yes` will pad every response to the size of a response
+    /// from a full batch (a Merkle tree of `batch_size` leaves). Response sizes then no longer
+    /// reveal how many requests were in a batch. Default is `off` (disabled).
+    fn pad_responses_enabled(&self) -> bool;
+
+    /// [Optional] A value of `on` or `yes` will send the responses to each batch in random
+    /// order instead of Merkle leaf (request arrival) order, so an observer can't link
+    /// requests to responses by their order. All responses of a batch are signed before any
+    /// is sent, so shuffling changes only which responses go out first. Default is `off`
+    /// (disabled).
+    fn shuffle_responses_enabled(&self) -> bool;
+
+    /// [Optional] A value of `on` or `yes` will reject requests containing tags this server
+    /// doesn't know. By default such tags (e.g. those of newer drafts, other implementations'
+    /// extensions) are ignored, as long as the request is otherwise well-formed and has the
+    /// tags the server needs. Default is `off` (disabled).
+    fn strict_parsing_enabled(&self) -> bool;
+
+    /// [Optional] If present, the length of each online key's delegation window. Delegations
+    /// run from creation of the online key until `online_key_validity` later, and a worker
+    /// replaces its online key rather than sign a response whose time falls outside the window.
+    /// Default is unbounded delegations (`MINT` 0, `MAXT` the maximum time).
+    fn online_key_validity(&self) -> Option<Duration>;
+
+    /// [Optional] If present, path of a file to append a newline-delimited JSON
+    /// [audit entry](../audit/index.html) to for each anomalous request. Default is no audit log.
+    fn audit_log(&self) -> Option<String>;
+
+    /// [Optional] If present, the long-term public key that `seed` must derive. Guards against
+    /// starting with the wrong seed (or KMS key) and silently changing the server's identity.
+    /// See [`kms::verify_seed`](../kms/fn.verify_seed.html). Default is no check.
+    fn public_key(&self) -> Option<&[u8]>;
+
+    /// [Optional] If present, path of a file (or `-` for stdout) the server writes the
+    /// address, port, and public key it is serving on to once it is listening. Lets tests and
+    /// orchestration discover an ephemeral `port` of `0`. Default is no discovery output.
+    fn discovery_file(&self) -> Option<String>;
+
+    /// [Optional] If present, the server treats its keys as compromised and signs nothing:
+    /// `silent` drops every request, `zero-signature` answers with an all-zero `SIG` that
+    /// clients reject. For use when a key is suspected to have leaked. Default is normal
+    /// operation.
+    fn compromised(&self) -> Option<CompromisedMode>;
+
+    /// [Optional] Sources of the time to sign; a majority of them must agree. Empty (the
+    /// default) uses the system clock alone. See [`clock`](../clock/index.html).
+    fn clock_sources(&self) -> Vec<ClockSpec>;
+
+    /// [Optional] Largest difference between `clock_sources` that counts as agreement.
+    /// Defaults to [DEFAULT_CLOCK_TOLERANCE](constant.DEFAULT_CLOCK_TOLERANCE.html)
+    fn clock_tolerance(&self) -> Duration;
+
+    /// [Optional] A value of `on` or `yes` will refuse to sign (dropping the batch) when no
+    /// majority of `clock_sources` agree. Default is `off`: the first source's time is signed
+    /// and the disagreement logged.
+    fn clock_quorum_required(&self) -> bool;
+
+    /// [Optional] If present, the `https://` (or, on a loopback host, `http://`) URL each
+    /// metrics snapshot is `POST`ed to, for deployments where the admin endpoint can't be
+    /// scraped. Default is no push.
+    fn metrics_push_url(&self) -> Option<&str>;
+
+    /// [Optional] If present, the bearer token sent with each metrics push
+    fn metrics_push_token(&self) -> Option<&str>;
+
+    /// [Optional] If present, leap second information added to every signed response. See
+    /// [`leap`](../leap/index.html). Default is none.
+    fn leap_info(&self) -> Option<LeapInfo>;
+
+    /// Where the value of the setting named `key` (its YAML key) came from. Defaults to
+    /// `SettingSource::Default` for implementations that don't track it.
+    fn setting_source(&self, _key: &str) -> SettingSource {
+        SettingSource::Default
+    }
+
     /// Convenience function to create a `SocketAddr` from the provided `interface` and `port`
     fn udp_socket_addr(&self) -> Result<SocketAddr, Error> {
         let addr = format!("{}:{}", self.interface(), self.port());
@@ -128,7 +274,8 @@ pub trait ServerConfig: Send {
 /// of the provided `arg`.
 ///
 ///   * `ENV` will return an [`EnvironmentConfig`](struct.EnvironmentConfig.html)
-///   * any other value returns a [`FileConfig`](struct.FileConfig.html)
+///   * any other value is the path of a [`FileConfig`](struct.FileConfig.html), whose
+///     settings are overridden by any `ROUGHENOUGH_*` environment variables that are set
 ///
 pub fn make_config(arg: &str) -> Result<Box<dyn ServerConfig>, Error> {
     if arg == "ENV" {
@@ -138,12 +285,95 @@ pub fn make_config(arg: &str) -> Result<Box<dyn ServerConfig>, Error> {
         }
     } else {
         match FileConfig::new(arg) {
-            Ok(cfg) => Ok(Box::new(cfg)),
+            Ok(cfg) => Ok(Box::new(EnvironmentConfig::overriding(&cfg)?)),
             Err(e) => Err(e),
         }
     }
 }
 
+///
+/// Every effective setting of `cfg`, defaults included, and where it came from as a single
+/// line JSON object: `{"port":{"value":2002,"source":"file"},...}`. Secret settings (`seed`,
+/// `psk`, `admin_token`, `metrics_push_token`) are reported only as `"<redacted>"` when set.
+///
+pub fn effective_settings_json(cfg: &dyn ServerConfig) -> String {
+    let opt = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+    let secret = |is_set: bool| {
+        if is_set {
+            r#""<redacted>""#.to_string()
+        } else {
+            "null".to_string()
+        }
+    };
+
+    let settings = [
+        ("interface", format!("{:?}", cfg.interface())),
+        ("port", cfg.port().to_string()),
+        ("seed", secret(!cfg.seed().is_empty())),
+        ("batch_size", cfg.batch_size().to_string()),
+        ("status_interval", cfg.status_interval().as_secs().to_string()),
+        ("health_check_port", opt(cfg.health_check_port().map(|p| p.to_string()))),
+        ("kms_protection", format!(r#""{}""#, cfg.kms_protection())),
+        ("client_stats", cfg.client_stats_enabled().to_string()),
+        ("fault_percentage", cfg.fault_percentage().to_string()),
+        ("num_workers", cfg.num_workers().to_string()),
+        ("admin_port", opt(cfg.admin_port().map(|p| p.to_string()))),
+        ("admin_token", secret(cfg.admin_token().is_some())),
+        ("psk", secret(cfg.psk().is_some())),
+        ("pad_responses", cfg.pad_responses_enabled().to_string()),
+        ("shuffle_responses", cfg.shuffle_responses_enabled().to_string()),
+        ("strict_parsing", cfg.strict_parsing_enabled().to_string()),
+        (
+            "online_key_validity",
+            opt(cfg.online_key_validity().map(|v| v.as_secs().to_string())),
+        ),
+        ("audit_log", opt(cfg.audit_log().map(|p| format!("{:?}", p)))),
+        (
+            "public_key",
+            opt(cfg.public_key().map(|k| format!(r#""{}""#, HEXLOWER.encode(k)))),
+        ),
+        (
+            "discovery_file",
+            opt(cfg.discovery_file().map(|p| format!("{:?}", p))),
+        ),
+        (
+            "compromised",
+            opt(cfg.compromised().map(|m| format!(r#""{}""#, m))),
+        ),
+        ("clock_sources", clock_sources_json(&cfg.clock_sources())),
+        ("clock_tolerance", cfg.clock_tolerance().as_millis().to_string()),
+        ("clock_quorum_required", cfg.clock_quorum_required().to_string()),
+        (
+            "metrics_push_url",
+            opt(cfg.metrics_push_url().map(|u| format!("{:?}", u))),
+        ),
+        ("metrics_push_token", secret(cfg.metrics_push_token().is_some())),
+        (
+            "leap_info",
+            opt(cfg.leap_info().map(|l| format!("{:?}", l.to_string()))),
+        ),
+    ];
+
+    let fields: Vec<String> = settings
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                r#""{}":{{"value":{},"source":"{}"}}"#,
+                key,
+                value,
+                cfg.setting_source(key).name()
+            )
+        })
+        .collect();
+
+    format!("{{{}}}", fields.join(","))
+}
+
+fn clock_sources_json(specs: &[ClockSpec]) -> String {
+    let specs: Vec<String> = specs.iter().map(|s| format!("{:?}", s.to_string())).collect();
+    format!("[{}]", specs.join(","))
+}
+
 ///
 /// Validate configuration settings. Returns `true` if the config is valid, `false` otherwise.
 ///
@@ -151,7 +381,8 @@ pub fn make_config(arg: &str) -> Result<Box<dyn ServerConfig>, Error> {
 pub fn is_valid_config(cfg: &dyn ServerConfig) -> bool {
     let mut is_valid = true;
 
-    if cfg.port() == 0 {
+    // An explicitly configured port 0 binds an ephemeral port
+    if cfg.port() == 0 && cfg.setting_source("port") == SettingSource::Default {
         error!("server port not set: {}", cfg.port());
         is_valid = false;
     }
@@ -179,10 +410,11 @@ pub fn is_valid_config(cfg: &dyn ServerConfig) -> bool {
         is_valid = false;
     }
 
-    if cfg.batch_size() < 1 || cfg.batch_size() > 64 {
+    if cfg.batch_size() < 1 || cfg.batch_size() as usize > MAX_BATCH_SIZE {
         error!(
-            "batch_size {} is invalid; valid range 1-64",
-            cfg.batch_size()
+            "batch_size {} is invalid; valid range 1-{}",
+            cfg.batch_size(),
+            MAX_BATCH_SIZE
         );
         is_valid = false;
     }
@@ -200,6 +432,73 @@ pub fn is_valid_config(cfg: &dyn ServerConfig) -> bool {
         is_valid = false;
     }
 
+    if cfg.admin_port().is_some() && cfg.admin_port() == cfg.health_check_port() {
+        error!("admin_port and health_check_port must be different");
+        is_valid = false;
+    }
+
+    if let Some(psk) = cfg.psk() {
+        if psk.len() < MIN_PSK_LENGTH {
+            error!("psk must be at least {} bytes long", MIN_PSK_LENGTH);
+            is_valid = false;
+        }
+    }
+
+    if let Some(token) = cfg.admin_token() {
+        if token.len() < MIN_ADMIN_TOKEN_LENGTH {
+            error!(
+                "admin_token must be at least {} characters long",
+                MIN_ADMIN_TOKEN_LENGTH
+            );
+            is_valid = false;
+        }
+    }
+
+    if let Some(public_key) = cfg.public_key() {
+        if public_key.len() != PUBLIC_KEY_LENGTH {
+            error!(
+                "public_key must be {} bytes long, found {}",
+                PUBLIC_KEY_LENGTH,
+                public_key.len()
+            );
+            is_valid = false;
+        }
+    }
+
+    if let Some(validity) = cfg.online_key_validity() {
+        if validity < MIN_ONLINE_KEY_VALIDITY {
+            error!(
+                "online_key_validity must be at least {} seconds",
+                MIN_ONLINE_KEY_VALIDITY.as_secs()
+            );
+            is_valid = false;
+        }
+    }
+
+    // The token may only travel unencrypted over loopback
+    if let Some(url) = cfg.metrics_push_url() {
+        match url.parse::<HttpUrl>() {
+            Ok(push_url) if !push_url.https && !push_url.is_loopback() => {
+                error!(
+                    "metrics_push_url must be an https:// URL unless its host is a loopback \
+                     address: '{}'",
+                    url
+                );
+                is_valid = false;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("metrics_push_url is invalid: {:?}", e);
+                is_valid = false;
+            }
+        }
+    }
+
+    if let Err(e) = ClockQuorum::from_config(cfg) {
+        error!("clock_sources are unusable: {:?}", e);
+        is_valid = false;
+    }
+
     if is_valid {
         if let Err(e) = cfg.udp_socket_addr() {
             error!(
@@ -214,3 +513,38 @@ pub fn is_valid_config(cfg: &dyn ServerConfig) -> bool {
 
     is_valid
 }
+
+#[cfg(test)]
+mod test {
+    use crate::config::{effective_settings_json, is_valid_config, MemoryConfig};
+
+    #[test]
+    fn effective_settings_redact_secrets() {
+        let mut config = MemoryConfig::new(2002);
+        config.psk = Some(vec![0x42; 16]);
+        config.admin_token = Some("super-secret-admin-token".to_string());
+
+        let json = effective_settings_json(&config);
+        assert!(json.starts_with(r#"{"interface":{"value":"127.0.0.1","source":"default"},"#));
+        assert!(json.contains(r#""port":{"value":2002,"source":"default"}"#));
+        assert!(json.contains(r#""seed":{"value":"<redacted>","#));
+        assert!(json.contains(r#""psk":{"value":"<redacted>","#));
+        assert!(json.contains(r#""admin_port":{"value":null,"#));
+        assert!(!json.contains("super-secret") && !json.contains("4242"));
+    }
+
+    #[test]
+    fn metrics_are_only_pushed_unencrypted_over_loopback() {
+        let mut config = MemoryConfig::new(2002);
+        assert!(is_valid_config(&config));
+
+        config.metrics_push_url = Some("http://127.0.0.1:9091/roughtime".to_string());
+        assert!(is_valid_config(&config));
+
+        config.metrics_push_url = Some("http://collector.example:9091/roughtime".to_string());
+        assert!(!is_valid_config(&config));
+
+        config.metrics_push_url = Some("https://collector.example/roughtime".to_string());
+        assert_eq!(is_valid_config(&config), cfg!(feature = "https"));
+    }
+}
@@ -53,6 +53,9 @@ pub enum Error {
     /// Otherwise invalid request
     InvalidRequest,
 
+    /// The request's nonce has the associated length, not the one its protocol version uses
+    InvalidNonceLength(usize),
+
     /// Otherwise invalid response
     InvalidResponse,
 
@@ -62,6 +65,9 @@ pub enum Error {
     /// The message length reported by the frame length != the actual message payload length
     LengthMismatch(u32, u32),
 
+    /// A byte stream did not hold a sequence of ROUGHTIM frames, for the reason provided
+    InvalidFraming(String),
+
     /// Request did not provide versions compatible with this implementation
     NoCompatibleVersion,
 
@@ -70,6 +76,95 @@ pub enum Error {
 
     /// The request's SRV value and this server's SRV value do not match
     SrvMismatch,
+
+    /// The request's padding lacks a valid pre-shared key authenticator
+    RequestNotAuthenticated,
+
+    /// The operation described did not complete in time
+    Timeout(String),
+
+    /// The associated time (in the protocol version's units) lies outside the online key's
+    /// delegation window, so a response signed with it would be invalid
+    OutsideDelegationWindow(u64),
+
+    /// The server's startup self-test failed for the reason provided
+    SelfTestFailed(String),
+
+    /// The operation described was abandoned because its client was cancelled
+    Cancelled(String),
+
+    /// The described wait for a response ended without one, after the server's port was
+    /// reported unreachable (ICMP port unreachable)
+    Unreachable(String),
+
+    /// An HTTP request was answered with a non-2xx status, or with a reply that isn't HTTP,
+    /// as described
+    UnexpectedHttpStatus(String),
+}
+
+///
+/// Stable categories of [`Error`](enum.Error.html) (and errors that convert into it).
+///
+/// Individual error variants come and go as the protocol evolves; match on the kind
+/// instead when only the category matters. New kinds may be added, so matches must include
+/// a wildcard arm.
+///
+#[non_exhaustive]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum ErrorKind {
+    /// Bytes are not a well-formed Roughtime message
+    Malformed,
+
+    /// A well-formed request that the server will not answer
+    Request,
+
+    /// A response that failed validation
+    Response,
+
+    /// Invalid configuration or server state, including failures to access a protected seed
+    /// via KMS, a clock outside the online key's delegation, and a failed startup self-test
+    Configuration,
+
+    /// Failure to read, write, or send data
+    Io,
+}
+
+impl Error {
+    /// The category of this error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::TagNotStrictlyIncreasing(_)
+            | Error::InvalidTag(_)
+            | Error::InvalidNumTags(_)
+            | Error::InvalidValueLength(_, _)
+            | Error::InvalidAlignment(_)
+            | Error::InvalidOffsetValue(_)
+            | Error::MessageTooShort
+            | Error::LengthMismatch(_, _)
+            | Error::InvalidFraming(_) => ErrorKind::Malformed,
+
+            Error::RequestTooShort
+            | Error::RequestTooLarge
+            | Error::InvalidRequest
+            | Error::InvalidNonceLength(_)
+            | Error::NoCompatibleVersion
+            | Error::SrvMismatch
+            | Error::RequestNotAuthenticated => ErrorKind::Request,
+
+            Error::InvalidResponse => ErrorKind::Response,
+
+            Error::InvalidConfiguration(_)
+            | Error::OutsideDelegationWindow(_)
+            | Error::SelfTestFailed(_) => ErrorKind::Configuration,
+
+            Error::EncodingFailure(_)
+            | Error::SendingResponseFailed
+            | Error::Timeout(_)
+            | Error::Cancelled(_)
+            | Error::Unreachable(_)
+            | Error::UnexpectedHttpStatus(_) => ErrorKind::Io,
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -96,3 +191,45 @@ impl From<KmsError> for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::error::{Error, ErrorKind};
+    use crate::kms::KmsError;
+    use crate::Tag;
+
+    #[test]
+    fn conversions_preserve_kind() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed");
+        assert_eq!(Error::from(io_err).kind(), ErrorKind::Io);
+
+        for kms_err in [
+            KmsError::OperationFailed("x".into()),
+            KmsError::InvalidConfiguration("x".into()),
+            KmsError::InvalidData("x".into()),
+            KmsError::InvalidKey("x".into()),
+        ] {
+            assert_eq!(Error::from(kms_err.clone()).kind(), kms_err.kind());
+        }
+    }
+
+    #[test]
+    fn kinds_group_related_errors() {
+        assert_eq!(
+            Error::InvalidTag(Box::from(&b"ABCD"[..])).kind(),
+            ErrorKind::Malformed
+        );
+        assert_eq!(
+            Error::TagNotStrictlyIncreasing(Tag::PAD).kind(),
+            ErrorKind::Malformed
+        );
+        assert_eq!(Error::SrvMismatch.kind(), ErrorKind::Request);
+        assert_eq!(Error::InvalidNonceLength(31).kind(), ErrorKind::Request);
+        assert_eq!(Error::RequestNotAuthenticated.kind(), ErrorKind::Request);
+        assert_eq!(Error::InvalidResponse.kind(), ErrorKind::Response);
+        assert_eq!(
+            Error::UnexpectedHttpStatus("HTTP/1.1 500".into()).kind(),
+            ErrorKind::Io
+        );
+    }
+}
@@ -68,6 +68,19 @@ impl Grease {
         }
     }
 
+    /// True if deliberate errors are being added
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    ///
+    /// Enable or disable deliberate errors at runtime. Enabling has no effect if the
+    /// configured `fault_percentage` is `0`.
+    ///
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
     ///
     /// Returns true `fault_percentage` percent of the time.
     ///
@@ -0,0 +1,406 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! A minimal HTTP/1.1 client that `POST`s JSON documents, used to deliver monitor alerts to
+//! webhooks and to push server metrics to a collector.
+//!
+//! `https://` URLs are supported when built with the `https` feature. The server's
+//! certificate is verified against the Mozilla root certificates compiled into the binary
+//! (via `webpki-roots`).
+//!
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::client::split_host_port;
+use crate::Error;
+
+///
+/// An `http://host[:port][/path]` or (with the `https` feature) `https://host[:port][/path]`
+/// URL. Brackets around an IPv6 host are removed.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl FromStr for HttpUrl {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = |why: &str| Error::InvalidConfiguration(format!("URL '{}': {}", s, why));
+
+        let (https, rest) = if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = s.strip_prefix("https://") {
+            if !cfg!(feature = "https") {
+                return Err(invalid("https:// URLs need the 'https' feature"));
+            }
+            (true, rest)
+        } else {
+            return Err(invalid("only http:// and https:// URLs are supported"));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = split_host_port(authority).map_err(|_| invalid("invalid host"))?;
+
+        Ok(HttpUrl {
+            https,
+            host: host.to_string(),
+            port: port.unwrap_or(if https { 443 } else { 80 }),
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl HttpUrl {
+    /// True if the host is `localhost` or a loopback address
+    pub fn is_loopback(&self) -> bool {
+        self.host.eq_ignore_ascii_case("localhost")
+            || self.host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    }
+}
+
+///
+/// `POST` `body` as JSON to `url`, with an `Authorization: Bearer <token>` header if
+/// `bearer_token` is provided. Fails with `Error::UnexpectedHttpStatus` unless the server
+/// answers with a 2xx status within `timeout`.
+///
+pub fn post_json(
+    url: &HttpUrl,
+    body: &str,
+    bearer_token: Option<&str>,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::InvalidConfiguration(format!("{} did not resolve", url)))?;
+
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    match url.https {
+        #[cfg(feature = "https")]
+        true => {
+            let stream = tls::connect(tls::webpki_config(), &url.host, stream)?;
+            exchange(stream, url, body, bearer_token)
+        }
+        _ => exchange(stream, url, body, bearer_token),
+    }
+}
+
+// Send the request over `stream` and check the status of the response
+fn exchange<S: Read + Write>(
+    mut stream: S,
+    url: &HttpUrl,
+    body: &str,
+    bearer_token: Option<&str>,
+) -> Result<(), Error> {
+    let authorization = match bearer_token {
+        Some(token) => format!("Authorization: Bearer {}\r\n", token),
+        None => String::new(),
+    };
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path,
+        url,
+        authorization,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+
+    let status = status_line
+        .strip_prefix("HTTP/")
+        .and_then(|rest| rest.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok());
+
+    match status {
+        Some(code) if (200..300).contains(&code) => Ok(()),
+        _ => Err(Error::UnexpectedHttpStatus(format!(
+            "{} answered '{}'",
+            url,
+            status_line.trim()
+        ))),
+    }
+}
+
+#[cfg(feature = "https")]
+mod tls {
+    use std::io;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+
+    use once_cell::sync::Lazy;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    static WEBPKI_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Arc::new(config)
+    });
+
+    /// Client configuration that trusts the Mozilla root certificates
+    pub fn webpki_config() -> Arc<ClientConfig> {
+        WEBPKI_CONFIG.clone()
+    }
+
+    /// Start a TLS session with `host` over `stream`. The handshake happens on first use.
+    pub fn connect(
+        config: Arc<ClientConfig>,
+        host: &str,
+        stream: TcpStream,
+    ) -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let conn = ClientConnection::new(config, name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(StreamOwned::new(conn, stream))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::http::{post_json, HttpUrl};
+    use crate::Error;
+
+    #[test]
+    fn urls() {
+        let url: HttpUrl = "http://collector.example:9091/metrics/job/rt"
+            .parse()
+            .unwrap();
+        assert_eq!(url.host, "collector.example");
+        assert_eq!(url.port, 9091);
+        assert_eq!(url.path, "/metrics/job/rt");
+        assert_eq!(url.to_string(), "collector.example:9091");
+
+        let url: HttpUrl = "http://[::1]".parse().unwrap();
+        assert_eq!(
+            (url.host.as_str(), url.port, url.path.as_str()),
+            ("::1", 80, "/")
+        );
+        assert_eq!(url.to_string(), "[::1]:80");
+        assert!(!url.https);
+
+        let url = "https://collector.example/metrics/job/rt".parse::<HttpUrl>();
+        match cfg!(feature = "https") {
+            true => {
+                let url = url.unwrap();
+                assert!(url.https);
+                assert_eq!((url.port, url.path.as_str()), (443, "/metrics/job/rt"));
+            }
+            false => assert!(url.is_err()),
+        }
+
+        for (url, loopback) in [
+            ("http://collector.example:9091/", false),
+            ("http://192.0.2.7/", false),
+            ("http://[2001:db8::1]/", false),
+            ("http://localhost:9091/", true),
+            ("http://127.0.0.53/", true),
+            ("http://[::1]:9091/", true),
+        ] {
+            let parsed = url.parse::<HttpUrl>().unwrap();
+            assert_eq!(parsed.is_loopback(), loopback, "{}", url);
+        }
+
+        for bad in [
+            "collector.example:80",
+            "ftp://collector.example/",
+            "http://:80/",
+            "http://collector.example:http/",
+        ] {
+            assert!(bad.parse::<HttpUrl>().is_err(), "accepted '{}'", bad);
+        }
+    }
+
+    // The head lines and body of the request `reader` holds
+    fn read_request<R: BufRead>(reader: &mut R) -> (Vec<String>, String) {
+        let mut head = Vec::new();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(len) = line.strip_prefix("Content-Length: ") {
+                content_length = len.trim().parse().unwrap();
+            }
+            head.push(line);
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn posts_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url: HttpUrl = format!("http://{}/ingest", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for reply in [
+                "HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n",
+                "SSH-2.0-OpenSSH_9.6\r\n",
+                "",
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                requests.push(read_request(&mut reader));
+
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let timeout = Duration::from_secs(5);
+        let body = r#"{"status":"ok"}"#;
+        assert!(post_json(&url, body, Some("s3cret"), timeout).is_ok());
+        for expected in ["'HTTP/1.1 401 Unauthorized'", "'SSH-2.0-OpenSSH_9.6'", "''"] {
+            match post_json(&url, body, None, timeout) {
+                Err(Error::UnexpectedHttpStatus(msg)) => {
+                    assert!(msg.ends_with(expected), "{}", msg)
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        let requests = server.join().unwrap();
+        for (head, received) in &requests {
+            assert_eq!(head[0], "POST /ingest HTTP/1.1\r\n");
+            assert_eq!(received, body);
+        }
+        assert!(requests[0]
+            .0
+            .contains(&"Authorization: Bearer s3cret\r\n".to_string()));
+        assert!(!requests[1].0.iter().any(|h| h.starts_with("Authorization")));
+    }
+
+    #[cfg(feature = "https")]
+    #[test]
+    fn posts_json_over_tls() {
+        use std::net::TcpStream;
+        use std::sync::Arc;
+
+        use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+        use rustls::{ClientConfig, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+        use crate::http::{exchange, tls};
+
+        // A test CA and a certificate it issued for localhost and 127.0.0.1
+        let ca = CertificateDer::from(&include_bytes!("../tests/tls/ca.der")[..]);
+        let cert = CertificateDer::from(&include_bytes!("../tests/tls/localhost.der")[..]);
+        let key = PrivatePkcs8KeyDer::from(&include_bytes!("../tests/tls/localhost.key.der")[..]);
+
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key.into())
+            .unwrap();
+        let server_config = Arc::new(server_config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let accept = || {
+                let (stream, _) = listener.accept().unwrap();
+                let conn = ServerConnection::new(server_config.clone()).unwrap();
+                StreamOwned::new(conn, stream)
+            };
+
+            // A client that doesn't trust the test CA aborts the handshake
+            let mut line = String::new();
+            assert!(BufReader::new(&mut accept()).read_line(&mut line).is_err());
+
+            let mut stream = accept();
+            let request = read_request(&mut BufReader::new(&mut stream));
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            stream.conn.send_close_notify();
+            stream.flush().unwrap();
+            request
+        });
+
+        let timeout = Duration::from_secs(5);
+        let body = r#"{"status":"ok"}"#;
+
+        let url: HttpUrl = format!("https://127.0.0.1:{}/ingest", port)
+            .parse()
+            .unwrap();
+        match post_json(&url, body, Some("s3cret"), timeout) {
+            Err(Error::EncodingFailure(msg)) => assert!(msg.contains("certificate"), "{}", msg),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let url: HttpUrl = format!("https://localhost:{}/ingest", port)
+            .parse()
+            .unwrap();
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let stream = tls::connect(Arc::new(config), &url.host, stream).unwrap();
+        exchange(stream, &url, body, Some("s3cret")).unwrap();
+
+        let (head, received) = server.join().unwrap();
+        assert_eq!(head[0], "POST /ingest HTTP/1.1\r\n");
+        assert!(head.contains(&"Authorization: Bearer s3cret\r\n".to_string()));
+        assert_eq!(received, body);
+    }
+}
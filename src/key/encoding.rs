@@ -0,0 +1,337 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Textual encodings of Ed25519 public keys as operators copy them from other tooling
+//!
+
+use data_encoding::{Encoding, BASE64, HEXLOWER_PERMISSIVE};
+
+use crate::Error;
+
+const HEX: Encoding = HEXLOWER_PERMISSIVE;
+
+/// Key type of an Ed25519 key in the OpenSSH public key format
+const SSH_ED25519: &str = "ssh-ed25519";
+
+// bech32 (BIP-173) and bech32m (BIP-350) checksum constants
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc8_30a3;
+const BECH32_CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CHECKSUM_LENGTH: usize = 6;
+
+///
+/// Decode a public key given in any of the encodings accepted from operators:
+///
+///   * hexadecimal
+///   * base64
+///   * an OpenSSH public key line (`ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAA... [comment]`)
+///   * bech32 or bech32m, with any human-readable prefix (e.g. `rt1...`)
+///
+/// Only the encoding is checked; callers are responsible for validating the decoded length.
+///
+pub fn decode_public_key(encoded: &str) -> Result<Vec<u8>, Error> {
+    let encoded = encoded.trim();
+
+    if encoded.is_empty() {
+        return Err(Error::InvalidConfiguration("empty public key".to_string()));
+    }
+
+    if encoded.starts_with(SSH_ED25519) {
+        return decode_openssh(encoded);
+    }
+
+    HEX.decode(encoded.as_bytes())
+        .ok()
+        .or_else(|| decode_bech32(encoded))
+        .or_else(|| BASE64.decode(encoded.as_bytes()).ok())
+        .ok_or_else(|| {
+            Error::InvalidConfiguration(format!("unrecognized public key encoding '{}'", encoded))
+        })
+}
+
+// `ssh-ed25519 <base64 blob> [comment]`, where the blob holds two length-prefixed strings:
+// the key type and the 32 byte key
+fn decode_openssh(line: &str) -> Result<Vec<u8>, Error> {
+    let invalid = |reason: &str| {
+        Error::InvalidConfiguration(format!("invalid OpenSSH public key: {}", reason))
+    };
+
+    let mut fields = line.split_whitespace();
+    if fields.next() != Some(SSH_ED25519) {
+        return Err(invalid("not an ssh-ed25519 key"));
+    }
+
+    let blob = fields
+        .next()
+        .ok_or_else(|| invalid("missing key data"))
+        .and_then(|b| {
+            BASE64
+                .decode(b.as_bytes())
+                .map_err(|_| invalid("key data not base64"))
+        })?;
+
+    let mut rest = &blob[..];
+    let mut next_string = || -> Result<Vec<u8>, Error> {
+        if rest.len() < 4 {
+            return Err(invalid("truncated key data"));
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() - 4 < len {
+            return Err(invalid("truncated key data"));
+        }
+        let value = rest[4..4 + len].to_vec();
+        rest = &rest[4 + len..];
+        Ok(value)
+    };
+
+    if next_string()? != SSH_ED25519.as_bytes() {
+        return Err(invalid("key data is not for an ssh-ed25519 key"));
+    }
+    let key = next_string()?;
+
+    if !rest.is_empty() {
+        return Err(invalid("trailing key data"));
+    }
+
+    Ok(key)
+}
+
+// Decode the data part of a bech32 or bech32m string, ignoring its human-readable prefix
+fn decode_bech32(encoded: &str) -> Option<Vec<u8>> {
+    // mixed case is not allowed
+    if encoded.bytes().any(|b| b.is_ascii_uppercase())
+        && encoded.bytes().any(|b| b.is_ascii_lowercase())
+    {
+        return None;
+    }
+    let encoded = encoded.to_ascii_lowercase();
+
+    let sep = encoded.rfind('1')?;
+    let (hrp, data) = (&encoded[..sep], &encoded[sep + 1..]);
+    if hrp.is_empty()
+        || data.len() < BECH32_CHECKSUM_LENGTH
+        || !hrp.bytes().all(|b| (33..=126).contains(&b))
+    {
+        return None;
+    }
+
+    let values: Vec<u8> = data
+        .bytes()
+        .map(|c| BECH32_CHARSET.iter().position(|&v| v == c).map(|v| v as u8))
+        .collect::<Option<_>>()?;
+
+    let mut checked: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    checked.push(0);
+    checked.extend(hrp.bytes().map(|b| b & 31));
+    checked.extend_from_slice(&values);
+
+    match bech32_polymod(&checked) {
+        BECH32_CONST | BECH32M_CONST => (),
+        _ => return None,
+    }
+
+    regroup_5_to_8(&values[..values.len() - BECH32_CHECKSUM_LENGTH])
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GEN: [u32; 5] = [
+        0x3b6a_57b2,
+        0x2650_8e6d,
+        0x1ea1_19fa,
+        0x3d42_33dd,
+        0x2a14_62b3,
+    ];
+
+    let mut chk: u32 = 1;
+    for v in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x01ff_ffff) << 5) ^ u32::from(*v);
+        for (i, g) in GEN.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+// Convert 5-bit groups to bytes; leftover padding must be fewer than 5 zero bits
+fn regroup_5_to_8(values: &[u8]) -> Option<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut out = Vec::with_capacity(values.len() * 5 / 8);
+
+    for v in values {
+        acc = (acc << 5) | u32::from(*v);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+
+    if bits >= 5 || (acc & ((1 << bits) - 1)) != 0 {
+        return None;
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use data_encoding::BASE64;
+    use rand::rngs::SmallRng;
+    use rand::{FromEntropy, Rng};
+
+    use crate::key::encoding::{
+        bech32_polymod, decode_public_key, BECH32M_CONST, BECH32_CHARSET, BECH32_CONST, HEX,
+    };
+    use crate::key::LongTermKey;
+    use crate::PUBLIC_KEY_LENGTH;
+
+    const SEED: &str = "a32049da0ffde0ded92ce10a0230d35fe615ec8461c14986baa63fe3b3bac3db";
+    const PUBKEY: &str = "d0756ee69ff5fe96cbcf9273208fec53124b1dd3a24d3910e07c7c54e2473012";
+
+    fn openssh(key: &[u8], comment: &str) -> String {
+        let mut blob = Vec::new();
+        for field in [&b"ssh-ed25519"[..], key] {
+            blob.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            blob.extend_from_slice(field);
+        }
+        format!("ssh-ed25519 {} {}", BASE64.encode(&blob), comment)
+    }
+
+    fn bech32(hrp: &str, key: &[u8], constant: u32) -> String {
+        let mut values = Vec::new();
+        let (mut acc, mut bits) = (0u32, 0);
+        for b in key {
+            acc = (acc << 8) | u32::from(*b);
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                values.push(((acc >> bits) & 31) as u8);
+            }
+        }
+        if bits > 0 {
+            values.push(((acc << (5 - bits)) & 31) as u8);
+        }
+
+        let mut checked: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+        checked.push(0);
+        checked.extend(hrp.bytes().map(|b| b & 31));
+        checked.extend_from_slice(&values);
+        checked.extend_from_slice(&[0; 6]);
+        let polymod = bech32_polymod(&checked) ^ constant;
+        values.extend((0..6).map(|i| ((polymod >> (5 * (5 - i))) & 31) as u8));
+
+        let data: String = values
+            .iter()
+            .map(|v| BECH32_CHARSET[*v as usize] as char)
+            .collect();
+        format!("{}1{}", hrp, data)
+    }
+
+    #[test]
+    fn all_encodings_decode_to_the_same_key() {
+        let key = LongTermKey::new(&HEX.decode(SEED.as_bytes()).unwrap()).public_key();
+        assert_eq!(HEX.encode(&key), PUBKEY);
+
+        let encodings = [
+            PUBKEY.to_string(),
+            PUBKEY.to_uppercase(),
+            BASE64.encode(&key),
+            openssh(&key, "ops@roughtime"),
+            format!("  {}\n", openssh(&key, "")),
+            bech32("rt", &key, BECH32_CONST),
+            bech32("rt", &key, BECH32M_CONST),
+            bech32("rt", &key, BECH32_CONST).to_uppercase(),
+        ];
+
+        for encoded in &encodings {
+            assert_eq!(decode_public_key(encoded).unwrap(), key, "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        let key = HEX.decode(PUBKEY.as_bytes()).unwrap();
+        let good = bech32("rt", &key, BECH32_CONST);
+
+        // a single changed character breaks the checksum (and isn't valid base64 either)
+        let mut corrupted = good.clone().into_bytes();
+        corrupted[10] = if corrupted[10] == b'q' { b'p' } else { b'q' };
+        let corrupted = String::from_utf8(corrupted).unwrap();
+
+        let rsa = openssh(&key, "").replace("ssh-ed25519 ", "ssh-rsa ");
+        let mut truncated = openssh(&key, "");
+        truncated.truncate(40);
+
+        for bad in [
+            "",
+            "not a key",
+            "ssh-ed25519",
+            "ssh-ed25519 !!!!",
+            &corrupted,
+            &good[..good.len() - 1],
+            &format!("Rt1{}", &good[3..]),
+            &rsa,
+            &truncated,
+        ] {
+            // a truncated bech32 string can happen to be valid base64, but never of a key
+            let decoded = decode_public_key(bad);
+            assert!(
+                !matches!(decoded, Ok(ref k) if k.len() == PUBLIC_KEY_LENGTH),
+                "accepted '{}'",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn decoder_dispatch_survives_random_input() {
+        let mut rng = SmallRng::from_entropy();
+        let key = HEX.decode(PUBKEY.as_bytes()).unwrap();
+        let alphabet: Vec<char> = "ssh-ed25519 rt1qpzry9x8gf2tvdw0s3jn54khce6mua7lABCDEF+/=\n"
+            .chars()
+            .collect();
+
+        for _ in 0..5000 {
+            // arbitrary strings biased towards each encoding's alphabet must never panic
+            let len = rng.gen_range(0, 100);
+            let input: String = (0..len)
+                .map(|_| alphabet[rng.gen_range(0, alphabet.len())])
+                .collect();
+            let _ = decode_public_key(&input);
+
+            // valid encodings of random keys round-trip, and truncating them never panics
+            let random_key: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
+            for encoded in [
+                HEX.encode(&random_key),
+                BASE64.encode(&random_key),
+                openssh(&random_key, "fuzz"),
+                bech32("rt", &random_key, BECH32M_CONST),
+            ] {
+                assert_eq!(decode_public_key(&encoded).unwrap(), random_key);
+                let cut = rng.gen_range(0, encoded.len());
+                let _ = decode_public_key(&encoded[..cut]);
+            }
+        }
+
+        assert_eq!(
+            decode_public_key(&bech32("roughtime", &key, BECH32_CONST)).unwrap(),
+            key
+        );
+    }
+}
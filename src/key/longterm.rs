@@ -20,7 +20,7 @@ use crate::key::OnlineKey;
 use crate::message::RtMessage;
 use crate::sign::MsgSigner;
 use crate::tag::Tag;
-use crate::CERTIFICATE_CONTEXT;
+use crate::{CERTIFICATE_CONTEXT, SRV_LENGTH};
 use ring::digest;
 use ring::digest::SHA512;
 use std::fmt;
@@ -35,11 +35,14 @@ pub struct LongTermKey {
 }
 
 impl LongTermKey {
+    /// The `SRV` value committing to the long-term public key `pubkey`: SHA512[0:32] over
+    /// (0xff || `pubkey`). Clients put it in requests to ask for responses from the server
+    /// holding that key only; servers reject requests whose `SRV` doesn't match their own.
     pub fn calc_srv_value(pubkey: &[u8]) -> Vec<u8> {
         let mut ctx = digest::Context::new(&SHA512);
         ctx.update(Tag::HASH_PREFIX_SRV);
         ctx.update(pubkey);
-        ctx.finish().as_ref()[0..32].to_vec()
+        ctx.finish().as_ref()[0..SRV_LENGTH].to_vec()
     }
 
     pub fn new(seed: &[u8]) -> Self {
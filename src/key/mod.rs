@@ -20,9 +20,11 @@ use std::fmt::Display;
 use std::fmt::Formatter;
 use std::str::FromStr;
 
+pub use self::encoding::decode_public_key;
 pub use self::longterm::LongTermKey;
 pub use self::online::OnlineKey;
 
+mod encoding;
 mod longterm;
 mod online;
 
@@ -66,7 +68,19 @@ impl FromStr for KmsProtection {
 mod test {
     use std::str::FromStr;
 
-    use crate::key::KmsProtection;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
+
+    use crate::key::{KmsProtection, LongTermKey, OnlineKey};
+    use crate::leap::{LeapInfo, MAX_LEAP_INFO_LENGTH, MAX_LEAP_SECONDS};
+    use crate::version::Version;
+    use crate::{
+        Error, RtMessage, Tag, CERT_LENGTH, CLASSIC_ROOT_LENGTH, CLASSIC_SREP_LENGTH, DELE_LENGTH,
+        RFC_ROOT_LENGTH, RFC_SREP_LENGTH, SRV_LENGTH,
+    };
+
+    const HEX: Encoding = HEXLOWER_PERMISSIVE;
 
     #[test]
     fn convert_from_string() {
@@ -92,4 +106,112 @@ mod test {
             e => panic!("unexpected result {:?}", e),
         }
     }
+
+    #[test]
+    fn srv_values_match_known_vectors() {
+        // (public key, SHA512[0:32] of 0xff || public key), computed independently
+        let vectors = [
+            (
+                "d0756ee69ff5fe96cbcf9273208fec53124b1dd3a24d3910e07c7c54e2473012",
+                "4f3ed5772a24f0bf8c2a7fe07da858b646114aaed818cace6accfe25576ae774",
+            ),
+            (
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "869807f2d387f1560493a87f4e0d07bdb8e68189999354e9907647eda74240f0",
+            ),
+        ];
+
+        for (pubkey, srv) in vectors {
+            let pubkey = HEX.decode(pubkey.as_bytes()).unwrap();
+            let srv = HEX.decode(srv.as_bytes()).unwrap();
+            assert_eq!(LongTermKey::calc_srv_value(&pubkey), srv);
+            assert_eq!(srv.len(), SRV_LENGTH);
+        }
+
+        // the first vector's key is derived from the seed in example.cfg
+        let seed = HEX
+            .decode(b"a32049da0ffde0ded92ce10a0230d35fe615ec8461c14986baa63fe3b3bac3db")
+            .unwrap();
+        let ltk = LongTermKey::new(&seed);
+        assert_eq!(HEX.encode(&ltk.public_key()), vectors[0].0);
+        assert_eq!(HEX.encode(ltk.srv_value()), vectors[0].1);
+    }
+
+    #[test]
+    fn signing_refused_when_clock_jumps_outside_delegation() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let validity = Duration::from_secs(3600);
+
+        for version in [Version::Classic, Version::Rfc, Version::RfcDraft11] {
+            let mut key = OnlineKey::with_validity(version, start, validity);
+            let root = [0; 32];
+
+            // both ends of the window are inclusive
+            for now in [start, start + validity / 2, start + validity] {
+                assert!(key.covers(version, now));
+                assert!(key.make_srep(version, now, &root, None).is_ok());
+            }
+
+            // backwards before MINT, forwards past MAXT, and far in either direction
+            for now in [
+                start - Duration::from_secs(1),
+                start + validity + Duration::from_secs(1),
+                UNIX_EPOCH,
+                start + Duration::from_secs(10 * 365 * 86_400),
+            ] {
+                assert!(!key.covers(version, now));
+                match key.make_srep(version, now, &root, None) {
+                    Err(Error::OutsideDelegationWindow(_)) => (),
+                    r => panic!("{:?} signed outside its window: {:?}", version, r.is_ok()),
+                }
+            }
+
+            // MINT and MAXT in the DELE are in the version's own units
+            let dele = key.make_dele();
+            let read = |tag| u64::from_le_bytes(dele.get_field(tag).unwrap().try_into().unwrap());
+            let expected_span = 3_600_000_000 / version.profile().micros_per_unit;
+            assert_eq!(read(Tag::MINT), key.mint());
+            assert_eq!(read(Tag::MAXT) - read(Tag::MINT), expected_span);
+        }
+
+        // keys without a window cover every time
+        let key = OnlineKey::new();
+        assert!(key.covers(Version::Rfc, UNIX_EPOCH));
+        assert!(key.covers(Version::Classic, start + Duration::from_secs(1 << 40)));
+    }
+
+    #[test]
+    fn encoded_sizes_match_wire_constants() {
+        let mut ltk = LongTermKey::new(&[0x11; 32]);
+        let mut online = OnlineKey::new();
+
+        assert_eq!(online.make_dele().encode().unwrap().len(), DELE_LENGTH);
+        assert_eq!(ltk.make_cert(&online).encode().unwrap().len(), CERT_LENGTH);
+
+        let now = SystemTime::now();
+        let most_leap_info = LeapInfo {
+            dut1: Some(-12_000),
+            dtai: Some(37),
+            leap: vec![61_771; MAX_LEAP_SECONDS],
+        };
+        for (version, root_len, srep_len) in [
+            (Version::Classic, CLASSIC_ROOT_LENGTH, CLASSIC_SREP_LENGTH),
+            (Version::Rfc, RFC_ROOT_LENGTH, RFC_SREP_LENGTH),
+            (Version::RfcDraft11, RFC_ROOT_LENGTH, RFC_SREP_LENGTH),
+        ] {
+            let root = vec![0; root_len];
+            let srep = online.make_srep(version, now, &root, None).unwrap();
+            assert_eq!(srep.get_field(Tag::SREP).unwrap().len(), srep_len);
+            assert!(RtMessage::from_bytes(srep.get_field(Tag::SREP).unwrap()).is_ok());
+
+            // the leap tags fall between the others
+            let srep = online.make_srep(version, now, &root, Some(&most_leap_info)).unwrap();
+            let srep = srep.get_field(Tag::SREP).unwrap();
+            assert_eq!(srep.len(), srep_len + MAX_LEAP_INFO_LENGTH);
+            assert_eq!(
+                RtMessage::from_bytes(srep).unwrap().get_field(Tag::DTAI),
+                Some(&37i32.to_le_bytes()[..])
+            );
+        }
+    }
 }
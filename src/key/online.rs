@@ -14,21 +14,24 @@
 
 use std::fmt;
 use std::fmt::Formatter;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use byteorder::{LittleEndian, WriteBytesExt};
 
+use crate::leap::LeapInfo;
 use crate::message::RtMessage;
 use crate::sign::MsgSigner;
 use crate::tag::Tag;
-use crate::version::Version;
-use crate::SIGNED_RESPONSE_CONTEXT;
+use crate::version::{Version, SUPPORTED_RFC_VERSIONS};
+use crate::{Error, SIGNATURE_LENGTH, SIGNED_RESPONSE_CONTEXT};
 
 ///
 /// Represents the delegated Roughtime ephemeral online key.
 ///
 pub struct OnlineKey {
     signer: MsgSigner,
+    mint: u64,
+    maxt: u64,
 }
 
 impl Default for OnlineKey {
@@ -38,76 +41,96 @@ impl Default for OnlineKey {
 }
 
 impl OnlineKey {
+    /// A new online key whose delegation never expires
     pub fn new() -> Self {
         OnlineKey {
             signer: MsgSigner::new(),
+            mint: 0,
+            maxt: u64::MAX,
         }
     }
 
-    /// Create a DELE message containing the public key of this online key
-    pub fn make_dele(&self) -> RtMessage {
-        let zeros = [0u8; 8];
-        let max = [0xff; 8];
-        let pub_key_bytes = self.signer.public_key_bytes();
-
-        let mut dele_msg = RtMessage::with_capacity(3);
-        dele_msg.add_field(Tag::PUBK, &pub_key_bytes).unwrap();
-        dele_msg.add_field(Tag::MINT, &zeros).unwrap();
-        dele_msg.add_field(Tag::MAXT, &max).unwrap();
+    /// A new online key delegated from `now` until `now + validity`, in the time units of
+    /// protocol version `ver`
+    pub fn with_validity(ver: Version, now: SystemTime, validity: Duration) -> Self {
+        OnlineKey {
+            signer: MsgSigner::new(),
+            mint: to_protocol_time(ver, now),
+            maxt: to_protocol_time(ver, now + validity),
+        }
+    }
 
-        dele_msg
+    /// An online key derived from `seed`, delegated like
+    /// [`with_validity`](#method.with_validity). Only for reproducible output such as test
+    /// vectors: a server's online keys must be random.
+    pub fn from_seed(seed: &[u8], ver: Version, now: SystemTime, validity: Duration) -> Self {
+        OnlineKey {
+            signer: MsgSigner::from_seed(seed),
+            mint: to_protocol_time(ver, now),
+            maxt: to_protocol_time(ver, now + validity),
+        }
     }
 
-    /// Classic protocol, epoch time in microseconds
-    fn classic_midp(&self, now: SystemTime) -> u64 {
-        let d = now
-            .duration_since(UNIX_EPOCH)
-            .expect("duration since epoch");
-        let secs = d.as_secs() * 1_000_000;
-        let nsecs = (d.subsec_nanos() as u64) / 1_000;
+    /// True if a response of protocol version `ver` made at `now` falls within this key's
+    /// delegation window (inclusive of `MINT` and `MAXT`)
+    pub fn covers(&self, ver: Version, now: SystemTime) -> bool {
+        let midp = to_protocol_time(ver, now);
+        self.mint <= midp && midp <= self.maxt
+    }
 
-        secs + nsecs
+    /// Public key of this online key
+    pub fn public_key_bytes(&self) -> Vec<u8> {
+        self.signer.public_key_bytes()
     }
 
-    /// RFC protocol, a uint64 count of seconds since the Unix epoch in UTC.
-    fn rfc_midp(&self, now: SystemTime) -> u64 {
-        now.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    /// Start of the delegation window (DELE `MINT` value)
+    pub fn mint(&self) -> u64 {
+        self.mint
     }
 
-    /// Create an SREP response containing the provided time and Merkle root,
-    /// signed by this online key.
-    pub fn make_srep(&mut self, ver: Version, now: SystemTime, merkle_root: &[u8]) -> RtMessage {
-        let mut radi = [0; 4];
-        let mut midp = [0; 8];
+    /// End of the delegation window (DELE `MAXT` value)
+    pub fn maxt(&self) -> u64 {
+        self.maxt
+    }
 
-        // RADI is hard coded at 5 seconds (providing a 10-second measurement window overall)
-        let radi_time = match ver {
-            Version::Classic => 5_000_000,           // five seconds in microseconds
-            Version::Rfc | Version::RfcDraft11 => 5, // five seconds
-        };
+    /// Create a DELE message containing the public key of this online key
+    pub fn make_dele(&self) -> RtMessage {
+        let mut mint = [0u8; 8];
+        let mut maxt = [0u8; 8];
+        let pub_key_bytes = self.signer.public_key_bytes();
 
-        (&mut radi as &mut [u8])
-            .write_u32::<LittleEndian>(radi_time)
+        (&mut mint as &mut [u8])
+            .write_u64::<LittleEndian>(self.mint)
+            .unwrap();
+        (&mut maxt as &mut [u8])
+            .write_u64::<LittleEndian>(self.maxt)
             .unwrap();
 
-        let midp_time = match ver {
-            Version::Classic => self.classic_midp(now),
-            Version::Rfc | Version::RfcDraft11 => self.rfc_midp(now),
-        };
+        let mut dele_msg = RtMessage::with_capacity(3);
+        dele_msg.add_field(Tag::PUBK, &pub_key_bytes).unwrap();
+        dele_msg.add_field(Tag::MINT, &mint).unwrap();
+        dele_msg.add_field(Tag::MAXT, &maxt).unwrap();
 
-        (&mut midp as &mut [u8])
-            .write_u64::<LittleEndian>(midp_time)
-            .unwrap();
+        dele_msg
+    }
 
-        // Signed response SREP
-        let srep_bytes = {
-            let mut srep_msg = RtMessage::with_capacity(3);
-            srep_msg.add_field(Tag::RADI, &radi).unwrap();
-            srep_msg.add_field(Tag::MIDP, &midp).unwrap();
-            srep_msg.add_field(Tag::ROOT, merkle_root).unwrap();
+    /// Create an SREP response containing the provided time and Merkle root (and leap second
+    /// information if provided), signed by this online key.
+    ///
+    /// Fails with `Error::OutsideDelegationWindow` instead of signing a time that lies outside
+    /// this key's delegation window, as clients would reject such a response as invalid.
+    pub fn make_srep(
+        &mut self,
+        ver: Version,
+        now: SystemTime,
+        merkle_root: &[u8],
+        leap_info: Option<&LeapInfo>,
+    ) -> Result<RtMessage, Error> {
+        if !self.covers(ver, now) {
+            return Err(Error::OutsideDelegationWindow(to_protocol_time(ver, now)));
+        }
 
-            srep_msg.encode().unwrap()
-        };
+        let srep_bytes = srep_bytes(ver, now, merkle_root, leap_info);
 
         // signature on SREP
         let srep_signature = {
@@ -120,10 +143,93 @@ impl OnlineKey {
         result.add_field(Tag::SIG, &srep_signature).unwrap();
         result.add_field(Tag::SREP, &srep_bytes).unwrap();
 
+        Ok(result)
+    }
+
+    /// Create an SREP response like [`make_srep`](#method.make_srep) but without signing it:
+    /// its `SIG` is all zeros, so clients reject it. Used when this key is compromised.
+    pub fn make_unsigned_srep(
+        &self,
+        ver: Version,
+        now: SystemTime,
+        merkle_root: &[u8],
+        leap_info: Option<&LeapInfo>,
+    ) -> RtMessage {
+        let mut result = RtMessage::with_capacity(2);
+        result
+            .add_field(Tag::SIG, &[0; SIGNATURE_LENGTH as usize])
+            .unwrap();
+        result
+            .add_field(Tag::SREP, &srep_bytes(ver, now, merkle_root, leap_info))
+            .unwrap();
         result
     }
 }
 
+// Encoded SREP message carrying `now`, `merkle_root`, and `leap_info`
+fn srep_bytes(
+    ver: Version,
+    now: SystemTime,
+    merkle_root: &[u8],
+    leap_info: Option<&LeapInfo>,
+) -> Vec<u8> {
+    let mut radi = [0; 4];
+    let mut midp = [0; 8];
+
+    // RADI is hard coded at 5 seconds (providing a 10-second measurement window overall)
+    let radi_time = (5_000_000 / ver.profile().micros_per_unit) as u32;
+
+    (&mut radi as &mut [u8])
+        .write_u32::<LittleEndian>(radi_time)
+        .unwrap();
+
+    let midp_time = to_protocol_time(ver, now);
+
+    (&mut midp as &mut [u8])
+        .write_u64::<LittleEndian>(midp_time)
+        .unwrap();
+
+    // Signed response SREP
+    let srep_bytes = {
+        let mut fields = vec![
+            (Tag::RADI, radi.to_vec()),
+            (Tag::MIDP, midp.to_vec()),
+            (Tag::ROOT, merkle_root.to_vec()),
+        ];
+        if ver.profile().version_tags {
+            let vers: Vec<u8> = SUPPORTED_RFC_VERSIONS
+                .iter()
+                .flat_map(|v| v.wire_bytes())
+                .copied()
+                .collect();
+            fields.push((Tag::VERS, vers));
+        }
+        if let Some(leap_info) = leap_info {
+            fields.extend(leap_info.fields());
+        }
+
+        // Tags must be added in increasing order, the leap tags fall between the others
+        fields.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+
+        let mut srep_msg = RtMessage::with_capacity(fields.len() as u32);
+        for (tag, value) in &fields {
+            srep_msg.add_field(*tag, value).unwrap();
+        }
+
+        srep_msg.encode().unwrap()
+    };
+
+    srep_bytes
+}
+
+/// `now` in the time units of protocol version `ver`: microseconds since the Unix epoch for
+/// the classic protocol, seconds for the RFC protocol. Times before the epoch are 0.
+fn to_protocol_time(ver: Version, now: SystemTime) -> u64 {
+    let d = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    (d.as_micros() / ver.profile().micros_per_unit as u128) as u64
+}
+
 impl fmt::Display for OnlineKey {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "{}", self.signer)
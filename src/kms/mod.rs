@@ -60,7 +60,8 @@ use ring;
 
 use crate::config::ServerConfig;
 use crate::error;
-use crate::key::KmsProtection;
+use crate::key::{KmsProtection, LongTermKey};
+use crate::SEED_LENGTH;
 #[cfg(feature = "awskms")]
 pub use crate::kms::awskms::inner::AwsKms;
 #[cfg(feature = "gcpkms")]
@@ -79,6 +80,14 @@ pub enum KmsError {
     InvalidKey(String),
 }
 
+impl KmsError {
+    /// The category of this error. KMS errors occur while obtaining the server's seed, so they
+    /// are all configuration errors.
+    pub fn kind(&self) -> error::ErrorKind {
+        error::ErrorKind::Configuration
+    }
+}
+
 impl From<std::io::Error> for KmsError {
     fn from(error: std::io::Error) -> Self {
         KmsError::OperationFailed(format!("{:?}", error))
@@ -213,3 +222,123 @@ pub fn load_seed(config: &dyn ServerConfig) -> Result<Vec<u8>, error::Error> {
         ))),
     }
 }
+
+///
+/// Confirm that `seed` is a valid long-term key seed and that it derives the public key
+/// expected by `config.public_key()`, if one is configured. Returns the derived public key.
+///
+pub fn check_seed(config: &dyn ServerConfig, seed: &[u8]) -> Result<Vec<u8>, error::Error> {
+    if seed.len() != SEED_LENGTH as usize {
+        return Err(error::Error::InvalidConfiguration(format!(
+            "seed is {} bytes, expected {}",
+            seed.len(),
+            SEED_LENGTH
+        )));
+    }
+
+    let public_key = LongTermKey::new(seed).public_key();
+
+    match config.public_key() {
+        Some(expected) if expected != public_key.as_slice() => {
+            Err(error::Error::InvalidConfiguration(format!(
+                "seed derives public key {}, but public_key {} is expected",
+                data_encoding::HEXLOWER.encode(&public_key),
+                data_encoding::HEXLOWER.encode(expected)
+            )))
+        }
+        _ => Ok(public_key),
+    }
+}
+
+/// Fewest distinct byte values a seed may contain. Fewer than this in 32 uniformly random
+/// bytes has a probability below 10^-15, while all-zero, repeated or hand-typed seeds fail.
+pub const MIN_DISTINCT_SEED_BYTES: usize = 16;
+
+///
+/// Sanity check that `seed` looks randomly generated. Rejects all-zero seeds and others
+/// with too few distinct byte values, which are well-known or easily guessed keys.
+///
+pub fn check_seed_entropy(seed: &[u8]) -> Result<(), error::Error> {
+    let mut seen = [false; 256];
+    for &b in seed {
+        seen[b as usize] = true;
+    }
+    let distinct = seen.iter().filter(|&&s| s).count();
+
+    if distinct < MIN_DISTINCT_SEED_BYTES {
+        return Err(error::Error::InvalidConfiguration(format!(
+            "seed has only {} distinct byte values (minimum {}); it is not randomly generated",
+            distinct, MIN_DISTINCT_SEED_BYTES
+        )));
+    }
+
+    Ok(())
+}
+
+///
+/// Read-only check of the configured seed: load it (unwrapping it with the KMS if it is
+/// protected) and [`check_seed`](fn.check_seed.html) it. Returns the long-term public key;
+/// the seed itself is never returned or logged.
+///
+pub fn verify_seed(config: &dyn ServerConfig) -> Result<Vec<u8>, error::Error> {
+    let seed = load_seed(config)?;
+    check_seed(config, &seed)
+}
+
+// Default builds compile in no cloud SDK: only plaintext seeds can be loaded
+#[cfg(all(test, not(any(feature = "awskms", feature = "gcpkms"))))]
+mod test {
+    use crate::config::MemoryConfig;
+    use crate::error::ErrorKind;
+    use crate::key::KmsProtection;
+    use crate::kms::{check_seed_entropy, load_seed, verify_seed};
+
+    #[test]
+    fn builds_without_kms_only_load_plaintext_seeds() {
+        let mut config = MemoryConfig::new(0);
+        assert_eq!(load_seed(&config).unwrap(), config.seed);
+
+        for protection in [
+            KmsProtection::AwsKmsEnvelope("arn:aws:kms:region:123:key/abc".to_string()),
+            KmsProtection::GoogleKmsEnvelope("projects/p/locations/l/keyRings/r".to_string()),
+        ] {
+            config.kms_protection = protection;
+            let err = load_seed(&config).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Configuration);
+        }
+    }
+
+    #[test]
+    fn verify_seed_checks_expected_public_key() {
+        let mut config = MemoryConfig::new(0);
+        let public_key = verify_seed(&config).unwrap();
+        assert_eq!(public_key.len(), 32);
+
+        config.public_key = Some(public_key);
+        assert!(verify_seed(&config).is_ok());
+
+        config.public_key = Some(vec![0u8; 32]);
+        let err = verify_seed(&config).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Configuration);
+
+        config.public_key = None;
+        config.seed.truncate(16);
+        assert!(verify_seed(&config).is_err());
+    }
+
+    #[test]
+    fn low_entropy_seeds_are_rejected() {
+        let config = MemoryConfig::new(0);
+        assert!(check_seed_entropy(&config.seed).is_ok());
+
+        let mut counting = [0u8; 32];
+        for (i, b) in counting.iter_mut().enumerate() {
+            *b = (i % 8) as u8;
+        }
+
+        for seed in [[0u8; 32], [0xa5; 32], counting] {
+            let err = check_seed_entropy(&seed).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Configuration);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Leap second information a server can advertise in its signed responses: the `DUT1`,
+//! `DTAI`, and `LEAP` tags proposed in draft-ietf-ntp-roughtime-02.
+//!
+//! Later drafts dropped these tags, so a server only adds them to `SREP` if its `leap_info`
+//! setting is configured. The setting is a comma separated list of `dut1=<microseconds>`,
+//! `dtai=<seconds>`, and up to `MAX_LEAP_SECONDS` `leap=<MJD>` entries, e.g.
+//! `dtai=37, dut1=-12000, leap=61771`.
+//!
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{Error, Tag};
+
+/// Most leap seconds one `LEAP` tag lists
+pub const MAX_LEAP_SECONDS: usize = 8;
+
+/// Largest size (in bytes) the leap tags add to an `SREP`: each tag's header entry (tag and
+/// offset) and value
+pub const MAX_LEAP_INFO_LENGTH: usize = 3 * 8 + 4 + 4 + 4 * MAX_LEAP_SECONDS;
+
+// UT1 - UTC is kept within 0.9 seconds
+const MAX_DUT1_MICROS: i32 = 900_000;
+
+///
+/// The leap second information of a signed response. Each part is optional, only those
+/// present are sent.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LeapInfo {
+    /// UT1 - UTC, in microseconds (`DUT1`)
+    pub dut1: Option<i32>,
+
+    /// TAI - UTC, in seconds (`DTAI`)
+    pub dtai: Option<i32>,
+
+    /// Upcoming leap seconds (`LEAP`), each the Modified Julian Date of the day that ends
+    /// with it. A negative date is a leap second removed from the end of that day.
+    pub leap: Vec<i32>,
+}
+
+impl LeapInfo {
+    /// True if there is nothing to send
+    pub fn is_empty(&self) -> bool {
+        self.dut1.is_none() && self.dtai.is_none() && self.leap.is_empty()
+    }
+
+    /// The tags (and their values) to add to an `SREP`
+    pub fn fields(&self) -> Vec<(Tag, Vec<u8>)> {
+        let mut fields = Vec::with_capacity(3);
+        if let Some(dut1) = self.dut1 {
+            fields.push((Tag::DUT1, dut1.to_le_bytes().to_vec()));
+        }
+        if let Some(dtai) = self.dtai {
+            fields.push((Tag::DTAI, dtai.to_le_bytes().to_vec()));
+        }
+        if !self.leap.is_empty() {
+            let leap = self.leap.iter().flat_map(|mjd| mjd.to_le_bytes()).collect();
+            fields.push((Tag::LEAP, leap));
+        }
+        fields
+    }
+
+    /// The leap information carried by the `DUT1`, `DTAI`, and `LEAP` values of an `SREP`,
+    /// if any of them are present
+    pub fn from_fields(
+        dut1: Option<&[u8]>,
+        dtai: Option<&[u8]>,
+        leap: Option<&[u8]>,
+    ) -> Result<Option<LeapInfo>, String> {
+        let int32 = |tag: Tag, value: &[u8]| match value.try_into() {
+            Ok(bytes) => Ok(i32::from_le_bytes(bytes)),
+            Err(_) => Err(format!("{} is {} bytes, not an int32", tag, value.len())),
+        };
+
+        let leap = match leap {
+            Some(value) if value.len() % 4 != 0 => {
+                return Err(format!(
+                    "LEAP is {} bytes, not a list of int32",
+                    value.len()
+                ))
+            }
+            Some(value) => value
+                .chunks(4)
+                .map(|v| int32(Tag::LEAP, v))
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+
+        let info = LeapInfo {
+            dut1: dut1.map(|v| int32(Tag::DUT1, v)).transpose()?,
+            dtai: dtai.map(|v| int32(Tag::DTAI, v)).transpose()?,
+            leap,
+        };
+
+        match info.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(info)),
+        }
+    }
+}
+
+impl fmt::Display for LeapInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(dut1) = self.dut1 {
+            parts.push(format!("dut1={}", dut1));
+        }
+        if let Some(dtai) = self.dtai {
+            parts.push(format!("dtai={}", dtai));
+        }
+        parts.extend(self.leap.iter().map(|mjd| format!("leap={}", mjd)));
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl FromStr for LeapInfo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: String| {
+            Error::InvalidConfiguration(format!("invalid leap_info '{}': {}", s, why))
+        };
+
+        let mut info = LeapInfo::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, value) = entry
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("'{}' is not <name>=<value>", entry)))?;
+            let value: i32 = value
+                .trim()
+                .parse()
+                .map_err(|_| invalid(format!("'{}' is not a whole number", value.trim())))?;
+
+            match name.trim() {
+                "dut1" if value.abs() > MAX_DUT1_MICROS => {
+                    return Err(invalid(format!("dut1 {} exceeds 0.9 seconds", value)))
+                }
+                "dut1" => info.dut1 = Some(value),
+                "dtai" => info.dtai = Some(value),
+                "leap" if value == 0 => return Err(invalid("leap 0 is not a date".to_string())),
+                "leap" => info.leap.push(value),
+                other => return Err(invalid(format!("unknown part '{}'", other))),
+            }
+        }
+
+        if info.leap.len() > MAX_LEAP_SECONDS {
+            return Err(invalid(format!(
+                "at most {} leap seconds can be listed",
+                MAX_LEAP_SECONDS
+            )));
+        }
+
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::leap::LeapInfo;
+    use crate::Tag;
+
+    #[test]
+    fn leap_info_round_trips() {
+        let info: LeapInfo = "dtai=37, dut1=-12000, leap=61771, leap=-62000"
+            .parse()
+            .unwrap();
+        assert_eq!(info.dut1, Some(-12000));
+        assert_eq!(info.dtai, Some(37));
+        assert_eq!(info.leap, vec![61771, -62000]);
+        assert_eq!(info.to_string().parse::<LeapInfo>().unwrap(), info);
+
+        let fields = info.fields();
+        let tags: Vec<Tag> = fields.iter().map(|(tag, _)| *tag).collect();
+        assert_eq!(tags, vec![Tag::DUT1, Tag::DTAI, Tag::LEAP]);
+
+        let value = |tag| {
+            fields
+                .iter()
+                .find(|(t, _)| *t == tag)
+                .map(|(_, v)| v.as_slice())
+        };
+        let decoded = LeapInfo::from_fields(value(Tag::DUT1), value(Tag::DTAI), value(Tag::LEAP));
+        assert_eq!(decoded, Ok(Some(info)));
+        assert_eq!(LeapInfo::from_fields(None, None, None), Ok(None));
+        assert!(LeapInfo::from_fields(None, Some(&[1, 2]), None).is_err());
+        assert!(LeapInfo::from_fields(None, None, Some(&[0; 6])).is_err());
+    }
+
+    #[test]
+    fn invalid_leap_info_is_rejected() {
+        for spec in [
+            "dtai",
+            "dtai=many",
+            "dut1=1000000",
+            "leap=0",
+            "tai=37",
+            "leap=1,leap=2,leap=3,leap=4,leap=5,leap=6,leap=7,leap=8,leap=9",
+        ] {
+            assert!(spec.parse::<LeapInfo>().is_err(), "{}", spec);
+        }
+        assert!("".parse::<LeapInfo>().unwrap().is_empty());
+    }
+}
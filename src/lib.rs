@@ -58,24 +58,38 @@
 #[macro_use]
 extern crate log;
 
-pub use crate::error::Error;
-pub use crate::message::RtMessage;
+pub use crate::error::{Error, ErrorKind};
+pub use crate::message::{ExtensionField, FrameDecoder, ParseFailure, RtMessage};
 pub use crate::tag::Tag;
 
 mod error;
 mod message;
 mod tag;
 
+pub mod admin;
+pub mod audit;
+pub mod chain;
+pub mod client;
+pub mod clock;
 pub mod config;
 pub mod grease;
+pub mod http;
 pub mod key;
 pub mod kms;
+pub mod leap;
 pub mod merkle;
+#[cfg(feature = "dangerous-testing")]
+pub mod misbehave;
+pub mod monitor;
 pub mod request;
+pub mod response;
 pub mod responder;
 pub mod server;
+pub mod server_list;
 pub mod sign;
+pub mod sink;
 pub mod stats;
+pub mod testvectors;
 pub mod version;
 
 /// Version of Roughenough
@@ -91,7 +105,13 @@ pub fn roughenough_version() -> String {
         ""
     };
 
-    format!("{}{}", VERSION, kms_str)
+    let testing_str = if cfg!(feature = "dangerous-testing") {
+        " (+DANGEROUS TESTING)"
+    } else {
+        ""
+    };
+
+    format!("{}{}{}", VERSION, kms_str, testing_str)
 }
 
 //  Constants and magic numbers of the Roughtime protocol
@@ -122,3 +142,122 @@ pub const TREE_NODE_TWEAK: &[u8] = &[0x01];
 
 /// RFC first field magic value
 pub const REQUEST_FRAMING_BYTES: &[u8] = b"ROUGHTIM";
+
+//  Wire sizes of protocol elements. The assertions below tie them to the numbers in the RFC
+//  draft; a change that breaks one fails to compile.
+
+/// Size (in bytes) of RFC framing: `ROUGHTIM` magic value followed by a `u32` message length
+pub const FRAME_HEADER_LENGTH: usize = 12;
+
+/// Size (in bytes) of a `VER` value
+pub const VERSION_LENGTH: usize = 4;
+
+/// Size (in bytes) of a classic protocol nonce
+pub const CLASSIC_NONCE_LENGTH: usize = 64;
+
+/// Size (in bytes) of an RFC protocol nonce
+pub const RFC_NONCE_LENGTH: usize = 32;
+
+/// Size (in bytes) of an Ed25519 public key
+pub const PUBLIC_KEY_LENGTH: usize = 32;
+
+/// Size (in bytes) of the `SRV` value, SHA-512[0:32] of the long-term public key
+pub const SRV_LENGTH: usize = 32;
+
+/// Size (in bytes) of a classic protocol Merkle tree `ROOT`, a full SHA-512 digest
+pub const CLASSIC_ROOT_LENGTH: usize = 64;
+
+/// Size (in bytes) of an RFC protocol Merkle tree `ROOT`, SHA-512[0:32]
+pub const RFC_ROOT_LENGTH: usize = 32;
+
+/// Size (in bytes) of each node in `PATH`. Interior nodes are full SHA-512 digests in
+/// both protocol versions, only the RFC `ROOT` is truncated.
+pub const PATH_NODE_LENGTH: usize = 64;
+
+/// Maximum number of requests answered by one signed response
+pub const MAX_BATCH_SIZE: usize = 64;
+
+/// Depth of the Merkle tree (and nodes in `PATH`) for a batch of `MAX_BATCH_SIZE`
+pub const MAX_PATH_NODES: usize = 6;
+
+/// Size (in bytes) of a message header with `num_tags` tags: the tag count, the offsets
+/// of all values after the first, and the tags themselves
+pub const fn message_header_length(num_tags: usize) -> usize {
+    if num_tags < 2 {
+        4 + 4 * num_tags
+    } else {
+        4 + 4 * (num_tags - 1) + 4 * num_tags
+    }
+}
+
+/// Size (in bytes) of the message inside an RFC request of `MIN_REQUEST_LENGTH` bytes
+pub const MIN_RFC_REQUEST_MESSAGE_LENGTH: usize = MIN_REQUEST_LENGTH - FRAME_HEADER_LENGTH;
+
+/// Size (in bytes) of the `ZZZZ` padding of a minimum size RFC request with `VER` and `NONC`
+pub const RFC_REQUEST_PADDING_LENGTH: usize =
+    MIN_RFC_REQUEST_MESSAGE_LENGTH - message_header_length(3) - VERSION_LENGTH - RFC_NONCE_LENGTH;
+
+/// Size (in bytes) of the `ZZZZ` padding of a minimum size RFC request with `VER`, `SRV`,
+/// and `NONC`
+pub const RFC_SRV_REQUEST_PADDING_LENGTH: usize = MIN_RFC_REQUEST_MESSAGE_LENGTH
+    - message_header_length(4)
+    - VERSION_LENGTH
+    - SRV_LENGTH
+    - RFC_NONCE_LENGTH;
+
+/// Size (in bytes) of a `DELE` message: `PUBK`, `MINT`, and `MAXT`
+pub const DELE_LENGTH: usize = message_header_length(3) + PUBLIC_KEY_LENGTH + 8 + 8;
+
+/// Size (in bytes) of a `CERT` message: `SIG` and `DELE`
+pub const CERT_LENGTH: usize = message_header_length(2) + SIGNATURE_LENGTH as usize + DELE_LENGTH;
+
+/// Size (in bytes) of a classic protocol `SREP` message: `RADI`, `MIDP`, and `ROOT`
+pub const CLASSIC_SREP_LENGTH: usize = message_header_length(3) + 4 + 8 + CLASSIC_ROOT_LENGTH;
+
+/// Size (in bytes) of the `VERS` list of versions supported by an RFC protocol server
+pub const VERS_LENGTH: usize = version::SUPPORTED_RFC_VERSIONS.len() * VERSION_LENGTH;
+
+/// Size (in bytes) of an RFC protocol `SREP` message: `RADI`, `MIDP`, `VERS`, and `ROOT`
+pub const RFC_SREP_LENGTH: usize =
+    message_header_length(4) + 4 + 8 + VERS_LENGTH + RFC_ROOT_LENGTH;
+
+/// Largest classic protocol response: `SIG`, `NONC`, `PATH`, `SREP`, `CERT`, and `INDX`
+pub const MAX_CLASSIC_RESPONSE_LENGTH: usize = message_header_length(6)
+    + SIGNATURE_LENGTH as usize
+    + CLASSIC_NONCE_LENGTH
+    + MAX_PATH_NODES * PATH_NODE_LENGTH
+    + CLASSIC_SREP_LENGTH
+    + CERT_LENGTH
+    + 4;
+
+/// Largest framed RFC protocol response: `SIG`, `VER`, `NONC`, `PATH`, `SREP`, `CERT`,
+/// and `INDX`
+pub const MAX_RFC_RESPONSE_LENGTH: usize = FRAME_HEADER_LENGTH
+    + message_header_length(7)
+    + SIGNATURE_LENGTH as usize
+    + VERSION_LENGTH
+    + RFC_NONCE_LENGTH
+    + MAX_PATH_NODES * PATH_NODE_LENGTH
+    + RFC_SREP_LENGTH
+    + CERT_LENGTH
+    + 4;
+
+const _: () = assert!(REQUEST_FRAMING_BYTES.len() + 4 == FRAME_HEADER_LENGTH);
+const _: () = assert!(MIN_REQUEST_LENGTH <= MAX_REQUEST_LENGTH);
+const _: () = assert!(1 << MAX_PATH_NODES == MAX_BATCH_SIZE);
+const _: () = assert!(MIN_RFC_REQUEST_MESSAGE_LENGTH == 1012);
+const _: () = assert!(RFC_REQUEST_PADDING_LENGTH == 952);
+const _: () = assert!(RFC_SRV_REQUEST_PADDING_LENGTH == 912);
+const _: () = assert!(DELE_LENGTH == 72);
+const _: () = assert!(CERT_LENGTH == 152);
+const _: () = assert!(RFC_SREP_LENGTH == 84);
+const _: () = assert!(CLASSIC_SREP_LENGTH == 100);
+const _: () = assert!(MAX_CLASSIC_RESPONSE_LENGTH == 816);
+const _: () = assert!(MAX_RFC_RESPONSE_LENGTH == 792);
+// Responses must never be larger than requests, or the server is an amplifier
+const _: () = assert!(MAX_CLASSIC_RESPONSE_LENGTH <= MIN_REQUEST_LENGTH);
+const _: () = assert!(MAX_RFC_RESPONSE_LENGTH <= MIN_REQUEST_LENGTH);
+// Nor when the SREP carries leap second information
+const _: () =
+    assert!(MAX_CLASSIC_RESPONSE_LENGTH + leap::MAX_LEAP_INFO_LENGTH <= MIN_REQUEST_LENGTH);
+const _: () = assert!(MAX_RFC_RESPONSE_LENGTH + leap::MAX_LEAP_INFO_LENGTH <= MIN_REQUEST_LENGTH);
@@ -17,7 +17,6 @@
 //!
 
 use crate::version::Version;
-use crate::version::Version::{Classic, Rfc, RfcDraft11};
 use ring::digest;
 
 use super::{TREE_LEAF_TWEAK, TREE_NODE_TWEAK};
@@ -25,11 +24,15 @@ use super::{TREE_LEAF_TWEAK, TREE_NODE_TWEAK};
 type Data = Vec<u8>;
 type Hash = Data;
 
+/// The sibling hashes from a leaf up to the root, concatenated as in a response's `PATH` tag
+pub type MerklePath = Vec<u8>;
+
 ///
 /// Merkle Tree implementation that uses the Roughtime leaf and node tweak values.
 ///
 pub struct MerkleTree {
     levels: Vec<Vec<Data>>,
+    num_leaves: usize,
     algorithm: &'static digest::Algorithm,
     version: Version,
 }
@@ -42,6 +45,7 @@ impl MerkleTree {
     pub fn new_sha512_ietf() -> MerkleTree {
         MerkleTree {
             levels: vec![vec![]],
+            num_leaves: 0,
             algorithm: &digest::SHA512,
             version: Version::Rfc,
         }
@@ -54,17 +58,31 @@ impl MerkleTree {
     pub fn new_sha512_classic() -> MerkleTree {
         MerkleTree {
             levels: vec![vec![]],
+            num_leaves: 0,
             algorithm: &digest::SHA512,
             version: Version::Classic,
         }
     }
 
+    ///
+    /// Create a new empty Merkle Tree of the kind used by protocol `version`
+    ///
+    pub fn for_version(version: Version) -> MerkleTree {
+        MerkleTree {
+            levels: vec![vec![]],
+            num_leaves: 0,
+            algorithm: &digest::SHA512,
+            version,
+        }
+    }
+
     pub fn push_leaf(&mut self, data: &[u8]) {
         let hash = self.hash_leaf(data);
         self.levels[0].push(hash);
+        self.num_leaves += 1;
     }
 
-    pub fn get_paths(&self, mut index: usize) -> Vec<u8> {
+    pub fn get_paths(&self, mut index: usize) -> MerklePath {
         let mut paths = Vec::with_capacity(self.levels.len() * self.algorithm.output_len());
         let mut level = 0;
 
@@ -78,6 +96,26 @@ impl MerkleTree {
         paths
     }
 
+    ///
+    /// The path of every leaf pushed, in leaf order, computed level by level in one pass over
+    /// the tree. Equivalent to calling `get_paths()` for each leaf. Requires `compute_root()`
+    /// to have been called.
+    ///
+    pub fn all_paths(&self) -> Vec<MerklePath> {
+        let depth = self.levels.iter().take_while(|l| !l.is_empty()).count();
+        let path_len = depth * self.algorithm.output_len();
+        let mut paths: Vec<MerklePath> = (0..self.num_leaves)
+            .map(|_| Vec::with_capacity(path_len))
+            .collect();
+
+        for (level, nodes) in self.levels[..depth].iter().enumerate() {
+            for (index, path) in paths.iter_mut().enumerate() {
+                path.extend_from_slice(&nodes[(index >> level) ^ 1]);
+            }
+        }
+        paths
+    }
+
     pub fn compute_root(&mut self) -> Hash {
         assert!(
             !self.levels[0].is_empty(),
@@ -116,10 +154,36 @@ impl MerkleTree {
         self.finalize_output(result)
     }
 
+    ///
+    /// Compute the root of the leaves pushed so far without modifying the tree, so more
+    /// leaves can be pushed afterwards. The result equals what `compute_root()` would return
+    /// for the same leaves.
+    ///
+    pub fn compute_root_nondestructive(&self) -> Hash {
+        assert!(
+            !self.levels[0].is_empty(),
+            "Must have at least one leaf to hash!"
+        );
+
+        // an odd node out is paired with zeros, as compute_root() pads each level
+        let padding = vec![0; self.algorithm.output_len()];
+        let mut nodes = self.levels[0].clone();
+
+        while nodes.len() > 1 {
+            nodes = nodes
+                .chunks(2)
+                .map(|pair| self.hash_nodes(&pair[0], pair.get(1).unwrap_or(&padding)))
+                .collect();
+        }
+
+        self.finalize_output(nodes.pop().unwrap())
+    }
+
     pub fn reset(&mut self) {
         for level in &mut self.levels {
             level.clear();
         }
+        self.num_leaves = 0;
     }
 
     fn hash_leaf(&self, leaf: &[u8]) -> Data {
@@ -171,10 +235,7 @@ impl MerkleTree {
 
     #[inline]
     fn finalize_output(&self, data: Hash) -> Hash {
-        match self.version {
-            Rfc | RfcDraft11 => data[0..32].into(),
-            Classic => data,
-        }
+        data[..self.version.profile().hash_length].into()
     }
 }
 
@@ -200,6 +261,12 @@ mod test {
 
                 assert_eq!(root, computed_root);
             }
+
+            let all_paths = merkle_impl.all_paths();
+            assert_eq!(all_paths.len(), num);
+            for (i, paths) in all_paths.iter().enumerate() {
+                assert_eq!(*paths, merkle_impl.get_paths(i));
+            }
         }
     }
 
@@ -215,5 +282,44 @@ mod test {
     fn not_power_of_two() {
         test_paths_with_num(1);
         test_paths_with_num(20);
+        test_paths_with_num(63);
+    }
+
+    #[test]
+    fn nondestructive_root_while_appending() {
+        for (mut incremental, new_tree) in [
+            (
+                MerkleTree::new_sha512_ietf(),
+                MerkleTree::new_sha512_ietf as fn() -> MerkleTree,
+            ),
+            (
+                MerkleTree::new_sha512_classic(),
+                MerkleTree::new_sha512_classic,
+            ),
+        ] {
+            for num in 1..=20 {
+                incremental.push_leaf(&[num as u8]);
+
+                let mut fresh = new_tree();
+                for i in 1..=num {
+                    fresh.push_leaf(&[i as u8]);
+                }
+
+                // intermediate roots don't disturb the leaves pushed so far
+                assert_eq!(
+                    incremental.compute_root_nondestructive(),
+                    fresh.compute_root()
+                );
+                assert_eq!(incremental.levels[0].len(), num);
+            }
+
+            let root = incremental.compute_root_nondestructive();
+            assert_eq!(incremental.compute_root(), root);
+            for i in 0..20 {
+                let paths = incremental.get_paths(i);
+                let leaf = [(i + 1) as u8];
+                assert_eq!(incremental.root_from_paths(i, &leaf, &paths), root);
+            }
+        }
     }
 }
@@ -15,16 +15,17 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use std::io::{Cursor, Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::iter::once;
 use std::string::String;
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
 
 use crate::error::Error;
 use crate::tag::Tag;
-use crate::REQUEST_FRAMING_BYTES;
+use crate::version::Version;
+use crate::{FRAME_HEADER_LENGTH, REQUEST_FRAMING_BYTES};
 
 const HEX: Encoding = HEXLOWER_PERMISSIVE;
 
@@ -35,6 +36,70 @@ const HEX: Encoding = HEXLOWER_PERMISSIVE;
 pub struct RtMessage {
     tags: Vec<Tag>,
     values: Vec<Vec<u8>>,
+    extensions: Vec<ExtensionField>,
+}
+
+///
+/// A field whose tag this implementation doesn't know, e.g. one added by a later draft,
+/// kept by [`RtMessage::from_bytes_lenient`](struct.RtMessage.html#method.from_bytes_lenient)
+/// so it survives a parse and encode round trip.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionField {
+    /// On-the-wire representation of the tag
+    pub tag: [u8; 4],
+    pub value: Vec<u8>,
+}
+
+impl ExtensionField {
+    // Tags are ordered by their little-endian wire value
+    fn wire_order(&self) -> u32 {
+        LittleEndian::read_u32(&self.tag)
+    }
+}
+
+///
+/// Why and where bytes failed to parse as an `RtMessage`
+///
+#[derive(Debug, PartialEq)]
+pub struct ParseFailure {
+    pub error: Error,
+    /// Byte offset in the message at which the problem was found
+    pub offset: usize,
+    /// The field involved, if known
+    pub tag: Option<Tag>,
+    pub message_length: usize,
+}
+
+impl ParseFailure {
+    fn new(error: Error, offset: usize, tag: Option<Tag>, bytes: &[u8]) -> Self {
+        ParseFailure {
+            error,
+            offset,
+            tag,
+            message_length: bytes.len(),
+        }
+    }
+}
+
+impl Display for ParseFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} at byte {} of {}",
+            self.error, self.offset, self.message_length
+        )?;
+        if let Some(tag) = self.tag {
+            write!(f, ", in the {} field", tag)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ParseFailure> for Error {
+    fn from(failure: ParseFailure) -> Self {
+        failure.error
+    }
 }
 
 impl RtMessage {
@@ -48,6 +113,7 @@ impl RtMessage {
         RtMessage {
             tags: Vec::with_capacity(num_fields as usize),
             values: Vec::with_capacity(num_fields as usize),
+            extensions: Vec::new(),
         }
     }
 
@@ -58,22 +124,40 @@ impl RtMessage {
     /// * `bytes` - On-the-wire representation with any framing removed
     ///
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        RtMessage::parse(bytes, false).map_err(|f| f.error)
+    }
+
+    /// Like [`from_bytes`](#method.from_bytes), but fields whose tags this implementation
+    /// doesn't know (extensions, or tags of other protocol versions) are kept aside as
+    /// [`extensions`](#method.extensions) rather than rejected. The message must otherwise be
+    /// well-formed, with its tags in strictly increasing order.
+    pub fn from_bytes_lenient(bytes: &[u8]) -> Result<Self, Error> {
+        RtMessage::parse(bytes, true).map_err(|f| f.error)
+    }
+
+    /// Like [`from_bytes`](#method.from_bytes), but a failure also tells where in `bytes` the
+    /// message is malformed, for reporting interoperability problems
+    pub fn from_bytes_detailed(bytes: &[u8]) -> Result<Self, ParseFailure> {
+        RtMessage::parse(bytes, false)
+    }
+
+    fn parse(bytes: &[u8], keep_unknown: bool) -> Result<Self, ParseFailure> {
         let bytes_len = bytes.len();
 
         if bytes_len < 4 {
-            return Err(Error::MessageTooShort);
+            return Err(ParseFailure::new(Error::MessageTooShort, 0, None, bytes));
         } else if bytes_len % 4 != 0 {
-            return Err(Error::InvalidAlignment(bytes_len as u32));
+            let error = Error::InvalidAlignment(bytes_len as u32);
+            return Err(ParseFailure::new(error, bytes_len, None, bytes));
         }
 
-        let mut msg = Cursor::new(bytes);
-        let num_tags = msg.read_u32::<LittleEndian>()?;
+        let num_tags = LittleEndian::read_u32(bytes);
 
         match num_tags {
             0 => Ok(RtMessage::with_capacity(0)),
-            1 => RtMessage::single_tag_message(bytes, &mut msg),
-            2..=1024 => RtMessage::multi_tag_message(num_tags, bytes, &mut msg),
-            _ => Err(Error::InvalidNumTags(num_tags)),
+            1 => RtMessage::single_tag_message(bytes, keep_unknown),
+            2..=1024 => RtMessage::multi_tag_message(num_tags, bytes, keep_unknown),
+            _ => Err(ParseFailure::new(Error::InvalidNumTags(num_tags), 0, None, bytes)),
         }
     }
 
@@ -84,24 +168,30 @@ impl RtMessage {
     /// ecosystem](https://roughtime.googlesource.com/roughtime/+/HEAD/ECOSYSTEM.md#maintaining-a-healthy-software-ecosystem).
     ///
     pub fn new_deliberately_invalid(tags: Vec<Tag>, values: Vec<Vec<u8>>) -> Self {
-        RtMessage { tags, values }
+        RtMessage {
+            tags,
+            values,
+            extensions: Vec::new(),
+        }
     }
 
     /// Internal function to create a single tag message
-    fn single_tag_message(bytes: &[u8], msg: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+    fn single_tag_message(bytes: &[u8], keep_unknown: bool) -> Result<Self, ParseFailure> {
         if bytes.len() < 8 {
-            return Err(Error::MessageTooShort);
+            return Err(ParseFailure::new(Error::MessageTooShort, 4, None, bytes));
         }
 
-        let pos = msg.position() as usize;
-        msg.set_position((pos + 4) as u64);
-
-        let mut value = Vec::new();
-        msg.read_to_end(&mut value)?;
-
-        let tag = Tag::from_wire(&bytes[pos..pos + 4])?;
         let mut rt_msg = RtMessage::with_capacity(1);
-        rt_msg.add_field(tag, &value)?;
+        match Tag::from_wire(&bytes[4..8]) {
+            Ok(tag) => rt_msg
+                .add_field(tag, &bytes[8..])
+                .map_err(|e| ParseFailure::new(e, 8, Some(tag), bytes))?,
+            Err(_) if keep_unknown => rt_msg.extensions.push(ExtensionField {
+                tag: [bytes[4], bytes[5], bytes[6], bytes[7]],
+                value: bytes[8..].to_vec(),
+            }),
+            Err(e) => return Err(ParseFailure::new(e, 4, None, bytes)),
+        }
 
         Ok(rt_msg)
     }
@@ -110,53 +200,71 @@ impl RtMessage {
     fn multi_tag_message(
         num_tags: u32,
         bytes: &[u8],
-        msg: &mut Cursor<&[u8]>,
-    ) -> Result<Self, Error> {
+        keep_unknown: bool,
+    ) -> Result<Self, ParseFailure> {
         let bytes_len = bytes.len();
-        let mut offsets = Vec::with_capacity((num_tags - 1) as usize);
+        let num_tags = num_tags as usize;
+        let mut offsets = Vec::with_capacity(num_tags - 1);
 
-        for _ in 0..num_tags - 1 {
-            let offset = msg.read_u32::<LittleEndian>()?;
+        // The header is the number of tags, the offset of every value but the first, and then
+        // the tags
+        for pos in (4..4 * num_tags).step_by(4) {
+            let offset = match bytes.get(pos..pos + 4) {
+                Some(word) => LittleEndian::read_u32(word),
+                None => return Err(ParseFailure::new(Error::MessageTooShort, pos, None, bytes)),
+            };
 
             if offset % 4 != 0 {
-                return Err(Error::InvalidAlignment(offset));
+                let error = Error::InvalidAlignment(offset);
+                return Err(ParseFailure::new(error, pos, None, bytes));
             } else if offset > bytes_len as u32 {
-                return Err(Error::InvalidOffsetValue(offset));
+                let error = Error::InvalidOffsetValue(offset);
+                return Err(ParseFailure::new(error, pos, None, bytes));
             }
 
             offsets.push(offset as usize);
         }
 
-        let mut buf = [0; 4];
-        let mut tags = Vec::with_capacity(num_tags as usize);
-
-        for _ in 0..num_tags {
-            if msg.read_exact(&mut buf).is_err() {
-                return Err(Error::MessageTooShort);
+        let mut tags = Vec::with_capacity(num_tags);
+        let mut last_wire_value = None;
+
+        // Unknown tags, if kept, are None. Known tags are declared in the order of their
+        // little-endian wire values, so comparing wire values orders both alike.
+        for pos in (4 * num_tags..8 * num_tags).step_by(4) {
+            let wire = match bytes.get(pos..pos + 4) {
+                Some(wire) => wire,
+                None => return Err(ParseFailure::new(Error::MessageTooShort, pos, None, bytes)),
+            };
+
+            let tag = match Tag::from_wire(wire) {
+                Ok(tag) => Some(tag),
+                Err(_) if keep_unknown => None,
+                Err(e) => return Err(ParseFailure::new(e, pos, None, bytes)),
+            };
+
+            let wire_value = LittleEndian::read_u32(wire);
+            if last_wire_value.is_some_and(|last| wire_value <= last) {
+                let error = match tag {
+                    Some(tag) => Error::TagNotStrictlyIncreasing(tag),
+                    None => Error::InvalidTag(Box::from(wire)),
+                };
+                return Err(ParseFailure::new(error, pos, tag, bytes));
             }
 
-            let tag = Tag::from_wire(&buf)?;
-
-            if let Some(last_tag) = tags.last() {
-                if tag <= *last_tag {
-                    return Err(Error::TagNotStrictlyIncreasing(tag));
-                }
-            }
-
-            tags.push(tag);
+            last_wire_value = Some(wire_value);
+            tags.push((tag, [wire[0], wire[1], wire[2], wire[3]]));
         }
 
-        // All offsets are relative to the end of the header,
-        // which is our current position
-        let header_end = msg.position() as usize;
+        // All offsets are relative to the end of the header
+        let header_end = 8 * num_tags;
 
         // Compute the end of the last value,
         // as an offset from the end of the header
-        let msg_end = bytes.len() - header_end;
+        let msg_end = bytes_len - header_end;
 
-        let mut rt_msg = RtMessage::with_capacity(num_tags);
+        let mut rt_msg = RtMessage::with_capacity(num_tags as u32);
 
-        for (tag, (value_start, value_end)) in tags.into_iter().zip(
+        for ((tag, wire), (value_start, value_end)) in tags.into_iter().zip(
             once(&0)
                 .chain(offsets.iter())
                 .zip(offsets.iter().chain(once(&msg_end))),
@@ -165,11 +273,22 @@ impl RtMessage {
             let end_idx = header_end + value_end;
 
             if end_idx > bytes_len || start_idx > end_idx {
-                return Err(Error::InvalidValueLength(tag, end_idx as u32));
+                let error = match tag {
+                    Some(tag) => Error::InvalidValueLength(tag, end_idx as u32),
+                    None => Error::InvalidOffsetValue(end_idx as u32),
+                };
+                return Err(ParseFailure::new(error, start_idx, tag, bytes));
             }
 
-            let value = bytes[start_idx..end_idx].to_vec();
-            rt_msg.add_field(tag, &value)?;
+            match tag {
+                Some(tag) => rt_msg
+                    .add_field(tag, &bytes[start_idx..end_idx])
+                    .map_err(|e| ParseFailure::new(e, start_idx, Some(tag), bytes))?,
+                None => rt_msg.extensions.push(ExtensionField {
+                    tag: wire,
+                    value: bytes[start_idx..end_idx].to_vec(),
+                }),
+            }
         }
 
         Ok(rt_msg)
@@ -214,11 +333,42 @@ impl RtMessage {
         None
     }
 
-    /// Returns the number of tag/value pairs in the message
+    /// Returns the number of tag/value pairs in the message, not counting extensions
     pub fn num_fields(&self) -> u32 {
         self.tags.len() as u32
     }
 
+    /// Fields with tags this implementation doesn't know, in wire order. Only messages parsed
+    /// with [`from_bytes_lenient`](#method.from_bytes_lenient) have any.
+    pub fn extensions(&self) -> &[ExtensionField] {
+        &self.extensions
+    }
+
+    /// Retrieve the value of the extension field with the on-the-wire tag `tag`, if present
+    pub fn get_extension(&self, tag: &[u8; 4]) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|ext| &ext.tag == tag)
+            .map(|ext| ext.value.as_slice())
+    }
+
+    // The wire tags and values of all fields, extensions included, in wire order
+    fn wire_fields(&self) -> Vec<(&[u8], &[u8])> {
+        let mut fields = Vec::with_capacity(self.tags.len() + self.extensions.len());
+        let mut extensions = self.extensions.iter().peekable();
+
+        for (tag, value) in self.tags.iter().zip(self.values.iter()) {
+            let order = LittleEndian::read_u32(tag.wire_value());
+            while let Some(ext) = extensions.next_if(|ext| ext.wire_order() < order) {
+                fields.push((&ext.tag[..], ext.value.as_slice()));
+            }
+            fields.push((tag.wire_value(), value.as_slice()));
+        }
+        fields.extend(extensions.map(|ext| (&ext.tag[..], ext.value.as_slice())));
+
+        fields
+    }
+
     /// Returns a slice of the tags in the message
     pub fn tags(&self) -> &[Tag] {
         &self.tags
@@ -245,9 +395,19 @@ impl RtMessage {
         Ok(frame)
     }
 
+    /// Encode this message as protocol `version` sends it, with RFC framing if the version
+    /// is framed.
+    pub fn encode_for(&self, version: Version) -> Result<Vec<u8>, Error> {
+        match version.profile().framed {
+            true => self.encode_framed(),
+            false => self.encode(),
+        }
+    }
+
     /// Encode this message into its on-the-wire representation.
     pub fn encode(&self) -> Result<Vec<u8>, Error> {
-        let num_tags = self.tags.len();
+        let fields = self.wire_fields();
+        let num_tags = fields.len();
         let mut out = Vec::with_capacity(self.encoded_size());
 
         // number of tags
@@ -255,21 +415,21 @@ impl RtMessage {
 
         // offset(s) to values, IFF there are two or more tags
         if num_tags > 1 {
-            let mut offset_sum = self.values[0].len();
+            let mut offset_sum = fields[0].1.len();
 
-            for val in &self.values[1..] {
+            for (_, val) in &fields[1..] {
                 out.write_u32::<LittleEndian>(offset_sum as u32)?;
                 offset_sum += val.len();
             }
         }
 
         // write tags
-        for tag in &self.tags {
-            out.write_all(tag.wire_value())?;
+        for (tag, _) in &fields {
+            out.write_all(tag)?;
         }
 
         // write values
-        for value in &self.values {
+        for (_, value) in &fields {
             out.write_all(value)?;
         }
 
@@ -281,10 +441,11 @@ impl RtMessage {
 
     /// Returns the length in bytes of this message's on-the-wire representation.
     pub fn encoded_size(&self) -> usize {
-        let num_tags = self.tags.len();
+        let num_tags = self.tags.len() + self.extensions.len();
         let tags_size = 4 * num_tags;
         let offsets_size = if num_tags < 2 { 0 } else { 4 * (num_tags - 1) };
-        let values_size: usize = self.values.iter().map(|v| v.len()).sum();
+        let values_size: usize = self.values.iter().map(|v| v.len()).sum::<usize>()
+            + self.extensions.iter().map(|e| e.value.len()).sum::<usize>();
 
         4 + tags_size + offsets_size + values_size
     }
@@ -298,7 +459,7 @@ impl RtMessage {
         }
 
         let mut padding_needed = 1024 - size;
-        if self.tags.len() == 1 {
+        if self.tags.len() + self.extensions.len() == 1 {
             // If we currently only have one tag, adding a padding tag will cause
             // a 32-bit offset value to be written
             padding_needed -= 4;
@@ -311,6 +472,7 @@ impl RtMessage {
     pub fn clear(&mut self) {
         self.tags.clear();
         self.values.clear();
+        self.extensions.clear();
     }
 
     pub fn to_string(&self, indent_level: usize) -> String {
@@ -348,6 +510,140 @@ impl RtMessage {
 
         result
     }
+
+    ///
+    /// The on-the-wire representation of this message as annotated hex for debugging, 16
+    /// bytes per line. Each line starts with its byte offset, and each part of the message
+    /// (the number of tags, each offset, tag, and value) is labeled. Nested messages (`SREP`,
+    /// `CERT`, `DELE`) are broken down the same way, labeled with their path, e.g.
+    /// `CERT.DELE.PUBK`.
+    ///
+    pub fn to_annotated_hex(&self) -> Result<String, Error> {
+        let mut out = String::new();
+        self.annotate_hex(&mut out, 0, "")?;
+        Ok(out)
+    }
+
+    fn annotate_hex(&self, out: &mut String, base: usize, path: &str) -> Result<(), Error> {
+        let encoded = self.encode()?;
+        let fields = self.wire_fields();
+        let names: Vec<String> = fields.iter().map(|(tag, _)| wire_tag_name(tag)).collect();
+        let num_tags = fields.len();
+
+        hex_lines(out, base, &encoded[..4], format!("{}num tags = {}", path, num_tags));
+        let mut pos = 4;
+        for name in names.iter().skip(1) {
+            let offset = LittleEndian::read_u32(&encoded[pos..pos + 4]);
+            let label = format!("{}offset of {} = {}", path, name, offset);
+            hex_lines(out, base + pos, &encoded[pos..pos + 4], label);
+            pos += 4;
+        }
+        for name in &names {
+            let label = format!("{}tag {}", path, name);
+            hex_lines(out, base + pos, &encoded[pos..pos + 4], label);
+            pos += 4;
+        }
+
+        for ((tag, value), name) in fields.iter().zip(names.iter()) {
+            let nested = match Tag::from_wire(tag) {
+                Ok(tag) if tag.is_nested() => RtMessage::from_bytes_lenient(value).ok(),
+                _ => None,
+            };
+            match nested {
+                Some(msg) => msg.annotate_hex(out, base + pos, &format!("{}{}.", path, name))?,
+                None => {
+                    let label = format!("{}{} ({} bytes)", path, name, value.len());
+                    hex_lines(out, base + pos, value, label);
+                }
+            }
+            pos += value.len();
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// A stable JSON representation of this message for inspection and debugging tools:
+    ///
+    /// ```json
+    /// {"fields":[{"tag":"RADI","length":4,"hex":"05000000","value":5},{"tag":"SREP","length":84,"message":{"fields":[...]}}]}
+    /// ```
+    ///
+    /// Fields are listed in wire order. Each has its `tag`, the `length` of its value in
+    /// bytes, and the value as `hex`; a nested message (`SREP`, `CERT`, `DELE`) that parses
+    /// has a `message` object of the same structure instead of `hex`. Fields with a known
+    /// interpretation also have a derived `value`: a number for `RADI`, `INDX`, `MIDP`,
+    /// `MINT`, and `MAXT`, and an array of version numbers for `VER` and `VERS`.
+    ///
+    pub fn to_debug_json(&self) -> String {
+        let fields: Vec<String> = self
+            .tags
+            .iter()
+            .zip(self.values.iter())
+            .map(|(tag, value)| {
+                let mut field = format!(r#"{{"tag":"{}","length":{}"#, tag, value.len());
+
+                let nested = match tag.is_nested() {
+                    true => RtMessage::from_bytes(value).ok(),
+                    false => None,
+                };
+                match nested {
+                    Some(msg) => field.push_str(&format!(r#","message":{}"#, msg.to_debug_json())),
+                    None => field.push_str(&format!(r#","hex":"{}""#, HEX.encode(value))),
+                }
+
+                let derived = match (tag, value.len()) {
+                    (Tag::RADI | Tag::INDX, 4) => Some(LittleEndian::read_u32(value).to_string()),
+                    (Tag::MIDP | Tag::MINT | Tag::MAXT, 8) => {
+                        Some(LittleEndian::read_u64(value).to_string())
+                    }
+                    (Tag::VER | Tag::VERS, len) if len % 4 == 0 => {
+                        let versions: Vec<String> = value
+                            .chunks(4)
+                            .map(|v| LittleEndian::read_u32(v).to_string())
+                            .collect();
+                        Some(format!("[{}]", versions.join(",")))
+                    }
+                    _ => None,
+                };
+                if let Some(derived) = derived {
+                    field.push_str(&format!(r#","value":{}"#, derived));
+                }
+
+                field.push('}');
+                field
+            })
+            .collect();
+
+        format!(r#"{{"fields":[{}]}}"#, fields.join(","))
+    }
+}
+
+// A tag's name for display: known tags by name, unknown printable ones as text, others in hex
+fn wire_tag_name(wire: &[u8]) -> String {
+    match Tag::from_wire(wire) {
+        Ok(tag) => tag.to_string(),
+        Err(_) if wire.iter().all(|b| b.is_ascii_graphic()) => {
+            String::from_utf8_lossy(wire).into_owned()
+        }
+        Err(_) => HEX.encode(wire),
+    }
+}
+
+// Append `bytes`, found at `offset`, as lines of 16 bytes in hex. The first line is labeled.
+fn hex_lines(out: &mut String, offset: usize, bytes: &[u8], label: String) {
+    let mut label = Some(label);
+    let mut chunks: Vec<&[u8]> = bytes.chunks(16).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let label = label.take().unwrap_or_default();
+        let line = format!("{:04x}  {:<32}  {}", offset + 16 * i, HEX.encode(chunk), label);
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
 }
 
 impl Display for RtMessage {
@@ -356,6 +652,109 @@ impl Display for RtMessage {
     }
 }
 
+///
+/// Splits a byte stream (e.g. a TCP connection) into the messages of its ROUGHTIM frames.
+/// A frame may arrive split over several reads and one read may hold several frames, so
+/// bytes are buffered until a whole frame is available.
+///
+/// Frames for the other direction are made by
+/// [`RtMessage::encode_framed`](struct.RtMessage.html#method.encode_framed).
+///
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    max_length: usize,
+}
+
+impl FrameDecoder {
+    /// A decoder of frames of at most `max_length` bytes, including the frame header
+    pub fn new(max_length: usize) -> Self {
+        FrameDecoder {
+            buf: Vec::with_capacity(max_length),
+            max_length,
+        }
+    }
+
+    /// Append bytes received from the stream
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Number of bytes received that are not yet part of a returned message
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    ///
+    /// The message of the next complete frame, without the frame header, or `Ok(None)` if
+    /// more bytes are needed. Non-blocking streams can `push` whatever each read returns and
+    /// call this until it returns `Ok(None)`.
+    ///
+    /// An error means the stream has lost sync and should be closed.
+    ///
+    pub fn next_message(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let magic_len = self.buf.len().min(REQUEST_FRAMING_BYTES.len());
+        if self.buf[..magic_len] != REQUEST_FRAMING_BYTES[..magic_len] {
+            return Err(Error::InvalidFraming("missing ROUGHTIM framing bytes".into()));
+        }
+
+        if self.buf.len() < FRAME_HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        let reported_len =
+            LittleEndian::read_u32(&self.buf[REQUEST_FRAMING_BYTES.len()..FRAME_HEADER_LENGTH]);
+        let frame_len = FRAME_HEADER_LENGTH.saturating_add(reported_len as usize);
+        if frame_len > self.max_length {
+            return Err(Error::InvalidFraming(format!(
+                "frame of {} bytes exceeds the limit of {}",
+                frame_len, self.max_length
+            )));
+        }
+
+        if self.buf.len() < frame_len {
+            return Ok(None);
+        }
+
+        let message = self.buf[FRAME_HEADER_LENGTH..frame_len].to_vec();
+        self.buf.drain(..frame_len);
+        Ok(Some(message))
+    }
+
+    ///
+    /// Read from a blocking `stream` until the next message is complete. Returns `Ok(None)`
+    /// if the stream ends cleanly between frames; bytes of a later frame read along the way
+    /// stay buffered for the next call.
+    ///
+    pub fn read_message<R: Read>(&mut self, stream: &mut R) -> Result<Option<Vec<u8>>, Error> {
+        let mut chunk = [0u8; 1024];
+
+        loop {
+            if let Some(message) = self.next_message()? {
+                return Ok(Some(message));
+            }
+
+            let num_bytes = match stream.read(&mut chunk) {
+                Ok(num_bytes) => num_bytes,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            if num_bytes == 0 {
+                return match self.buf.is_empty() {
+                    true => Ok(None),
+                    false => Err(Error::InvalidFraming(format!(
+                        "stream ended {} bytes into a frame",
+                        self.buf.len()
+                    ))),
+                };
+            }
+
+            self.push(&chunk[..num_bytes]);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::io::{Cursor, Read};
@@ -549,4 +948,180 @@ mod test {
         let bytes = &[0x02, 0, 0, 0, 4, 0, 0, 0, 0, 0];
         RtMessage::from_bytes(bytes).unwrap();
     }
+
+    #[test]
+    fn lenient_parsing_keeps_unknown_tags() {
+        // VER, NONC, an unknown TYPE, and ZZZZ, in increasing order of their wire values
+        let mut bytes = vec![];
+        for word in [4u32, 4, 8, 12] {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        for tag in [&b"VER\x00"[..], b"NONC", b"TYPE", b"ZZZZ"] {
+            bytes.extend_from_slice(tag);
+        }
+        bytes.extend_from_slice(&[1, 0, 0, 0, 0x42, 0x42, 0x42, 0x42, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert!(matches!(
+            RtMessage::from_bytes(&bytes),
+            Err(Error::InvalidTag(_))
+        ));
+
+        let msg = RtMessage::from_bytes_lenient(&bytes).unwrap();
+        assert_eq!(msg.tags(), &[Tag::VER, Tag::NONC, Tag::ZZZZ]);
+        assert_eq!(msg.get_field(Tag::NONC), Some(&[0x42u8; 4][..]));
+        assert_eq!(msg.get_field(Tag::ZZZZ), Some(&[0u8; 4][..]));
+
+        // the unknown field is kept, and written back in place
+        assert_eq!(msg.extensions().len(), 1);
+        assert_eq!(msg.get_extension(b"TYPE"), Some(&[0u8; 4][..]));
+        assert_eq!(msg.encoded_size(), bytes.len());
+        assert_eq!(msg.encode().unwrap(), bytes);
+
+        // unknown tags must still be in order
+        bytes[24..28].copy_from_slice(b"AAAA");
+        assert!(matches!(
+            RtMessage::from_bytes_lenient(&bytes),
+            Err(Error::InvalidTag(_))
+        ));
+    }
+
+    #[test]
+    fn parse_failures_locate_the_problem() {
+        // SIG and NONC with the offset of NONC's value past the end of the message
+        let mut bytes = vec![];
+        for word in [2u32, 84] {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(b"SIG\x00NONC");
+        bytes.extend_from_slice(&[0u8; 64]);
+
+        let failure = RtMessage::from_bytes_detailed(&bytes).unwrap_err();
+        assert_eq!(failure.error, Error::InvalidOffsetValue(84));
+        assert_eq!(failure.offset, 4);
+        assert_eq!(failure.tag, None);
+        assert_eq!(failure.message_length, 80);
+
+        // now the offset is within the message, but SIG's value would run past its end
+        bytes[4..8].copy_from_slice(&20u32.to_le_bytes());
+        bytes.truncate(24);
+        let failure = RtMessage::from_bytes_detailed(&bytes).unwrap_err();
+        assert_eq!(failure.error, Error::InvalidValueLength(Tag::SIG, 36));
+        assert_eq!(failure.offset, 16);
+        assert_eq!(failure.tag, Some(Tag::SIG));
+        assert_eq!(
+            failure.to_string(),
+            "InvalidValueLength(SIG, 36) at byte 16 of 24, in the SIG field"
+        );
+
+        // the plain error is the same
+        assert_eq!(RtMessage::from_bytes(&bytes).unwrap_err(), failure.error);
+    }
+
+    #[test]
+    fn frames_are_reassembled_from_a_stream() {
+        let mut first = RtMessage::with_capacity(1);
+        first.add_field(Tag::NONC, &[0x11; 32]).unwrap();
+        let mut second = RtMessage::with_capacity(1);
+        second.add_field(Tag::NONC, &[0x22; 32]).unwrap();
+
+        let mut stream = first.encode_framed().unwrap();
+        stream.extend(second.encode_framed().unwrap());
+
+        // one byte at a time
+        let mut decoder = FrameDecoder::new(1024);
+        let mut messages = vec![];
+        for byte in stream.iter() {
+            decoder.push(&[*byte]);
+            while let Some(message) = decoder.next_message().unwrap() {
+                messages.push(message);
+            }
+        }
+        assert_eq!(messages, vec![first.encode().unwrap(), second.encode().unwrap()]);
+        assert_eq!(decoder.buffered(), 0);
+
+        // both frames in one read
+        let mut decoder = FrameDecoder::new(1024);
+        let mut reader = Cursor::new(stream.clone());
+        assert_eq!(
+            decoder.read_message(&mut reader).unwrap(),
+            Some(first.encode().unwrap())
+        );
+        assert_eq!(
+            decoder.read_message(&mut reader).unwrap(),
+            Some(second.encode().unwrap())
+        );
+        assert_eq!(decoder.read_message(&mut reader).unwrap(), None);
+
+        // a stream ending mid-frame
+        let mut reader = Cursor::new(stream[..50].to_vec());
+        assert!(matches!(
+            FrameDecoder::new(1024).read_message(&mut reader),
+            Err(Error::InvalidFraming(_))
+        ));
+    }
+
+    #[test]
+    fn bad_frames_are_rejected() {
+        let mut decoder = FrameDecoder::new(1024);
+        decoder.push(b"ROUGH");
+        assert_eq!(decoder.next_message(), Ok(None));
+        decoder.push(b"TIN");
+        assert!(matches!(
+            decoder.next_message(),
+            Err(Error::InvalidFraming(_))
+        ));
+
+        let mut decoder = FrameDecoder::new(1024);
+        decoder.push(b"ROUGHTIM");
+        decoder.push(&2048u32.to_le_bytes());
+        assert!(matches!(
+            decoder.next_message(),
+            Err(Error::InvalidFraming(_))
+        ));
+    }
+
+    #[test]
+    fn annotated_hex_labels_every_part() {
+        let mut dele = RtMessage::with_capacity(1);
+        dele.add_field(Tag::PUBK, &[0xaa; 4]).unwrap();
+        let mut msg = RtMessage::with_capacity(2);
+        msg.add_field(Tag::NONC, &[0x42; 20]).unwrap();
+        msg.add_field(Tag::DELE, &dele.encode().unwrap()).unwrap();
+
+        let expected = "\
+0000  02000000                          num tags = 2
+0004  14000000                          offset of DELE = 20
+0008  4e4f4e43                          tag NONC
+000c  44454c45                          tag DELE
+0010  42424242424242424242424242424242  NONC (20 bytes)
+0020  42424242
+0024  01000000                          DELE.num tags = 1
+0028  5055424b                          DELE.tag PUBK
+002c  aaaaaaaa                          DELE.PUBK (4 bytes)
+";
+        assert_eq!(msg.to_annotated_hex().unwrap(), expected);
+    }
+
+    #[test]
+    fn debug_json_structure() {
+        let mut srep = RtMessage::with_capacity(2);
+        srep.add_field(Tag::RADI, &5u32.to_le_bytes()).unwrap();
+        srep.add_field(Tag::MIDP, &1_700_000_000u64.to_le_bytes()).unwrap();
+
+        let mut msg = RtMessage::with_capacity(3);
+        msg.add_field(Tag::SIG, &[0xab, 0xcd]).unwrap();
+        msg.add_field(Tag::VER, &[0x0c, 0, 0, 0x80, 1, 0, 0, 0]).unwrap();
+        msg.add_field(Tag::SREP, &srep.encode().unwrap()).unwrap();
+
+        assert_eq!(
+            msg.to_debug_json(),
+            concat!(
+                r#"{"fields":[{"tag":"SIG","length":2,"hex":"abcd"},"#,
+                r#"{"tag":"VER","length":8,"hex":"0c00008001000000","value":[2147483660,1]},"#,
+                r#"{"tag":"SREP","length":28,"message":{"fields":["#,
+                r#"{"tag":"RADI","length":4,"hex":"05000000","value":5},"#,
+                r#"{"tag":"MIDP","length":8,"hex":"00f1536500000000","value":1700000000}]}}]}"#
+            )
+        );
+    }
 }
@@ -0,0 +1,187 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! **Dangerous**: deliberately wrong server clocks for testing clients.
+//!
+//! Only compiled with the `dangerous-testing` feature. A server running with a
+//! [`Misbehavior`](enum.Misbehavior.html) returns validly signed responses containing the
+//! wrong time, so client developers (and tools that detect and report misbehaving servers)
+//! can be tested end-to-end against a genuinely misbehaving server.
+//!
+//! **Never** enable this on a server that real clients use.
+//!
+
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use rand::rngs::SmallRng;
+use rand::{FromEntropy, Rng};
+
+use crate::Error;
+
+/// Largest jump (in seconds, in either direction) of `random-jump` when no bound is given
+pub const DEFAULT_MAX_JUMP_SECS: u64 = 3600;
+
+///
+/// Ways the server's clock can misbehave.
+///
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Misbehavior {
+    /// Offset every response's time by a fixed number of seconds (`skew-seconds=<N>`, N may be
+    /// negative).
+    SkewSeconds(i64),
+
+    /// Respond with the time the clock was created, forever (`frozen-clock`).
+    FrozenClock,
+
+    /// Offset each batch's time by a random amount of at most the given number of seconds in
+    /// either direction (`random-jump[=<N>]`).
+    RandomJump(u64),
+}
+
+impl FromStr for Misbehavior {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, value) = match s.split_once('=') {
+            Some((mode, value)) => (mode, Some(value)),
+            None => (s, None),
+        };
+
+        let invalid = || {
+            Error::InvalidConfiguration(format!(
+                "invalid misbehavior '{}'; valid modes are skew-seconds=<N>, frozen-clock, \
+                 and random-jump[=<N>]",
+                s
+            ))
+        };
+
+        match (mode, value) {
+            ("skew-seconds", Some(v)) => {
+                Ok(Misbehavior::SkewSeconds(v.parse().map_err(|_| invalid())?))
+            }
+            ("frozen-clock", None) => Ok(Misbehavior::FrozenClock),
+            ("random-jump", None) => Ok(Misbehavior::RandomJump(DEFAULT_MAX_JUMP_SECS)),
+            ("random-jump", Some(v)) => {
+                Ok(Misbehavior::RandomJump(v.parse().map_err(|_| invalid())?))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+///
+/// A clock that misbehaves in the manner of its [`Misbehavior`](enum.Misbehavior.html).
+///
+pub struct MisbehavingClock {
+    misbehavior: Misbehavior,
+    created_at: SystemTime,
+    prng: SmallRng,
+}
+
+impl MisbehavingClock {
+    pub fn new(misbehavior: Misbehavior) -> Self {
+        MisbehavingClock {
+            misbehavior,
+            created_at: SystemTime::now(),
+            prng: SmallRng::from_entropy(),
+        }
+    }
+
+    /// The misbehavior of this clock
+    pub fn misbehavior(&self) -> Misbehavior {
+        self.misbehavior
+    }
+
+    /// The (wrong) current time
+    pub fn now(&mut self) -> SystemTime {
+        let now = SystemTime::now();
+
+        match self.misbehavior {
+            Misbehavior::SkewSeconds(secs) => offset(now, secs),
+            Misbehavior::FrozenClock => self.created_at,
+            Misbehavior::RandomJump(max) => {
+                let max = max.min(i64::MAX as u64) as i64;
+                let jump = self.prng.gen_range(-max, max.saturating_add(1));
+                offset(now, jump)
+            }
+        }
+    }
+}
+
+fn offset(time: SystemTime, secs: i64) -> SystemTime {
+    let delta = Duration::from_secs(secs.unsigned_abs());
+    if secs < 0 {
+        time - delta
+    } else {
+        time + delta
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use crate::misbehave::{MisbehavingClock, Misbehavior, DEFAULT_MAX_JUMP_SECS};
+
+    #[test]
+    fn parse_modes() {
+        assert_eq!(
+            "skew-seconds=-90".parse::<Misbehavior>().unwrap(),
+            Misbehavior::SkewSeconds(-90)
+        );
+        assert_eq!(
+            "frozen-clock".parse::<Misbehavior>().unwrap(),
+            Misbehavior::FrozenClock
+        );
+        assert_eq!(
+            "random-jump".parse::<Misbehavior>().unwrap(),
+            Misbehavior::RandomJump(DEFAULT_MAX_JUMP_SECS)
+        );
+        assert_eq!(
+            "random-jump=10".parse::<Misbehavior>().unwrap(),
+            Misbehavior::RandomJump(10)
+        );
+
+        for bad in [
+            "",
+            "skew-seconds",
+            "skew-seconds=x",
+            "frozen-clock=1",
+            "sideways",
+        ] {
+            assert!(bad.parse::<Misbehavior>().is_err(), "accepted '{}'", bad);
+        }
+    }
+
+    #[test]
+    fn clocks_misbehave() {
+        let before = SystemTime::now();
+        let mut skewed = MisbehavingClock::new(Misbehavior::SkewSeconds(-3600));
+        assert!(skewed.now() < before - Duration::from_secs(3500));
+
+        let mut frozen = MisbehavingClock::new(Misbehavior::FrozenClock);
+        let first = frozen.now();
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(frozen.now(), first);
+
+        let mut jumpy = MisbehavingClock::new(Misbehavior::RandomJump(60));
+        for _ in 0..100 {
+            let t = jumpy.now();
+            assert!(t >= before - Duration::from_secs(60));
+            assert!(t <= SystemTime::now() + Duration::from_secs(60));
+        }
+    }
+}
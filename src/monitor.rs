@@ -0,0 +1,449 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Black-box monitoring of Roughtime servers.
+//!
+//! A monitor probes each server periodically and records every outcome in the server's
+//! [`ServerHealth`](struct.ServerHealth.html), which raises an [`Alert`](enum.Alert.html)
+//! when a probe violates the configured [`Thresholds`](struct.Thresholds.html):
+//!
+//!   * the success rate over the most recent probes falls below a minimum,
+//!   * a response's round-trip time exceeds a maximum, or
+//!   * the time that passed between two responses, according to the server, is inconsistent
+//!     with the time that passed locally (interval sanity).
+//!
+//! Alerts can be delivered to an HTTP webhook with [`post_webhook`](fn.post_webhook.html).
+//!
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::http;
+use crate::stats::LatencyHistogram;
+use crate::Error;
+
+/// Default number of recent probes the success rate is computed over
+pub const DEFAULT_WINDOW: usize = 20;
+
+/// Outcome of one probe of a server
+#[derive(Debug, Clone)]
+pub enum ProbeOutcome {
+    /// A response that validated. `lower` and `upper` are the earliest and latest time it
+    /// allows, in microseconds since the Unix epoch.
+    Valid {
+        rtt: Duration,
+        lower: u64,
+        upper: u64,
+    },
+
+    /// No valid response, for the reason provided
+    Failed(String),
+}
+
+/// Limits whose violation raises an alert
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// Lowest acceptable percentage (0 to 100) of successful recent probes
+    pub min_success_rate: f64,
+
+    /// Longest acceptable round-trip time of a response
+    pub max_latency: Duration,
+}
+
+/// A violated threshold
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    /// Percentage of recent probes that succeeded, below the threshold
+    SuccessRate { rate: f64, threshold: f64 },
+
+    /// Round-trip time of a response, above the threshold
+    Latency { rtt: Duration, threshold: Duration },
+
+    /// Time between two responses according to the server (earliest and latest possible,
+    /// in microseconds) excludes the time that passed locally
+    IntervalMismatch {
+        local_us: i128,
+        server_min_us: i128,
+        server_max_us: i128,
+    },
+}
+
+impl Alert {
+    /// Short, stable name of the kind of alert
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Alert::SuccessRate { .. } => "success_rate",
+            Alert::Latency { .. } => "latency",
+            Alert::IntervalMismatch { .. } => "interval_mismatch",
+        }
+    }
+
+    /// JSON object describing this alert for `server`
+    pub fn to_json(&self, server: &str) -> String {
+        format!(
+            r#"{{"server":{:?},"alert":"{}","message":{:?}}}"#,
+            server,
+            self.kind(),
+            self.to_string()
+        )
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Alert::SuccessRate { rate, threshold } => write!(
+                f,
+                "success rate {:.1}% is below {:.1}%",
+                rate, threshold
+            ),
+            Alert::Latency { rtt, threshold } => write!(
+                f,
+                "round-trip time {}ms exceeds {}ms",
+                rtt.as_millis(),
+                threshold.as_millis()
+            ),
+            Alert::IntervalMismatch {
+                local_us,
+                server_min_us,
+                server_max_us,
+            } => write!(
+                f,
+                "{}µs passed locally between responses, but the server's times allow only {}µs to {}µs",
+                local_us, server_min_us, server_max_us
+            ),
+        }
+    }
+}
+
+// The last valid response: when it arrived, its round-trip time, and the times it allows
+struct LastValid {
+    at: Instant,
+    rtt: Duration,
+    lower: u64,
+    upper: u64,
+}
+
+///
+/// Probe outcomes of one server, checked against thresholds as they are recorded.
+///
+pub struct ServerHealth {
+    window: usize,
+    recent: VecDeque<bool>,
+    probes: u64,
+    failures: u64,
+    latency: LatencyHistogram,
+    last_valid: Option<LastValid>,
+}
+
+impl ServerHealth {
+    /// Track a server, computing its success rate over the last `window` probes
+    pub fn new(window: usize) -> Self {
+        ServerHealth {
+            window: window.max(1),
+            recent: VecDeque::with_capacity(window.max(1)),
+            probes: 0,
+            failures: 0,
+            latency: LatencyHistogram::new(),
+            last_valid: None,
+        }
+    }
+
+    ///
+    /// Record the outcome of a probe completed at `at`, returning the thresholds it violates.
+    ///
+    /// The success rate is computed over the probes recorded so far until the window fills,
+    /// so a monitor that runs only briefly still alerts on failures.
+    ///
+    pub fn record(
+        &mut self,
+        at: Instant,
+        outcome: &ProbeOutcome,
+        thresholds: &Thresholds,
+    ) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent
+            .push_back(matches!(outcome, ProbeOutcome::Valid { .. }));
+        self.probes += 1;
+
+        match *outcome {
+            ProbeOutcome::Valid { rtt, lower, upper } => {
+                self.latency.record(rtt);
+                if rtt > thresholds.max_latency {
+                    alerts.push(Alert::Latency {
+                        rtt,
+                        threshold: thresholds.max_latency,
+                    });
+                }
+
+                if let Some(prev) = &self.last_valid {
+                    if let Some(alert) = check_interval(prev, at, rtt, lower, upper) {
+                        alerts.push(alert);
+                    }
+                }
+                self.last_valid = Some(LastValid {
+                    at,
+                    rtt,
+                    lower,
+                    upper,
+                });
+            }
+            ProbeOutcome::Failed(_) => self.failures += 1,
+        }
+
+        let rate = self.success_rate();
+        if rate < thresholds.min_success_rate {
+            alerts.push(Alert::SuccessRate {
+                rate,
+                threshold: thresholds.min_success_rate,
+            });
+        }
+
+        alerts
+    }
+
+    /// Percentage (0 to 100) of the recent probes that succeeded; 100 if there were none
+    pub fn success_rate(&self) -> f64 {
+        if self.recent.is_empty() {
+            return 100.0;
+        }
+        let ok = self.recent.iter().filter(|&&ok| ok).count();
+        100.0 * ok as f64 / self.recent.len() as f64
+    }
+
+    /// Total number of probes recorded
+    pub fn probes(&self) -> u64 {
+        self.probes
+    }
+
+    /// Total number of failed probes recorded
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+
+    /// Round-trip times of valid responses
+    pub fn latency(&self) -> &LatencyHistogram {
+        &self.latency
+    }
+
+    /// JSON object summarizing the health of `server`
+    pub fn to_json(&self, server: &str) -> String {
+        let micros = |pct: f64| self.latency.percentile(pct).unwrap_or_default().as_micros();
+
+        format!(
+            r#"{{"server":{:?},"probes":{},"failures":{},"success_rate":{:.1},"latency_p50_us":{},"latency_p99_us":{}}}"#,
+            server,
+            self.probes,
+            self.failures,
+            self.success_rate(),
+            micros(50.0),
+            micros(99.0)
+        )
+    }
+}
+
+// The server's clock must have advanced between two responses by about as much as the local
+// one did. A response's time was taken at some point during its round trip, so the local
+// interval is only known to within the two round-trip times.
+fn check_interval(
+    prev: &LastValid,
+    at: Instant,
+    rtt: Duration,
+    lower: u64,
+    upper: u64,
+) -> Option<Alert> {
+    let local_us = at.saturating_duration_since(prev.at).as_micros() as i128;
+    let slack_us = (prev.rtt + rtt).as_micros() as i128;
+    let server_min_us = lower as i128 - prev.upper as i128;
+    let server_max_us = upper as i128 - prev.lower as i128;
+
+    if local_us + slack_us < server_min_us || local_us - slack_us > server_max_us {
+        Some(Alert::IntervalMismatch {
+            local_us,
+            server_min_us,
+            server_max_us,
+        })
+    } else {
+        None
+    }
+}
+
+///
+/// POST `body` as JSON to `url` (`http://host[:port][/path]`, or `https://` with the `https`
+/// feature), failing unless the webhook answers with a 2xx status within `timeout`.
+///
+pub fn post_webhook(url: &str, body: &str, timeout: Duration) -> Result<(), Error> {
+    http::post_json(&url.parse()?, body, None, timeout)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::monitor::{post_webhook, Alert, ProbeOutcome, ServerHealth, Thresholds};
+    use crate::Error;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        min_success_rate: 50.0,
+        max_latency: Duration::from_millis(100),
+    };
+
+    fn valid(rtt_ms: u64, midpoint_s: u64) -> ProbeOutcome {
+        let radius = 1_000_000;
+        ProbeOutcome::Valid {
+            rtt: Duration::from_millis(rtt_ms),
+            lower: midpoint_s * 1_000_000 - radius,
+            upper: midpoint_s * 1_000_000 + radius,
+        }
+    }
+
+    #[test]
+    fn success_rate_over_window() {
+        let mut health = ServerHealth::new(4);
+        let start = Instant::now();
+        let failed = ProbeOutcome::Failed("timeout".to_string());
+
+        assert!(health
+            .record(start, &valid(10, 100), &THRESHOLDS)
+            .is_empty());
+        assert!(health.record(start, &failed, &THRESHOLDS).is_empty());
+        assert_eq!(health.success_rate(), 50.0);
+
+        let alerts = health.record(start, &failed, &THRESHOLDS);
+        assert_eq!(
+            alerts,
+            vec![Alert::SuccessRate {
+                rate: 100.0 / 3.0,
+                threshold: 50.0
+            }]
+        );
+
+        // once the only success ages out of the window, the rate drops to zero
+        health.record(start, &failed, &THRESHOLDS);
+        assert_eq!(health.success_rate(), 25.0);
+        for _ in 0..4 {
+            health.record(start, &ProbeOutcome::Failed(String::new()), &THRESHOLDS);
+        }
+        assert_eq!(health.success_rate(), 0.0);
+        assert_eq!(health.probes(), 8);
+        assert_eq!(health.failures(), 7);
+    }
+
+    #[test]
+    fn slow_responses_alert() {
+        let mut health = ServerHealth::new(4);
+        let alerts = health.record(Instant::now(), &valid(250, 100), &THRESHOLDS);
+
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind(), "latency");
+        assert_eq!(health.latency().count(), 1);
+    }
+
+    #[test]
+    fn interval_sanity() {
+        let mut health = ServerHealth::new(4);
+        let start = Instant::now();
+        health.record(start, &valid(10, 100), &THRESHOLDS);
+
+        // 60s later locally and according to the server
+        let later = start + Duration::from_secs(60);
+        assert!(health
+            .record(later, &valid(10, 160), &THRESHOLDS)
+            .is_empty());
+
+        // 60s later locally, but the server's clock jumped ahead an hour
+        let later = later + Duration::from_secs(60);
+        let alerts = health.record(later, &valid(10, 3820), &THRESHOLDS);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind(), "interval_mismatch");
+
+        // or stood still
+        let later = later + Duration::from_secs(60);
+        let alerts = health.record(later, &valid(10, 3820), &THRESHOLDS);
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0]
+            .to_json("rt")
+            .contains(r#""alert":"interval_mismatch""#));
+    }
+
+    #[test]
+    fn webhook_posts_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/roughtime", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let mut results = Vec::new();
+            for reply in [
+                "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+                "OK",
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header == "\r\n" {
+                        break;
+                    }
+                    if let Some(len) = header.strip_prefix("Content-Length: ") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                results.push((request_line, String::from_utf8(body).unwrap()));
+
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            results
+        });
+
+        let body = Alert::Latency {
+            rtt: Duration::from_millis(250),
+            threshold: Duration::from_millis(100),
+        }
+        .to_json("rt");
+        let timeout = Duration::from_secs(5);
+
+        assert!(post_webhook(&url, &body, timeout).is_ok());
+        for expected in ["'HTTP/1.1 500 Internal Server Error'", "'OK'"] {
+            match post_webhook(&url, &body, timeout) {
+                Err(Error::UnexpectedHttpStatus(msg)) => {
+                    assert!(msg.ends_with(expected), "{}", msg)
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert!(post_webhook("https://example.com/", &body, timeout).is_err());
+
+        for (request_line, received) in server.join().unwrap() {
+            assert_eq!(request_line, "POST /hooks/roughtime HTTP/1.1\r\n");
+            assert_eq!(received, body);
+        }
+    }
+}
@@ -12,56 +12,220 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! Extract nonces from requests
+//! Build requests and extract nonces from them
 
 use std::io::Cursor;
 
 use byteorder::{LittleEndian, ReadBytesExt};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
 
 use crate::version::Version;
-use crate::{Error, RtMessage, Tag, MAX_REQUEST_LENGTH, MIN_REQUEST_LENGTH, REQUEST_FRAMING_BYTES};
+use crate::{
+    Error, RtMessage, Tag, FRAME_HEADER_LENGTH, MAX_REQUEST_LENGTH, MIN_REQUEST_LENGTH,
+    REQUEST_FRAMING_BYTES,
+};
 
-/// Guess which protocol the request is using and extract the client's nonce from the request
-pub fn nonce_from_request(buf: &[u8], num_bytes: usize, expected_srv: &[u8]) -> Result<(Vec<u8>, Version), Error> {
+/// Size (in bytes) of a pre-shared key request authenticator, HMAC-SHA256(psk, nonce)
+pub const AUTHENTICATOR_LENGTH: usize = 32;
+
+///
+/// Authenticates requests to private deployments using a pre-shared key (PSK).
+///
+/// An authenticated request carries HMAC-SHA256(psk, nonce) in the first 32 bytes of its
+/// padding (`ZZZZ` in RFC requests, `PAD` in classic requests). Padding contents are ignored
+/// by other servers, so authenticated requests remain valid Roughtime requests.
+///
+pub struct RequestAuthenticator {
+    key: hmac::Key,
+}
+
+impl RequestAuthenticator {
+    pub fn new(psk: &[u8]) -> Self {
+        RequestAuthenticator {
+            key: hmac::Key::new(hmac::HMAC_SHA256, psk),
+        }
+    }
+
+    /// Write the authenticator for `nonce` into the start of `padding`
+    pub fn authenticate(&self, nonce: &[u8], padding: &mut [u8]) {
+        let tag = hmac::sign(&self.key, nonce);
+        padding[..AUTHENTICATOR_LENGTH].copy_from_slice(tag.as_ref());
+    }
+
+    /// True if `padding` starts with the authenticator for `nonce`
+    pub fn is_authentic(&self, nonce: &[u8], padding: &[u8]) -> bool {
+        padding.len() >= AUTHENTICATOR_LENGTH
+            && hmac::verify(&self.key, nonce, &padding[..AUTHENTICATOR_LENGTH]).is_ok()
+    }
+}
+
+/// Size (in bytes) of the buffer requests are received into: one byte more than the largest
+/// accepted request. A larger datagram is truncated to this size and then rejected as
+/// `Error::RequestTooLarge`, rather than being truncated to an acceptable length.
+pub const RECV_BUFFER_LENGTH: usize = MAX_REQUEST_LENGTH + 1;
+
+/// A new random nonce of the length protocol `version` uses
+pub fn new_nonce(version: Version) -> Vec<u8> {
+    let mut nonce = vec![0u8; version.profile().nonce_length];
+    SystemRandom::new().fill(&mut nonce).unwrap();
+    nonce
+}
+
+///
+/// Build a request for `version` carrying `nonce`, its message padded to the minimum request
+/// length. RFC requests name the server's long-term key if `srv_value` is provided and must
+/// be sent framed (`encode_framed()`). The padding carries a pre-shared key authenticator if
+/// `authenticator` is provided.
+///
+pub fn make_request(
+    version: Version,
+    nonce: &[u8],
+    srv_value: Option<&[u8]>,
+    authenticator: Option<&RequestAuthenticator>,
+) -> RtMessage {
+    let with_padding = |padding: &[u8]| {
+        let mut msg = RtMessage::with_capacity(4);
+        match version {
+            Version::Classic => {
+                msg.add_field(Tag::NONC, nonce).unwrap();
+                msg.add_field(Tag::PAD, padding).unwrap();
+            }
+            Version::Rfc | Version::RfcDraft11 => {
+                msg.add_field(Tag::VER, version.wire_bytes()).unwrap();
+                if let Some(srv) = srv_value {
+                    msg.add_field(Tag::SRV, srv).unwrap();
+                }
+                msg.add_field(Tag::NONC, nonce).unwrap();
+                msg.add_field(Tag::ZZZZ, padding).unwrap();
+            }
+        }
+        msg
+    };
+
+    let mut padding = vec![0u8; with_padding(&[]).calculate_padding_length()];
+    if let Some(auth) = authenticator {
+        auth.authenticate(nonce, &mut padding);
+    }
+
+    with_padding(&padding)
+}
+
+/// Guess which protocol the request is using and extract the client's nonce from the request.
+/// Tags this implementation doesn't know (e.g. those of newer drafts or extensions) are
+/// ignored. An RFC request whose nonce isn't the length its version uses is rejected with
+/// `Error::InvalidNonceLength`. Classic requests are answered whatever their nonce length, as
+/// RFC messages sent without framing are answered as classic requests.
+///
+/// If `authenticator` is provided, requests without a valid authenticator in their padding are
+/// rejected with `Error::RequestNotAuthenticated`.
+pub fn nonce_from_request(
+    buf: &[u8],
+    num_bytes: usize,
+    expected_srv: &[u8],
+    authenticator: Option<&RequestAuthenticator>,
+) -> Result<(Vec<u8>, Version), Error> {
+    parse_request(buf, num_bytes, expected_srv, authenticator, false)
+}
+
+/// Like [`nonce_from_request`](fn.nonce_from_request.html), but requests with tags this
+/// implementation doesn't know are rejected with `Error::InvalidTag`
+pub fn nonce_from_request_strict(
+    buf: &[u8],
+    num_bytes: usize,
+    expected_srv: &[u8],
+    authenticator: Option<&RequestAuthenticator>,
+) -> Result<(Vec<u8>, Version), Error> {
+    parse_request(buf, num_bytes, expected_srv, authenticator, true)
+}
+
+fn parse_request(
+    buf: &[u8],
+    num_bytes: usize,
+    expected_srv: &[u8],
+    authenticator: Option<&RequestAuthenticator>,
+    strict: bool,
+) -> Result<(Vec<u8>, Version), Error> {
     if num_bytes < MIN_REQUEST_LENGTH {
-        return Err(Error::RequestTooShort)
+        return Err(Error::RequestTooShort);
     } else if num_bytes > MAX_REQUEST_LENGTH {
-        return Err(Error::RequestTooLarge)
+        return Err(Error::RequestTooLarge);
     }
 
     if is_classic_request(buf) {
-        nonce_from_classic_request(&buf[..num_bytes])
+        nonce_from_classic_request(&buf[..num_bytes], authenticator, strict)
     } else {
-        nonce_from_rfc_request(&buf[..num_bytes], expected_srv)
+        nonce_from_rfc_request(&buf[..num_bytes], expected_srv, authenticator, strict)
     }
 }
 
 /// Inspect the message in `buf`, if it doesn't start with RFC framing, we guess
 /// it is a classic request
 fn is_classic_request(buf: &[u8]) -> bool {
-    &buf[0..8] != REQUEST_FRAMING_BYTES
+    &buf[0..REQUEST_FRAMING_BYTES.len()] != REQUEST_FRAMING_BYTES
 }
 
-fn nonce_from_classic_request(buf: &[u8]) -> Result<(Vec<u8>, Version), Error> {
-    let msg = RtMessage::from_bytes(buf)?;
+fn nonce_from_classic_request(
+    buf: &[u8],
+    authenticator: Option<&RequestAuthenticator>,
+    strict: bool,
+) -> Result<(Vec<u8>, Version), Error> {
+    let msg = parse_message(buf, strict)?;
     match msg.get_field(Tag::NONC) {
-        Some(nonce) => Ok((nonce.to_vec(), Version::Classic)),
+        Some(nonce) => {
+            check_authenticator(&msg, Tag::PAD, nonce, authenticator)?;
+            Ok((nonce.to_vec(), Version::Classic))
+        }
         None => Err(Error::InvalidRequest),
     }
 }
 
-// This could be any VER that we support. Extract VER from request and return it.
-fn nonce_from_rfc_request(buf: &[u8], expected_srv: &[u8]) -> Result<(Vec<u8>, Version), Error> {
+fn parse_message(buf: &[u8], strict: bool) -> Result<RtMessage, Error> {
+    match strict {
+        true => RtMessage::from_bytes(buf),
+        false => RtMessage::from_bytes_lenient(buf),
+    }
+}
+
+fn check_nonce_length(nonce: &[u8], version: Version) -> Result<(), Error> {
+    match nonce.len() == version.profile().nonce_length {
+        true => Ok(()),
+        false => Err(Error::InvalidNonceLength(nonce.len())),
+    }
+}
+
+fn check_authenticator(
+    msg: &RtMessage,
+    padding_tag: Tag,
+    nonce: &[u8],
+    authenticator: Option<&RequestAuthenticator>,
+) -> Result<(), Error> {
+    match authenticator {
+        None => Ok(()),
+        Some(auth) => match msg.get_field(padding_tag) {
+            Some(padding) if auth.is_authentic(nonce, padding) => Ok(()),
+            _ => Err(Error::RequestNotAuthenticated),
+        },
+    }
+}
+
+// This could be any VER that we support. Negotiate the VER to respond with and return it.
+fn nonce_from_rfc_request(
+    buf: &[u8],
+    expected_srv: &[u8],
+    authenticator: Option<&RequestAuthenticator>,
+    strict: bool,
+) -> Result<(Vec<u8>, Version), Error> {
     // first 8 bytes were RFC_REQUEST_FRAME_BYTES, [0..8]
-    let mut cur = Cursor::new(&buf[8..12]);
+    let mut cur = Cursor::new(&buf[REQUEST_FRAMING_BYTES.len()..FRAME_HEADER_LENGTH]);
     let reported_len = cur.read_u32::<LittleEndian>()?;
-    let actual_len = (buf.len() - 12) as u32;
+    let actual_len = (buf.len() - FRAME_HEADER_LENGTH) as u32;
 
     if reported_len != actual_len {
         return Err(Error::LengthMismatch(reported_len, actual_len));
     }
 
-    let msg = RtMessage::from_bytes(&buf[12..])?;
+    let msg = parse_message(&buf[FRAME_HEADER_LENGTH..], strict)?;
 
     let version = get_supported_version(&msg);
     if version.is_none() {
@@ -75,23 +239,237 @@ fn nonce_from_rfc_request(buf: &[u8], expected_srv: &[u8]) -> Result<(Vec<u8>, V
     }
 
     match msg.get_field(Tag::NONC) {
-        Some(nonce) => Ok((nonce.to_vec(), version.unwrap())),
+        Some(nonce) => {
+            check_nonce_length(nonce, version.unwrap())?;
+            check_authenticator(&msg, Tag::ZZZZ, nonce, authenticator)?;
+            Ok((nonce.to_vec(), version.unwrap()))
+        }
         None => Err(Error::InvalidRequest),
     }
 }
 
+// The version to answer in: the most preferred one of those the request's VER lists,
+// regardless of the order the client listed them in
 fn get_supported_version(msg: &RtMessage) -> Option<Version> {
-    const SUPPORTED_VERSIONS: &[Version] = &[Version::RfcDraft11, Version::Rfc];
-
-    if let Some(tag_bytes) = msg.get_field(Tag::VER) {
-        // Iterate the list of supplied versions, looking for the first match
-        for found_ver_bytes in tag_bytes.chunks(4) {
-            for ver in SUPPORTED_VERSIONS {
-                if ver.wire_bytes() == found_ver_bytes {
-                    return Some(*ver);
-                }
+    // Most preferred first: the final RFC version, then drafts from newest to oldest
+    const PREFERRED_VERSIONS: &[Version] = &[Version::Rfc, Version::RfcDraft11];
+
+    let offered = msg.get_field(Tag::VER)?;
+    PREFERRED_VERSIONS
+        .iter()
+        .copied()
+        .find(|ver| offered.chunks(4).any(|v| v == ver.wire_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use crate::request::{
+        make_request, new_nonce, nonce_from_request, nonce_from_request_strict,
+        RequestAuthenticator, RECV_BUFFER_LENGTH,
+    };
+    use crate::version::Version;
+    use crate::{Error, RtMessage, Tag, MAX_REQUEST_LENGTH, MIN_REQUEST_LENGTH};
+
+    const PSK: &[u8] = b"0123456789abcdef";
+
+    fn rfc_request(nonce: &[u8], auth: Option<&RequestAuthenticator>) -> Vec<u8> {
+        let mut padding = vec![0u8; 980];
+        if let Some(auth) = auth {
+            auth.authenticate(nonce, &mut padding);
+        }
+
+        let mut msg = RtMessage::with_capacity(3);
+        msg.add_field(Tag::VER, Version::Rfc.wire_bytes()).unwrap();
+        msg.add_field(Tag::NONC, nonce).unwrap();
+        msg.add_field(Tag::ZZZZ, &padding).unwrap();
+        msg.encode_framed().unwrap()
+    }
+
+    fn classic_request(nonce: &[u8], auth: Option<&RequestAuthenticator>) -> Vec<u8> {
+        let mut padding = vec![0u8; 980];
+        if let Some(auth) = auth {
+            auth.authenticate(nonce, &mut padding);
+        }
+
+        let mut msg = RtMessage::with_capacity(2);
+        msg.add_field(Tag::NONC, nonce).unwrap();
+        msg.add_field(Tag::PAD, &padding).unwrap();
+        msg.encode().unwrap()
+    }
+
+    #[test]
+    fn requests_accepted_without_psk() {
+        let nonce = [0x42; 32];
+        let req = rfc_request(&nonce, None);
+
+        let (found, version) = nonce_from_request(&req, req.len(), &[], None).unwrap();
+        assert_eq!(found, nonce);
+        assert_eq!(version, Version::Rfc);
+    }
+
+    #[test]
+    fn psk_gate_requires_valid_authenticator() {
+        let auth = RequestAuthenticator::new(PSK);
+        let other = RequestAuthenticator::new(b"fedcba9876543210");
+        let rfc_nonce = [0x42; 32];
+        let classic_nonce = [0x43; 64];
+
+        let req = rfc_request(&rfc_nonce, Some(&auth));
+        let (found, _) = nonce_from_request(&req, req.len(), &[], Some(&auth)).unwrap();
+        assert_eq!(found, rfc_nonce);
+
+        let req = classic_request(&classic_nonce, Some(&auth));
+        let (found, _) = nonce_from_request(&req, req.len(), &[], Some(&auth)).unwrap();
+        assert_eq!(found, classic_nonce);
+
+        for req in [
+            rfc_request(&rfc_nonce, None),
+            rfc_request(&rfc_nonce, Some(&other)),
+            classic_request(&classic_nonce, None),
+            classic_request(&classic_nonce, Some(&other)),
+        ] {
+            assert_eq!(
+                nonce_from_request(&req, req.len(), &[], Some(&auth)),
+                Err(Error::RequestNotAuthenticated)
+            );
+        }
+    }
+
+    #[test]
+    fn most_preferred_offered_version_is_chosen() {
+        let unknown: &[u8] = &[0x0c, 0x00, 0x00, 0x80];
+        let offers = [
+            (
+                [Version::RfcDraft11.wire_bytes(), Version::Rfc.wire_bytes()].concat(),
+                Ok(Version::Rfc),
+            ),
+            (
+                [unknown, Version::RfcDraft11.wire_bytes()].concat(),
+                Ok(Version::RfcDraft11),
+            ),
+            (unknown.to_vec(), Err(Error::NoCompatibleVersion)),
+        ];
+
+        for (ver, expected) in offers {
+            let mut msg = RtMessage::with_capacity(3);
+            msg.add_field(Tag::VER, &ver).unwrap();
+            msg.add_field(Tag::NONC, &[0x42; 32]).unwrap();
+            msg.add_field(Tag::ZZZZ, &[0u8; 972]).unwrap();
+            let req = msg.encode_framed().unwrap();
+
+            let version = nonce_from_request(&req, req.len(), &[], None).map(|(_, v)| v);
+            assert_eq!(version, expected, "VER {:?}", ver);
+        }
+    }
+
+    #[test]
+    fn unknown_tags_are_rejected_only_when_strict() {
+        // a request with a tag unknown here (as newer drafts' TYPE) in place of ZZZZ, the
+        // third tag after the frame header, tag count, and two offsets
+        let mut req = rfc_request(&[0x42; 32], None);
+        assert_eq!(&req[32..36], b"ZZZZ");
+        req[32..36].copy_from_slice(b"TYPE");
+
+        let (nonce, version) = nonce_from_request(&req, req.len(), &[], None).unwrap();
+        assert_eq!(nonce, [0x42; 32]);
+        assert_eq!(version, Version::Rfc);
+
+        assert!(matches!(
+            nonce_from_request_strict(&req, req.len(), &[], None),
+            Err(Error::InvalidTag(_))
+        ));
+    }
+
+    #[test]
+    fn built_requests_are_accepted() {
+        let auth = RequestAuthenticator::new(PSK);
+        let srv = [0x17; 32];
+
+        for (version, nonce) in [
+            (Version::Classic, vec![0x43; 64]),
+            (Version::Rfc, vec![0x42; 32]),
+            (Version::RfcDraft11, vec![0x41; 32]),
+        ] {
+            let msg = make_request(version, &nonce, Some(&srv), Some(&auth));
+            assert_eq!(msg.encoded_size(), MIN_REQUEST_LENGTH, "{}", version);
+
+            let req = msg.encode_for(version).unwrap();
+
+            let parsed = nonce_from_request(&req, req.len(), &srv, Some(&auth));
+            assert_eq!(parsed, Ok((nonce, version)));
+
+            if version != Version::Classic {
+                assert_eq!(
+                    nonce_from_request(&req, req.len(), &[0x18; 32], Some(&auth)),
+                    Err(Error::SrvMismatch)
+                );
             }
         }
     }
-    None
+
+    #[test]
+    fn nonces_have_the_length_of_their_version() {
+        for version in [Version::Classic, Version::Rfc, Version::RfcDraft11] {
+            assert_eq!(new_nonce(version).len(), version.profile().nonce_length);
+        }
+        assert_ne!(new_nonce(Version::Rfc), new_nonce(Version::Rfc));
+
+        let req = rfc_request(&[0x42; 64], None);
+        assert_eq!(
+            nonce_from_request(&req, req.len(), &[], None),
+            Err(Error::InvalidNonceLength(64))
+        );
+
+        // classic requests are answered whatever their nonce length
+        let req = classic_request(&[0x43; 32], None);
+        assert_eq!(
+            nonce_from_request(&req, req.len(), &[], None),
+            Ok((vec![0x43; 32], Version::Classic))
+        );
+    }
+
+    #[test]
+    fn authenticator_is_bound_to_nonce() {
+        let auth = RequestAuthenticator::new(PSK);
+        let mut padding = [0u8; 64];
+        auth.authenticate(&[1; 32], &mut padding);
+
+        assert!(auth.is_authentic(&[1; 32], &padding));
+        assert!(!auth.is_authentic(&[2; 32], &padding));
+        assert!(!auth.is_authentic(&[1; 32], &padding[..31]));
+    }
+
+    #[test]
+    fn oversized_datagrams_are_truncated_and_rejected() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buf = [0u8; RECV_BUFFER_LENGTH];
+
+        // the largest acceptable request arrives whole
+        let mut req = rfc_request(&[0x42; 32], None);
+        req.resize(MAX_REQUEST_LENGTH, 0);
+        client.send_to(&req, server.local_addr().unwrap()).unwrap();
+        let (num_bytes, _) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(num_bytes, MAX_REQUEST_LENGTH);
+        assert_eq!(&buf[..num_bytes], &req[..]);
+
+        // anything larger is cut off at the buffer size, which is still too large
+        for len in [MAX_REQUEST_LENGTH + 1, 2048, 9000] {
+            let mut req = rfc_request(&[0x42; 32], None);
+            req.resize(len, 0);
+            client.send_to(&req, server.local_addr().unwrap()).unwrap();
+            let (num_bytes, _) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(num_bytes, RECV_BUFFER_LENGTH);
+            assert_eq!(
+                nonce_from_request(&buf, num_bytes, &[], None),
+                Err(Error::RequestTooLarge)
+            );
+        }
+    }
 }
@@ -16,24 +16,65 @@
 //! Organizes requests and corresponding replies
 //!
 
+use std::fmt;
 use std::net::SocketAddr;
+use std::str::FromStr;
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use byteorder::{LittleEndian, WriteBytesExt};
 use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
 use mio::net::UdpSocket;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
 
+use crate::clock::{ClockQuorum, ClockReading};
 use crate::config::ServerConfig;
 use crate::grease::Grease;
 use crate::key::{LongTermKey, OnlineKey};
+use crate::leap::LeapInfo;
 use crate::merkle::MerkleTree;
+#[cfg(feature = "dangerous-testing")]
+use crate::misbehave::{Misbehavior, MisbehavingClock};
 use crate::stats::ServerStats;
 use crate::version::Version;
-use crate::{RtMessage, Tag};
+use crate::{RtMessage, Tag, PATH_NODE_LENGTH};
 
 const HEX: Encoding = HEXLOWER_PERMISSIVE;
 
+/// How a server whose keys are suspected compromised answers requests. Either way nothing is
+/// signed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompromisedMode {
+    /// Drop every request unanswered
+    Silent,
+
+    /// Answer with an all-zero signature, which clients reject immediately rather than
+    /// timing out. Monitoring can recognize it as this server declaring itself compromised.
+    ZeroSignature,
+}
+
+impl fmt::Display for CompromisedMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompromisedMode::Silent => write!(f, "silent"),
+            CompromisedMode::ZeroSignature => write!(f, "zero-signature"),
+        }
+    }
+}
+
+impl FromStr for CompromisedMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CompromisedMode, String> {
+        match s {
+            "silent" => Ok(CompromisedMode::Silent),
+            "zero-signature" => Ok(CompromisedMode::ZeroSignature),
+            s => Err(format!("unknown compromised mode '{}'", s)),
+        }
+    }
+}
+
 pub struct Responder {
     version: Version,
     online_key: OnlineKey,
@@ -43,23 +84,53 @@ pub struct Responder {
     merkle: MerkleTree,
     grease: Grease,
     thread_id: String,
+
+    // When padding is enabled, every response is padded as if its PATH were this long
+    padded_path_length: Option<usize>,
+
+    // Length of each online key's delegation window, unbounded if None
+    online_key_validity: Option<Duration>,
+
+    // Batches and responses signed by the current online key (its delegation epoch)
+    epoch_batches: u64,
+    epoch_responses: u64,
+
+    // If set, the keys are treated as compromised and nothing is signed
+    compromised: Option<CompromisedMode>,
+
+    // Sources of the time to sign if configured, otherwise the system clock is used
+    clocks: Option<ClockQuorum>,
+
+    // If set, batches are dropped rather than signed when the clock sources have no quorum
+    clock_quorum_required: bool,
+
+    #[cfg(feature = "dangerous-testing")]
+    clock: Option<MisbehavingClock>,
+
+    // Emit each batch's responses in random order rather than Merkle leaf (arrival) order
+    shuffle_order: bool,
+
+    // Leap second information added to every SREP, if configured
+    leap_info: Option<LeapInfo>,
 }
 
 impl Responder {
     pub fn new(version: Version, config: &dyn ServerConfig, ltk: &mut LongTermKey) -> Responder {
-        let online_key = OnlineKey::new();
+        let online_key_validity = config.online_key_validity();
+        let online_key = make_online_key(version, online_key_validity, SystemTime::now());
         let cert_bytes = ltk.make_cert(&online_key).encode().expect("make_cert");
         let long_term_public_key = HEX.encode(&ltk.public_key());
         let requests = Vec::with_capacity(config.batch_size() as usize);
         let grease = Grease::new(config.fault_percentage());
         let thread_id = thread::current().name().unwrap().to_string();
-
-        let merkle = if version == Version::Classic {
-            MerkleTree::new_sha512_classic()
+        let padded_path_length = if config.pad_responses_enabled() {
+            Some(max_path_length(config.batch_size()))
         } else {
-            MerkleTree::new_sha512_ietf()
+            None
         };
 
+        let merkle = MerkleTree::for_version(version);
+
         Responder {
             version,
             online_key,
@@ -69,9 +140,89 @@ impl Responder {
             requests,
             grease,
             thread_id,
+            padded_path_length,
+            online_key_validity,
+            epoch_batches: 0,
+            epoch_responses: 0,
+            compromised: config.compromised(),
+            clocks: ClockQuorum::from_config(config).expect("clock sources"),
+            clock_quorum_required: config.clock_quorum_required(),
+
+            #[cfg(feature = "dangerous-testing")]
+            clock: None,
+
+            shuffle_order: config.shuffle_responses_enabled(),
+            leap_info: config.leap_info(),
         }
     }
 
+    /// Protocol version this responder answers
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// The delegated online key used to sign responses
+    pub fn online_key(&self) -> &OnlineKey {
+        &self.online_key
+    }
+
+    /// Number of responses signed by the current online key
+    pub fn epoch_responses(&self) -> u64 {
+        self.epoch_responses
+    }
+
+    /// Replace the online key with a newly generated one delegated by `ltk`, logging a
+    /// summary of the closed epoch of the old key
+    pub fn rotate_online_key(&mut self, ltk: &mut LongTermKey) {
+        let now = self.read_clock().best();
+        self.replace_online_key(ltk, now);
+    }
+
+    // Replace the online key with one whose delegation window starts at `now`
+    fn replace_online_key(&mut self, ltk: &mut LongTermKey, now: SystemTime) {
+        info!(
+            "{} closed {} online key epoch: key {}.., MINT {}, MAXT {}; {} responses signed in {} batches",
+            self.thread_id,
+            self.version,
+            HEX.encode(&self.online_key.public_key_bytes()[..4]),
+            self.online_key.mint(),
+            self.online_key.maxt(),
+            self.epoch_responses,
+            self.epoch_batches
+        );
+
+        self.online_key = make_online_key(self.version, self.online_key_validity, now);
+        self.cert_bytes = ltk.make_cert(&self.online_key).encode().expect("make_cert");
+        self.epoch_batches = 0;
+        self.epoch_responses = 0;
+    }
+
+    /// True if deliberate errors are being added to responses
+    pub fn grease_enabled(&self) -> bool {
+        self.grease.is_enabled()
+    }
+
+    /// Enable or disable deliberate errors in responses
+    pub fn set_grease_enabled(&mut self, enabled: bool) {
+        self.grease.set_enabled(enabled);
+    }
+
+    /// The way requests are answered while the keys are treated as compromised, if they are
+    pub fn compromised(&self) -> Option<CompromisedMode> {
+        self.compromised
+    }
+
+    /// Treat the keys as compromised and stop signing, answering requests as `mode` specifies
+    pub fn set_compromised(&mut self, mode: CompromisedMode) {
+        self.compromised = Some(mode);
+    }
+
+    /// **Dangerous**: respond with the time of a clock that misbehaves as specified
+    #[cfg(feature = "dangerous-testing")]
+    pub fn set_misbehavior(&mut self, misbehavior: Misbehavior) {
+        self.clock = Some(MisbehavingClock::new(misbehavior));
+    }
+
     /// Reset internal state to prepare for a new batch of requests
     pub fn reset(&mut self) {
         self.merkle.reset();
@@ -89,34 +240,127 @@ impl Responder {
         self.requests.push((nonce, src_addr));
     }
 
-    /// Send responses for all queued requests
-    pub fn send_responses(&mut self, socket: &mut UdpSocket, stats: &mut Box<dyn ServerStats>) {
+    /// Send responses for all queued requests. If the time to put in the responses falls
+    /// outside the online key's delegation window, the online key is first replaced with a
+    /// new one delegated by `ltk`.
+    pub fn send_responses(
+        &mut self,
+        socket: &mut UdpSocket,
+        stats: &mut Box<dyn ServerStats>,
+        ltk: &mut LongTermKey,
+    ) {
         if self.is_empty() {
             return;
         }
 
         let merkle_root = self.merkle.compute_root();
+        let reading = self.read_clock();
+        if reading.dissenting > 0 {
+            stats.add_clock_disagreement();
+        }
+
+        let now = match reading.agreed {
+            Some(now) => now,
+            None if self.clock_quorum_required => {
+                error!(
+                    "{} clock sources have no quorum, refusing to sign; {} {} requests dropped",
+                    self.thread_id,
+                    self.requests.len(),
+                    self.version
+                );
+                return;
+            }
+            None => reading.primary,
+        };
+
+        if let Some(mode) = self.compromised {
+            error!(
+                "{} keys are COMPROMISED, refusing to sign; {} {} requests answered {}",
+                self.thread_id,
+                self.requests.len(),
+                self.version,
+                mode
+            );
+            if mode == CompromisedMode::ZeroSignature {
+                let leap_info = self.leap_info.as_ref();
+                let srep = self
+                    .online_key
+                    .make_unsigned_srep(self.version, now, &merkle_root, leap_info);
+                self.send_batch(&srep, socket, stats, false);
+            }
+            return;
+        }
+
+        // Never sign a time the delegation doesn't cover; such a response would be
+        // cryptographic proof that this server misbehaved
+        if !self.online_key.covers(self.version, now) {
+            error!(
+                "{} time {:?} is outside the {} online key's delegation window (MINT {}, MAXT {}); replacing the online key",
+                self.thread_id,
+                now,
+                self.version,
+                self.online_key.mint(),
+                self.online_key.maxt()
+            );
+            stats.add_delegation_window_violation();
+            stats.add_online_key_rotation();
+            self.replace_online_key(ltk, now);
+        }
 
         // The SREP tag is identical for each response
-        let srep = self
-            .online_key
-            .make_srep(self.version, SystemTime::now(), &merkle_root);
+        let signing_start = Instant::now();
+        let leap_info = self.leap_info.as_ref();
+        let srep = match self.online_key.make_srep(self.version, now, &merkle_root, leap_info) {
+            Ok(srep) => srep,
+            Err(e) => {
+                error!(
+                    "{} refusing to answer {} {} requests: {:?}",
+                    self.thread_id,
+                    self.requests.len(),
+                    self.version,
+                    e
+                );
+                return;
+            }
+        };
+        stats.add_signing_latency(signing_start.elapsed());
+        self.epoch_batches += 1;
+
+        self.send_batch(&srep, socket, stats, true);
+    }
+
+    // Send a response under `srep` to each queued request. Only responses that carry a
+    // signature (`signed`) are counted in the online key's epoch or greased.
+    fn send_batch(
+        &mut self,
+        srep: &RtMessage,
+        socket: &mut UdpSocket,
+        stats: &mut Box<dyn ServerStats>,
+        signed: bool,
+    ) {
+        let all_paths = self.merkle.all_paths();
 
-        for (idx, &(ref nonce, ref src_addr)) in self.requests.iter().enumerate() {
-            let paths = self.merkle.get_paths(idx);
+        for idx in self.response_order() {
+            let (nonce, src_addr) = &self.requests[idx];
+            let paths = &all_paths[idx];
             let resp_msg = {
-                let r = self.make_response(&srep, &self.cert_bytes, &paths, idx as u32, nonce);
-                if self.grease.should_add_error() {
+                let r = make_response(
+                    self.version,
+                    srep,
+                    &self.cert_bytes,
+                    paths,
+                    idx as u32,
+                    nonce,
+                    self.padded_path_length,
+                );
+                if signed && self.grease.should_add_error() {
                     self.grease.add_errors(&r)
                 } else {
                     r
                 }
             };
 
-            let resp_bytes = match self.version {
-                Version::Classic => resp_msg.encode().unwrap(),
-                Version::Rfc | Version::RfcDraft11 => resp_msg.encode_framed().unwrap(),
-            };
+            let resp_bytes = resp_msg.encode_for(self.version).unwrap();
 
             let mut bytes_sent: usize = 0;
             let mut successful_send: bool = true;
@@ -137,6 +381,9 @@ impl Responder {
             );
 
             if successful_send {
+                if signed {
+                    self.epoch_responses += 1;
+                }
                 match self.version {
                     Version::Classic => stats.add_classic_response(&src_addr.ip(), bytes_sent),
                     Version::Rfc | Version::RfcDraft11 => {
@@ -149,38 +396,29 @@ impl Responder {
         }
     }
 
-    fn make_response(
-        &self,
-        srep: &RtMessage,
-        cert_bytes: &[u8],
-        path: &[u8],
-        idx: u32,
-        nonce: &Vec<u8>,
-    ) -> RtMessage {
-        let mut index = [0; 4];
-        (&mut index as &mut [u8])
-            .write_u32::<LittleEndian>(idx)
-            .unwrap();
+    // Time to put in responses
+    fn read_clock(&mut self) -> ClockReading {
+        #[cfg(feature = "dangerous-testing")]
+        if let Some(clock) = self.clock.as_mut() {
+            return ClockReading::single(clock.now());
+        }
 
-        let sig_bytes = srep.get_field(Tag::SIG).unwrap();
-        let srep_bytes = srep.get_field(Tag::SREP).unwrap();
+        match self.clocks.as_mut() {
+            Some(clocks) => clocks.read(),
+            None => ClockReading::single(SystemTime::now()),
+        }
+    }
 
-        let mut response = RtMessage::with_capacity(6);
-        response.add_field(Tag::SIG, sig_bytes).unwrap();
+    // Order in which queued requests are answered: Merkle leaf order, unless shuffled.
+    // The order is drawn from a CSPRNG so it can't be predicted from earlier batches.
+    fn response_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.requests.len()).collect();
 
-        if self.version != Version::Classic {
-            response
-                .add_field(Tag::VER, self.version.wire_bytes())
-                .unwrap();
+        if self.shuffle_order {
+            order.shuffle(&mut thread_rng());
         }
 
-        response.add_field(Tag::NONC, nonce).unwrap();
-        response.add_field(Tag::PATH, path).unwrap();
-        response.add_field(Tag::SREP, srep_bytes).unwrap();
-        response.add_field(Tag::CERT, cert_bytes).unwrap();
-        response.add_field(Tag::INDX, &index).unwrap();
-
-        response
+        order
     }
 
     /// Returns a reference to the long-term public key
@@ -197,3 +435,363 @@ impl Responder {
         &self.thread_id
     }
 }
+
+///
+/// The response of protocol `version` to the request at index `idx` of a batch signed by
+/// `srep`, with `path` its Merkle path. If `padded_path_length` is provided, a response with
+/// a shorter path is padded to the size it would have with a path of that length.
+///
+pub(crate) fn make_response(
+    version: Version,
+    srep: &RtMessage,
+    cert_bytes: &[u8],
+    path: &[u8],
+    idx: u32,
+    nonce: &[u8],
+    padded_path_length: Option<usize>,
+) -> RtMessage {
+    let mut index = [0; 4];
+    (&mut index as &mut [u8])
+        .write_u32::<LittleEndian>(idx)
+        .unwrap();
+
+    let sig_bytes = srep.get_field(Tag::SIG).unwrap();
+    let srep_bytes = srep.get_field(Tag::SREP).unwrap();
+
+    let mut response = RtMessage::with_capacity(6);
+    response.add_field(Tag::SIG, sig_bytes).unwrap();
+
+    if version.profile().version_tags {
+        response.add_field(Tag::VER, version.wire_bytes()).unwrap();
+    }
+
+    response.add_field(Tag::NONC, nonce).unwrap();
+    response.add_field(Tag::PATH, path).unwrap();
+    response.add_field(Tag::SREP, srep_bytes).unwrap();
+    response.add_field(Tag::CERT, cert_bytes).unwrap();
+    response.add_field(Tag::INDX, &index).unwrap();
+
+    // Shallower trees have shorter paths; make up the difference with padding. The shortfall
+    // is a whole number of path nodes, always enough to cover the 8 bytes that the padding
+    // tag adds to the message header.
+    if let Some(padded_len) = padded_path_length {
+        if path.len() < padded_len {
+            let padding = vec![0; padded_len - path.len() - 8];
+            let tag = version.profile().padding_tag;
+            response.add_field(tag, &padding).unwrap();
+        }
+    }
+
+    response
+}
+
+// A new online key delegated from `now` for `validity`, or without bounds if None
+fn make_online_key(version: Version, validity: Option<Duration>, now: SystemTime) -> OnlineKey {
+    match validity {
+        Some(validity) => OnlineKey::with_validity(version, now, validity),
+        None => OnlineKey::new(),
+    }
+}
+
+// Length of the PATH in a response from a full batch of `batch_size` requests
+fn max_path_length(batch_size: u8) -> usize {
+    let depth = (batch_size as usize).next_power_of_two().trailing_zeros() as usize;
+    depth * PATH_NODE_LENGTH
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::io::Cursor;
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use byteorder::{LittleEndian, ReadBytesExt};
+    use mio::net::UdpSocket;
+
+    use crate::clock::ClockSpec;
+    use crate::config::{MemoryConfig, ServerConfig};
+    use crate::key::{LongTermKey, OnlineKey};
+    use crate::merkle::MerkleTree;
+    use crate::responder::{CompromisedMode, Responder};
+    use crate::sign::MsgVerifier;
+    use crate::stats::{AggregatedStats, ServerStats};
+    use crate::version::Version;
+    use crate::{
+        RtMessage, Tag, FRAME_HEADER_LENGTH, MAX_RFC_RESPONSE_LENGTH, PATH_NODE_LENGTH,
+        RFC_NONCE_LENGTH, SIGNED_RESPONSE_CONTEXT,
+    };
+
+    // Answer `num_requests` requests in batches of at most `batch_size`, the same way the
+    // server does, and return the raw responses
+    fn respond_in_batches(
+        config: &MemoryConfig,
+        version: Version,
+        num_requests: usize,
+    ) -> Vec<Vec<u8>> {
+        let mut ltk = LongTermKey::new(&config.seed());
+        let mut responder = Responder::new(version, config, &mut ltk);
+
+        let mut stats: Box<dyn ServerStats> = Box::new(AggregatedStats::new());
+        let mut socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        let nonce_len = version.profile().nonce_length;
+
+        let mut responses = Vec::with_capacity(num_requests);
+        let mut remaining = num_requests;
+
+        while remaining > 0 {
+            let batch = remaining.min(config.batch_size() as usize);
+            responder.reset();
+
+            for i in 0..batch {
+                let nonce = vec![(remaining - i) as u8; nonce_len];
+                responder.add_request(nonce, client_addr);
+            }
+            responder.send_responses(&mut socket, &mut stats, &mut ltk);
+
+            for _ in 0..batch {
+                let mut buf = [0u8; 4096];
+                let (len, _) = client.recv_from(&mut buf).expect("missing response");
+                responses.push(buf[..len].to_vec());
+            }
+
+            remaining -= batch;
+        }
+
+        assert_eq!(stats.total_responses_sent(), num_requests as u64);
+        responses
+    }
+
+    // Validate each response's Merkle proof and signature, as a client would
+    fn verify_responses(version: Version, responses: &[Vec<u8>], batch_size: usize) {
+        let mut seen_indexes = HashSet::new();
+
+        for (i, bytes) in responses.iter().enumerate() {
+            let profile = version.profile();
+            let max_len = profile.max_response_length;
+            let payload = match profile.framed {
+                true => &bytes[FRAME_HEADER_LENGTH..],
+                false => &bytes[..],
+            };
+
+            // a full batch has the deepest tree and therefore the largest responses
+            if responses.len() == batch_size {
+                assert_eq!(bytes.len(), max_len);
+            } else {
+                assert!(bytes.len() <= max_len);
+            }
+
+            let msg = RtMessage::from_bytes(payload).unwrap();
+            let nonce = msg.get_field(Tag::NONC).unwrap();
+            let path = msg.get_field(Tag::PATH).unwrap();
+            let srep_bytes = msg.get_field(Tag::SREP).unwrap();
+            let srep = RtMessage::from_bytes(srep_bytes).unwrap();
+            let index = Cursor::new(msg.get_field(Tag::INDX).unwrap())
+                .read_u32::<LittleEndian>()
+                .unwrap();
+
+            let tree = MerkleTree::for_version(version);
+            assert_eq!(
+                tree.root_from_paths(index as usize, nonce, path),
+                srep.get_field(Tag::ROOT).unwrap(),
+                "bad proof for response {} (index {})",
+                i,
+                index
+            );
+
+            let cert = RtMessage::from_bytes(msg.get_field(Tag::CERT).unwrap()).unwrap();
+            let dele = RtMessage::from_bytes(cert.get_field(Tag::DELE).unwrap()).unwrap();
+            let mut verifier = MsgVerifier::new(dele.get_field(Tag::PUBK).unwrap());
+            verifier.update(SIGNED_RESPONSE_CONTEXT.as_bytes());
+            verifier.update(srep_bytes);
+            assert!(verifier.verify(msg.get_field(Tag::SIG).unwrap()));
+
+            // each batch uses indexes 0..batch_size exactly once
+            let batch_num = i / batch_size;
+            assert!(seen_indexes.insert((batch_num, index)));
+        }
+    }
+
+    fn check_batch_boundaries(version: Version, shuffle: bool) {
+        let mut config = MemoryConfig::new(0);
+        config.shuffle_responses = shuffle;
+
+        for num_requests in [1, 2, 63, 64, 65] {
+            let responses = respond_in_batches(&config, version, num_requests);
+            assert_eq!(responses.len(), num_requests);
+            verify_responses(version, &responses, 64);
+        }
+    }
+
+    #[test]
+    fn batch_boundaries_in_leaf_order() {
+        check_batch_boundaries(Version::Rfc, false);
+        check_batch_boundaries(Version::Classic, false);
+    }
+
+    #[test]
+    fn batch_boundaries_in_shuffled_order() {
+        check_batch_boundaries(Version::Rfc, true);
+        check_batch_boundaries(Version::RfcDraft11, true);
+        check_batch_boundaries(Version::Classic, true);
+    }
+
+    #[test]
+    fn padded_responses_have_uniform_size() {
+        let mut config = MemoryConfig::new(0);
+        config.pad_responses = true;
+
+        for version in [Version::Rfc, Version::Classic] {
+            let max_len = version.profile().max_response_length;
+
+            // 64 + 1 + 2 requests: a full batch, then batches with one- and two-leaf trees
+            let mut responses = respond_in_batches(&config, version, 65);
+            responses.extend(respond_in_batches(&config, version, 2));
+
+            assert!(responses.iter().all(|r| r.len() == max_len));
+            verify_responses(version, &responses[..64], 64);
+            verify_responses(version, &responses[64..65], 64);
+            verify_responses(version, &responses[65..], 64);
+        }
+
+        // smaller batches are padded to their own (shallower) full size
+        config.batch_size = 5;
+        let responses = respond_in_batches(&config, Version::Rfc, 6);
+        let padded_len = MAX_RFC_RESPONSE_LENGTH - 3 * PATH_NODE_LENGTH;
+        assert!(responses.iter().all(|r| r.len() == padded_len));
+    }
+
+    #[test]
+    fn online_key_replaced_when_clock_leaves_delegation_window() {
+        let mut config = MemoryConfig::new(0);
+        config.online_key_validity = Some(Duration::from_secs(60));
+
+        let mut ltk = LongTermKey::new(&config.seed());
+        let mut responder = Responder::new(Version::Rfc, &config, &mut ltk);
+
+        // an online key whose window closed an hour ago
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        let expired = OnlineKey::with_validity(Version::Rfc, hour_ago, Duration::from_secs(60));
+        let expired_pubk = expired.public_key_bytes();
+        responder.online_key = expired;
+
+        let mut stats: Box<dyn ServerStats> = Box::new(AggregatedStats::new());
+        let mut socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        responder.add_request(vec![1; RFC_NONCE_LENGTH], client.local_addr().unwrap());
+        responder.send_responses(&mut socket, &mut stats, &mut ltk);
+
+        assert_ne!(responder.online_key().public_key_bytes(), expired_pubk);
+        assert_eq!(stats.total_delegation_window_violations(), 1);
+        assert_eq!(stats.total_online_key_rotations(), 1);
+
+        // the response is signed by the new key, whose window contains the response's MIDP
+        let mut buf = [0u8; 4096];
+        let (len, _) = client.recv_from(&mut buf).expect("missing response");
+        let responses = vec![buf[..len].to_vec()];
+        verify_responses(Version::Rfc, &responses, 64);
+
+        let msg = RtMessage::from_bytes(&responses[0][FRAME_HEADER_LENGTH..]).unwrap();
+        let srep = RtMessage::from_bytes(msg.get_field(Tag::SREP).unwrap()).unwrap();
+        let cert = RtMessage::from_bytes(msg.get_field(Tag::CERT).unwrap()).unwrap();
+        let dele = RtMessage::from_bytes(cert.get_field(Tag::DELE).unwrap()).unwrap();
+        let read_u64 = |msg: &RtMessage, tag: Tag| {
+            Cursor::new(msg.get_field(tag).unwrap())
+                .read_u64::<LittleEndian>()
+                .unwrap()
+        };
+
+        let midp = read_u64(&srep, Tag::MIDP);
+        assert_eq!(
+            dele.get_field(Tag::PUBK).unwrap(),
+            &responder.online_key().public_key_bytes()[..]
+        );
+        assert!(read_u64(&dele, Tag::MINT) <= midp && midp <= read_u64(&dele, Tag::MAXT));
+    }
+
+    #[test]
+    fn compromised_responder_signs_nothing() {
+        let mut config = MemoryConfig::new(0);
+        config.compromised = Some(CompromisedMode::ZeroSignature);
+
+        let mut ltk = LongTermKey::new(&config.seed());
+        let mut responder = Responder::new(Version::Rfc, &config, &mut ltk);
+        let mut stats: Box<dyn ServerStats> = Box::new(AggregatedStats::new());
+        let mut socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+
+        // answered with an all-zero signature
+        responder.add_request(vec![1; RFC_NONCE_LENGTH], client.local_addr().unwrap());
+        responder.send_responses(&mut socket, &mut stats, &mut ltk);
+
+        let mut buf = [0u8; 4096];
+        let (len, _) = client.recv_from(&mut buf).expect("missing response");
+        let msg = RtMessage::from_bytes(&buf[FRAME_HEADER_LENGTH..len]).unwrap();
+        assert!(msg.get_field(Tag::SIG).unwrap().iter().all(|b| *b == 0));
+        assert_eq!(responder.epoch_responses(), 0);
+
+        // not answered at all
+        responder.set_compromised(CompromisedMode::Silent);
+        responder.reset();
+        responder.add_request(vec![2; RFC_NONCE_LENGTH], client.local_addr().unwrap());
+        responder.send_responses(&mut socket, &mut stats, &mut ltk);
+        assert!(client.recv_from(&mut buf).is_err());
+
+        assert_eq!("zero-signature".parse(), Ok(CompromisedMode::ZeroSignature));
+        assert!("loud".parse::<CompromisedMode>().is_err());
+    }
+
+    #[test]
+    fn clock_majority_sets_the_signed_time() {
+        let mut config = MemoryConfig::new(0);
+        config.clock_sources = ClockSpec::parse_list("system, system@3600000, system").unwrap();
+        config.clock_quorum_required = true;
+
+        let mut ltk = LongTermKey::new(&config.seed());
+        let mut stats: Box<dyn ServerStats> = Box::new(AggregatedStats::new());
+        let mut socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+
+        // the clock an hour ahead is outvoted
+        let mut responder = Responder::new(Version::Rfc, &config, &mut ltk);
+        responder.add_request(vec![1; RFC_NONCE_LENGTH], client.local_addr().unwrap());
+        responder.send_responses(&mut socket, &mut stats, &mut ltk);
+
+        let mut buf = [0u8; 4096];
+        let (len, _) = client.recv_from(&mut buf).expect("missing response");
+        let msg = RtMessage::from_bytes(&buf[FRAME_HEADER_LENGTH..len]).unwrap();
+        let srep = RtMessage::from_bytes(msg.get_field(Tag::SREP).unwrap()).unwrap();
+        let midp = Cursor::new(srep.get_field(Tag::MIDP).unwrap())
+            .read_u64::<LittleEndian>()
+            .unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!(midp <= now && now - midp < 60, "{} vs {}", midp, now);
+        assert_eq!(stats.total_clock_disagreements(), 1);
+
+        // without a majority nothing is signed
+        config.clock_sources = ClockSpec::parse_list("system, system@3600000").unwrap();
+        let mut responder = Responder::new(Version::Rfc, &config, &mut ltk);
+        responder.add_request(vec![2; RFC_NONCE_LENGTH], client.local_addr().unwrap());
+        responder.send_responses(&mut socket, &mut stats, &mut ltk);
+
+        assert!(client.recv_from(&mut buf).is_err());
+        assert_eq!(responder.epoch_responses(), 0);
+        assert_eq!(stats.total_clock_disagreements(), 2);
+    }
+}
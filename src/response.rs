@@ -0,0 +1,424 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Validation of server responses, shared by the client and the server's startup self-test.
+//!
+
+use std::collections::HashMap;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::leap::LeapInfo;
+use crate::merkle::MerkleTree;
+use crate::sign::MsgVerifier;
+use crate::version::Version;
+use crate::{
+    RtMessage, Tag, CERTIFICATE_CONTEXT, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH,
+    SIGNED_RESPONSE_CONTEXT,
+};
+
+///
+/// Validates a response to a request carrying `nonce`, checking the response's Merkle proof,
+/// that its midpoint lies within the delegation, and, given the server's long-term public
+/// key, the signatures on the delegation and the signed response.
+///
+/// Errors describe the first check that failed.
+///
+pub struct ResponseHandler {
+    pub_key: Option<Vec<u8>>,
+    msg: HashMap<Tag, Vec<u8>>,
+    srep: HashMap<Tag, Vec<u8>>,
+    cert: HashMap<Tag, Vec<u8>>,
+    dele: HashMap<Tag, Vec<u8>>,
+    nonce: Vec<u8>,
+    version: Version,
+}
+
+/// Time reported by a response that passed validation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsedResponse {
+    /// True if the signatures were checked against the server's long-term public key
+    pub verified: bool,
+
+    /// Midpoint of the response (`MIDP`), in the protocol version's units
+    pub midpoint: u64,
+
+    /// Radius of the response's uncertainty (`RADI`), in the protocol version's units
+    pub radius: u32,
+
+    /// End of the online key's delegation (`MAXT`), in the protocol version's units
+    pub maxt: u64,
+}
+
+impl ResponseHandler {
+    /// Check that `response` carries every tag needed for validation. Signatures are only
+    /// checked if `pub_key` is provided.
+    pub fn new(
+        version: Version,
+        pub_key: Option<Vec<u8>>,
+        response: RtMessage,
+        nonce: Vec<u8>,
+    ) -> Result<ResponseHandler, String> {
+        let msg = response.into_hash_map();
+        let srep = nested_message(&msg, Tag::SREP)?;
+        let cert = nested_message(&msg, Tag::CERT)?;
+        let dele = nested_message(&cert, Tag::DELE)?;
+
+        require_tags(&msg, &[Tag::SIG, Tag::PATH, Tag::INDX])?;
+        require_tags(&srep, &[Tag::MIDP, Tag::RADI, Tag::ROOT])?;
+        require_tags(&cert, &[Tag::SIG])?;
+        require_tags(&dele, &[Tag::PUBK, Tag::MINT, Tag::MAXT])?;
+
+        Ok(ResponseHandler {
+            pub_key,
+            msg,
+            srep,
+            cert,
+            dele,
+            nonce,
+            version,
+        })
+    }
+
+    /// Validate the response, returning the time it reports
+    pub fn extract_time(&self) -> Result<ParsedResponse, String> {
+        let midpoint = read_u64(&self.srep, Tag::MIDP)?;
+        let radius = read_u32(&self.srep, Tag::RADI)?;
+        let maxt = read_u64(&self.dele, Tag::MAXT)?;
+
+        self.validate_merkle()?;
+        self.validate_midpoint(midpoint)?;
+
+        let verified = if self.pub_key.is_some() {
+            self.validate_dele()?;
+            self.validate_srep()?;
+            true
+        } else {
+            false
+        };
+
+        Ok(ParsedResponse {
+            verified,
+            midpoint,
+            radius,
+            maxt,
+        })
+    }
+
+    /// Every version listed in the signed response's `VERS` tag, in order, as wire values.
+    /// Empty if there is no `VERS` tag (classic responses and older drafts).
+    pub fn supported_versions(&self) -> Vec<u32> {
+        self.srep
+            .get(&Tag::VERS)
+            .map(|vers| vers.chunks(4).map(wire_value).collect())
+            .unwrap_or_default()
+    }
+
+    /// Leap second information in the signed response (its `DUT1`, `DTAI`, and `LEAP` tags),
+    /// if it carries any. Only authentic if the response was verified.
+    pub fn leap_info(&self) -> Result<Option<LeapInfo>, String> {
+        LeapInfo::from_fields(
+            self.srep.get(&Tag::DUT1).map(Vec::as_slice),
+            self.srep.get(&Tag::DTAI).map(Vec::as_slice),
+            self.srep.get(&Tag::LEAP).map(Vec::as_slice),
+        )
+    }
+
+    /// The version the server chose (`VER`) as a wire value, taken from the signed response
+    /// if it carries one
+    pub fn negotiated_version(&self) -> Option<u32> {
+        self.srep
+            .get(&Tag::VER)
+            .or_else(|| self.msg.get(&Tag::VER))
+            .map(|ver| wire_value(ver))
+    }
+
+    /// Fails unless the server answered with the `expected` version. A classic response has
+    /// no `VER` and is always the classic version.
+    pub fn expect_version(&self, expected: Version) -> Result<(), String> {
+        if self.version == Version::Classic {
+            return match expected {
+                Version::Classic => Ok(()),
+                _ => Err(format!(
+                    "Server answered with version {}, expected {}",
+                    self.version, expected
+                )),
+            };
+        }
+
+        match self.negotiated_version() {
+            Some(ver) if Version::from_wire(&ver.to_le_bytes()) == Some(expected) => Ok(()),
+            Some(ver) => Err(format!(
+                "Server answered with version {}, expected {}",
+                version_name(ver),
+                expected
+            )),
+            None => Err(format!(
+                "Response is missing the VER tag, expected version {}",
+                expected
+            )),
+        }
+    }
+
+    ///
+    /// Inconsistencies between the versions in an RFC response and the `requested` versions
+    /// offered by the request: the negotiated `VER` must be one the request offered and, if
+    /// the response has a `VERS` list, appear in it. `VERS` must be in ascending order.
+    ///
+    /// These don't make the time invalid, so they are reported rather than failing
+    /// [`extract_time`](#method.extract_time).
+    ///
+    pub fn version_warnings(&self, requested: &[Version]) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if !self.version.profile().version_tags {
+            return warnings;
+        }
+
+        let negotiated = match self.negotiated_version() {
+            Some(ver) => ver,
+            None => return vec!["Response is missing the VER tag".to_string()],
+        };
+
+        if !requested
+            .iter()
+            .any(|v| wire_value(v.wire_bytes()) == negotiated)
+        {
+            warnings.push(format!(
+                "Server chose version {}, which the request didn't offer",
+                version_name(negotiated)
+            ));
+        }
+
+        let supported = self.supported_versions();
+        if let Some(vers) = self.srep.get(&Tag::VERS) {
+            if vers.is_empty() || vers.len() % 4 != 0 {
+                warnings.push(format!(
+                    "VERS is {} bytes, not a list of versions",
+                    vers.len()
+                ));
+            } else if !supported.contains(&negotiated) {
+                warnings.push(format!(
+                    "Server chose version {}, which its VERS list doesn't include",
+                    version_name(negotiated)
+                ));
+            }
+        }
+        if supported.windows(2).any(|pair| pair[0] >= pair[1]) {
+            warnings.push("VERS is not in ascending order".to_string());
+        }
+
+        warnings
+    }
+
+    fn validate_dele(&self) -> Result<(), String> {
+        let mut full_cert = Vec::from(CERTIFICATE_CONTEXT.as_bytes());
+        full_cert.extend(&self.cert[&Tag::DELE]);
+
+        if !self.validate_sig(
+            self.pub_key.as_ref().unwrap(),
+            &self.cert[&Tag::SIG],
+            &full_cert,
+        ) {
+            return Err("Invalid signature on DELE tag, response may not be authentic".into());
+        }
+        Ok(())
+    }
+
+    fn validate_srep(&self) -> Result<(), String> {
+        let mut full_srep = Vec::from(SIGNED_RESPONSE_CONTEXT.as_bytes());
+        full_srep.extend(&self.msg[&Tag::SREP]);
+
+        if !self.validate_sig(&self.dele[&Tag::PUBK], &self.msg[&Tag::SIG], &full_srep) {
+            return Err("Invalid signature on SREP tag, response may not be authentic".into());
+        }
+        Ok(())
+    }
+
+    fn validate_merkle(&self) -> Result<(), String> {
+        let index = read_u32(&self.msg, Tag::INDX)?;
+        let paths = &self.msg[&Tag::PATH];
+
+        let hash = MerkleTree::for_version(self.version).root_from_paths(
+            index as usize,
+            &self.nonce,
+            paths,
+        );
+
+        if hash != self.srep[&Tag::ROOT] {
+            return Err("Nonce is not present in the response's merkle tree".into());
+        }
+        Ok(())
+    }
+
+    fn validate_midpoint(&self, midpoint: u64) -> Result<(), String> {
+        let mint = read_u64(&self.dele, Tag::MINT)?;
+        let maxt = read_u64(&self.dele, Tag::MAXT)?;
+
+        if midpoint < mint {
+            return Err(format!(
+                "Response midpoint {} lies *before* delegation span ({}, {})",
+                midpoint, mint, maxt
+            ));
+        }
+        if midpoint > maxt {
+            return Err(format!(
+                "Response midpoint {} lies *after* delegation span ({}, {})",
+                midpoint, mint, maxt
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_sig(&self, public_key: &[u8], sig: &[u8], data: &[u8]) -> bool {
+        if public_key.len() != PUBLIC_KEY_LENGTH || sig.len() != SIGNATURE_LENGTH as usize {
+            return false;
+        }
+
+        let mut verifier = MsgVerifier::new(public_key);
+        verifier.update(data);
+        verifier.verify(sig)
+    }
+}
+
+// A version's 4 byte wire value (fewer bytes are zero-extended)
+fn wire_value(bytes: &[u8]) -> u32 {
+    let mut value = [0u8; 4];
+    let len = bytes.len().min(4);
+    value[..len].copy_from_slice(&bytes[..len]);
+    u32::from_le_bytes(value)
+}
+
+fn version_name(value: u32) -> String {
+    match Version::from_wire(&value.to_le_bytes()) {
+        Some(version) => version.to_string().to_string(),
+        None => format!("0x{:08x}", value),
+    }
+}
+
+fn nested_message(map: &HashMap<Tag, Vec<u8>>, tag: Tag) -> Result<HashMap<Tag, Vec<u8>>, String> {
+    require_tags(map, &[tag])?;
+    RtMessage::from_bytes_detailed(&map[&tag])
+        .map(RtMessage::into_hash_map)
+        .map_err(|e| format!("Malformed {} message: {}", tag, e))
+}
+
+fn require_tags(map: &HashMap<Tag, Vec<u8>>, tags: &[Tag]) -> Result<(), String> {
+    match tags.iter().find(|tag| !map.contains_key(tag)) {
+        Some(tag) => Err(format!("Response is missing the {} tag", tag)),
+        None => Ok(()),
+    }
+}
+
+fn read_u32(map: &HashMap<Tag, Vec<u8>>, tag: Tag) -> Result<u32, String> {
+    map[&tag]
+        .as_slice()
+        .read_u32::<LittleEndian>()
+        .map_err(|_| format!("{} value is too short", tag))
+}
+
+fn read_u64(map: &HashMap<Tag, Vec<u8>>, tag: Tag) -> Result<u64, String> {
+    map[&tag]
+        .as_slice()
+        .read_u64::<LittleEndian>()
+        .map_err(|_| format!("{} value is too short", tag))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::response::ResponseHandler;
+    use crate::version::Version;
+    use crate::Tag;
+
+    fn handler(ver: Option<&[u8]>, vers: Option<&[u8]>) -> ResponseHandler {
+        let mut msg = HashMap::new();
+        let mut srep = HashMap::new();
+        if let Some(ver) = ver {
+            msg.insert(Tag::VER, ver.to_vec());
+        }
+        if let Some(vers) = vers {
+            srep.insert(Tag::VERS, vers.to_vec());
+        }
+
+        ResponseHandler {
+            pub_key: None,
+            msg,
+            srep,
+            cert: HashMap::new(),
+            dele: HashMap::new(),
+            nonce: Vec::new(),
+            version: Version::Rfc,
+        }
+    }
+
+    const RFC: &[u8] = &[0x01, 0x00, 0x00, 0x00];
+    const RFC_AND_DRAFT11: &[u8] = &[0x01, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x00, 0x80];
+
+    #[test]
+    fn consistent_versions_have_no_warnings() {
+        let h = handler(Some(RFC), Some(RFC_AND_DRAFT11));
+        assert_eq!(h.negotiated_version(), Some(1));
+        assert_eq!(h.supported_versions(), vec![1, 0x8000000b]);
+        assert!(h.version_warnings(&[Version::Rfc]).is_empty());
+
+        // older servers send no VERS
+        assert!(handler(Some(RFC), None)
+            .version_warnings(&[Version::Rfc])
+            .is_empty());
+    }
+
+    #[test]
+    fn inconsistent_versions_are_reported() {
+        let h = handler(Some(RFC), Some(RFC_AND_DRAFT11));
+        assert_eq!(
+            h.version_warnings(&[Version::RfcDraft11]),
+            vec!["Server chose version Rfc, which the request didn't offer"]
+        );
+
+        let h = handler(Some(&[0x0c, 0x00, 0x00, 0x80]), Some(RFC_AND_DRAFT11));
+        assert_eq!(h.version_warnings(&[Version::Rfc]).len(), 2);
+
+        let descending = [&RFC_AND_DRAFT11[4..], &RFC_AND_DRAFT11[..4]].concat();
+        let h = handler(Some(RFC), Some(&descending));
+        assert_eq!(
+            h.version_warnings(&[Version::Rfc]),
+            vec!["VERS is not in ascending order"]
+        );
+
+        let h = handler(None, Some(RFC_AND_DRAFT11));
+        assert_eq!(
+            h.version_warnings(&[Version::Rfc]),
+            vec!["Response is missing the VER tag"]
+        );
+    }
+
+    #[test]
+    fn unexpected_versions_fail() {
+        let h = handler(Some(RFC), Some(RFC_AND_DRAFT11));
+        assert!(h.expect_version(Version::Rfc).is_ok());
+        assert_eq!(
+            h.expect_version(Version::RfcDraft11),
+            Err("Server answered with version Rfc, expected RfcDraft11".to_string())
+        );
+        assert!(h.expect_version(Version::Classic).is_err());
+        assert!(handler(None, None).expect_version(Version::Rfc).is_err());
+
+        let mut classic = handler(None, None);
+        classic.version = Version::Classic;
+        assert!(classic.expect_version(Version::Classic).is_ok());
+        assert!(classic.expect_version(Version::Rfc).is_err());
+    }
+}
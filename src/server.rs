@@ -18,7 +18,8 @@
 
 use std::io::ErrorKind;
 use std::io::Write;
-use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::net::{IpAddr, Shutdown, SocketAddr, UdpSocket as StdUdpSocket};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -27,13 +28,22 @@ use mio::net::{TcpListener, UdpSocket};
 use mio::{Events, Poll, PollOpt, Ready, Token};
 use mio_extras::timer::Timer;
 
+use crate::admin::{AdminAction, AdminRegistry, OnlineKeyStatus, WorkerCounters};
+use crate::audit::{AuditLog, Auditor};
 use crate::config::ServerConfig;
 use crate::key::LongTermKey;
 use crate::kms;
+#[cfg(feature = "dangerous-testing")]
+use crate::misbehave::Misbehavior;
 use crate::request;
-use crate::responder::Responder;
-use crate::stats::{AggregatedStats, ClientStatEntry, PerClientStats, ServerStats};
+use crate::request::{RequestAuthenticator, RECV_BUFFER_LENGTH};
+use crate::responder::{CompromisedMode, Responder};
+use crate::response::ResponseHandler;
+use crate::stats::{
+    AggregatedStats, ClientStatEntry, MetricsAggregator, PerClientStats, ServerStats,
+};
 use crate::version::Version;
+use crate::{Error, RtMessage, FRAME_HEADER_LENGTH};
 
 // mio event registrations
 const EVT_MESSAGE: Token = Token(0);
@@ -64,11 +74,20 @@ pub struct Server {
     responder_rfc: Responder,
     responder_draft: Responder,
     responder_classic: Responder,
-    buf: [u8; 65_536],
+    buf: [u8; RECV_BUFFER_LENGTH],
     thread_name: String,
     srv_value: Vec<u8>,
+    long_term_key: LongTermKey,
+    authenticator: Option<RequestAuthenticator>,
+    strict_parsing: bool,
 
     stats: Box<dyn ServerStats>,
+    admin_registry: Option<Arc<AdminRegistry>>,
+    auditor: Option<Auditor>,
+    metrics_aggregator: Option<Arc<MetricsAggregator>>,
+
+    // Set once the keys have been revoked through the admin endpoint
+    exit_requested: bool,
 
     // Used to send requests to ourselves in fuzzing mode
     #[cfg(fuzzing)]
@@ -80,7 +99,19 @@ impl Server {
     /// Create a new server instance from the provided
     /// [`ServerConfig`](../config/trait.ServerConfig.html) trait object instance.
     ///
+    /// The seed is loaded from the config, unwrapping it via KMS if it is protected.
+    ///
     pub fn new(config: &dyn ServerConfig, socket: UdpSocket) -> Server {
+        let seed = kms::load_seed(config).expect("failed loading seed");
+        Server::with_seed(config, socket, &seed)
+    }
+
+    ///
+    /// Create a new server instance like [`new`](#method.new), with the long-term key derived
+    /// from an already loaded `seed`. Nothing is loaded from a KMS, so a server can be
+    /// recreated (e.g. to restart a worker) while the KMS is unavailable.
+    ///
+    pub fn with_seed(config: &dyn ServerConfig, socket: UdpSocket, seed: &[u8]) -> Server {
         let mut timer: Timer<()> = Timer::default();
         timer.set_timeout(config.status_interval(), ());
 
@@ -122,10 +153,7 @@ impl Server {
             Box::new(AggregatedStats::new())
         };
 
-        let mut long_term_key = {
-            let seed = kms::load_seed(config).expect("failed loading seed");
-            LongTermKey::new(&seed)
-        };
+        let mut long_term_key = LongTermKey::new(seed);
 
         let responder_rfc = Responder::new(Version::Rfc, config, &mut long_term_key);
         let responder_draft = Responder::new(Version::RfcDraft11, config, &mut long_term_key);
@@ -136,6 +164,8 @@ impl Server {
         let thread_name = thread::current().name().unwrap().to_string();
         let poll_duration = Some(Duration::from_millis(100));
         let srv_value = long_term_key.srv_value().to_vec();
+        let authenticator = config.psk().map(RequestAuthenticator::new);
+        let strict_parsing = config.strict_parsing_enabled();
 
         Server {
             batch_size,
@@ -148,10 +178,17 @@ impl Server {
             responder_rfc,
             responder_draft,
             responder_classic,
-            buf: [0u8; 65_536],
+            buf: [0u8; RECV_BUFFER_LENGTH],
             thread_name,
             srv_value,
+            long_term_key,
+            authenticator,
+            strict_parsing,
             stats,
+            admin_registry: None,
+            auditor: None,
+            metrics_aggregator: None,
+            exit_requested: false,
 
             #[cfg(fuzzing)]
             fake_client_socket: UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap(),
@@ -163,6 +200,104 @@ impl Server {
         &self.responder_rfc.get_public_key()
     }
 
+    /// Publish this server's online keys and counters to the provided admin registry
+    pub fn set_admin_registry(&mut self, registry: Arc<AdminRegistry>) {
+        let public_key = self.get_public_key().to_string();
+        registry.register_worker(&self.thread_name, &public_key, self.online_key_status());
+        registry.set_grease_enabled(&self.thread_name, self.responder_rfc.grease_enabled());
+        self.admin_registry = Some(registry);
+    }
+
+    /// Record anomalous requests received by this server in the provided audit log
+    pub fn set_audit_log(&mut self, log: Arc<AuditLog>) {
+        self.auditor = Some(Auditor::new(log, &self.thread_name));
+    }
+
+    /// Report this server's request count at the end of each status interval to the
+    /// provided aggregator, which logs how evenly requests are spread across workers
+    pub fn set_metrics_aggregator(&mut self, aggregator: Arc<MetricsAggregator>) {
+        self.metrics_aggregator = Some(aggregator);
+    }
+
+    /// **Dangerous**: make every response carry the time of a misbehaving clock
+    #[cfg(feature = "dangerous-testing")]
+    pub fn set_misbehavior(&mut self, misbehavior: Misbehavior) {
+        self.responder_rfc.set_misbehavior(misbehavior);
+        self.responder_draft.set_misbehavior(misbehavior);
+        self.responder_classic.set_misbehavior(misbehavior);
+    }
+
+    /// True once the keys have been revoked through the admin endpoint. The server no longer
+    /// signs anything and its process should exit.
+    pub fn exit_requested(&self) -> bool {
+        self.exit_requested
+    }
+
+    fn online_key_status(&self) -> Vec<OnlineKeyStatus> {
+        vec![
+            OnlineKeyStatus::from_responder(&self.responder_rfc),
+            OnlineKeyStatus::from_responder(&self.responder_draft),
+            OnlineKeyStatus::from_responder(&self.responder_classic),
+        ]
+    }
+
+    // Apply any actions queued by the admin endpoint. Called between batches, so a
+    // rotation never changes the online key while a batch is being answered.
+    fn apply_admin_actions(&mut self) {
+        let actions = match &self.admin_registry {
+            Some(registry) => registry.take_actions(&self.thread_name),
+            None => return,
+        };
+
+        for action in actions {
+            match action {
+                AdminAction::RotateOnlineKey => {
+                    self.responder_rfc
+                        .rotate_online_key(&mut self.long_term_key);
+                    self.responder_draft
+                        .rotate_online_key(&mut self.long_term_key);
+                    self.responder_classic
+                        .rotate_online_key(&mut self.long_term_key);
+
+                    self.stats.add_online_key_rotation();
+
+                    let online_keys = self.online_key_status();
+                    if let Some(registry) = &self.admin_registry {
+                        registry.update_online_keys(&self.thread_name, online_keys);
+                    }
+                    info!("{} rotated online keys", self.thread_name);
+                }
+                AdminAction::DumpMetrics => self.flush_stats(),
+                AdminAction::SetGrease(enabled) => {
+                    self.responder_rfc.set_grease_enabled(enabled);
+                    self.responder_draft.set_grease_enabled(enabled);
+                    self.responder_classic.set_grease_enabled(enabled);
+
+                    if let Some(registry) = &self.admin_registry {
+                        registry.set_grease_enabled(&self.thread_name, enabled);
+                    }
+                    info!(
+                        "{} deliberate response errors enabled: {}",
+                        self.thread_name, enabled
+                    );
+                }
+                AdminAction::RevokeAndExit => {
+                    self.responder_rfc.set_compromised(CompromisedMode::Silent);
+                    self.responder_draft
+                        .set_compromised(CompromisedMode::Silent);
+                    self.responder_classic
+                        .set_compromised(CompromisedMode::Silent);
+                    self.exit_requested = true;
+
+                    error!(
+                        "{} online keys REVOKED by admin action; no longer signing",
+                        self.thread_name
+                    );
+                }
+            }
+        }
+    }
+
     #[cfg(fuzzing)]
     pub fn send_to_self(&mut self, data: &[u8]) {
         let res = self
@@ -175,6 +310,8 @@ impl Server {
     /// called repeatedly in a loop to process requests.
     ///
     pub fn process_events(&mut self, events: &mut Events) {
+        self.apply_admin_actions();
+
         self.poll
             .poll(events, self.poll_duration)
             .expect("server event poll failed; cannot recover");
@@ -188,12 +325,21 @@ impl Server {
 
                     let socket_now_empty = self.collect_requests();
 
-                    self.responder_rfc
-                        .send_responses(&mut self.socket, &mut self.stats);
-                    self.responder_draft
-                        .send_responses(&mut self.socket, &mut self.stats);
-                    self.responder_classic
-                        .send_responses(&mut self.socket, &mut self.stats);
+                    self.responder_rfc.send_responses(
+                        &mut self.socket,
+                        &mut self.stats,
+                        &mut self.long_term_key,
+                    );
+                    self.responder_draft.send_responses(
+                        &mut self.socket,
+                        &mut self.stats,
+                        &mut self.long_term_key,
+                    );
+                    self.responder_classic.send_responses(
+                        &mut self.socket,
+                        &mut self.stats,
+                        &mut self.long_term_key,
+                    );
 
                     if socket_now_empty {
                         break;
@@ -204,6 +350,18 @@ impl Server {
                 _ => unreachable!(),
             }
         }
+
+        if !events.is_empty() {
+            self.publish_counters();
+        }
+    }
+
+    fn publish_counters(&self) {
+        if let Some(registry) = &self.admin_registry {
+            let counters = WorkerCounters::from_stats(self.stats.as_ref());
+            registry.update_counters(&self.thread_name, counters);
+            registry.update_online_keys(&self.thread_name, self.online_key_status());
+        }
     }
 
     // Read and process client requests from socket until socket is empty or 'batch_size' number
@@ -212,7 +370,22 @@ impl Server {
         for i in 0..self.batch_size {
             match self.socket.recv_from(&mut self.buf) {
                 Ok((num_bytes, src_addr)) => {
-                    match request::nonce_from_request(&self.buf, num_bytes, &self.srv_value) {
+                    let parse = match self.strict_parsing {
+                        true => request::nonce_from_request_strict,
+                        false => request::nonce_from_request,
+                    };
+                    let request = parse(
+                        &self.buf,
+                        num_bytes,
+                        &self.srv_value,
+                        self.authenticator.as_ref(),
+                    );
+
+                    if let Some(auditor) = &mut self.auditor {
+                        auditor.audit(&request, &src_addr, &self.buf[..num_bytes]);
+                    }
+
+                    match request {
                         Ok((nonce, Version::Rfc)) => {
                             self.responder_rfc.add_request(nonce, src_addr);
                             self.stats.add_rfc_request(&src_addr.ip());
@@ -279,6 +452,12 @@ impl Server {
     }
 
     fn handle_status_update(&mut self) {
+        self.flush_stats();
+        self.timer.set_timeout(self.status_interval, ());
+    }
+
+    // Log the current stats and start a new status interval
+    fn flush_stats(&mut self) {
         let mut vec: Vec<(&IpAddr, &ClientStatEntry)> = self.stats.iter().collect();
         // sort in descending order
         vec.sort_by(|lhs, rhs| {
@@ -318,11 +497,197 @@ impl Server {
             self.stats.total_retried_send_attempts()
         );
 
+        let signing = self.stats.signing_latency();
+        if signing.count() > 0 {
+            let micros = |pct: f64| signing.percentile(pct).unwrap_or_default().as_micros();
+            info!(
+                "{} Signing: {} batches signed; latency p50 {}µs, p90 {}µs, p99 {}µs, max {}µs; {} online key rotations, {} delegation window violations, {} clock disagreements",
+                self.thread_name(),
+                signing.count(),
+                micros(50.0),
+                micros(90.0),
+                micros(99.0),
+                signing.max().as_micros(),
+                self.stats.total_online_key_rotations(),
+                self.stats.total_delegation_window_violations(),
+                self.stats.total_clock_disagreements()
+            );
+        }
+
+        // Interval stats are cleared below; epoch counts span intervals until the key rotates
+        let epochs: Vec<String> = self
+            .online_key_status()
+            .iter()
+            .map(|k| {
+                format!(
+                    "{} key {}.. (MINT {}) {} resp",
+                    k.protocol,
+                    &k.public_key[..8],
+                    k.mint,
+                    k.responses_signed
+                )
+            })
+            .collect();
+        info!("{} Epochs: {}", self.thread_name(), epochs.join("; "));
+
+        let balance = self
+            .metrics_aggregator
+            .as_ref()
+            .and_then(|agg| agg.add_interval(&self.thread_name, self.stats.total_valid_requests()));
+        if let Some(balance) = balance {
+            info!(
+                "Worker balance: imbalance coefficient {:.2}; busiest {} handled {:.0}% of {} requests across {} workers",
+                balance.coefficient,
+                balance.busiest_worker,
+                balance.busiest_share * 100.0,
+                balance.total_requests,
+                balance.num_workers
+            );
+            if balance.is_degraded() {
+                error!(
+                    "Worker metrics degraded: only {} of {} workers reported this interval; stale workers: {}",
+                    balance.num_workers,
+                    balance.expected_workers,
+                    if balance.missing_workers.is_empty() {
+                        "never reported".to_string()
+                    } else {
+                        balance.missing_workers.join(", ")
+                    }
+                );
+            }
+            if balance.is_imbalanced() {
+                warn!(
+                    "{} handled a disproportionate {:.0}% of requests; SO_REUSEPORT is balancing poorly, consider enabling receive packet steering (RPS/RFS) on the NIC or reducing num_workers",
+                    balance.busiest_worker,
+                    balance.busiest_share * 100.0
+                );
+            }
+        }
+
         self.stats.clear();
-        self.timer.set_timeout(self.status_interval, ());
+        if let Some(registry) = &self.admin_registry {
+            registry.reset_counters(&self.thread_name);
+        }
     }
 
     pub fn thread_name(&self) -> &str {
         &self.thread_name
     }
 }
+
+///
+/// Exercise the full request pipeline before serving clients. For each protocol version a
+/// request is built as a client would, parsed as the server would, answered by a responder
+/// using the configured long-term key, and the response validated as a client would.
+///
+/// A failure means the server would send bad responses, e.g. because a seed unwrapped via
+/// KMS isn't the expected key material or doesn't derive the configured `public_key`; the
+/// error describes the first failed step. On success, returns the long-term public key.
+///
+/// A server configured as `compromised` signs nothing, so only its seed is checked.
+///
+pub fn self_test(config: &dyn ServerConfig) -> Result<Vec<u8>, Error> {
+    let seed = kms::load_seed(config)
+        .map_err(|e| Error::SelfTestFailed(format!("loading seed: {:?}", e)))?;
+    kms::check_seed(config, &seed)
+        .map_err(|e| Error::SelfTestFailed(format!("checking seed: {:?}", e)))?;
+
+    let mut long_term_key = LongTermKey::new(&seed);
+    let public_key = long_term_key.public_key();
+    let srv_value = long_term_key.srv_value().to_vec();
+    let authenticator = config.psk().map(RequestAuthenticator::new);
+
+    // A server whose keys are compromised signs nothing, so there are no responses to check
+    if config.compromised().is_some() {
+        return Ok(public_key);
+    }
+
+    let mut socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap())?;
+    let client = StdUdpSocket::bind("127.0.0.1:0")?;
+    client.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut stats: Box<dyn ServerStats> = Box::new(AggregatedStats::new());
+
+    for version in [Version::Rfc, Version::RfcDraft11, Version::Classic] {
+        let failed = |step: &str, reason: String| {
+            Error::SelfTestFailed(format!("{} {}: {}", version, step, reason))
+        };
+
+        let nonce = request::new_nonce(version);
+
+        let msg = request::make_request(version, &nonce, Some(&srv_value), authenticator.as_ref());
+        let req = msg.encode_for(version)?;
+
+        match request::nonce_from_request(&req, req.len(), &srv_value, authenticator.as_ref()) {
+            Ok((ref parsed, parsed_version)) if *parsed == nonce && parsed_version == version => (),
+            Ok((_, parsed_version)) => {
+                return Err(failed(
+                    "request",
+                    format!("parsed as a {} request", parsed_version),
+                ))
+            }
+            Err(e) => return Err(failed("request", format!("rejected: {:?}", e))),
+        }
+
+        let mut responder = Responder::new(version, config, &mut long_term_key);
+        responder.set_grease_enabled(false);
+        responder.add_request(nonce.clone(), client.local_addr()?);
+        responder.send_responses(&mut socket, &mut stats, &mut long_term_key);
+
+        let mut buf = [0u8; 4096];
+        let num_bytes = client
+            .recv(&mut buf)
+            .map_err(|e| failed("response", format!("not received: {}", e)))?;
+        let resp_bytes = match version.profile().framed {
+            true => &buf[FRAME_HEADER_LENGTH.min(num_bytes)..num_bytes],
+            false => &buf[..num_bytes],
+        };
+
+        RtMessage::from_bytes(resp_bytes)
+            .map_err(|e| format!("malformed: {:?}", e))
+            .and_then(|resp| ResponseHandler::new(version, Some(public_key.clone()), resp, nonce))
+            .and_then(|handler| {
+                handler.extract_time()?;
+                match handler.version_warnings(&[version]).pop() {
+                    Some(warning) => Err(warning),
+                    None => Ok(()),
+                }
+            })
+            .map_err(|e| failed("response", e))?;
+    }
+
+    Ok(public_key)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::config::MemoryConfig;
+    use crate::server::self_test;
+    use crate::Error;
+
+    #[test]
+    fn self_test_passes_with_valid_configs() {
+        let mut config = MemoryConfig::new(0);
+        self_test(&config).unwrap();
+
+        config.psk = Some(b"0123456789abcdef".to_vec());
+        config.pad_responses = true;
+        config.fault_percentage = 50;
+        config.online_key_validity = Some(Duration::from_secs(60));
+        self_test(&config).unwrap();
+    }
+
+    #[test]
+    fn self_test_fails_with_bad_seed() {
+        let mut config = MemoryConfig::new(0);
+        config.seed = vec![0x17; 31];
+
+        match self_test(&config) {
+            Err(Error::SelfTestFailed(reason)) => {
+                assert!(reason.contains("31 bytes"), "{}", reason)
+            }
+            other => panic!("self-test passed with a bad seed: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,1003 @@
+// Copyright 2017-2024 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Lists of Roughtime servers in the JSON "ecosystem" format shared with other
+//! Roughtime implementations.
+//!
+//! Roughenough extends the format with optional per-key validity windows so that a
+//! list can announce a server's next public key before it is put into service:
+//!
+//! ```json
+//! {
+//!   "servers": [
+//!     {
+//!       "name": "roughtime.int08h.com",
+//!       "version": "IETF-Roughtime",
+//!       "publicKeyType": "ed25519",
+//!       "keys": [
+//!         {
+//!           "publicKey": "AW5uAoTSTDfG5NfY1bTh08GUnOqlRb+HVhbJ3ODJvsE=",
+//!           "validUntil": "2025-01-01T00:00:00Z"
+//!         },
+//!         {
+//!           "publicKey": "0GD7c3yP8xEc4Zl2zeuN2SlLvDVVocjsPSL8/Rl/7zg=",
+//!           "validFrom": "2025-01-01T00:00:00Z"
+//!         }
+//!       ],
+//!       "addresses": [
+//!         {
+//!           "protocol": "udp",
+//!           "address": "roughtime.int08h.com:2002",
+//!           "fallbackAddresses": ["192.0.2.7:2002", "[2001:db8::7]:2002"]
+//!         }
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! The single `publicKey` field of the original format is still accepted and may be
+//! combined with top-level `validFrom`/`validUntil` values.
+//!
+//! An address may also list `fallbackAddresses`, IP literals to use when its hostname
+//! cannot be resolved at all. Devices whose DNS depends on having the correct time (for
+//! example to validate DNSSEC signatures) can then still reach the server to learn it.
+//!
+//! [`Server::to_json`](struct.Server.html#method.to_json) writes an entry back out in this
+//! format, for operators publishing a new server.
+//!
+//! A list may require corroboration by several servers with a top-level `"witnesses": M`.
+//! Clients then only trust a time (e.g. to set the system clock) that the responses of at
+//! least M servers with distinct public keys agree on, so no single compromised server (or
+//! key) can move their clock. See [`client::corroborate`](../client/fn.corroborate.html).
+//!
+//! Lists are parsed as a stream of entries, so memory use doesn't depend on the size of
+//! the list's source. Clients that only need a few servers from a large list can sample
+//! them while the list is read with
+//! [`ServerList::sample_from_reader`](struct.ServerList.html#method.sample_from_reader),
+//! which only ever holds the sampled entries and the one being parsed, or pick them from a
+//! list already loaded with
+//! [`ServerList::choose_random`](struct.ServerList.html#method.choose_random).
+//!
+
+use std::fs::File;
+use std::io::{self, BufReader, Bytes, Read};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use data_encoding::BASE64;
+use rand::Rng;
+use yaml_rust::parser::{Event, EventReceiver, Parser};
+use yaml_rust::scanner::TScalarStyle;
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use crate::key::decode_public_key;
+use crate::version::Version;
+use crate::{Error, PUBLIC_KEY_LENGTH};
+
+/// Amount of time before a key's `validUntil` at which clients should warn the list is stale
+pub const DEFAULT_EXPIRY_WARNING: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+///
+/// A server public key, optionally restricted to a window of validity.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerKey {
+    /// Ed25519 long-term public key of the server
+    pub public_key: Vec<u8>,
+
+    /// Key must not be used before this time (inclusive)
+    pub valid_from: Option<SystemTime>,
+
+    /// Key must not be used at or after this time
+    pub valid_until: Option<SystemTime>,
+}
+
+impl ServerKey {
+    /// True if this key may be used to validate responses at time `t`
+    pub fn is_valid_at(&self, t: SystemTime) -> bool {
+        let started = match self.valid_from {
+            Some(from) => t >= from,
+            None => true,
+        };
+        let ended = match self.valid_until {
+            Some(until) => t >= until,
+            None => false,
+        };
+
+        started && !ended
+    }
+
+    /// True if this key stops being valid within `horizon` of time `t`
+    pub fn expires_within(&self, t: SystemTime, horizon: Duration) -> bool {
+        match self.valid_until {
+            Some(until) => until <= t + horizon,
+            None => false,
+        }
+    }
+}
+
+///
+/// A network address at which a server can be reached.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAddress {
+    /// Transport protocol, only `udp` is used by Roughenough
+    pub protocol: String,
+
+    /// `host:port` of the server
+    pub address: String,
+
+    /// IP literals to use if `address` cannot be resolved
+    pub fallbacks: Vec<SocketAddr>,
+}
+
+///
+/// An entry in a server list.
+///
+#[derive(Debug, Clone)]
+pub struct Server {
+    pub name: String,
+    pub version: String,
+    pub keys: Vec<ServerKey>,
+    pub addresses: Vec<ServerAddress>,
+}
+
+impl Server {
+    /// The key to use at time `t`, if any. When the validity windows of several keys overlap,
+    /// the most recently introduced key (latest `validFrom`) is preferred.
+    pub fn key_valid_at(&self, t: SystemTime) -> Option<&ServerKey> {
+        self.keys
+            .iter()
+            .filter(|k| k.is_valid_at(t))
+            .max_by_key(|k| k.valid_from.unwrap_or(UNIX_EPOCH))
+    }
+
+    /// The Roughtime protocol version the server speaks, `None` if it is not one Roughenough
+    /// supports.
+    pub fn protocol_version(&self) -> Option<Version> {
+        match self.version.as_str() {
+            "Google-Roughtime" => Some(Version::Classic),
+            "IETF-Roughtime" => Some(Version::RfcDraft11),
+            _ => None,
+        }
+    }
+
+    /// Addresses of the server that use UDP transport
+    pub fn udp_addresses(&self) -> impl Iterator<Item = &ServerAddress> {
+        self.addresses
+            .iter()
+            .filter(|a| a.protocol.eq_ignore_ascii_case("udp"))
+    }
+
+    /// Fallback IP literals of the server's UDP addresses, in list order without duplicates
+    pub fn udp_fallbacks(&self) -> Vec<SocketAddr> {
+        let mut fallbacks = Vec::new();
+        for addr in self.udp_addresses().flat_map(|a| a.fallbacks.iter()) {
+            if !fallbacks.contains(addr) {
+                fallbacks.push(*addr);
+            }
+        }
+        fallbacks
+    }
+
+    /// This server as an entry of a JSON server list, ready to be added to its `servers`
+    pub fn to_json(&self) -> String {
+        let keys: Vec<String> = self.keys.iter().map(key_json).collect();
+        let addresses: Vec<String> = self.addresses.iter().map(address_json).collect();
+
+        format!(
+            "{{\n  \"name\": {:?},\n  \"version\": {:?},\n  \"publicKeyType\": \"ed25519\",\n  \
+             \"keys\": [\n{}\n  ],\n  \"addresses\": [\n{}\n  ]\n}}",
+            self.name,
+            self.version,
+            keys.join(",\n"),
+            addresses.join(",\n")
+        )
+    }
+}
+
+fn key_json(key: &ServerKey) -> String {
+    let mut fields = vec![format!(
+        "\"publicKey\": \"{}\"",
+        BASE64.encode(&key.public_key)
+    )];
+    for (field, time) in [
+        ("validFrom", key.valid_from),
+        ("validUntil", key.valid_until),
+    ] {
+        if let Some(time) = time {
+            let time = DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true);
+            fields.push(format!("\"{}\": \"{}\"", field, time));
+        }
+    }
+
+    format!("    {{ {} }}", fields.join(", "))
+}
+
+fn address_json(addr: &ServerAddress) -> String {
+    let mut fields = vec![
+        format!("\"protocol\": {:?}", addr.protocol),
+        format!("\"address\": {:?}", addr.address),
+    ];
+    if !addr.fallbacks.is_empty() {
+        let fallbacks: Vec<String> = addr
+            .fallbacks
+            .iter()
+            .map(|a| format!("\"{}\"", a))
+            .collect();
+        fields.push(format!("\"fallbackAddresses\": [{}]", fallbacks.join(", ")));
+    }
+
+    format!("    {{ {} }}", fields.join(", "))
+}
+
+///
+/// A list of Roughtime servers.
+///
+#[derive(Debug, Clone)]
+pub struct ServerList {
+    servers: Vec<Server>,
+    witnesses: Option<usize>,
+}
+
+impl ServerList {
+    /// Read a server list from the JSON file at `path`
+    pub fn from_file(path: &str) -> Result<Self, Error> {
+        let file = File::open(path)
+            .map_err(|e| invalid(format!("could not read server list '{}': {}", path, e)))?;
+
+        ServerList::from_reader(file)
+    }
+
+    /// Parse a server list from its JSON representation
+    pub fn from_json(contents: &str) -> Result<Self, Error> {
+        ServerList::from_reader(contents.as_bytes())
+    }
+
+    /// Parse a server list from the JSON that `reader` yields
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, Error> {
+        let mut servers = Vec::new();
+        let witnesses = parse_list(reader, |_, server| servers.push(server))?;
+
+        Ok(ServerList { servers, witnesses })
+    }
+
+    ///
+    /// Parse the server list `reader` yields, keeping only up to `count` of its servers,
+    /// chosen uniformly at random and in list order. Entries are sampled (by reservoir
+    /// sampling) as they are parsed, so only the chosen servers are held however long the
+    /// list is.
+    ///
+    /// `witnesses` is checked against the length of the whole list.
+    ///
+    pub fn sample_from_reader<R: Read, G: Rng + ?Sized>(
+        reader: R,
+        count: usize,
+        rng: &mut G,
+    ) -> Result<Self, Error> {
+        let mut reservoir: Vec<(usize, Server)> = Vec::with_capacity(count);
+
+        let witnesses = parse_list(reader, |i, server| {
+            if reservoir.len() < count {
+                reservoir.push((i, server));
+            } else {
+                let j = rng.gen_range(0, i + 1);
+                if j < count {
+                    reservoir[j] = (i, server);
+                }
+            }
+        })?;
+
+        reservoir.sort_by_key(|(i, _)| *i);
+        let servers = reservoir.into_iter().map(|(_, server)| server).collect();
+
+        Ok(ServerList { servers, witnesses })
+    }
+
+    /// The servers in this list
+    pub fn servers(&self) -> &[Server] {
+        &self.servers
+    }
+
+    /// Number of servers with distinct public keys that must agree on the time, if the list
+    /// requires corroboration
+    pub fn witnesses(&self) -> Option<usize> {
+        self.witnesses
+    }
+
+    /// Up to `count` servers chosen uniformly at random, in list order. Reservoir sampling
+    /// keeps only `count` references however long the list is.
+    pub fn choose_random<R: Rng + ?Sized>(&self, count: usize, rng: &mut R) -> Vec<&Server> {
+        let mut reservoir: Vec<(usize, &Server)> = Vec::with_capacity(count);
+
+        for (i, server) in self.servers.iter().enumerate() {
+            if reservoir.len() < count {
+                reservoir.push((i, server));
+            } else {
+                let j = rng.gen_range(0, i + 1);
+                if j < count {
+                    reservoir[j] = (i, server);
+                }
+            }
+        }
+
+        reservoir.sort_by_key(|(i, _)| *i);
+        reservoir.into_iter().map(|(_, server)| server).collect()
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidConfiguration(msg)
+}
+
+//
+// Parse the server list `reader` yields one entry at a time, handing each server and its
+// index in the list to `on_server`, and return the list's `witnesses`. JSON is a subset of
+// YAML, so the YAML parser used for server configuration reads it; only the entry being
+// parsed is held as a `Yaml` tree.
+//
+fn parse_list<R, F>(reader: R, on_server: F) -> Result<Option<usize>, Error>
+where
+    R: Read,
+    F: FnMut(usize, Server),
+{
+    let mut read_error = None;
+    let mut receiver = ListReceiver {
+        on_server,
+        state: ListState::Start,
+        builder: NodeBuilder::default(),
+        key: None,
+        witnesses: Yaml::BadValue,
+        num_servers: 0,
+        has_servers: false,
+        error: None,
+    };
+
+    let chars = Utf8Chars {
+        bytes: BufReader::new(reader).bytes(),
+        error: &mut read_error,
+    };
+    let loaded = Parser::new(chars).load(&mut receiver, false);
+
+    if let Some(e) = read_error {
+        return Err(invalid(format!("could not read server list: {}", e)));
+    }
+    if let Err(e) = loaded {
+        return Err(invalid(format!("malformed server list: {}", e)));
+    }
+    if let Some(e) = receiver.error {
+        return Err(e);
+    }
+    if !receiver.has_servers {
+        return Err(invalid("server list has no 'servers' array".to_string()));
+    }
+
+    parse_witnesses(&receiver.witnesses, receiver.num_servers)
+}
+
+// Where in the list document the parser is
+enum ListState {
+    // Before the top-level object
+    Start,
+    // In the top-level object, expecting a key
+    Key,
+    // In the value of the top-level key `key`
+    Value,
+    // In the `servers` array, between or inside entries
+    Servers,
+    // After the top-level object, or after an error
+    Done,
+}
+
+struct ListReceiver<F> {
+    on_server: F,
+    state: ListState,
+    builder: NodeBuilder,
+    key: Option<String>,
+    witnesses: Yaml,
+    num_servers: usize,
+    has_servers: bool,
+    error: Option<Error>,
+}
+
+impl<F: FnMut(usize, Server)> EventReceiver for ListReceiver<F> {
+    fn on_event(&mut self, ev: Event) {
+        match self.state {
+            ListState::Start => match ev {
+                Event::MappingStart(_) => self.state = ListState::Key,
+                Event::Scalar(..) | Event::SequenceStart(_) => self.state = ListState::Done,
+                _ => (),
+            },
+            ListState::Key => match ev {
+                Event::Scalar(key, ..) => {
+                    self.state = ListState::Value;
+                    self.key = Some(key);
+                }
+                _ => self.state = ListState::Done,
+            },
+            ListState::Value => match (self.key.as_deref(), ev) {
+                (Some("servers"), Event::SequenceStart(_)) if self.builder.is_idle() => {
+                    self.has_servers = true;
+                    self.state = ListState::Servers;
+                }
+                (key, ev) => {
+                    if let Some(value) = self.builder.on_event(ev) {
+                        if key == Some("witnesses") {
+                            self.witnesses = value;
+                        }
+                        self.state = ListState::Key;
+                    }
+                }
+            },
+            ListState::Servers => match ev {
+                Event::SequenceEnd if self.builder.is_idle() => self.state = ListState::Key,
+                ev => {
+                    if let Some(entry) = self.builder.on_event(ev) {
+                        match parse_server(&entry) {
+                            Ok(server) => {
+                                (self.on_server)(self.num_servers, server);
+                                self.num_servers += 1;
+                            }
+                            Err(e) => {
+                                self.error = Some(e);
+                                self.state = ListState::Done;
+                            }
+                        }
+                    }
+                }
+            },
+            ListState::Done => (),
+        }
+    }
+}
+
+// Builds the `Yaml` tree of one value from its parser events
+#[derive(Default)]
+struct NodeBuilder {
+    // Arrays and objects being built, with the pending key of each object
+    stack: Vec<(Yaml, Option<Yaml>)>,
+}
+
+impl NodeBuilder {
+    fn is_idle(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    // Add `ev` to the value, returning the value once it is complete
+    fn on_event(&mut self, ev: Event) -> Option<Yaml> {
+        let node = match ev {
+            Event::SequenceStart(_) => {
+                self.stack.push((Yaml::Array(Vec::new()), None));
+                return None;
+            }
+            Event::MappingStart(_) => {
+                self.stack.push((Yaml::Hash(Hash::new()), None));
+                return None;
+            }
+            Event::SequenceEnd | Event::MappingEnd => self.stack.pop()?.0,
+            Event::Scalar(value, TScalarStyle::Plain, ..) => Yaml::from_str(&value),
+            Event::Scalar(value, ..) => Yaml::String(value),
+            _ => return None,
+        };
+
+        match self.stack.last_mut() {
+            None => Some(node),
+            Some((Yaml::Array(values), _)) => {
+                values.push(node);
+                None
+            }
+            Some((Yaml::Hash(entries), key)) => {
+                match key.take() {
+                    Some(key) => {
+                        entries.insert(key, node);
+                    }
+                    None => *key = Some(node),
+                }
+                None
+            }
+            Some(_) => unreachable!("only arrays and objects are stacked"),
+        }
+    }
+}
+
+// The characters of UTF-8 encoded bytes, ending early at the first read or decoding error
+struct Utf8Chars<'a, R> {
+    bytes: Bytes<BufReader<R>>,
+    error: &'a mut Option<io::Error>,
+}
+
+impl<'a, R: Read> Utf8Chars<'a, R> {
+    fn fail(&mut self, error: io::Error) -> Option<char> {
+        *self.error = Some(error);
+        None
+    }
+}
+
+impl<'a, R: Read> Iterator for Utf8Chars<'a, R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        let mut buf = [0u8; 4];
+        buf[0] = match self.bytes.next()? {
+            Ok(b) => b,
+            Err(e) => return self.fail(e),
+        };
+        let len = match buf[0] {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 0,
+        };
+
+        for b in buf.iter_mut().take(len).skip(1) {
+            *b = match self.bytes.next() {
+                Some(Ok(b)) => b,
+                Some(Err(e)) => return self.fail(e),
+                None => break,
+            };
+        }
+
+        match std::str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|s| s.chars().next())
+        {
+            Some(c) => Some(c),
+            None => self.fail(io::Error::new(io::ErrorKind::InvalidData, "invalid UTF-8")),
+        }
+    }
+}
+
+fn parse_witnesses(value: &Yaml, num_servers: usize) -> Result<Option<usize>, Error> {
+    if value.is_badvalue() {
+        return Ok(None);
+    }
+
+    match value.as_i64() {
+        Some(m) if m >= 1 && m as usize <= num_servers => Ok(Some(m as usize)),
+        _ => Err(invalid(format!(
+            "'witnesses' must be between 1 and the number of servers ({}), found {:?}",
+            num_servers, value
+        ))),
+    }
+}
+
+fn parse_server(entry: &Yaml) -> Result<Server, Error> {
+    let name = match entry["name"].as_str() {
+        Some(name) => name.to_string(),
+        None => return Err(invalid("server list entry without a 'name'".to_string())),
+    };
+
+    let version = entry["version"]
+        .as_str()
+        .unwrap_or("Google-Roughtime")
+        .to_string();
+
+    if let Some(key_type) = entry["publicKeyType"].as_str() {
+        if key_type != "ed25519" {
+            return Err(invalid(format!(
+                "server '{}': unsupported publicKeyType '{}'",
+                name, key_type
+            )));
+        }
+    }
+
+    let mut keys = Vec::new();
+
+    if !entry["publicKey"].is_badvalue() {
+        keys.push(parse_key(&name, entry)?);
+    }
+
+    if let Some(key_entries) = entry["keys"].as_vec() {
+        for key_entry in key_entries {
+            keys.push(parse_key(&name, key_entry)?);
+        }
+    }
+
+    if keys.is_empty() {
+        return Err(invalid(format!("server '{}' has no public keys", name)));
+    }
+
+    let mut addresses = Vec::new();
+    if let Some(addr_entries) = entry["addresses"].as_vec() {
+        for addr in addr_entries {
+            match (addr["protocol"].as_str(), addr["address"].as_str()) {
+                (Some(protocol), Some(address)) => addresses.push(ServerAddress {
+                    protocol: protocol.to_string(),
+                    address: address.to_string(),
+                    fallbacks: parse_fallbacks(&name, &addr["fallbackAddresses"])?,
+                }),
+                _ => {
+                    return Err(invalid(format!(
+                        "server '{}': address requires 'protocol' and 'address'",
+                        name
+                    )))
+                }
+            }
+        }
+    }
+
+    if addresses.is_empty() {
+        return Err(invalid(format!("server '{}' has no addresses", name)));
+    }
+
+    Ok(Server {
+        name,
+        version,
+        keys,
+        addresses,
+    })
+}
+
+fn parse_fallbacks(name: &str, value: &Yaml) -> Result<Vec<SocketAddr>, Error> {
+    if value.is_badvalue() {
+        return Ok(Vec::new());
+    }
+
+    let entries = match value.as_vec() {
+        Some(entries) => entries,
+        None => {
+            return Err(invalid(format!(
+                "server '{}': 'fallbackAddresses' must be an array",
+                name
+            )))
+        }
+    };
+
+    let mut fallbacks = Vec::with_capacity(entries.len());
+    for entry in entries {
+        match entry.as_str().map(str::parse::<SocketAddr>) {
+            Some(Ok(addr)) => fallbacks.push(addr),
+            _ => {
+                return Err(invalid(format!(
+                    "server '{}': fallback {:?} is not an IP:port literal",
+                    name, entry
+                )))
+            }
+        }
+    }
+
+    Ok(fallbacks)
+}
+
+fn parse_key(name: &str, entry: &Yaml) -> Result<ServerKey, Error> {
+    let encoded = match entry["publicKey"].as_str() {
+        Some(encoded) => encoded,
+        None => return Err(invalid(format!("server '{}': missing 'publicKey'", name))),
+    };
+
+    let public_key = decode_public_key(encoded)
+        .map_err(|_| invalid(format!("server '{}': undecodable key '{}'", name, encoded)))?;
+
+    if public_key.len() != PUBLIC_KEY_LENGTH {
+        return Err(invalid(format!(
+            "server '{}': public key must be 32 bytes, found {}",
+            name,
+            public_key.len()
+        )));
+    }
+
+    let valid_from = parse_time(name, &entry["validFrom"])?;
+    let valid_until = parse_time(name, &entry["validUntil"])?;
+
+    if let (Some(from), Some(until)) = (valid_from, valid_until) {
+        if from >= until {
+            return Err(invalid(format!(
+                "server '{}': validFrom must be before validUntil",
+                name
+            )));
+        }
+    }
+
+    Ok(ServerKey {
+        public_key,
+        valid_from,
+        valid_until,
+    })
+}
+
+fn parse_time(name: &str, value: &Yaml) -> Result<Option<SystemTime>, Error> {
+    if value.is_badvalue() {
+        return Ok(None);
+    }
+
+    match value.as_str().map(DateTime::parse_from_rfc3339) {
+        Some(Ok(t)) => Ok(Some(SystemTime::from(t))),
+        _ => Err(invalid(format!(
+            "server '{}': invalid RFC 3339 timestamp {:?}",
+            name, value
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::io;
+    use std::io::Read;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use crate::server_list::*;
+
+    // 2024-01-01T00:00:00Z
+    const JAN_2024: u64 = 1_704_067_200;
+    // 2025-01-01T00:00:00Z
+    const JAN_2025: u64 = 1_735_689_600;
+
+    const LIST: &str = r#"{
+      "servers": [
+        {
+          "name": "rotating",
+          "version": "IETF-Roughtime",
+          "publicKeyType": "ed25519",
+          "keys": [
+            {
+              "publicKey": "AW5uAoTSTDfG5NfY1bTh08GUnOqlRb+HVhbJ3ODJvsE=",
+              "validFrom": "2024-01-01T00:00:00Z",
+              "validUntil": "2025-01-01T00:00:00Z"
+            },
+            {
+              "publicKey": "0GD7c3yP8xEc4Zl2zeuN2SlLvDVVocjsPSL8/Rl/7zg=",
+              "validFrom": "2025-01-01T00:00:00Z"
+            }
+          ],
+          "addresses": [
+            { "protocol": "tcp", "address": "localhost:2002", "fallbackAddresses": ["127.0.0.3:2002"] },
+            { "protocol": "udp", "address": "localhost:2002", "fallbackAddresses": ["127.0.0.1:2002", "[::1]:2002"] },
+            { "protocol": "udp", "address": "localhost:2003", "fallbackAddresses": ["[::1]:2002"] }
+          ]
+        },
+        {
+          "name": "classic",
+          "version": "Google-Roughtime",
+          "publicKeyType": "ed25519",
+          "publicKey": "016e6e0284d24c37c6e4d7d8d5b4e1d3c1949ceaa545bf875616c9dce0c9bec1",
+          "addresses": [
+            { "protocol": "udp", "address": "127.0.0.1:2002" }
+          ]
+        }
+      ]
+    }"#;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn parse_ecosystem_list() {
+        let list = ServerList::from_json(LIST).unwrap();
+        let servers = list.servers();
+
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].name, "rotating");
+        assert_eq!(servers[0].keys.len(), 2);
+        assert_eq!(servers[0].keys[0].valid_from, Some(at(JAN_2024)));
+        assert_eq!(servers[0].keys[0].valid_until, Some(at(JAN_2025)));
+        assert_eq!(servers[0].protocol_version(), Some(Version::RfcDraft11));
+        assert_eq!(servers[0].udp_addresses().count(), 2);
+        assert_eq!(
+            servers[0].udp_fallbacks(),
+            vec![
+                "127.0.0.1:2002".parse().unwrap(),
+                "[::1]:2002".parse().unwrap()
+            ]
+        );
+
+        // hex and base64 encodings of the same key
+        assert_eq!(servers[1].keys[0].public_key, servers[0].keys[0].public_key);
+        assert_eq!(servers[1].keys[0].valid_from, None);
+        assert_eq!(servers[1].protocol_version(), Some(Version::Classic));
+        assert!(servers[1].udp_fallbacks().is_empty());
+    }
+
+    #[test]
+    fn key_chosen_by_validity_window() {
+        let list = ServerList::from_json(LIST).unwrap();
+        let server = &list.servers()[0];
+
+        assert_eq!(server.key_valid_at(at(JAN_2024 - 1)), None);
+        assert_eq!(server.key_valid_at(at(JAN_2024)), Some(&server.keys[0]));
+        assert_eq!(server.key_valid_at(at(JAN_2025 - 1)), Some(&server.keys[0]));
+        assert_eq!(server.key_valid_at(at(JAN_2025)), Some(&server.keys[1]));
+        assert_eq!(server.key_valid_at(at(JAN_2025 * 2)), Some(&server.keys[1]));
+    }
+
+    #[test]
+    fn key_expiry_horizon() {
+        let list = ServerList::from_json(LIST).unwrap();
+        let key = &list.servers()[0].keys[0];
+        let day = Duration::from_secs(86_400);
+
+        assert!(!key.expires_within(at(JAN_2025) - 2 * day, day));
+        assert!(key.expires_within(at(JAN_2025) - day, day));
+        assert!(!list.servers()[0].keys[1].expires_within(at(JAN_2025), day));
+    }
+
+    #[test]
+    fn entries_round_trip_through_json() {
+        let list = ServerList::from_json(LIST).unwrap();
+
+        for server in list.servers() {
+            let json = format!(r#"{{ "servers": [ {} ] }}"#, server.to_json());
+            let parsed = ServerList::from_json(&json).unwrap();
+            let copy = &parsed.servers()[0];
+
+            assert_eq!(copy.name, server.name);
+            assert_eq!(copy.version, server.version);
+            assert_eq!(copy.keys, server.keys);
+            assert_eq!(copy.addresses, server.addresses);
+        }
+    }
+
+    #[test]
+    fn random_choice_is_a_subset_in_list_order() {
+        let entries: Vec<String> = (0..50)
+            .map(|i| {
+                format!(
+                    r#"{{ "name": "s{}", "publicKey": "AW5uAoTSTDfG5NfY1bTh08GUnOqlRb+HVhbJ3ODJvsE=", "addresses": [ {{ "protocol": "udp", "address": "a:1" }} ] }}"#,
+                    i
+                )
+            })
+            .collect();
+        let json = format!(r#"{{ "servers": [ {} ] }}"#, entries.join(","));
+        let list = ServerList::from_json(&json).unwrap();
+        let mut rng = SmallRng::seed_from_u64(2491);
+
+        let mut seen = HashSet::new();
+        for _ in 0..100 {
+            let chosen = list.choose_random(5, &mut rng);
+            assert_eq!(chosen.len(), 5);
+
+            let indexes: Vec<usize> = chosen
+                .iter()
+                .map(|s| s.name[1..].parse().unwrap())
+                .collect();
+            assert!(indexes.windows(2).all(|w| w[0] < w[1]));
+            seen.extend(indexes);
+        }
+
+        // every entry can be chosen, including the first and last
+        assert_eq!(seen.len(), 50);
+        assert_eq!(list.choose_random(80, &mut rng).len(), 50);
+        assert!(list.choose_random(0, &mut rng).is_empty());
+    }
+
+    // A list of `remaining` servers produced as it is read, never held whole in memory
+    struct GeneratedList {
+        next: usize,
+        remaining: usize,
+        pending: Vec<u8>,
+    }
+
+    impl Read for GeneratedList {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pending.is_empty() {
+                self.pending = match (self.next, self.remaining) {
+                    (0, _) => r#"{ "witnesses": 2, "servers": ["#.into(),
+                    (_, 0) => return Ok(0),
+                    (_, 1) => b"] }".to_vec(),
+                    _ => format!(
+                        r#"{}{{ "name": "s{}", "publicKey": "AW5uAoTSTDfG5NfY1bTh08GUnOqlRb+HVhbJ3ODJvsE=", "addresses": [ {{ "protocol": "udp", "address": "a:1" }} ] }}"#,
+                        if self.next == 1 { "" } else { "," },
+                        self.next - 1
+                    )
+                    .into_bytes(),
+                };
+                if self.next > 0 {
+                    self.remaining -= 1;
+                }
+                self.next += 1;
+            }
+
+            let len = buf.len().min(self.pending.len());
+            buf[..len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn sampled_while_parsing() {
+        let mut rng = SmallRng::seed_from_u64(2495);
+        let generated = |servers: usize| GeneratedList {
+            next: 0,
+            remaining: servers + 1,
+            pending: Vec::new(),
+        };
+
+        let list = ServerList::sample_from_reader(generated(10_000), 3, &mut rng).unwrap();
+        let indexes: Vec<usize> = list
+            .servers()
+            .iter()
+            .map(|s| s.name[1..].parse().unwrap())
+            .collect();
+        assert_eq!(indexes.len(), 3);
+        assert!(indexes.windows(2).all(|w| w[0] < w[1]));
+        assert!(indexes.iter().all(|&i| i < 10_000));
+
+        // witnesses are checked against the whole list, not the sample
+        assert_eq!(list.witnesses(), Some(2));
+        assert!(ServerList::sample_from_reader(generated(1), 1, &mut rng).is_err());
+
+        let all = ServerList::from_reader(generated(40)).unwrap();
+        assert_eq!(all.servers().len(), 40);
+        assert_eq!(all.servers()[39].name, "s39");
+    }
+
+    #[test]
+    fn entries_are_decoded_as_utf8() {
+        let json = LIST.replace(r#""name": "classic""#, r#""name": "zürich-時計""#);
+        let list = ServerList::from_reader(json.as_bytes()).unwrap();
+        assert_eq!(list.servers()[1].name, "zürich-時計");
+
+        let mut bytes = json.into_bytes();
+        let idx = bytes.iter().position(|&b| b == 0xc3).unwrap();
+        bytes[idx] = 0xff;
+        match ServerList::from_reader(&bytes[..]) {
+            Err(Error::InvalidConfiguration(msg)) => assert!(msg.contains("UTF-8"), "{}", msg),
+            e => panic!("expected a decoding error, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn witnesses_required_by_the_list() {
+        assert_eq!(ServerList::from_json(LIST).unwrap().witnesses(), None);
+
+        let json = LIST.replacen("{", r#"{ "witnesses": 2,"#, 1);
+        assert_eq!(ServerList::from_json(&json).unwrap().witnesses(), Some(2));
+
+        for bad in ["0", "3", "\"two\""] {
+            let json = LIST.replacen("{", &format!(r#"{{ "witnesses": {},"#, bad), 1);
+            assert!(matches!(
+                ServerList::from_json(&json),
+                Err(Error::InvalidConfiguration(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn reject_malformed_lists() {
+        let bad = [
+            r#"{ "no_servers": [] }"#,
+            r#"{ "servers": {} }"#,
+            "",
+            r#"{ "servers": [ "#,
+            r#"{ "servers": [ { "name": "no-keys", "addresses": [ { "protocol": "udp", "address": "a:1" } ] } ] }"#,
+            r#"{ "servers": [ { "name": "no-addr", "publicKey": "AW5uAoTSTDfG5NfY1bTh08GUnOqlRb+HVhbJ3ODJvsE=" } ] }"#,
+            r#"{ "servers": [ { "name": "short", "publicKey": "AAAA", "addresses": [ { "protocol": "udp", "address": "a:1" } ] } ] }"#,
+            r#"{ "servers": [ { "name": "bad-time", "publicKey": "AW5uAoTSTDfG5NfY1bTh08GUnOqlRb+HVhbJ3ODJvsE=", "validFrom": "yesterday", "addresses": [ { "protocol": "udp", "address": "a:1" } ] } ] }"#,
+            r#"{ "servers": [ { "name": "inverted", "publicKey": "AW5uAoTSTDfG5NfY1bTh08GUnOqlRb+HVhbJ3ODJvsE=", "validFrom": "2025-01-01T00:00:00Z", "validUntil": "2024-01-01T00:00:00Z", "addresses": [ { "protocol": "udp", "address": "a:1" } ] } ] }"#,
+            r#"{ "servers": [ { "name": "fallback-host", "publicKey": "AW5uAoTSTDfG5NfY1bTh08GUnOqlRb+HVhbJ3ODJvsE=", "addresses": [ { "protocol": "udp", "address": "a:1", "fallbackAddresses": ["b:1"] } ] } ] }"#,
+            r#"{ "servers": [ { "name": "fallback-str", "publicKey": "AW5uAoTSTDfG5NfY1bTh08GUnOqlRb+HVhbJ3ODJvsE=", "addresses": [ { "protocol": "udp", "address": "a:1", "fallbackAddresses": "192.0.2.1:1" } ] } ] }"#,
+        ];
+
+        for json in bad.iter() {
+            match ServerList::from_json(json) {
+                Err(Error::InvalidConfiguration(_)) => (),
+                e => panic!("expected error for {}, got {:?}", json, e),
+            }
+        }
+    }
+}
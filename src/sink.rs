@@ -0,0 +1,469 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Destinations other than stdout for the client's results, e.g. for IoT gateways that
+//! forward verified time and violations to other systems.
+//!
+//! Each [`Event`](enum.Event.html) is published as a JSON object. Sinks are created from a
+//! spec with [`from_spec`](fn.from_spec.html):
+//!
+//! Spec                      | Sink
+//! ------------------------- | ----
+//! `file:<path>`             | Appends one JSON object per line to the file at `path`
+//! `syslog`                  | Sends each object to the local syslog daemon (`/dev/log`)
+//! `mqtt://host:port/topic`  | Publishes each object to an MQTT 3.1.1 broker (QoS 0). Requires the `mqtt` feature.
+//!
+
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "mqtt")]
+use std::io::Read;
+use std::io::Write;
+#[cfg(feature = "mqtt")]
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(feature = "mqtt")]
+use std::time::Duration;
+
+use crate::Error;
+
+/// Socket of the local syslog daemon
+#[cfg(unix)]
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Something the client observed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A response that passed validation. Times are microseconds since the Unix epoch.
+    Time {
+        server: &'a str,
+        midpoint: u64,
+        radius: u64,
+        verified: bool,
+    },
+
+    /// A response that failed validation (`invalid_response`) or contradicted an earlier one
+    /// (`causality_violation`)
+    Violation {
+        server: &'a str,
+        kind: &'a str,
+        detail: &'a str,
+    },
+}
+
+impl Event<'_> {
+    /// True for events that indicate a problem
+    pub fn is_violation(&self) -> bool {
+        matches!(self, Event::Violation { .. })
+    }
+
+    pub fn to_json(&self) -> String {
+        match self {
+            Event::Time {
+                server,
+                midpoint,
+                radius,
+                verified,
+            } => format!(
+                r#"{{"event":"time","server":{:?},"midpoint_us":{},"radius_us":{},"verified":{}}}"#,
+                server, midpoint, radius, verified
+            ),
+            Event::Violation {
+                server,
+                kind,
+                detail,
+            } => format!(
+                r#"{{"event":"violation","server":{:?},"kind":{:?},"detail":{:?}}}"#,
+                server, kind, detail
+            ),
+        }
+    }
+}
+
+/// A destination that events are published to
+pub trait Sink {
+    fn publish(&mut self, event: &Event) -> Result<(), Error>;
+}
+
+///
+/// Create the sink described by `spec`; see the [module documentation](index.html) for the
+/// accepted specs.
+///
+pub fn from_spec(spec: &str) -> Result<Box<dyn Sink>, Error> {
+    if let Some(path) = spec.strip_prefix("file:") {
+        return Ok(Box::new(FileSink::open(path)?));
+    }
+
+    if spec == "syslog" {
+        #[cfg(unix)]
+        return Ok(Box::new(SyslogSink::connect(SYSLOG_SOCKET)?));
+
+        #[cfg(not(unix))]
+        return Err(Error::InvalidConfiguration(
+            "the syslog sink is only available on unix".to_string(),
+        ));
+    }
+
+    if let Some(rest) = spec.strip_prefix("mqtt://") {
+        #[cfg(feature = "mqtt")]
+        return Ok(Box::new(MqttSink::from_url_rest(rest)?));
+
+        #[cfg(not(feature = "mqtt"))]
+        return Err(Error::InvalidConfiguration(format!(
+            "sink 'mqtt://{}' requires a client built with the 'mqtt' feature",
+            rest
+        )));
+    }
+
+    Err(Error::InvalidConfiguration(format!(
+        "unknown sink '{}'; expected file:<path>, syslog, or mqtt://host:port/topic",
+        spec
+    )))
+}
+
+/// Appends events as newline-delimited JSON to a file
+pub struct FileSink {
+    file: File,
+}
+
+impl FileSink {
+    /// Append to the file at `path`, creating it if needed
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::InvalidConfiguration(format!("sink file '{}': {}", path, e)))?;
+
+        Ok(FileSink { file })
+    }
+}
+
+impl Sink for FileSink {
+    fn publish(&mut self, event: &Event) -> Result<(), Error> {
+        self.file
+            .write_all(format!("{}\n", event.to_json()).as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Sends events to the local syslog daemon, at `warning` severity for violations and `info`
+/// for everything else
+#[cfg(unix)]
+pub struct SyslogSink {
+    socket: UnixDatagram,
+    ident: String,
+}
+
+#[cfg(unix)]
+impl SyslogSink {
+    // facility 'user', as in RFC 3164
+    const FACILITY_USER: u8 = 1;
+    const SEVERITY_WARNING: u8 = 4;
+    const SEVERITY_INFO: u8 = 6;
+
+    /// Send to the syslog daemon listening on the unix datagram socket at `path`
+    pub fn connect(path: &str) -> Result<Self, Error> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(path)
+            .map_err(|e| Error::InvalidConfiguration(format!("syslog socket '{}': {}", path, e)))?;
+
+        Ok(SyslogSink {
+            socket,
+            ident: format!("roughenough-client[{}]", std::process::id()),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Sink for SyslogSink {
+    fn publish(&mut self, event: &Event) -> Result<(), Error> {
+        let severity = if event.is_violation() {
+            SyslogSink::SEVERITY_WARNING
+        } else {
+            SyslogSink::SEVERITY_INFO
+        };
+        let priority = SyslogSink::FACILITY_USER * 8 + severity;
+
+        let line = format!("<{}>{}: {}", priority, self.ident, event.to_json());
+        self.socket.send(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+///
+/// Publishes events to a topic of an MQTT 3.1.1 broker at QoS 0 (at most once, no
+/// acknowledgement).
+///
+#[cfg(feature = "mqtt")]
+pub struct MqttSink {
+    stream: TcpStream,
+    topic: String,
+}
+
+#[cfg(feature = "mqtt")]
+impl MqttSink {
+    const CONNECT: u8 = 0x10;
+    const CONNACK: u8 = 0x20;
+    const PUBLISH: u8 = 0x30;
+    const DISCONNECT: u8 = 0xe0;
+
+    const KEEP_ALIVE_SECS: u16 = 60;
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // `host:port/topic`, the part of an mqtt:// URL after the scheme
+    fn from_url_rest(rest: &str) -> Result<Self, Error> {
+        match rest.split_once('/') {
+            Some((addr, topic)) if !addr.is_empty() && !topic.is_empty() => {
+                MqttSink::connect(addr, topic)
+            }
+            _ => Err(Error::InvalidConfiguration(format!(
+                "invalid MQTT sink 'mqtt://{}'; expected mqtt://host:port/topic",
+                rest
+            ))),
+        }
+    }
+
+    /// Connect to the broker at `addr` (`host:port`) to publish to `topic`
+    pub fn connect(addr: &str, topic: &str) -> Result<Self, Error> {
+        let invalid = |reason: String| {
+            Error::InvalidConfiguration(format!("MQTT broker '{}': {}", addr, reason))
+        };
+
+        let sock_addr = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| invalid("can't resolve address".to_string()))?;
+
+        let mut stream = TcpStream::connect_timeout(&sock_addr, MqttSink::TIMEOUT)
+            .map_err(|e| invalid(e.to_string()))?;
+        stream.set_read_timeout(Some(MqttSink::TIMEOUT))?;
+        stream.set_write_timeout(Some(MqttSink::TIMEOUT))?;
+
+        let client_id = format!("roughenough-{}", std::process::id());
+        let mut body = Vec::new();
+        put_string(&mut body, "MQTT");
+        body.push(4); // protocol level 3.1.1
+        body.push(0x02); // clean session
+        body.extend_from_slice(&MqttSink::KEEP_ALIVE_SECS.to_be_bytes());
+        put_string(&mut body, &client_id);
+        stream.write_all(&packet(MqttSink::CONNECT, &body))?;
+
+        let mut connack = [0u8; 4];
+        stream
+            .read_exact(&mut connack)
+            .map_err(|e| invalid(format!("no CONNACK: {}", e)))?;
+        if connack[0] != MqttSink::CONNACK || connack[3] != 0 {
+            return Err(invalid(format!(
+                "connection refused (CONNACK {:02x?})",
+                connack
+            )));
+        }
+
+        Ok(MqttSink {
+            stream,
+            topic: topic.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "mqtt")]
+impl Sink for MqttSink {
+    fn publish(&mut self, event: &Event) -> Result<(), Error> {
+        let mut body = Vec::new();
+        put_string(&mut body, &self.topic);
+        body.extend_from_slice(event.to_json().as_bytes());
+
+        self.stream.write_all(&packet(MqttSink::PUBLISH, &body))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mqtt")]
+impl Drop for MqttSink {
+    fn drop(&mut self) {
+        let _ = self.stream.write_all(&[MqttSink::DISCONNECT, 0]);
+    }
+}
+
+// An MQTT control packet: type byte, variable length "remaining length", then `body`
+#[cfg(feature = "mqtt")]
+fn packet(packet_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![packet_type];
+    let mut remaining = body.len();
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if remaining == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+// A length-prefixed MQTT UTF-8 string
+#[cfg(feature = "mqtt")]
+fn put_string(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::sink::{from_spec, Event};
+
+    fn time_event() -> Event<'static> {
+        Event::Time {
+            server: "roughtime.int08h.com",
+            midpoint: 1_700_000_000_000_000,
+            radius: 1_000_000,
+            verified: true,
+        }
+    }
+
+    #[test]
+    fn file_sink_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("roughenough-sink-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let spec = format!("file:{}", path.display());
+
+        let violation = Event::Violation {
+            server: "bad",
+            kind: "invalid_response",
+            detail: "Invalid signature on SREP tag",
+        };
+        {
+            let mut sink = from_spec(&spec).unwrap();
+            sink.publish(&time_event()).unwrap();
+        }
+        from_spec(&spec).unwrap().publish(&violation).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            contents,
+            concat!(
+                r#"{"event":"time","server":"roughtime.int08h.com","midpoint_us":1700000000000000,"radius_us":1000000,"verified":true}"#,
+                "\n",
+                r#"{"event":"violation","server":"bad","kind":"invalid_response","detail":"Invalid signature on SREP tag"}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        for spec in [
+            "",
+            "stdout",
+            "file:/nonexistent-dir/x/y",
+            "mqtt://broker:1883",
+        ] {
+            assert!(from_spec(spec).is_err(), "accepted '{}'", spec);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn syslog_sink_sends_prioritized_messages() {
+        use std::os::unix::net::UnixDatagram;
+
+        use crate::sink::{Sink, SyslogSink};
+
+        let path = std::env::temp_dir().join(format!("roughenough-syslog-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let daemon = UnixDatagram::bind(&path).unwrap();
+
+        let mut sink = SyslogSink::connect(path.to_str().unwrap()).unwrap();
+        sink.publish(&time_event()).unwrap();
+        sink.publish(&Event::Violation {
+            server: "a",
+            kind: "causality_violation",
+            detail: "",
+        })
+        .unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = daemon.recv(&mut buf).unwrap();
+        let info = String::from_utf8_lossy(&buf[..n]).to_string();
+        let n = daemon.recv(&mut buf).unwrap();
+        let warning = String::from_utf8_lossy(&buf[..n]).to_string();
+        fs::remove_file(&path).unwrap();
+
+        assert!(info.starts_with("<14>roughenough-client["), "{}", info);
+        assert!(info.ends_with(&time_event().to_json()), "{}", info);
+        assert!(warning.starts_with("<12>"), "{}", warning);
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn mqtt_sink_connects_and_publishes() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::thread;
+
+        let broker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = broker.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let (mut conn, _) = broker.accept().unwrap();
+            let read_packet = |conn: &mut TcpStream| {
+                let mut header = [0u8; 2];
+                conn.read_exact(&mut header).unwrap();
+                let (mut len, mut shift, mut byte) = (0usize, 0, header[1]);
+                loop {
+                    len |= ((byte & 0x7f) as usize) << shift;
+                    if byte & 0x80 == 0 {
+                        break;
+                    }
+                    shift += 7;
+                    let mut next = [0u8; 1];
+                    conn.read_exact(&mut next).unwrap();
+                    byte = next[0];
+                }
+                let mut body = vec![0u8; len];
+                conn.read_exact(&mut body).unwrap();
+                (header[0], body)
+            };
+
+            let (connect, body) = read_packet(&mut conn);
+            assert_eq!(connect, 0x10);
+            assert_eq!(&body[..7], b"\x00\x04MQTT\x04");
+            conn.write_all(&[0x20, 2, 0, 0]).unwrap();
+
+            let (publish, body) = read_packet(&mut conn);
+            let (disconnect, _) = read_packet(&mut conn);
+            (publish, body, disconnect)
+        });
+
+        let spec = format!("mqtt://{}/gateway/time", addr);
+        from_spec(&spec).unwrap().publish(&time_event()).unwrap();
+
+        let (publish, body, disconnect) = handle.join().unwrap();
+        assert_eq!(publish, 0x30);
+        assert_eq!(&body[..14], b"\x00\x0cgateway/time");
+        assert_eq!(&body[14..], time_event().to_json().as_bytes());
+        assert_eq!(disconnect, 0xe0);
+    }
+}
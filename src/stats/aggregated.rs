@@ -13,11 +13,13 @@
 // limitations under the License.
 
 use crate::stats::ClientStatEntry;
+use crate::stats::LatencyHistogram;
 use crate::stats::ServerStats;
 use crate::Error;
 use std::collections::hash_map::Iter;
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::time::Duration;
 
 ///
 /// Implementation of `ServerStats` that provides high-level aggregated client statistics. No
@@ -31,9 +33,13 @@ pub struct AggregatedStats {
     health_checks: u64,
     rfc_responses_sent: u64,
     classic_responses_sent: u64,
-    bytes_sent: usize,
+    bytes_sent: u64,
     send_failed_attempts: u64,
     send_retry_attempts: u64,
+    signing_latency: LatencyHistogram,
+    online_key_rotations: u64,
+    delegation_window_violations: u64,
+    clock_disagreements: u64,
     empty_map: HashMap<IpAddr, ClientStatEntry>,
 }
 
@@ -56,6 +62,10 @@ impl AggregatedStats {
             bytes_sent: 0,
             send_failed_attempts: 0,
             send_retry_attempts: 0,
+            signing_latency: LatencyHistogram::new(),
+            online_key_rotations: 0,
+            delegation_window_violations: 0,
+            clock_disagreements: 0,
             empty_map: HashMap::new(),
         }
     }
@@ -87,15 +97,31 @@ impl ServerStats for AggregatedStats {
     }
 
     fn add_rfc_response(&mut self, _: &IpAddr, bytes_sent: usize) {
-        self.bytes_sent += bytes_sent;
+        self.bytes_sent += bytes_sent as u64;
         self.rfc_responses_sent += 1;
     }
 
     fn add_classic_response(&mut self, _: &IpAddr, bytes_sent: usize) {
-        self.bytes_sent += bytes_sent;
+        self.bytes_sent += bytes_sent as u64;
         self.classic_responses_sent += 1;
     }
 
+    fn add_signing_latency(&mut self, latency: Duration) {
+        self.signing_latency.record(latency);
+    }
+
+    fn add_online_key_rotation(&mut self) {
+        self.online_key_rotations += 1;
+    }
+
+    fn add_delegation_window_violation(&mut self) {
+        self.delegation_window_violations += 1;
+    }
+
+    fn add_clock_disagreement(&mut self) {
+        self.clock_disagreements += 1;
+    }
+
     fn total_valid_requests(&self) -> u64 {
         self.rfc_requests + self.classic_requests
     }
@@ -136,7 +162,7 @@ impl ServerStats for AggregatedStats {
         self.classic_responses_sent
     }
 
-    fn total_bytes_sent(&self) -> usize {
+    fn total_bytes_sent(&self) -> u64 {
         self.bytes_sent
     }
 
@@ -144,6 +170,22 @@ impl ServerStats for AggregatedStats {
         0
     }
 
+    fn signing_latency(&self) -> &LatencyHistogram {
+        &self.signing_latency
+    }
+
+    fn total_online_key_rotations(&self) -> u64 {
+        self.online_key_rotations
+    }
+
+    fn total_delegation_window_violations(&self) -> u64 {
+        self.delegation_window_violations
+    }
+
+    fn total_clock_disagreements(&self) -> u64 {
+        self.clock_disagreements
+    }
+
     fn stats_for_client(&self, _addr: &IpAddr) -> Option<&ClientStatEntry> {
         None
     }
@@ -162,5 +204,9 @@ impl ServerStats for AggregatedStats {
         self.bytes_sent = 0;
         self.send_failed_attempts = 0;
         self.send_retry_attempts = 0;
+        self.signing_latency.clear();
+        self.online_key_rotations = 0;
+        self.delegation_window_violations = 0;
+        self.clock_disagreements = 0;
     }
 }
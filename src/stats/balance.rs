@@ -0,0 +1,230 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+// A worker is overloaded when it handles more than this multiple of its fair share
+const OVERLOAD_FACTOR: f64 = 1.5;
+
+// Intervals with fewer requests than this across all workers are too small to judge
+const MIN_REQUESTS: u64 = 100;
+
+///
+/// How evenly the requests of one status interval were spread across the workers.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerBalance {
+    /// Coefficient of variation (standard deviation / mean) of the workers' request
+    /// counts. 0.0 is a perfectly even spread.
+    pub coefficient: f64,
+    pub busiest_worker: String,
+    /// Fraction (0.0 to 1.0) of all requests handled by the busiest worker
+    pub busiest_share: f64,
+    pub total_requests: u64,
+    /// Number of workers that reported in this interval
+    pub num_workers: usize,
+    /// Workers that have reported before but not in this interval. Only known workers are
+    /// named, one that never reported is counted in `expected_workers` alone.
+    pub missing_workers: Vec<String>,
+    pub expected_workers: usize,
+}
+
+impl WorkerBalance {
+    /// True if the busiest worker handled a disproportionate share of enough requests
+    pub fn is_imbalanced(&self) -> bool {
+        let fair_share = 1.0 / self.num_workers as f64;
+        self.total_requests >= MIN_REQUESTS && self.busiest_share > fair_share * OVERLOAD_FACTOR
+    }
+
+    /// True if some workers didn't report, so the totals cover only part of the server
+    pub fn is_degraded(&self) -> bool {
+        self.num_workers < self.expected_workers
+    }
+}
+
+///
+/// Collects the request counts of every worker at the end of each status interval.
+/// SO_REUSEPORT spreads clients over workers by hashing their addresses, which can be badly
+/// skewed when a few clients send most of the requests.
+///
+/// Workers share a status interval, so a worker reporting twice before all the others have
+/// reported once means they are a full interval late (hung, or restarting after a panic).
+/// The interval is then closed without them and reported as
+/// [degraded](struct.WorkerBalance.html#method.is_degraded).
+///
+pub struct MetricsAggregator {
+    num_workers: usize,
+    state: Mutex<AggregatorState>,
+}
+
+#[derive(Default)]
+struct AggregatorState {
+    intervals: BTreeMap<String, u64>,
+    known_workers: BTreeSet<String>,
+}
+
+impl MetricsAggregator {
+    pub fn new(num_workers: usize) -> Self {
+        MetricsAggregator {
+            num_workers,
+            state: Mutex::new(AggregatorState::default()),
+        }
+    }
+
+    ///
+    /// Record the valid requests `worker` handled in the status interval that just ended.
+    /// Returns the balance across workers once every worker has reported, or once `worker`
+    /// reports again before the others did, and starts over.
+    ///
+    pub fn add_interval(&self, worker: &str, requests: u64) -> Option<WorkerBalance> {
+        let mut state = self.state.lock().unwrap();
+        state.known_workers.insert(worker.to_string());
+
+        if state.intervals.contains_key(worker) {
+            let late = self.balance(&mut state);
+            state.intervals.insert(worker.to_string(), requests);
+            return late;
+        }
+
+        state.intervals.insert(worker.to_string(), requests);
+        if state.intervals.len() < self.num_workers {
+            return None;
+        }
+
+        self.balance(&mut state)
+    }
+
+    // Close the current interval and compute the balance of the workers that reported in it
+    fn balance(&self, state: &mut AggregatorState) -> Option<WorkerBalance> {
+        let counts = std::mem::take(&mut state.intervals);
+        if counts.is_empty() {
+            return None;
+        }
+
+        let missing_workers = state
+            .known_workers
+            .iter()
+            .filter(|w| !counts.contains_key(*w))
+            .cloned()
+            .collect();
+        let total: u64 = counts.values().sum();
+        let mean = total as f64 / counts.len() as f64;
+        let variance = counts
+            .values()
+            .map(|&c| (c as f64 - mean).powi(2))
+            .sum::<f64>()
+            / counts.len() as f64;
+
+        let (busiest_worker, busiest) = counts.iter().max_by_key(|(_, &c)| c).unwrap();
+
+        Some(WorkerBalance {
+            coefficient: if mean > 0.0 {
+                variance.sqrt() / mean
+            } else {
+                0.0
+            },
+            busiest_worker: busiest_worker.clone(),
+            busiest_share: if total > 0 {
+                *busiest as f64 / total as f64
+            } else {
+                0.0
+            },
+            total_requests: total,
+            num_workers: counts.len(),
+            missing_workers,
+            expected_workers: self.num_workers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stats::balance::MetricsAggregator;
+
+    #[test]
+    fn balance_reported_once_every_worker_reports() {
+        let agg = MetricsAggregator::new(3);
+        assert!(agg.add_interval("worker-0", 500).is_none());
+        assert!(agg.add_interval("worker-1", 500).is_none());
+
+        let balance = agg.add_interval("worker-2", 500).unwrap();
+        assert_eq!(balance.total_requests, 1500);
+        assert_eq!(balance.coefficient, 0.0);
+        assert!(!balance.is_imbalanced());
+        assert!(!balance.is_degraded());
+
+        // next interval starts empty
+        assert!(agg.add_interval("worker-0", 1).is_none());
+    }
+
+    #[test]
+    fn skewed_workers_are_imbalanced() {
+        let agg = MetricsAggregator::new(4);
+        agg.add_interval("worker-0", 100);
+        agg.add_interval("worker-1", 700);
+        agg.add_interval("worker-2", 100);
+        let balance = agg.add_interval("worker-3", 100).unwrap();
+
+        assert_eq!(balance.busiest_worker, "worker-1");
+        assert_eq!(balance.busiest_share, 0.7);
+        assert!((balance.coefficient - 1.039).abs() < 0.001);
+        assert!(balance.is_imbalanced());
+    }
+
+    #[test]
+    fn late_workers_degrade_the_interval() {
+        let agg = MetricsAggregator::new(3);
+        for worker in ["worker-0", "worker-1", "worker-2"] {
+            agg.add_interval(worker, 100);
+        }
+
+        // worker-2 stops reporting, the interval closes when worker-0 reports again
+        assert!(agg.add_interval("worker-0", 100).is_none());
+        assert!(agg.add_interval("worker-1", 100).is_none());
+        let balance = agg.add_interval("worker-0", 50).unwrap();
+
+        assert!(balance.is_degraded());
+        assert_eq!(balance.num_workers, 2);
+        assert_eq!(balance.expected_workers, 3);
+        assert_eq!(balance.missing_workers, vec!["worker-2".to_string()]);
+        assert_eq!(balance.total_requests, 200);
+
+        // worker-0's report went to the next interval
+        agg.add_interval("worker-1", 50);
+        let balance = agg.add_interval("worker-2", 50).unwrap();
+        assert!(!balance.is_degraded());
+        assert_eq!(balance.total_requests, 150);
+    }
+
+    #[test]
+    fn workers_that_never_report_are_counted() {
+        let agg = MetricsAggregator::new(2);
+        agg.add_interval("worker-0", 10);
+        let balance = agg.add_interval("worker-0", 10).unwrap();
+
+        assert!(balance.is_degraded());
+        assert!(balance.missing_workers.is_empty());
+    }
+
+    #[test]
+    fn quiet_intervals_are_not_imbalanced() {
+        let agg = MetricsAggregator::new(2);
+        agg.add_interval("worker-0", 0);
+        let balance = agg.add_interval("worker-1", 10).unwrap();
+
+        assert_eq!(balance.busiest_share, 1.0);
+        assert!(!balance.is_imbalanced());
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+// Each power of two is split into this many equal sub-buckets, bounding the error of a
+// reported percentile to 1/8 (12.5%) of its value
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+// Latencies at or above 2^31 µs (~36 minutes) are counted in the last bucket
+const MAX_BITS: u32 = 31;
+const NUM_BUCKETS: usize =
+    (SUB_BUCKETS + (MAX_BITS - SUB_BUCKET_BITS) as u64 * SUB_BUCKETS) as usize;
+
+///
+/// Histogram of latencies with microsecond resolution and constant memory use, from which
+/// approximate percentiles are computed.
+///
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    counts: [u64; NUM_BUCKETS],
+    total: u64,
+    max: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        LatencyHistogram {
+            counts: [0; NUM_BUCKETS],
+            total: 0,
+            max: Duration::ZERO,
+        }
+    }
+
+    /// Record one latency sample
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().min((1 << MAX_BITS) - 1) as u64;
+        self.counts[bucket_index(micros)] += 1;
+        self.total += 1;
+        self.max = self.max.max(latency);
+    }
+
+    /// Number of samples recorded
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Largest sample recorded
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Latency that `pct` percent (0 to 100) of the samples did not exceed, rounded up to the
+    /// upper bound of its bucket. `None` if no samples have been recorded.
+    pub fn percentile(&self, pct: f64) -> Option<Duration> {
+        if self.total == 0 {
+            return None;
+        }
+
+        let rank = ((pct.clamp(0.0, 100.0) / 100.0) * self.total as f64).ceil() as u64;
+        let rank = rank.max(1);
+
+        let mut seen = 0;
+        for (idx, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper = Duration::from_micros(bucket_upper_bound(idx));
+                return Some(upper.min(self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+
+    pub fn clear(&mut self) {
+        self.counts = [0; NUM_BUCKETS];
+        self.total = 0;
+        self.max = Duration::ZERO;
+    }
+}
+
+fn bucket_index(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+
+    let msb = 63 - micros.leading_zeros();
+    let shift = msb - SUB_BUCKET_BITS;
+    let sub = (micros >> shift) & (SUB_BUCKETS - 1);
+
+    (SUB_BUCKETS + shift as u64 * SUB_BUCKETS + sub) as usize
+}
+
+fn bucket_upper_bound(idx: usize) -> u64 {
+    let idx = idx as u64;
+    if idx < SUB_BUCKETS {
+        return idx;
+    }
+
+    let shift = (idx - SUB_BUCKETS) / SUB_BUCKETS;
+    let sub = (idx - SUB_BUCKETS) % SUB_BUCKETS;
+
+    ((SUB_BUCKETS + sub + 1) << shift) - 1
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::stats::latency::{bucket_index, bucket_upper_bound, LatencyHistogram};
+
+    #[test]
+    fn buckets_cover_values() {
+        for micros in [0, 1, 7, 8, 9, 15, 16, 17, 100, 1234, 99_999, (1 << 31) - 1] {
+            let idx = bucket_index(micros);
+            assert!(
+                micros <= bucket_upper_bound(idx),
+                "{} above bucket {}",
+                micros,
+                idx
+            );
+            if idx > 0 {
+                assert!(
+                    micros > bucket_upper_bound(idx - 1),
+                    "{} below bucket {}",
+                    micros,
+                    idx
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn percentiles_are_within_bucket_precision() {
+        let mut hist = LatencyHistogram::new();
+        assert_eq!(hist.percentile(50.0), None);
+
+        for micros in 1..=1000 {
+            hist.record(Duration::from_micros(micros));
+        }
+        assert_eq!(hist.count(), 1000);
+        assert_eq!(hist.max(), Duration::from_micros(1000));
+
+        for (pct, exact) in [(50.0, 500), (90.0, 900), (99.0, 990)] {
+            let reported = hist.percentile(pct).unwrap().as_micros() as f64;
+            assert!(reported >= exact as f64, "p{} = {}", pct, reported);
+            assert!(reported <= exact as f64 * 1.125, "p{} = {}", pct, reported);
+        }
+        assert_eq!(hist.percentile(100.0), Some(Duration::from_micros(1000)));
+
+        hist.clear();
+        assert_eq!(hist.count(), 0);
+        assert_eq!(hist.percentile(99.0), None);
+    }
+}
@@ -17,12 +17,17 @@
 //!
 
 pub use crate::stats::aggregated::AggregatedStats;
+pub use crate::stats::balance::{MetricsAggregator, WorkerBalance};
+pub use crate::stats::latency::LatencyHistogram;
 pub use crate::stats::per_client::PerClientStats;
 use crate::Error;
 use std::collections::hash_map::Iter;
 use std::net::IpAddr;
+use std::time::Duration;
 
 mod aggregated;
+mod balance;
+mod latency;
 mod per_client;
 
 ///
@@ -36,7 +41,7 @@ pub struct ClientStatEntry {
     pub health_checks: u64,
     pub rfc_responses_sent: u64,
     pub classic_responses_sent: u64,
-    pub bytes_sent: usize,
+    pub bytes_sent: u64,
     pub failed_send_attempts: u64,
     pub retried_send_attempts: u64,
 }
@@ -77,6 +82,17 @@ pub trait ServerStats {
 
     fn add_classic_response(&mut self, addr: &IpAddr, bytes_sent: usize);
 
+    /// Time taken to create and sign one batch's SREP
+    fn add_signing_latency(&mut self, latency: Duration);
+
+    fn add_online_key_rotation(&mut self);
+
+    /// The clock was found outside the online key's delegation window before signing
+    fn add_delegation_window_violation(&mut self);
+
+    /// The configured clock sources disagreed when read for a batch
+    fn add_clock_disagreement(&mut self);
+
     fn total_valid_requests(&self) -> u64;
 
     fn num_rfc_requests(&self) -> u64;
@@ -97,10 +113,18 @@ pub trait ServerStats {
 
     fn num_classic_responses_sent(&self) -> u64;
 
-    fn total_bytes_sent(&self) -> usize;
+    fn total_bytes_sent(&self) -> u64;
 
     fn total_unique_clients(&self) -> u64;
 
+    fn signing_latency(&self) -> &LatencyHistogram;
+
+    fn total_online_key_rotations(&self) -> u64;
+
+    fn total_delegation_window_violations(&self) -> u64;
+
+    fn total_clock_disagreements(&self) -> u64;
+
     fn stats_for_client(&self, addr: &IpAddr) -> Option<&ClientStatEntry>;
 
     fn iter(&self) -> Iter<IpAddr, ClientStatEntry>;
@@ -110,7 +134,7 @@ pub trait ServerStats {
 
 #[cfg(test)]
 mod test {
-    use crate::stats::{PerClientStats, ServerStats};
+    use crate::stats::{AggregatedStats, PerClientStats, ServerStats};
     use crate::Error;
     use std::net::{IpAddr, Ipv4Addr};
 
@@ -172,6 +196,22 @@ mod test {
         assert_eq!(entry.retried_send_attempts, 0);
     }
 
+    #[test]
+    fn bytes_sent_counts_past_4gib() {
+        // usize is 32 bits on ARM32 and other 32-bit targets; byte totals must not wrap there
+        let ip = "127.0.0.3".parse().unwrap();
+        let mut per_client = PerClientStats::new();
+        let mut aggregated = AggregatedStats::new();
+
+        for _ in 0..5 {
+            per_client.add_rfc_response(&ip, 1 << 30);
+            aggregated.add_rfc_response(&ip, 1 << 30);
+        }
+
+        assert_eq!(per_client.total_bytes_sent(), 5 << 30);
+        assert_eq!(aggregated.total_bytes_sent(), 5 << 30);
+    }
+
     #[test]
     fn overflow_max_entries() {
         let mut stats = PerClientStats::with_limit(100);
@@ -13,11 +13,13 @@
 // limitations under the License.
 
 use crate::stats::ClientStatEntry;
+use crate::stats::LatencyHistogram;
 use crate::stats::ServerStats;
 use crate::Error;
 use std::collections::hash_map::Iter;
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::time::Duration;
 
 ///
 /// Implementation of `ServerStats` that provides granular per-client request/response counts.
@@ -30,6 +32,10 @@ pub struct PerClientStats {
     clients: HashMap<IpAddr, ClientStatEntry>,
     num_overflows: u64,
     max_clients: usize,
+    signing_latency: LatencyHistogram,
+    online_key_rotations: u64,
+    delegation_window_violations: u64,
+    clock_disagreements: u64,
 }
 
 impl Default for PerClientStats {
@@ -47,6 +53,10 @@ impl PerClientStats {
             clients: HashMap::with_capacity(MAX_CLIENTS),
             num_overflows: 0,
             max_clients: MAX_CLIENTS,
+            signing_latency: LatencyHistogram::new(),
+            online_key_rotations: 0,
+            delegation_window_violations: 0,
+            clock_disagreements: 0,
         }
     }
 
@@ -57,6 +67,10 @@ impl PerClientStats {
             clients: HashMap::with_capacity(64),
             num_overflows: 0,
             max_clients: limit,
+            signing_latency: LatencyHistogram::new(),
+            online_key_rotations: 0,
+            delegation_window_violations: 0,
+            clock_disagreements: 0,
         }
     }
 
@@ -148,7 +162,7 @@ impl ServerStats for PerClientStats {
             .or_insert_with(ClientStatEntry::new);
 
         entry.rfc_responses_sent += 1;
-        entry.bytes_sent += bytes_sent;
+        entry.bytes_sent += bytes_sent as u64;
     }
 
     fn add_classic_response(&mut self, addr: &IpAddr, bytes_sent: usize) {
@@ -161,7 +175,23 @@ impl ServerStats for PerClientStats {
             .or_insert_with(ClientStatEntry::new);
 
         entry.classic_responses_sent += 1;
-        entry.bytes_sent += bytes_sent;
+        entry.bytes_sent += bytes_sent as u64;
+    }
+
+    fn add_signing_latency(&mut self, latency: Duration) {
+        self.signing_latency.record(latency);
+    }
+
+    fn add_online_key_rotation(&mut self) {
+        self.online_key_rotations += 1;
+    }
+
+    fn add_delegation_window_violation(&mut self) {
+        self.delegation_window_violations += 1;
+    }
+
+    fn add_clock_disagreement(&mut self) {
+        self.clock_disagreements += 1;
     }
 
     fn total_valid_requests(&self) -> u64 {
@@ -213,7 +243,7 @@ impl ServerStats for PerClientStats {
             .sum()
     }
 
-    fn total_bytes_sent(&self) -> usize {
+    fn total_bytes_sent(&self) -> u64 {
         self.clients.values().map(|&v| v.bytes_sent).sum()
     }
 
@@ -221,6 +251,22 @@ impl ServerStats for PerClientStats {
         self.clients.len() as u64
     }
 
+    fn signing_latency(&self) -> &LatencyHistogram {
+        &self.signing_latency
+    }
+
+    fn total_online_key_rotations(&self) -> u64 {
+        self.online_key_rotations
+    }
+
+    fn total_delegation_window_violations(&self) -> u64 {
+        self.delegation_window_violations
+    }
+
+    fn total_clock_disagreements(&self) -> u64 {
+        self.clock_disagreements
+    }
+
     fn stats_for_client(&self, addr: &IpAddr) -> Option<&ClientStatEntry> {
         self.clients.get(addr)
     }
@@ -232,5 +278,9 @@ impl ServerStats for PerClientStats {
     fn clear(&mut self) {
         self.clients.clear();
         self.num_overflows = 0;
+        self.signing_latency.clear();
+        self.online_key_rotations = 0;
+        self.delegation_window_violations = 0;
+        self.clock_disagreements = 0;
     }
 }
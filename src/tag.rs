@@ -39,6 +39,7 @@ pub enum Tag {
     LEAP,
     MIDP,
     SREP,
+    VERS,
     MINT,
     ROOT,
     CERT,
@@ -67,6 +68,7 @@ impl Tag {
     const BYTES_SRV: &'static [u8] = b"SRV\x00";
     const BYTES_SREP: &'static [u8] = b"SREP";
     const BYTES_VER: &'static [u8] = b"VER\x00";
+    const BYTES_VERS: &'static [u8] = b"VERS";
     const BYTES_DUT1: &'static [u8] = b"DUT1";
     const BYTES_DTAI: &'static [u8] = b"DTAI";
     const BYTES_LEAP: &'static [u8] = b"LEAP";
@@ -91,6 +93,7 @@ impl Tag {
             Tag::SRV => Tag::BYTES_SRV,
             Tag::SREP => Tag::BYTES_SREP,
             Tag::VER => Tag::BYTES_VER,
+            Tag::VERS => Tag::BYTES_VERS,
             Tag::DUT1 => Tag::BYTES_DUT1,
             Tag::DTAI => Tag::BYTES_DTAI,
             Tag::LEAP => Tag::BYTES_LEAP,
@@ -118,6 +121,7 @@ impl Tag {
             Tag::BYTES_SRV => Ok(Tag::SRV),
             Tag::BYTES_SREP => Ok(Tag::SREP),
             Tag::BYTES_VER => Ok(Tag::VER),
+            Tag::BYTES_VERS => Ok(Tag::VERS),
             Tag::BYTES_DUT1 => Ok(Tag::DUT1),
             Tag::BYTES_DTAI => Ok(Tag::DTAI),
             Tag::BYTES_LEAP => Ok(Tag::LEAP),
@@ -0,0 +1,225 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Protocol conformance test vectors: a canonical request and the response this server sends
+//! to it, for each supported protocol version.
+//!
+//! Every input is fixed (the long-term and online key seeds, the nonce, and the time the
+//! response is signed at), and Ed25519 signatures are deterministic, so the vectors are the
+//! same byte for byte on every run. Other implementations can check that they build the same
+//! request and parse and validate the response against `LONG_TERM_SEED`'s public key. The
+//! `roughenough-testvectors` binary prints them.
+//!
+//! The response is that of a batch holding only this request, sent without padding or
+//! grease.
+//!
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use data_encoding::{Encoding, HEXLOWER_PERMISSIVE};
+
+use crate::key::{LongTermKey, OnlineKey};
+use crate::merkle::MerkleTree;
+use crate::request;
+use crate::responder::make_response;
+use crate::version::Version;
+
+const HEX: Encoding = HEXLOWER_PERMISSIVE;
+
+/// Seed of the long-term key that delegates the online key
+pub const LONG_TERM_SEED: &[u8; 32] = b"roughenough test vector ltk seed";
+
+/// Seed of the online key that signs the responses
+pub const ONLINE_KEY_SEED: &[u8; 32] = b"roughenough test vector olk seed";
+
+/// Time the responses are signed at, in seconds since the Unix epoch
+/// (2023-11-14T22:13:20Z)
+pub const SIGNED_AT: u64 = 1_700_000_000;
+
+/// The online key is delegated from one hour before `SIGNED_AT` until a day after that
+const DELEGATION_START: u64 = SIGNED_AT - 3600;
+const DELEGATION_LENGTH: u64 = 24 * 3600;
+
+/// Protocol versions test vectors are generated for
+pub const VERSIONS: &[Version] = &[Version::Classic, Version::Rfc, Version::RfcDraft11];
+
+///
+/// A request and its response for one protocol version
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVector {
+    pub version: Version,
+
+    /// Public key of `LONG_TERM_SEED`, the key responses validate against
+    pub long_term_public_key: Vec<u8>,
+
+    /// Nonce of the request: the bytes 0, 1, 2, ... up to the version's nonce length
+    pub nonce: Vec<u8>,
+
+    /// Request as sent, framed for RFC versions. RFC requests carry the `SRV` of the
+    /// long-term key.
+    pub request: Vec<u8>,
+
+    /// Response as sent, framed for RFC versions
+    pub response: Vec<u8>,
+
+    /// `MIDP` of the response, in the version's time units
+    pub midpoint: u64,
+
+    /// `RADI` of the response, in the version's time units
+    pub radius: u32,
+}
+
+impl TestVector {
+    /// Generate the test vector of protocol `version`
+    pub fn generate(version: Version) -> TestVector {
+        let profile = version.profile();
+        let nonce: Vec<u8> = (0..profile.nonce_length).map(|i| i as u8).collect();
+
+        let mut ltk = LongTermKey::new(LONG_TERM_SEED);
+        let request = request::make_request(version, &nonce, Some(ltk.srv_value()), None)
+            .encode_for(version)
+            .unwrap();
+
+        let signed_at = UNIX_EPOCH + Duration::from_secs(SIGNED_AT);
+        let mut online_key = OnlineKey::from_seed(
+            ONLINE_KEY_SEED,
+            version,
+            UNIX_EPOCH + Duration::from_secs(DELEGATION_START),
+            Duration::from_secs(DELEGATION_LENGTH),
+        );
+        let cert_bytes = ltk.make_cert(&online_key).encode().unwrap();
+
+        let mut merkle = MerkleTree::for_version(version);
+        merkle.push_leaf(&nonce);
+        let root = merkle.compute_root();
+        let path = &merkle.all_paths()[0];
+
+        let srep = online_key
+            .make_srep(version, signed_at, &root, None)
+            .unwrap();
+        let response = make_response(version, &srep, &cert_bytes, path, 0, &nonce, None)
+            .encode_for(version)
+            .unwrap();
+
+        TestVector {
+            version,
+            long_term_public_key: ltk.public_key(),
+            nonce,
+            request,
+            response,
+            midpoint: to_units(version, signed_at),
+            radius: (5_000_000 / profile.micros_per_unit) as u32,
+        }
+    }
+
+    /// The test vectors of every version in `VERSIONS`
+    pub fn all() -> Vec<TestVector> {
+        VERSIONS.iter().map(|&v| TestVector::generate(v)).collect()
+    }
+
+    /// This test vector, and the fixed inputs it was generated from, as a JSON object.
+    /// Byte strings are hex encoded.
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{ "version": "{}", "version_wire": "{}", "long_term_seed": "{}", "online_key_seed": "{}", "signed_at": {}, "long_term_public_key": "{}", "nonce": "{}", "request": "{}", "response": "{}", "midpoint": {}, "radius": {} }}"#,
+            self.version,
+            HEX.encode(self.version.wire_bytes()),
+            HEX.encode(LONG_TERM_SEED),
+            HEX.encode(ONLINE_KEY_SEED),
+            SIGNED_AT,
+            HEX.encode(&self.long_term_public_key),
+            HEX.encode(&self.nonce),
+            HEX.encode(&self.request),
+            HEX.encode(&self.response),
+            self.midpoint,
+            self.radius
+        )
+    }
+}
+
+// `time` in the time units of `version`
+fn to_units(version: Version, time: SystemTime) -> u64 {
+    let micros = time.duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+    micros / version.profile().micros_per_unit
+}
+
+#[cfg(test)]
+mod test {
+    use ring::digest;
+
+    use crate::key::LongTermKey;
+    use crate::request::nonce_from_request;
+    use crate::response::ResponseHandler;
+    use crate::testvectors::{TestVector, HEX};
+    use crate::version::Version;
+    use crate::{RtMessage, FRAME_HEADER_LENGTH};
+
+    #[test]
+    fn test_vectors_validate() {
+        for vector in TestVector::all() {
+            let srv = LongTermKey::calc_srv_value(&vector.long_term_public_key);
+            let (nonce, version) =
+                nonce_from_request(&vector.request, vector.request.len(), &srv, None).unwrap();
+            assert_eq!(nonce, vector.nonce);
+            assert_eq!(version, vector.version);
+
+            let message = match vector.version.profile().framed {
+                true => &vector.response[FRAME_HEADER_LENGTH..],
+                false => &vector.response[..],
+            };
+            let handler = ResponseHandler::new(
+                vector.version,
+                Some(vector.long_term_public_key.clone()),
+                RtMessage::from_bytes(message).unwrap(),
+                vector.nonce.clone(),
+            )
+            .unwrap();
+
+            let parsed = handler.extract_time().unwrap();
+            assert!(parsed.verified, "{}", vector.version);
+            assert_eq!(parsed.midpoint, vector.midpoint);
+            assert_eq!(parsed.radius, vector.radius);
+        }
+    }
+
+    #[test]
+    fn test_vectors_are_stable() {
+        // A change to these is a change to what the server sends, which other
+        // implementations may have pinned
+        let expected = [
+            (
+                Version::Classic,
+                "6557d6567878e5dded764531da31c2ba62912e20b5c391ee3e4f2709ece9e041",
+            ),
+            (
+                Version::Rfc,
+                "91e049ec6e70c4c94b487d4468ba138f7338cefb0fe111bc8d8a09581f39955c",
+            ),
+            (
+                Version::RfcDraft11,
+                "619129d2c8556065d37255c3a84814d77e8ca83500dad651570c1c8c45efac0b",
+            ),
+        ];
+
+        for (version, hash) in expected {
+            let vector = TestVector::generate(version);
+            assert_eq!(vector, TestVector::generate(version));
+
+            let actual = digest::digest(&digest::SHA256, vector.to_json().as_bytes());
+            assert_eq!(HEX.encode(actual.as_ref()), hash, "{}", version);
+        }
+    }
+}
@@ -14,6 +14,12 @@
 
 use std::fmt::{Display, Formatter};
 
+use crate::tag::Tag;
+use crate::{
+    CLASSIC_NONCE_LENGTH, CLASSIC_ROOT_LENGTH, MAX_CLASSIC_RESPONSE_LENGTH,
+    MAX_RFC_RESPONSE_LENGTH, RFC_NONCE_LENGTH, RFC_ROOT_LENGTH,
+};
+
 /// Version of the Roughtime protocol
 #[derive(Debug, PartialEq, Eq, PartialOrd, Hash, Clone, Copy)]
 pub enum Version {
@@ -39,7 +45,77 @@ const STR_VER_RFC: &'static str = "Rfc";
 const BYTES_VER_RFC_DRAFT11: &'static [u8] = &[0x0b, 0x00, 0x00, 0x80];
 const STR_VER_RFC_DRAFT11: &'static str = "RfcDraft11";
 
+/// RFC protocol versions supported by this implementation, in the ascending order of their
+/// wire values that a response's `VERS` tag lists them in
+pub const SUPPORTED_RFC_VERSIONS: &[Version] = &[Version::Rfc, Version::RfcDraft11];
+
+///
+/// How a protocol version's messages differ from those of the others. Code handling several
+/// versions consults the [`profile`](enum.Version.html#method.profile) of a version rather
+/// than matching on the version itself.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionProfile {
+    /// Length of the `NONC` value of requests and responses
+    pub nonce_length: usize,
+
+    /// Messages are preceded by a `ROUGHTIM` frame header
+    pub framed: bool,
+
+    /// Messages carry `VER` tags, and signed responses a `VERS` tag
+    pub version_tags: bool,
+
+    /// Tag of the field that pads requests (and padded responses) to length
+    pub padding_tag: Tag,
+
+    /// Length of the Merkle tree hashes in `ROOT` and `PATH`: SHA-512 truncated to this
+    pub hash_length: usize,
+
+    /// Microseconds in one unit of `MIDP`, `RADI`, `MINT`, and `MAXT`
+    pub micros_per_unit: u64,
+
+    /// Length of the largest response, including any frame header
+    pub max_response_length: usize,
+}
+
+const PROFILE_CLASSIC: VersionProfile = VersionProfile {
+    nonce_length: CLASSIC_NONCE_LENGTH,
+    framed: false,
+    version_tags: false,
+    padding_tag: Tag::PAD,
+    hash_length: CLASSIC_ROOT_LENGTH,
+    micros_per_unit: 1,
+    max_response_length: MAX_CLASSIC_RESPONSE_LENGTH,
+};
+
+const PROFILE_RFC: VersionProfile = VersionProfile {
+    nonce_length: RFC_NONCE_LENGTH,
+    framed: true,
+    version_tags: true,
+    padding_tag: Tag::ZZZZ,
+    hash_length: RFC_ROOT_LENGTH,
+    micros_per_unit: 1_000_000,
+    max_response_length: MAX_RFC_RESPONSE_LENGTH,
+};
+
 impl Version {
+    /// How messages of this version differ from those of the others
+    pub const fn profile(self) -> VersionProfile {
+        match self {
+            Version::Classic => PROFILE_CLASSIC,
+            Version::Rfc | Version::RfcDraft11 => PROFILE_RFC,
+        }
+    }
+
+    /// The `Version` with on-the-wire representation `bytes`, if it is one this
+    /// implementation knows
+    pub fn from_wire(bytes: &[u8]) -> Option<Version> {
+        SUPPORTED_RFC_VERSIONS
+            .iter()
+            .copied()
+            .find(|v| v.wire_bytes() == bytes)
+    }
+
     /// On-the-wire representation of the version value
     pub const fn wire_bytes(self) -> &'static [u8] {
         match self {
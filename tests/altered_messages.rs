@@ -0,0 +1,379 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Requests, and responses from this crate's own responder, altered by hand, each either
+//! accepted or rejected with the reason a user would see.
+//!
+//! Responses start as genuine responses from this crate's responder and are then altered
+//! the way a non-conforming peer might alter them, so every case exercises the full parsing
+//! and validation path and the expected rejection reasons are pinned. These are not responses
+//! of other Roughtime implementations' traffic and pin only our reading of the protocol.
+//!
+
+use std::net::UdpSocket as StdUdpSocket;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+use mio::net::UdpSocket;
+
+use roughenough::config::MemoryConfig;
+use roughenough::key::LongTermKey;
+use roughenough::request::{make_request, nonce_from_request};
+use roughenough::responder::Responder;
+use roughenough::response::{ParsedResponse, ResponseHandler};
+use roughenough::stats::{AggregatedStats, ServerStats};
+use roughenough::version::Version;
+use roughenough::{
+    Error, RtMessage, Tag, CLASSIC_NONCE_LENGTH, FRAME_HEADER_LENGTH, RFC_NONCE_LENGTH,
+};
+
+const SEED: &str = "a32049da0ffde0ded92ce10a0230d35fe615ec8461c14986baa63fe3b3bac3db";
+const OTHER_SEED: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+fn long_term_key(seed: &str) -> LongTermKey {
+    LongTermKey::new(&data_encoding::HEXLOWER.decode(seed.as_bytes()).unwrap())
+}
+
+fn version_list(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+// An RFC request whose VER field lists `versions`, padded to the minimum request length
+fn rfc_request_offering(versions: &[u32]) -> Vec<u8> {
+    let mut msg = RtMessage::with_capacity(3);
+    msg.add_field(Tag::VER, &version_list(versions)).unwrap();
+    msg.add_field(Tag::NONC, &[0x42; RFC_NONCE_LENGTH]).unwrap();
+    msg.add_field(Tag::ZZZZ, &[]).unwrap();
+    let padding = msg.calculate_padding_length();
+
+    let mut msg = RtMessage::with_capacity(3);
+    msg.add_field(Tag::VER, &version_list(versions)).unwrap();
+    msg.add_field(Tag::NONC, &[0x42; RFC_NONCE_LENGTH]).unwrap();
+    msg.add_field(Tag::ZZZZ, &vec![0; padding]).unwrap();
+    msg.encode_framed().unwrap()
+}
+
+fn built_request(version: Version, srv: Option<&[u8]>) -> Vec<u8> {
+    let nonce = match version {
+        Version::Classic => vec![0x43; CLASSIC_NONCE_LENGTH],
+        Version::Rfc | Version::RfcDraft11 => vec![0x42; RFC_NONCE_LENGTH],
+    };
+    let msg = make_request(version, &nonce, srv, None);
+    match version {
+        Version::Classic => msg.encode().unwrap(),
+        Version::Rfc | Version::RfcDraft11 => msg.encode_framed().unwrap(),
+    }
+}
+
+#[test]
+fn altered_requests_are_accepted_or_rejected() {
+    let srv = long_term_key(SEED).srv_value().to_vec();
+    let other_srv = long_term_key(OTHER_SEED).srv_value().to_vec();
+
+    let unframed = {
+        let framed = built_request(Version::Rfc, None);
+        let mut padded = framed[FRAME_HEADER_LENGTH..].to_vec();
+        padded.extend([0u8; FRAME_HEADER_LENGTH]);
+        padded
+    };
+    let misreported_length = {
+        let mut req = built_request(Version::Rfc, None);
+        let actual = (req.len() - FRAME_HEADER_LENGTH) as u32;
+        LittleEndian::write_u32(&mut req[8..12], actual + 8);
+        (req, actual)
+    };
+    let without_nonce = {
+        let mut msg = RtMessage::with_capacity(2);
+        msg.add_field(Tag::VER, Version::Rfc.wire_bytes()).unwrap();
+        msg.add_field(Tag::ZZZZ, &[0; 1000]).unwrap();
+        msg.encode_framed().unwrap()
+    };
+
+    let cases: Vec<(&str, Vec<u8>, Result<Version, Error>)> = vec![
+        (
+            "RFC request naming only the final version",
+            built_request(Version::Rfc, Some(&srv)),
+            Ok(Version::Rfc),
+        ),
+        (
+            "draft-11 request",
+            built_request(Version::RfcDraft11, None),
+            Ok(Version::RfcDraft11),
+        ),
+        (
+            "classic (Google-protocol) request",
+            built_request(Version::Classic, None),
+            Ok(Version::Classic),
+        ),
+        (
+            "VER offers an older draft before draft 11",
+            rfc_request_offering(&[0x8000_0008, 0x8000_000b]),
+            Ok(Version::RfcDraft11),
+        ),
+        (
+            "VER offers only drafts this server doesn't speak",
+            rfc_request_offering(&[0x8000_0007, 0x8000_0008]),
+            Err(Error::NoCompatibleVersion),
+        ),
+        (
+            "SRV names a different long-term key",
+            built_request(Version::Rfc, Some(&other_srv)),
+            Err(Error::SrvMismatch),
+        ),
+        (
+            "RFC message sent without framing is answered as classic",
+            unframed,
+            Ok(Version::Classic),
+        ),
+        (
+            "frame length disagrees with the datagram",
+            misreported_length.0,
+            Err(Error::LengthMismatch(
+                misreported_length.1 + 8,
+                misreported_length.1,
+            )),
+        ),
+        (
+            "request without NONC",
+            without_nonce,
+            Err(Error::InvalidRequest),
+        ),
+        (
+            "request without padding",
+            rfc_request_offering(&[0x1])[..100].to_vec(),
+            Err(Error::RequestTooShort),
+        ),
+    ];
+
+    for (name, req, expected) in cases {
+        let parsed = nonce_from_request(&req, req.len(), &srv, None).map(|(_, ver)| ver);
+        assert_eq!(parsed, expected, "{}", name);
+    }
+}
+
+// A genuine response from this crate's responder, and what's needed to validate it
+struct OwnResponse {
+    version: Version,
+    nonce: Vec<u8>,
+    msg: RtMessage,
+}
+
+impl OwnResponse {
+    fn new(version: Version) -> OwnResponse {
+        let config = MemoryConfig::new(0);
+        let mut ltk = long_term_key(SEED);
+        let mut responder = Responder::new(version, &config, &mut ltk);
+        responder.set_grease_enabled(false);
+
+        let mut stats: Box<dyn ServerStats> = Box::new(AggregatedStats::new());
+        let mut socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let nonce = match version {
+            Version::Classic => vec![0x43; CLASSIC_NONCE_LENGTH],
+            Version::Rfc | Version::RfcDraft11 => vec![0x42; RFC_NONCE_LENGTH],
+        };
+        responder.add_request(nonce.clone(), client.local_addr().unwrap());
+        responder.send_responses(&mut socket, &mut stats, &mut ltk);
+
+        let mut buf = [0u8; 4096];
+        let len = client.recv(&mut buf).expect("missing response");
+        let start = match version {
+            Version::Classic => 0,
+            Version::Rfc | Version::RfcDraft11 => FRAME_HEADER_LENGTH,
+        };
+
+        OwnResponse {
+            version,
+            nonce,
+            msg: RtMessage::from_bytes(&buf[start..len]).unwrap(),
+        }
+    }
+
+    fn field(&self, tag: Tag) -> Vec<u8> {
+        self.msg.get_field(tag).unwrap().to_vec()
+    }
+
+    fn set(&mut self, tag: Tag, value: &[u8]) {
+        self.msg = with_field(&self.msg, tag, Some(value));
+    }
+
+    fn remove(&mut self, tag: Tag) {
+        self.msg = with_field(&self.msg, tag, None);
+    }
+
+    fn midpoint(&self) -> u64 {
+        let srep = RtMessage::from_bytes(&self.field(Tag::SREP)).unwrap();
+        LittleEndian::read_u64(srep.get_field(Tag::MIDP).unwrap())
+    }
+
+    // Replace (or remove) a field of the DELE message nested inside CERT
+    fn set_dele(&mut self, tag: Tag, value: Option<&[u8]>) {
+        let cert = RtMessage::from_bytes(&self.field(Tag::CERT)).unwrap();
+        let dele = RtMessage::from_bytes(cert.get_field(Tag::DELE).unwrap()).unwrap();
+        let dele = with_field(&dele, tag, value).encode().unwrap();
+        let cert = with_field(&cert, Tag::DELE, Some(&dele)).encode().unwrap();
+        self.set(Tag::CERT, &cert);
+    }
+
+    // Replace a field of the signed response (SREP)
+    fn set_srep(&mut self, tag: Tag, value: &[u8]) {
+        let srep = RtMessage::from_bytes(&self.field(Tag::SREP)).unwrap();
+        let srep = with_field(&srep, tag, Some(value)).encode().unwrap();
+        self.set(Tag::SREP, &srep);
+    }
+
+    fn validate(self, public_key: &[u8]) -> Result<ParsedResponse, String> {
+        ResponseHandler::new(
+            self.version,
+            Some(public_key.to_vec()),
+            self.msg,
+            self.nonce,
+        )?
+        .extract_time()
+    }
+}
+
+// A copy of `msg` with `tag` set to `value`, or dropped if `value` is None
+fn with_field(msg: &RtMessage, tag: Tag, value: Option<&[u8]>) -> RtMessage {
+    let mut out = RtMessage::with_capacity(msg.num_fields());
+    for (t, v) in msg.tags().iter().zip(msg.values()) {
+        match (*t == tag, value) {
+            (false, _) => out.add_field(*t, v).unwrap(),
+            (true, Some(value)) => out.add_field(*t, value).unwrap(),
+            (true, None) => (),
+        }
+    }
+    out
+}
+
+type Mutation = fn(&mut OwnResponse);
+
+#[test]
+fn altered_responses_are_accepted_or_rejected() {
+    let public_key = long_term_key(SEED).public_key();
+    let other_public_key = long_term_key(OTHER_SEED).public_key();
+
+    let unchanged: Mutation = |_| ();
+    let cases: Vec<(&str, Mutation, Result<(), &str>)> = vec![
+        ("well-formed response", unchanged, Ok(())),
+        (
+            "SREP omitted",
+            |c| c.remove(Tag::SREP),
+            Err("Response is missing the SREP tag"),
+        ),
+        (
+            "INDX omitted",
+            |c| c.remove(Tag::INDX),
+            Err("Response is missing the INDX tag"),
+        ),
+        (
+            "delegation without MAXT",
+            |c| c.set_dele(Tag::MAXT, None),
+            Err("Response is missing the MAXT tag"),
+        ),
+        (
+            "SREP that isn't a message",
+            |c| c.set(Tag::SREP, &[0xff; 8]),
+            Err("Malformed SREP message"),
+        ),
+        (
+            "RADI encoded in two bytes",
+            |c| c.set_srep(Tag::RADI, &[0x40, 0x42]),
+            Err("Malformed SREP message: InvalidAlignment"),
+        ),
+        (
+            "empty INDX",
+            |c| c.set(Tag::INDX, &[]),
+            Err("INDX value is too short"),
+        ),
+        (
+            "answer to another client's nonce",
+            |c| c.nonce[0] ^= 0x01,
+            Err("Nonce is not present in the response's merkle tree"),
+        ),
+        (
+            "midpoint before the delegation starts",
+            |c| {
+                let mint = c.midpoint() + 1;
+                c.set_dele(Tag::MINT, Some(&mint.to_le_bytes()))
+            },
+            Err("lies *before* delegation span"),
+        ),
+        (
+            "midpoint after the delegation ends",
+            |c| {
+                let maxt = c.midpoint() - 1;
+                c.set_dele(Tag::MAXT, Some(&maxt.to_le_bytes()))
+            },
+            Err("lies *after* delegation span"),
+        ),
+        (
+            "SREP signature over different bytes",
+            |c| {
+                let mut sig = c.field(Tag::SIG);
+                sig[0] ^= 0x01;
+                c.set(Tag::SIG, &sig)
+            },
+            Err("Invalid signature on SREP tag"),
+        ),
+    ];
+
+    for version in [Version::Rfc, Version::RfcDraft11, Version::Classic] {
+        for (name, mutate, expected) in &cases {
+            let mut response = OwnResponse::new(version);
+            mutate(&mut response);
+
+            match (response.validate(&public_key), expected) {
+                (Ok(parsed), Ok(())) => assert!(parsed.verified, "{} {}", version, name),
+                (Err(reason), Err(expected)) => assert!(
+                    reason.contains(expected),
+                    "{} {}: rejected with '{}'",
+                    version,
+                    name,
+                    reason
+                ),
+                (result, _) => panic!("{} {}: unexpected {:?}", version, name, result),
+            }
+        }
+
+        // A delegation from a key other than the one the client was configured with
+        match OwnResponse::new(version).validate(&other_public_key) {
+            Err(reason) => assert!(
+                reason.contains("Invalid signature on DELE tag"),
+                "{}: {}",
+                version,
+                reason
+            ),
+            Ok(_) => panic!("{}: accepted a response from another server", version),
+        }
+    }
+}
+
+#[test]
+fn unrequested_version_is_reported() {
+    let response = OwnResponse::new(Version::Rfc);
+    let handler =
+        ResponseHandler::new(response.version, None, response.msg, response.nonce).unwrap();
+
+    assert!(handler.version_warnings(&[Version::Rfc]).is_empty());
+    assert_eq!(
+        handler.version_warnings(&[Version::RfcDraft11]),
+        vec!["Server chose version Rfc, which the request didn't offer"]
+    );
+}
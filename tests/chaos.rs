@@ -0,0 +1,276 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Crash-recovery: kill the server while clients are querying it and start it again.
+//!
+//! Clients may see timeouts (or refused requests) while the server is down, but every
+//! response they do receive must validate, and the restarted server must answer under a
+//! fresh delegation.
+//!
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use roughenough::client::Client;
+use roughenough::key::LongTermKey;
+use roughenough::merkle::MerkleTree;
+use roughenough::sign::MsgVerifier;
+use roughenough::version::Version;
+use roughenough::{
+    ErrorKind, RtMessage, Tag, CERTIFICATE_CONTEXT, FRAME_HEADER_LENGTH, RFC_NONCE_LENGTH,
+    RFC_REQUEST_PADDING_LENGTH, SIGNED_RESPONSE_CONTEXT,
+};
+
+const SEED: &str = "a32049da0ffde0ded92ce10a0230d35fe615ec8461c14986baa63fe3b3bac3db";
+const NUM_CLIENTS: usize = 4;
+
+// What a client saw for one request
+enum Outcome {
+    // A response that validated, signed by the contained online key
+    Valid(Vec<u8>),
+    // No response: timed out, or the request was refused while the server was down
+    Unavailable,
+    Invalid(String),
+}
+
+struct ServerProcess {
+    config: PathBuf,
+    child: Child,
+}
+
+impl ServerProcess {
+    fn start(port: u16) -> ServerProcess {
+        let config = std::env::temp_dir().join(format!("roughenough-chaos-{}.cfg", port));
+        fs::write(
+            &config,
+            format!("interface: 127.0.0.1\nport: {}\nseed: {}\n", port, SEED),
+        )
+        .unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_roughenough-server"))
+            .arg(&config)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start server");
+
+        ServerProcess { config, child }
+    }
+
+    // Crash, not a clean shutdown: SIGKILL on unix
+    fn kill(mut self) {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = fs::remove_file(&self.config);
+    }
+}
+
+fn free_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn make_request(nonce: &[u8]) -> Vec<u8> {
+    let mut msg = RtMessage::with_capacity(3);
+    msg.add_field(Tag::VER, Version::Rfc.wire_bytes()).unwrap();
+    msg.add_field(Tag::NONC, nonce).unwrap();
+    msg.add_field(Tag::ZZZZ, &[0; RFC_REQUEST_PADDING_LENGTH])
+        .unwrap();
+    msg.encode_framed().unwrap()
+}
+
+// Validate `response` to a request with `nonce` the way a careful client would, returning
+// the online key that signed it
+fn validate(nonce: &[u8], response: &[u8], long_term_key: &[u8]) -> Result<Vec<u8>, String> {
+    let field = |msg: &RtMessage, tag: Tag| {
+        msg.get_field(tag)
+            .map(|v| v.to_vec())
+            .ok_or(format!("missing {}", tag))
+    };
+
+    if response.len() < FRAME_HEADER_LENGTH {
+        return Err("short response".to_string());
+    }
+    let msg =
+        RtMessage::from_bytes(&response[FRAME_HEADER_LENGTH..]).map_err(|e| format!("{:?}", e))?;
+    let cert = RtMessage::from_bytes(&field(&msg, Tag::CERT)?).map_err(|e| format!("{:?}", e))?;
+    let dele_bytes = field(&cert, Tag::DELE)?;
+    let dele = RtMessage::from_bytes(&dele_bytes).map_err(|e| format!("{:?}", e))?;
+    let online_key = field(&dele, Tag::PUBK)?;
+    let srep_bytes = field(&msg, Tag::SREP)?;
+    let srep = RtMessage::from_bytes(&srep_bytes).map_err(|e| format!("{:?}", e))?;
+
+    let mut verifier = MsgVerifier::new(long_term_key);
+    verifier.update(CERTIFICATE_CONTEXT.as_bytes());
+    verifier.update(&dele_bytes);
+    if !verifier.verify(&field(&cert, Tag::SIG)?) {
+        return Err("invalid DELE signature".to_string());
+    }
+
+    let mut verifier = MsgVerifier::new(&online_key);
+    verifier.update(SIGNED_RESPONSE_CONTEXT.as_bytes());
+    verifier.update(&srep_bytes);
+    if !verifier.verify(&field(&msg, Tag::SIG)?) {
+        return Err("invalid SREP signature".to_string());
+    }
+
+    let index = field(&msg, Tag::INDX)?
+        .as_slice()
+        .read_u32::<LittleEndian>()
+        .map_err(|e| e.to_string())?;
+    let root = MerkleTree::new_sha512_ietf().root_from_paths(
+        index as usize,
+        nonce,
+        &field(&msg, Tag::PATH)?,
+    );
+    if root != field(&srep, Tag::ROOT)? {
+        return Err("invalid Merkle proof".to_string());
+    }
+
+    Ok(online_key)
+}
+
+fn query_until_stopped(
+    addr: SocketAddr,
+    long_term_key: Vec<u8>,
+    running: Arc<AtomicBool>,
+    outcomes: Arc<Mutex<Vec<Outcome>>>,
+) {
+    let client = Client::builder()
+        .read_timeout(Duration::from_millis(100))
+        .build();
+    let rng = SystemRandom::new();
+
+    while running.load(Ordering::Acquire) {
+        let mut nonce = [0u8; RFC_NONCE_LENGTH];
+        rng.fill(&mut nonce).unwrap();
+
+        let outcome = match client.exchange(&addr, &make_request(&nonce)) {
+            Ok(response) => match validate(&nonce, &response, &long_term_key) {
+                Ok(online_key) => Outcome::Valid(online_key),
+                Err(e) => Outcome::Invalid(e),
+            },
+            Err(e) if e.kind() == ErrorKind::Io => Outcome::Unavailable,
+            Err(e) => Outcome::Invalid(format!("{:?}", e)),
+        };
+        outcomes.lock().unwrap().push(outcome);
+    }
+}
+
+// Wait until responses from `count` distinct online keys have been seen
+fn await_online_keys(outcomes: &Mutex<Vec<Outcome>>, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(30);
+
+    while Instant::now() < deadline {
+        let keys: HashSet<Vec<u8>> = outcomes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|o| match o {
+                Outcome::Valid(key) => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+
+        if keys.len() >= count {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    panic!(
+        "no responses from {} online key(s) within 30 seconds",
+        count
+    );
+}
+
+#[test]
+fn server_restart_under_load() {
+    let port = free_port();
+    let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let long_term_key =
+        LongTermKey::new(&data_encoding::HEXLOWER.decode(SEED.as_bytes()).unwrap()).public_key();
+
+    let running = Arc::new(AtomicBool::new(true));
+    let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+    let server = ServerProcess::start(port);
+
+    let clients: Vec<_> = (0..NUM_CLIENTS)
+        .map(|_| {
+            let long_term_key = long_term_key.clone();
+            let running = Arc::clone(&running);
+            let outcomes = Arc::clone(&outcomes);
+            thread::spawn(move || query_until_stopped(addr, long_term_key, running, outcomes))
+        })
+        .collect();
+
+    await_online_keys(&outcomes, 1);
+    server.kill();
+
+    // let clients run into the outage
+    thread::sleep(Duration::from_millis(300));
+    let server = ServerProcess::start(port);
+    await_online_keys(&outcomes, 2);
+
+    running.store(false, Ordering::Release);
+    for client in clients {
+        client.join().unwrap();
+    }
+    drop(server);
+
+    let outcomes = outcomes.lock().unwrap();
+    let mut online_keys = Vec::new();
+    let mut unavailable = 0;
+
+    for outcome in outcomes.iter() {
+        match outcome {
+            Outcome::Valid(key) => {
+                if online_keys.last() != Some(key) {
+                    online_keys.push(key.clone());
+                }
+            }
+            Outcome::Unavailable => unavailable += 1,
+            Outcome::Invalid(e) => panic!("client received an invalid response: {}", e),
+        }
+    }
+
+    assert!(unavailable > 0, "clients never noticed the outage");
+
+    // responses switch from the first delegation to the second exactly once; the restarted
+    // server never reuses the delegation of the one that crashed
+    let distinct: HashSet<&Vec<u8>> = online_keys.iter().collect();
+    assert_eq!(distinct.len(), 2);
+    assert_eq!(online_keys.len(), 2);
+}
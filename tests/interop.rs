@@ -0,0 +1,131 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Replay the captures of other Roughtime implementations' traffic in `tests/interop/`
+//! through the response parser and validator. Each must be accepted or rejected with the
+//! reason listed in `tests/interop/expected.txt`.
+//!
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use roughenough::response::ResponseHandler;
+use roughenough::version::Version;
+use roughenough::{RtMessage, Tag, FRAME_HEADER_LENGTH, REQUEST_FRAMING_BYTES};
+
+struct Capture {
+    name: String,
+    version: Version,
+    public_key: Vec<u8>,
+    expected: Result<(), String>,
+}
+
+fn corpus_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/interop")
+}
+
+fn parse_version(value: &str) -> Version {
+    [Version::Rfc, Version::RfcDraft11, Version::Classic]
+        .into_iter()
+        .find(|v| v.to_string() == value)
+        .unwrap_or_else(|| panic!("unknown version '{}'", value))
+}
+
+// Every capture listed in `expected.txt`, skipping comments and blank lines
+fn captures(dir: &Path) -> Vec<Capture> {
+    let path = dir.join("expected.txt");
+    let table = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("can't read {}: {}", path.display(), e));
+
+    table
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let fields: Vec<&str> = line.splitn(4, char::is_whitespace).collect();
+            let [name, version, public_key, outcome] = fields[..] else {
+                panic!("malformed line in {}: '{}'", path.display(), line);
+            };
+            let expected = match outcome.trim() {
+                "ok" => Ok(()),
+                other => match other.strip_prefix("reject:") {
+                    Some(reason) => Err(reason.trim().to_string()),
+                    None => panic!("unknown outcome '{}' for {}", other, name),
+                },
+            };
+
+            Capture {
+                name: name.to_string(),
+                version: parse_version(version),
+                public_key: data_encoding::HEXLOWER_PERMISSIVE
+                    .decode(public_key.as_bytes())
+                    .unwrap_or_else(|e| panic!("bad public key for {}: {}", name, e)),
+                expected,
+            }
+        })
+        .collect()
+}
+
+// The message in `file`, without its frame header if it has one
+fn read_message(dir: &Path, file: &str) -> RtMessage {
+    let bytes = fs::read(dir.join(file)).unwrap_or_else(|e| panic!("can't read {}: {}", file, e));
+    let unframed = match bytes.strip_prefix(REQUEST_FRAMING_BYTES) {
+        Some(_) => &bytes[FRAME_HEADER_LENGTH.min(bytes.len())..],
+        None => &bytes[..],
+    };
+    RtMessage::from_bytes(unframed).unwrap_or_else(|e| panic!("{} is malformed: {:?}", file, e))
+}
+
+fn validate(dir: &Path, capture: &Capture) -> Result<(), String> {
+    let request = read_message(dir, &format!("{}.request.bin", capture.name));
+    let nonce = request
+        .get_field(Tag::NONC)
+        .unwrap_or_else(|| panic!("{} request has no NONC", capture.name))
+        .to_vec();
+
+    let response = read_message(dir, &format!("{}.response.bin", capture.name));
+    ResponseHandler::new(
+        capture.version,
+        Some(capture.public_key.clone()),
+        response,
+        nonce,
+    )?
+    .extract_time()
+    .map(|_| ())
+}
+
+#[test]
+fn captures_are_accepted_or_rejected() {
+    let dir = corpus_dir();
+
+    let mut failures = Vec::new();
+    for capture in captures(&dir) {
+        let result = validate(&dir, &capture);
+        let matches = match (&result, &capture.expected) {
+            (Ok(()), Ok(())) => true,
+            (Err(reason), Err(expected)) => reason.contains(expected.as_str()),
+            _ => false,
+        };
+
+        if !matches {
+            failures.push(format!(
+                "{}: expected {:?}, got {:?}",
+                capture.name, capture.expected, result
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "{:#?}", failures);
+}
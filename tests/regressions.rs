@@ -0,0 +1,166 @@
+// Copyright 2017-2022 int08h LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//!
+//! Replay the regression corpus in `tests/regressions/` through the request parser, the
+//! response parser and validator, and a running server. None of them may panic, and the
+//! server must still answer a valid request afterwards.
+//!
+//! Every input that once crashed or hung something (a fuzzer finding, a packet from the
+//! wild) belongs in the corpus, as is, in a file named after what it exercises.
+//!
+
+use std::fs;
+use std::net::UdpSocket as StdUdpSocket;
+use std::panic;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use mio::net::UdpSocket;
+use mio::Events;
+
+use roughenough::config::MemoryConfig;
+use roughenough::key::LongTermKey;
+use roughenough::request::{self, RequestAuthenticator};
+use roughenough::response::ResponseHandler;
+use roughenough::server::Server;
+use roughenough::version::Version;
+use roughenough::{
+    RtMessage, CLASSIC_NONCE_LENGTH, FRAME_HEADER_LENGTH, REQUEST_FRAMING_BYTES, RFC_NONCE_LENGTH,
+};
+
+fn corpus() -> Vec<(String, Vec<u8>)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/regressions");
+
+    let mut entries: Vec<(String, Vec<u8>)> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("can't read {}: {}", dir.display(), e))
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            (name, fs::read(&path).unwrap())
+        })
+        .collect();
+
+    entries.sort();
+    assert!(
+        !entries.is_empty(),
+        "no regression inputs in {}",
+        dir.display()
+    );
+    entries
+}
+
+// Feed `input` to the request parsers, with and without request authentication
+fn parse_as_request(input: &[u8], srv_value: &[u8]) {
+    let authenticator = RequestAuthenticator::new(b"0123456789abcdef");
+
+    let _ = request::nonce_from_request(input, input.len(), srv_value, None);
+    let _ = request::nonce_from_request(input, input.len(), srv_value, Some(&authenticator));
+    let _ = request::nonce_from_request_strict(input, input.len(), srv_value, None);
+}
+
+// Feed `input` to the response parser and validator as every protocol version
+fn parse_as_response(input: &[u8], public_key: &[u8]) {
+    let unframed = match input.strip_prefix(REQUEST_FRAMING_BYTES) {
+        Some(_) => &input[FRAME_HEADER_LENGTH.min(input.len())..],
+        None => input,
+    };
+
+    let msg = match RtMessage::from_bytes(unframed) {
+        Ok(msg) => msg,
+        Err(_) => return,
+    };
+
+    for version in [Version::Rfc, Version::RfcDraft11, Version::Classic] {
+        let nonce = match version {
+            Version::Classic => vec![0u8; CLASSIC_NONCE_LENGTH],
+            Version::Rfc | Version::RfcDraft11 => vec![0u8; RFC_NONCE_LENGTH],
+        };
+
+        for pub_key in [None, Some(public_key.to_vec())] {
+            if let Ok(handler) = ResponseHandler::new(version, pub_key, msg.clone(), nonce.clone())
+            {
+                let _ = handler.extract_time();
+                let _ = handler.version_warnings(&[version]);
+            }
+        }
+    }
+}
+
+#[test]
+fn corpus_does_not_panic_parsers() {
+    let config = MemoryConfig::new(0);
+    let long_term_key = LongTermKey::new(&config.seed);
+    let public_key = long_term_key.public_key();
+    let srv_value = long_term_key.srv_value().to_vec();
+
+    let mut failures = Vec::new();
+    for (name, input) in corpus() {
+        let result = panic::catch_unwind(|| {
+            parse_as_request(&input, &srv_value);
+            parse_as_response(&input, &public_key);
+        });
+
+        if result.is_err() {
+            failures.push(name);
+        }
+    }
+
+    assert!(failures.is_empty(), "parsers panicked on {:?}", failures);
+}
+
+#[test]
+fn corpus_does_not_disrupt_server() {
+    let config = MemoryConfig::new(0);
+    let socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    let mut server = Server::new(&config, socket);
+    let mut events = Events::with_capacity(64);
+
+    let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+
+    for (_, input) in corpus() {
+        client.send_to(&input, server_addr).unwrap();
+        server.process_events(&mut events);
+    }
+
+    // Some inputs are merely unusual rather than invalid and may be answered
+    let mut buf = [0u8; 4096];
+    while client.recv(&mut buf).is_ok() {}
+
+    let long_term_key = LongTermKey::new(&config.seed);
+    let srv_value = long_term_key.srv_value().to_vec();
+    let nonce = vec![0x42u8; RFC_NONCE_LENGTH];
+    let request = request::make_request(Version::Rfc, &nonce, Some(&srv_value), None)
+        .encode_framed()
+        .unwrap();
+
+    client.send_to(&request, server_addr).unwrap();
+    for _ in 0..10 {
+        server.process_events(&mut events);
+        if let Ok(num_bytes) = client.recv(&mut buf) {
+            let resp = RtMessage::from_bytes(&buf[FRAME_HEADER_LENGTH..num_bytes]).unwrap();
+            let handler =
+                ResponseHandler::new(Version::Rfc, Some(long_term_key.public_key()), resp, nonce)
+                    .unwrap();
+            handler.extract_time().unwrap();
+            return;
+        }
+    }
+
+    panic!("server stopped answering after replaying the corpus");
+}